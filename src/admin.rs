@@ -0,0 +1,442 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+};
+
+use crate::{
+    borg::Borg,
+    config::{Config, ConfigError},
+    dictionary::SaveMode,
+    learn_queue::LearnQueue,
+};
+
+/////////////////////////////////////////////////////////////////////////////
+// Request Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// Request is one line of the admin protocol, e.g.
+/// {"token": "...", "cmd": "stats"}.
+#[derive(Debug, PartialEq, Deserialize)]
+struct Request {
+    token: String,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(tag = "cmd", rename_all = "lowercase")]
+enum Command {
+    Stats,
+    Save,
+    /// Lists the sentences that contain the text, and only removes them when
+    /// confirm is true.
+    Forget {
+        text: String,
+        #[serde(default)]
+        confirm: bool,
+    },
+    Decisions,
+    Sample {
+        n: usize,
+    },
+    /// Tells who taught the sentence, or null if nobody is known to have.
+    Source {
+        sentence: String,
+    },
+    /// Reads the configuration file again and switches to its behavior.
+    Reload,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Context Struct
+/////////////////////////////////////////////////////////////////////////////
+
+pub struct Context {
+    borg: Arc<RwLock<Borg>>,
+    /// Only read from, to report how many lines it dropped.
+    learn_queue: LearnQueue,
+    dictionary_path: PathBuf,
+    config_path: PathBuf,
+    token: String,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Context Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl Context {
+    pub fn new(
+        borg: Arc<RwLock<Borg>>,
+        learn_queue: LearnQueue,
+        dictionary_path: PathBuf,
+        config_path: PathBuf,
+        token: String,
+    ) -> Context {
+        Context {
+            borg,
+            learn_queue,
+            dictionary_path,
+            config_path,
+            token,
+        }
+    }
+
+    async fn run_request(&self, line: &str) -> Value {
+        let request = match serde_json::from_str::<Request>(line) {
+            Ok(r) => r,
+            Err(e) => return error_response(&format!("Invalid request: {}", e)),
+        };
+        if request.token != self.token {
+            warn!("Refused an admin request with the wrong token");
+            return error_response("Wrong token");
+        }
+        debug!("[run_request] Running {:?}", request.command);
+        match request.command {
+            Command::Stats => {
+                let stats = self.borg.read().await.stats();
+                json!({
+                    "ok": true,
+                    "sentence_count": stats.sentence_count,
+                    "word_count": stats.word_count,
+                    "dropped_learn_lines": self.learn_queue.dropped(),
+                })
+            }
+            Command::Save => match self
+                .borg
+                .write()
+                .await
+                .save_dictionary(&self.dictionary_path, SaveMode::Full)
+            {
+                Ok(()) => json!({ "ok": true }),
+                Err(e) => error_response(&format!("Could not save the dictionary: {}", e)),
+            },
+            Command::Forget {
+                text,
+                confirm: false,
+            } => {
+                let sentences = self.borg.read().await.forgettable(&text);
+                json!({ "ok": true, "sentences": sentences })
+            }
+            Command::Forget {
+                text,
+                confirm: true,
+            } => {
+                let forgotten = self.borg.write().await.forget(&text);
+                json!({ "ok": true, "forgotten": forgotten })
+            }
+            Command::Decisions => {
+                let decisions = self.borg.read().await.decisions();
+                json!({ "ok": true, "decisions": decisions })
+            }
+            Command::Sample { n } => {
+                let borg = self.borg.read().await;
+                json!({ "ok": true, "sentences": borg.sample(n) })
+            }
+            Command::Source { sentence } => {
+                let borg = self.borg.read().await;
+                json!({ "ok": true, "source": borg.source_of(&sentence) })
+            }
+            Command::Reload => match self.reload(&self.config_path).await {
+                Ok(()) => json!({ "ok": true }),
+                Err(e) => error_response(&format!("Could not reload the configuration: {}", e)),
+            },
+        }
+    }
+
+    /// Switches the bot to the main behavior of the configuration file. Only
+    /// the behavior is reloaded; the other settings need a restart.
+    async fn reload(&self, path: &Path) -> Result<(), ConfigError> {
+        let config = Config::load(path)?;
+        self.borg.write().await.reload_behavior(config.behavior)?;
+        info!("Reloaded the behavior from {:?}", path);
+        Ok(())
+    }
+}
+
+fn error_response(message: &str) -> Value {
+    json!({ "ok": false, "error": message })
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Connection Handler
+/////////////////////////////////////////////////////////////////////////////
+
+/// Answers every line sent on the connection with a line of JSON, until the
+/// client disconnects.
+async fn handle(context: Arc<Context>, mut stream: TcpStream) -> io::Result<()> {
+    let (reader, mut writer) = stream.split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut response = context.run_request(&line).await.to_string();
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Run Method
+/////////////////////////////////////////////////////////////////////////////
+
+/// Listens on the bind address, which must be a loopback address: the
+/// token is sent in the clear.
+pub async fn run(context: Arc<Context>, bind_address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(bind_address).await?;
+    if !listener.local_addr()?.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a loopback address", bind_address),
+        ));
+    }
+    info!("Admin interface listening on {}", bind_address);
+    serve(context, listener).await
+}
+
+async fn serve(context: Arc<Context>, mut listener: TcpListener) -> io::Result<()> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        debug!("[serve] Admin connection from {}", address);
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(context, stream).await {
+                warn!("Admin connection from {} failed: {}", address, e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        borg::ChatKind,
+        config::MainBehavior,
+        dictionary::{Dictionary, Source},
+        learn_queue::LearnJob,
+    };
+    use std::fs;
+
+    async fn request(lines: &mut tokio::io::Lines<BufReader<TcpStream>>, line: &str) -> Value {
+        let stream = lines.get_mut().get_mut();
+        stream
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+        let response = lines.next_line().await.unwrap().unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_commands() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let path = std::env::temp_dir().join("borg_test_admin_commands.json");
+        let _ = fs::remove_file(&path);
+        let borg = Borg::new(dictionary, MainBehavior::default());
+        borg.reply_reason("ferris", "#crabs", ChatKind::Group, "hello", false, &None);
+        let borg = Arc::new(RwLock::new(borg));
+        let context = Arc::new(Context::new(
+            borg,
+            LearnQueue::new(1).0,
+            path.clone(),
+            PathBuf::from("config.yml"),
+            "sekrit".to_owned(),
+        ));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(context, listener));
+        let stream = TcpStream::connect(address).await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+
+        assert_eq!(
+            json!({"ok": false, "error": "Wrong token"}),
+            request(&mut lines, r#"{"token": "guess", "cmd": "stats"}"#).await
+        );
+        assert_eq!(
+            json!({
+                "ok": true,
+                "sentence_count": 2,
+                "word_count": 3,
+                "dropped_learn_lines": 0,
+            }),
+            request(&mut lines, r#"{"token": "sekrit", "cmd": "stats"}"#).await
+        );
+        assert_eq!(
+            json!({"ok": true, "sentences": ["hello lobsters."]}),
+            request(
+                &mut lines,
+                r#"{"token": "sekrit", "cmd": "forget", "text": "lobsters"}"#
+            )
+            .await
+        );
+        assert_eq!(
+            json!({"ok": true, "forgotten": 1}),
+            request(
+                &mut lines,
+                r#"{"token": "sekrit", "cmd": "forget", "text": "lobsters", "confirm": true}"#
+            )
+            .await
+        );
+        assert_eq!(
+            json!({"ok": true}),
+            request(&mut lines, r#"{"token": "sekrit", "cmd": "save"}"#).await
+        );
+        let saved = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(1, saved.stats().sentence_count);
+
+        let response = request(&mut lines, r#"{"token": "sekrit", "cmd": "decisions"}"#).await;
+        assert_eq!(
+            json!({
+                "chat_id": "#crabs",
+                "user_id": "ferris",
+                "input": "hello",
+                "decision": "not_chosen",
+            }),
+            {
+                let mut decision = response["decisions"][0].clone();
+                decision.as_object_mut().unwrap().remove("time");
+                decision
+            }
+        );
+
+        assert_eq!(
+            json!({"ok": true, "sentences": ["hello crabs."]}),
+            request(
+                &mut lines,
+                r#"{"token": "sekrit", "cmd": "sample", "n": 5}"#
+            )
+            .await
+        );
+
+        let response = request(&mut lines, r#"{"token": "sekrit", "cmd": "dance"}"#).await;
+        assert_eq!(json!(false), response["ok"]);
+    }
+
+    #[tokio::test]
+    async fn test_stats_report_dropped_learn_lines() {
+        let borg = Arc::new(RwLock::new(Borg::new(
+            Dictionary::new_empty(),
+            MainBehavior::default(),
+        )));
+        let (learn_queue, _jobs) = LearnQueue::new(1);
+        for line in &["hello crabs.", "hello lobsters."] {
+            learn_queue.push(LearnJob {
+                line: (*line).to_owned(),
+                source: None,
+            });
+        }
+        let context = Context::new(
+            borg,
+            learn_queue,
+            PathBuf::from("dictionary.json"),
+            PathBuf::from("config.yml"),
+            "sekrit".to_owned(),
+        );
+
+        let response = context
+            .run_request(r#"{"token": "sekrit", "cmd": "stats"}"#)
+            .await;
+        assert_eq!(json!(1), response["dropped_learn_lines"]);
+    }
+
+    #[tokio::test]
+    async fn test_source() {
+        let mut dictionary = Dictionary::new_empty();
+        let ferris = Source::new("irc", "#crabs", "ferris");
+        dictionary.learn_batch_attributed(
+            vec![("hello crabs.", Some(&ferris)), ("hello lobsters.", None)].into_iter(),
+        );
+        let borg = Arc::new(RwLock::new(Borg::new(dictionary, MainBehavior::default())));
+        let context = Context::new(
+            borg,
+            LearnQueue::new(1).0,
+            PathBuf::from("dictionary.json"),
+            PathBuf::from("config.yml"),
+            "sekrit".to_owned(),
+        );
+
+        assert_eq!(
+            json!({
+                "ok": true,
+                "source": {"platform": "irc", "chat_id": "#crabs", "user_id": "ferris"},
+            }),
+            context
+                .run_request(r#"{"token": "sekrit", "cmd": "source", "sentence": "Hello crabs."}"#)
+                .await
+        );
+        for sentence in &["hello lobsters.", "hello squids."] {
+            assert_eq!(
+                json!({"ok": true, "source": null}),
+                context
+                    .run_request(
+                        &json!({"token": "sekrit", "cmd": "source", "sentence": sentence})
+                            .to_string()
+                    )
+                    .await
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Arc::new(RwLock::new(Borg::new(dictionary, MainBehavior::default())));
+        let path =
+            std::env::temp_dir().join(format!("borg_test_reload_{}.yml", std::process::id()));
+        let context = Context::new(
+            borg.clone(),
+            LearnQueue::new(1).0,
+            PathBuf::from("dictionary.json"),
+            path.clone(),
+            "sekrit".to_owned(),
+        );
+        let config = |reply_rate| {
+            format!(
+                r#"
+dictionary_path: dictionary.json
+auto_save_period: 60
+behavior:
+  speaking: true
+  learning: true
+  reply_rate: {}
+  reply_nick: 1.0
+  reply_magic: 0.0
+  nick_patterns: []
+  magic_patterns: []
+  blacklisted_patterns: []
+  ignored_users: []
+"#,
+                reply_rate
+            )
+        };
+        let reload = r#"{"token": "sekrit", "cmd": "reload"}"#;
+        let replies =
+            |borg: &Borg| borg.reply_reason("1", "a", ChatKind::Group, "hello", false, &None);
+
+        fs::write(&path, config(1.0)).unwrap();
+        assert_eq!(json!({"ok": true}), context.run_request(reload).await);
+        assert!(replies(&*borg.read().await).is_some());
+
+        // An invalid configuration leaves the behavior as it was.
+        fs::write(&path, config(2.0)).unwrap();
+        let response = context.run_request(reload).await;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(json!(false), response["ok"]);
+        assert!(replies(&*borg.read().await).is_some());
+    }
+}
@@ -0,0 +1,73 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{sync::RwLock, time::delay_for};
+
+use crate::{backup, borg::Borg, config::Backups, dictionary::SaveMode, util::unix_time};
+
+/////////////////////////////////////////////////////////////////////////////
+// Auto-save Task
+/////////////////////////////////////////////////////////////////////////////
+
+/// Saves the dictionary every period, forever. In journal mode, every
+/// compact_every saves is a full one that compacts the journal. Rare words are
+/// pruned before every full save when min_word_count is set, and every full
+/// save is backed up when backups are configured.
+pub async fn run(
+    borg: Arc<RwLock<Borg>>,
+    path: PathBuf,
+    period: Duration,
+    mode: SaveMode,
+    compact_every: u32,
+    min_word_count: Option<usize>,
+    backups: Option<Backups>,
+) {
+    let mut saves: u32 = 0;
+    loop {
+        delay_for(period).await;
+        saves = saves.wrapping_add(1);
+        let mode = save_mode_for(mode, saves, compact_every);
+        debug!("[run] Auto-saving the dictionary with {:?}", mode);
+        let mut borg = borg.write().await;
+        if let (SaveMode::Full, Some(min_count)) = (mode, min_word_count) {
+            let pruned = borg.prune_rare_words(min_count);
+            debug!("[run] Pruned {} rare words", pruned);
+        }
+        if let Err(e) = borg.save_dictionary(&path, mode) {
+            error!("Could not auto-save the dictionary: {}", e);
+            continue;
+        }
+        drop(borg);
+        if let (SaveMode::Full, Some(b)) = (mode, &backups) {
+            match backup::create(&path, Path::new(&b.directory), b.keep, unix_time()) {
+                Ok(backup) => debug!("[run] Backed up the dictionary to {:?}", backup),
+                Err(e) => error!("Could not back up the dictionary: {}", e),
+            }
+        }
+    }
+}
+
+fn save_mode_for(mode: SaveMode, saves: u32, compact_every: u32) -> SaveMode {
+    if mode == SaveMode::Journal && compact_every > 0 && saves % compact_every == 0 {
+        SaveMode::Full
+    } else {
+        mode
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_mode_for() {
+        assert_eq!(SaveMode::Full, save_mode_for(SaveMode::Full, 1, 3));
+        assert_eq!(SaveMode::Journal, save_mode_for(SaveMode::Journal, 1, 3));
+        assert_eq!(SaveMode::Journal, save_mode_for(SaveMode::Journal, 2, 3));
+        assert_eq!(SaveMode::Full, save_mode_for(SaveMode::Journal, 3, 3));
+        assert_eq!(SaveMode::Journal, save_mode_for(SaveMode::Journal, 3, 0));
+    }
+}
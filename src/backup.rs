@@ -0,0 +1,147 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::dictionary::Dictionary;
+
+/////////////////////////////////////////////////////////////////////////////
+// Backup Rotation
+/////////////////////////////////////////////////////////////////////////////
+
+/// Copies the dictionary file into the backup directory, under its own name
+/// followed by the given time, and removes all but the newest keep backups.
+/// Returns the path of the new backup.
+pub fn create(path: &Path, directory: &Path, keep: usize, now: u64) -> io::Result<PathBuf> {
+    fs::create_dir_all(directory)?;
+    let backup = directory.join(format!("{}.{}", file_name(path), now));
+    fs::copy(path, &backup)?;
+    for old in list(path, directory)?.iter().rev().skip(keep) {
+        debug!("[create] Removing the old backup {:?}", old);
+        fs::remove_file(old)?;
+    }
+    Ok(backup)
+}
+
+/// Replaces the dictionary file with the newest backup that can be loaded,
+/// and returns the path of that backup. The replaced file is kept next to it
+/// with a .corrupt extension, so that it can still be looked into.
+pub fn restore_newest(path: &Path, directory: &Path) -> io::Result<Option<PathBuf>> {
+    for backup in list(path, directory)?.into_iter().rev() {
+        match Dictionary::load(&backup) {
+            Ok(_) => {
+                let mut corrupt = path.as_os_str().to_owned();
+                corrupt.push(".corrupt");
+                fs::rename(path, corrupt)?;
+                fs::copy(&backup, path)?;
+                return Ok(Some(backup));
+            }
+            Err(e) => warn!("Skipping unreadable backup {:?}: {}", backup, e),
+        }
+    }
+    Ok(None)
+}
+
+/// Lists the backups of the dictionary file, from the oldest to the newest.
+/// A backup directory that does not exist yet has no backups.
+fn list(path: &Path, directory: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let prefix = format!("{}.", file_name(path));
+    let mut backups = vec![];
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(&prefix) {
+            continue;
+        }
+        if let Ok(time) = name[prefix.len()..].parse::<u64>() {
+            backups.push((time, entry.path()));
+        }
+    }
+    backups.sort();
+    Ok(backups.into_iter().map(|(_, path)| path).collect())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "dictionary".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("borg_test_backup_{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_create_prunes_old_backups() {
+        let dir = temp_dir("prune");
+        let path = dir.join("dictionary.json");
+        let backups = dir.join("backups");
+        let mut d = Dictionary::new_empty();
+        d.learn("hello crabs.");
+        d.write_to_disk(&path).unwrap();
+
+        for now in &[100, 300, 200, 400] {
+            create(&path, &backups, 3, *now).unwrap();
+        }
+        fs::write(backups.join("notes.txt"), "not a backup").unwrap();
+
+        assert_eq!(
+            vec![
+                backups.join("dictionary.json.200"),
+                backups.join("dictionary.json.300"),
+                backups.join("dictionary.json.400"),
+            ],
+            list(&path, &backups).unwrap()
+        );
+        assert!(backups.join("notes.txt").is_file());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_newest_valid_backup() {
+        let dir = temp_dir("restore");
+        let path = dir.join("dictionary.json");
+        let backups = dir.join("backups");
+        let mut d = Dictionary::new_empty();
+        d.learn("hello crabs.");
+        d.write_to_disk(&path).unwrap();
+        create(&path, &backups, 3, 100).unwrap();
+        fs::write(backups.join("dictionary.json.200"), "{\"sentences\": [").unwrap();
+        fs::write(&path, "{\"sentences\": [\"hello").unwrap();
+        assert!(Dictionary::load(&path).is_err());
+
+        assert_eq!(
+            Some(backups.join("dictionary.json.100")),
+            restore_newest(&path, &backups).unwrap()
+        );
+        assert_eq!(d, Dictionary::load(&path).unwrap());
+        assert!(dir.join("dictionary.json.corrupt").is_file());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_restore_without_backups() {
+        let dir = temp_dir("none");
+        let path = dir.join("dictionary.json");
+        fs::write(&path, "{").unwrap();
+
+        assert_eq!(None, restore_newest(&path, &dir.join("backups")).unwrap());
+        assert!(path.is_file());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,18 +1,553 @@
-use crate::config::{BehaviorValueResolver, MainBehavior};
+use crate::config::{
+    BehaviorValueResolver, BlacklistMode, InputSanitization, LanguageFilter, LearnFilter,
+    LongResponseMode, MainBehavior, MentionHandling, PatternOwner, ReplyScoring, ShortResponseMode,
+    UncertainLanguage, UrlHandling,
+};
 use crate::{
-    config::BehaviorOverrideValueResolver, dictionary::Dictionary, pattern, rand_core::RngCore,
+    config::{BehaviorOverrideValueResolver, ConfigError},
+    dictionary,
+    dictionary::{Dictionary, SaveMode, Source, Stats},
+    learn_queue::LearnJob,
+    pattern, postprocess,
+    sentiment::{self, Sentiment},
+    util::{unix_time, unix_time_ms},
 };
+use onig::Regex;
 use rand::rngs::SmallRng;
+use rand::seq::SliceRandom;
+use rand::Rng;
 use rand_core::SeedableRng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use unicode_normalization::UnicodeNormalization;
 
 /////////////////////////////////////////////////////////////////////////////
 // Borg Type
 /////////////////////////////////////////////////////////////////////////////
 
+/// Borg only needs a shared reference to decide on and generate replies, so
+/// that replies in different chats can be generated at the same time. Only
+/// learning needs exclusive access.
 pub struct Borg {
     dictionary: Dictionary,
     behavior: MainBehavior,
-    rng: SmallRng,
+    user_throttle: Mutex<UserThrottle>,
+    learn_velocity: Mutex<LearnVelocity>,
+    response_cache: Mutex<ResponseCache>,
+    decisions: Mutex<VecDeque<DecisionRecord>>,
+    sent_responses: Mutex<SentResponses>,
+    recent_responses: Mutex<SentResponses>,
+    kill_switch: Mutex<KillSwitch>,
+    /// The user and hash of the last message of each chat.
+    last_messages: Mutex<HashMap<String, (String, u64)>>,
+    /// When each chat last had a message that was considered for a reply, in
+    /// seconds since the Unix epoch.
+    last_activity: Mutex<HashMap<String, u64>>,
+    /// When the chats that went quiet were last forgotten.
+    chats_pruned_at: Mutex<u64>,
+    /// When the cooldown after the last reply to each chat ends, in
+    /// milliseconds since the Unix epoch, so that it can be saved.
+    reply_cooldowns: Mutex<HashMap<String, u64>>,
+    rng: Mutex<SmallRng>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ReplyReason Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// ReplyReason is what made the bot decide to reply.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyReason {
+    /// The input matched a nick pattern.
+    Nick,
+    /// The input was only the name of the bot.
+    Name,
+    /// The input matched a magic pattern.
+    Magic,
+    /// The input matched a pattern of the magic group at this position in
+    /// magic_groups.
+    MagicGroup(usize),
+    /// The input matched a pattern of the canned response at this position
+    /// in canned_responses.
+    Canned(usize),
+    /// The bot replied at random, according to the reply rate.
+    Rate,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Decision Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// Decision is what the bot decided about replying to an input: either why
+/// it replied, or why it did not.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Decision {
+    Reply(ReplyReason),
+    /// The kill switch is on.
+    Silenced,
+    /// The user matched one of the ignored_users patterns.
+    IgnoredUser,
+    /// The user is sending messages too fast.
+    Throttled,
+    /// Speaking is off.
+    NotSpeaking,
+    /// The dictionary has fewer sentences than min_dictionary_sentences.
+    SmallDictionary,
+    /// The input is not in the language of the language filter.
+    WrongLanguage,
+    /// The bot was not addressed and only replies when it is.
+    NotAddressed,
+    /// None of the reply chances decided to reply.
+    NotChosen,
+    /// The bot would have replied, but replied to the chat too recently.
+    CoolingDown,
+}
+
+/// DecisionRecord is a decision kept in the decision log, along with what it
+/// was about.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DecisionRecord {
+    pub time: u64,
+    pub chat_id: String,
+    pub user_id: String,
+    pub input: String,
+    pub decision: Decision,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// KillSwitch Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// KillSwitch silences the bot in every chat at once, whatever their
+/// behavior, until it is turned off again. It is not saved, so a restart
+/// turns it off.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KillSwitch {
+    Off,
+    /// The bot does not reply, but keeps learning.
+    Replies,
+    /// The bot neither replies nor learns.
+    RepliesAndLearning,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ChatKind Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// ChatKind tells one-on-one chats with the bot apart from groups, which
+/// can have their own reply rates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChatKind {
+    Private,
+    Group,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ReplyAction Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// ReplyAction is how the bot answers a message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplyAction {
+    /// Send the text as a message.
+    Text(String),
+    /// React to the message with the emoji.
+    React(String),
+}
+
+/// SentimentReaction is the emoji the bot reacts with to a strongly positive
+/// or negative message, and whether it still replies to it as usual.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SentimentReaction {
+    pub emoji: String,
+    pub with_reply: bool,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// UserThrottle Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// How long, in seconds, the bucket of a quiet user is kept. Any bucket is
+/// full again after a minute, at which point it is the same as a new one.
+const THROTTLE_IDLE_SECS: u64 = 60;
+
+/// UserThrottle keeps a token bucket per user. Every message takes a token
+/// and the buckets refill at the configured messages-per-minute rate.
+#[derive(Debug, Default)]
+struct UserThrottle {
+    buckets: HashMap<String, TokenBucket>,
+    pruned_at: u64,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: u64,
+    throttled: bool,
+}
+
+impl UserThrottle {
+    /// Takes a token from the user's bucket, returning false if the bucket is
+    /// empty.
+    fn take(&mut self, user_id: &str, per_minute: u32, now: u64) -> bool {
+        self.prune(now);
+        let capacity = f64::from(per_minute);
+        let bucket = self
+            .buckets
+            .entry(user_id.to_owned())
+            .or_insert(TokenBucket {
+                tokens: capacity,
+                updated_at: now,
+                throttled: false,
+            });
+        let elapsed = now.saturating_sub(bucket.updated_at) as f64;
+        bucket.tokens = (bucket.tokens + elapsed * capacity / 60.0).min(capacity);
+        bucket.updated_at = now;
+        bucket.throttled = bucket.tokens < 1.0;
+        if !bucket.throttled {
+            bucket.tokens -= 1.0;
+        }
+        !bucket.throttled
+    }
+
+    /// Forgets the buckets of the users who have been quiet for long enough
+    /// that their buckets are full again, so that the buckets do not pile up.
+    /// The buckets are only looked through once every THROTTLE_IDLE_SECS.
+    fn prune(&mut self, now: u64) {
+        if now.saturating_sub(self.pruned_at) < THROTTLE_IDLE_SECS {
+            return;
+        }
+        self.pruned_at = now;
+        self.buckets
+            .retain(|_, b| now.saturating_sub(b.updated_at) < THROTTLE_IDLE_SECS);
+    }
+
+    /// Returns true if the user's last message found their bucket empty.
+    fn is_throttled(&self, user_id: &str) -> bool {
+        self.buckets
+            .get(user_id)
+            .map(|b| b.throttled)
+            .unwrap_or(false)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnVelocity Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// How far back LearnVelocity looks, in seconds.
+const LEARN_VELOCITY_WINDOW_SECS: u64 = 60;
+
+/// LearnVelocity counts the sentences added to the dictionary in a sliding
+/// window of the last minute, so that learning can pause while the dictionary
+/// grows too fast.
+#[derive(Debug, Default)]
+struct LearnVelocity {
+    /// When sentences were added, and how many, from the oldest to the newest.
+    growth: VecDeque<(u64, usize)>,
+    paused: bool,
+}
+
+impl LearnVelocity {
+    fn record(&mut self, added: usize, now: u64) {
+        self.expire(now);
+        if added > 0 {
+            self.growth.push_back((now, added));
+        }
+    }
+
+    fn expire(&mut self, now: u64) {
+        while let Some(&(time, _)) = self.growth.front() {
+            if now.saturating_sub(time) < LEARN_VELOCITY_WINDOW_SECS {
+                break;
+            }
+            self.growth.pop_front();
+        }
+    }
+
+    /// Returns true while at least per_minute sentences were added in the
+    /// last minute. Pausing and resuming are logged.
+    fn is_too_fast(&mut self, per_minute: u32, now: u64) -> bool {
+        self.expire(now);
+        let added: usize = self.growth.iter().map(|&(_, added)| added).sum();
+        let too_fast = added >= per_minute as usize;
+        if too_fast && !self.paused {
+            warn!(
+                "The dictionary grew by {} sentences in the last minute. Pausing learning",
+                added
+            );
+        } else if !too_fast && self.paused {
+            info!("The dictionary grows slower again. Resuming learning");
+        }
+        self.paused = too_fast;
+        too_fast
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ResponseCache Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// ResponseCache remembers the latest response made up for an input in a
+/// chat, so that inputs that come up often do not need a new response every
+/// time. Chats have their own entries, since their behaviors can differ. Entries
+/// expire after a while so that responses still vary, and the least recently
+/// used entry makes room for new ones.
+#[derive(Debug, Default)]
+struct ResponseCache {
+    entries: HashMap<(Option<String>, String), CachedResponse>,
+    uses: u64,
+}
+
+#[derive(Debug)]
+struct CachedResponse {
+    response: String,
+    created_at: u64,
+    last_used: u64,
+}
+
+impl ResponseCache {
+    fn get(
+        &mut self,
+        chat_id: Option<&str>,
+        input: &str,
+        ttl_secs: u64,
+        now: u64,
+    ) -> Option<String> {
+        let key = (chat_id.map(str::to_owned), cache_key(input));
+        match self.entries.get(&key) {
+            Some(entry) if now.saturating_sub(entry.created_at) >= ttl_secs => {
+                self.entries.remove(&key);
+                None
+            }
+            Some(_) => {
+                self.uses += 1;
+                let entry = self.entries.get_mut(&key)?;
+                entry.last_used = self.uses;
+                Some(entry.response.clone())
+            }
+            None => None,
+        }
+    }
+
+    fn insert(
+        &mut self,
+        chat_id: Option<&str>,
+        input: &str,
+        response: String,
+        capacity: usize,
+        now: u64,
+    ) {
+        if capacity == 0 {
+            return;
+        }
+        let key = (chat_id.map(str::to_owned), cache_key(input));
+        if !self.entries.contains_key(&key) && self.entries.len() >= capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, e)| e.last_used)
+                .map(|(k, _)| k.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.uses += 1;
+        self.entries.insert(
+            key,
+            CachedResponse {
+                response,
+                created_at: now,
+                last_used: self.uses,
+            },
+        );
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// How many sentences sample returns at most, so that a sample fits in one
+/// message.
+pub const MAX_SAMPLE_SIZE: usize = 20;
+
+/////////////////////////////////////////////////////////////////////////////
+// SentResponses Type
+/////////////////////////////////////////////////////////////////////////////
+
+/// How long, in seconds, the last message and activity of a quiet chat are
+/// remembered.
+const CHAT_MEMORY_TTL_SECS: u64 = 7 * 24 * 60 * 60;
+
+/// How often, in seconds, the chats that went quiet are forgotten.
+const CHAT_PRUNE_INTERVAL_SECS: u64 = 60 * 60;
+
+/// How many sent responses are remembered per chat at most.
+const MAX_SENT_RESPONSES_PER_CHAT: usize = 100;
+
+/// SentResponses remembers the responses recently sent to each chat, so that
+/// the bot can tell when its own words are echoed back to it, e.g. by another
+/// bot or a bridge, and so that it can avoid repeating itself. Only hashes of
+/// the responses are kept.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct SentResponses {
+    chats: HashMap<String, VecDeque<SentResponse>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SentResponse {
+    hash: u64,
+    sent_at: u64,
+}
+
+impl SentResponses {
+    fn insert(
+        &mut self,
+        chat_id: &str,
+        response: &str,
+        capacity: usize,
+        window_secs: u64,
+        now: u64,
+    ) {
+        let sent = self.chats.entry(chat_id.to_owned()).or_default();
+        forget_expired(sent, window_secs, now);
+        while sent.len() >= capacity {
+            sent.pop_front();
+        }
+        sent.push_back(SentResponse {
+            hash: text_hash(response),
+            sent_at: now,
+        });
+    }
+
+    fn contains(&mut self, chat_id: &str, input: &str, window_secs: u64, now: u64) -> bool {
+        let sent = match self.chats.get_mut(chat_id) {
+            Some(sent) => sent,
+            None => return false,
+        };
+        forget_expired(sent, window_secs, now);
+        let hash = text_hash(input);
+        let found = sent.iter().any(|r| r.hash == hash);
+        if sent.is_empty() {
+            self.chats.remove(chat_id);
+        }
+        found
+    }
+}
+
+fn forget_expired(sent: &mut VecDeque<SentResponse>, window_secs: u64, now: u64) {
+    while let Some(oldest) = sent.front() {
+        if now.saturating_sub(oldest.sent_at) < window_secs {
+            break;
+        }
+        sent.pop_front();
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ChatState Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// ChatState is what the bot remembers about each chat, saved next to the
+/// dictionary when persist_chat_state is on.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ChatState {
+    #[serde(default)]
+    sent_responses: SentResponses,
+    #[serde(default)]
+    recent_responses: SentResponses,
+    #[serde(default)]
+    last_messages: HashMap<String, (String, u64)>,
+    #[serde(default)]
+    last_activity: HashMap<String, u64>,
+    #[serde(default)]
+    reply_cooldowns: HashMap<String, u64>,
+}
+
+/// Returns the path of the chat state file that belongs to the dictionary at
+/// the given path.
+pub fn chat_state_path(path: &Path) -> PathBuf {
+    let mut state = path.as_os_str().to_owned();
+    state.push(".state");
+    PathBuf::from(state)
+}
+
+/// Returns the seed saved next to the dictionary at the given path, or picks
+/// a new one and saves it there. The bot then makes the same random decisions
+/// after a restart as if rng_seed had been set to the seed.
+pub fn saved_seed(path: &Path) -> u64 {
+    let mut seed_path = path.as_os_str().to_owned();
+    seed_path.push(".seed");
+    let seed_path = PathBuf::from(seed_path);
+    if let Some(seed) = fs::read_to_string(&seed_path)
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+    {
+        return seed;
+    }
+    let seed: u64 = rand::random();
+    if let Err(e) = fs::write(&seed_path, seed.to_string()) {
+        warn!("Could not save the random seed to {:?}: {}", seed_path, e);
+    }
+    seed
+}
+
+/// Hashes the text the same way whatever its case and surrounding
+/// whitespace, since that can change on the way back. The hashes are saved
+/// in the chat state, so this is FNV-1a rather than DefaultHasher, whose
+/// hashes may change between Rust releases.
+fn text_hash(text: &str) -> u64 {
+    cache_key(text)
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+}
+
+fn cache_key(input: &str) -> String {
+    input.trim().to_lowercase()
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ReplyScorer Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// ReplyScorer works out the reply rate of an input from its features, as
+/// weighed by the reply scoring of the behavior.
+struct ReplyScorer<'a> {
+    scoring: ReplyScoring,
+    dictionary: &'a Dictionary,
+}
+
+impl<'a> ReplyScorer<'a> {
+    fn new(scoring: ReplyScoring, dictionary: &'a Dictionary) -> ReplyScorer<'a> {
+        ReplyScorer {
+            scoring,
+            dictionary,
+        }
+    }
+
+    /// Returns the reply rate for the input. The weights are scaled by the
+    /// multiplier, like the flat reply rate they are added to.
+    fn rate(&self, input: &str, reply_rate: f32, multiplier: f32) -> f32 {
+        let words = dictionary::split_words(input).len() as f32;
+        let known_words = self.dictionary.known_words(input).len() as f32;
+        let question = input.trim_end().ends_with('?');
+        let mut score = self.scoring.per_word * words + self.scoring.per_known_word * known_words;
+        if question {
+            score += self.scoring.question;
+        }
+        (reply_rate + score * multiplier)
+            .max(0.0)
+            .min(self.scoring.max_rate)
+    }
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -21,144 +556,3318 @@ pub struct Borg {
 
 /// This implementation is platform agnostic.
 impl Borg {
+    /// Creates a Borg whose random decisions are seeded with the behavior's
+    /// rng_seed, or with entropy when it has none. An empty dictionary is
+    /// warned about, since the bot cannot say anything until it learns.
     pub fn new(dictionary: Dictionary, behavior: MainBehavior) -> Borg {
-        Borg {
+        let rng = seeded_rng(behavior.rng_seed);
+        let borg = Borg {
             dictionary,
             behavior,
-            rng: SmallRng::from_entropy(),
+            user_throttle: Mutex::new(UserThrottle::default()),
+            learn_velocity: Mutex::new(LearnVelocity::default()),
+            response_cache: Mutex::new(ResponseCache::default()),
+            decisions: Mutex::new(VecDeque::new()),
+            sent_responses: Mutex::new(SentResponses::default()),
+            recent_responses: Mutex::new(SentResponses::default()),
+            kill_switch: Mutex::new(KillSwitch::Off),
+            last_messages: Mutex::new(HashMap::new()),
+            last_activity: Mutex::new(HashMap::new()),
+            chats_pruned_at: Mutex::new(0),
+            reply_cooldowns: Mutex::new(HashMap::new()),
+            rng: Mutex::new(rng),
+        };
+        if let Some(warning) = borg.empty_dictionary_warning() {
+            warn!("{}", warning);
         }
+        borg
     }
 
-    pub fn respond_to(&mut self, line: &str) -> Option<String> {
-        self.dictionary.respond_to(line, &mut self.rng)
+    /// Returns a warning when the dictionary has no sentences, so that a bot
+    /// that will not talk is not mistaken for a broken one.
+    fn empty_dictionary_warning(&self) -> Option<&'static str> {
+        if self.dictionary.stats().sentence_count > 0 {
+            return None;
+        }
+        Some(if self.behavior.min_dictionary_sentences > 0 {
+            "The dictionary is empty. The bot stays quiet until it has learned \
+            min_dictionary_sentences sentences."
+        } else {
+            "The dictionary is empty, so the bot cannot reply until it learns \
+            something. Set min_dictionary_sentences to 1 to keep it from trying."
+        })
     }
 
-    pub fn learn(&mut self, line: &str) {
-        self.dictionary.learn(line);
+    /// Replaces the main behavior, e.g. after the configuration file changed.
+    /// The patterns of the new behavior are compiled and its values checked
+    /// first, so that the old behavior stays in place when the new one is
+    /// invalid. Resolvers borrow the behavior, so none of them can outlive the
+    /// swap. Cached responses were made with the old behavior, so they are
+    /// forgotten. A behavior without rng_seed keeps the seed in use.
+    pub fn reload_behavior(&mut self, mut behavior: MainBehavior) -> Result<(), ConfigError> {
+        behavior.compile_patterns()?;
+        behavior.validate()?;
+        if behavior.rng_seed.is_none() {
+            behavior.rng_seed = self.behavior.rng_seed;
+        }
+        if behavior.rng_seed != self.behavior.rng_seed {
+            *self.rng.get_mut().unwrap_or_else(PoisonError::into_inner) =
+                seeded_rng(behavior.rng_seed);
+        }
+        self.behavior = behavior;
+        self.clear_response_cache();
+        debug!("[reload_behavior] Reloaded the main behavior");
+        Ok(())
     }
 
-    pub fn should_learn(
-        &mut self,
-        user_id: &str,
-        input: &str,
+    pub fn respond_to(
+        &self,
+        line: &str,
         behavior: &Option<BehaviorOverrideValueResolver>,
-    ) -> bool {
-        let b = BehaviorValueResolver::new(&self.behavior, behavior);
-        debug!(
-            "[should_learn] Using {:?} for resolving behavior values.",
-            b
-        );
+    ) -> Option<String> {
+        self.respond_to_many(line, 1, behavior).into_iter().next()
+    }
 
-        match pattern::matches_any(user_id, b.ignored_users()) {
-            Some(pattern) => {
+    /// Makes up to k distinct responses to the line, each picked the same way
+    /// as by respond_to. Fewer are returned when the dictionary cannot come
+    /// up with k different ones within the generation attempts, and none when
+    /// the line has no known words. Only the first response is cached.
+    pub fn respond_to_many(
+        &self,
+        line: &str,
+        k: usize,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Vec<String> {
+        self.generate(line, k, None, true, behavior)
+    }
+
+    /// Makes up a response to the line like respond_to, but without caching
+    /// it, so that previewing a response does not decide what the bot replies
+    /// next.
+    pub fn preview(
+        &self,
+        line: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Option<String> {
+        self.generate(line, 1, None, false, behavior)
+            .into_iter()
+            .next()
+    }
+
+    /// Makes up a response to the line in the chat. Responses recently made
+    /// in the chat are only used when nothing else can be made up.
+    pub fn respond_to_in_chat(
+        &self,
+        line: &str,
+        chat_id: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Option<String> {
+        self.generate(line, 1, Some(chat_id), true, behavior)
+            .into_iter()
+            .next()
+    }
+
+    fn generate(
+        &self,
+        line: &str,
+        k: usize,
+        chat_id: Option<&str>,
+        fill_cache: bool,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Vec<String> {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        if k == 0 {
+            return Vec::new();
+        }
+        let min_confidence = b.min_confidence();
+        if min_confidence > 0.0 {
+            let confidence = self.dictionary.confidence(line);
+            if confidence < min_confidence {
                 debug!(
-                    "[should_learn] User {:?} matches ignore pattern {:?}. Refusing to learn",
-                    user_id, pattern
+                    "[generate] Confidence {:?} is below the minimum of {:?}",
+                    confidence, min_confidence
                 );
-                return false;
+                return Vec::new();
             }
-            None => {
-                debug!(
-                    "[should_learn] User ID {:?} does not match any blacklisted pattern.",
-                    user_id
-                );
+        }
+        let length_bias = if b.prefer_longer() {
+            b.prefer_longer_strength()
+        } else {
+            0.0
+        };
+        let is_recent = |response: &str| match chat_id {
+            Some(chat_id) => self.is_recent(chat_id, response),
+            None => false,
+        };
+        let mut responses: Vec<String> = self
+            .cached_response(chat_id, line, &b)
+            .filter(|r| !is_recent(r))
+            .into_iter()
+            .collect();
+        let cached = !responses.is_empty();
+        let mut stale = vec![];
+        if responses.len() < k {
+            let mut rng = self.fork_rng();
+            let max_attempts = self.behavior.max_generation_attempts.saturating_mul(k);
+            for _ in 0..max_attempts {
+                let candidate =
+                    match self
+                        .dictionary
+                        .respond_to_weighted(line, length_bias, &mut rng)
+                    {
+                        Some(candidate) => candidate,
+                        None => break,
+                    };
+                let blacklisted = pattern::matches_any(&candidate, b.blacklisted_patterns())
+                    .or_else(|| pattern::matches_any(&candidate, b.output_blacklist()));
+                let max_words = b.max_response_words();
+                let too_long = max_words > 0 && candidate.split_whitespace().count() > max_words;
+                let response = match blacklisted {
+                    Some(pattern) => {
+                        debug!(
+                            "[generate] Candidate {:?} matches blacklisted pattern {:?}",
+                            candidate, pattern
+                        );
+                        continue;
+                    }
+                    None if too_long && b.long_responses() == LongResponseMode::Reject => {
+                        debug!(
+                            "[generate] Candidate {:?} is longer than {} words",
+                            candidate, max_words
+                        );
+                        continue;
+                    }
+                    None if too_long => postprocess::truncate_words(&candidate, max_words),
+                    None => candidate,
+                };
+                let min_words = b.min_response_words();
+                if min_words > 0 && response.split_whitespace().count() < min_words {
+                    debug!(
+                        "[generate] Candidate {:?} is shorter than {} words",
+                        response, min_words
+                    );
+                    match b.short_responses() {
+                        ShortResponseMode::Retry => continue,
+                        ShortResponseMode::GiveUp => break,
+                    }
+                }
+                if is_recent(&response) {
+                    debug!("[generate] Candidate {:?} was recently used", response);
+                    if !stale.contains(&response) {
+                        stale.push(response);
+                    }
+                    continue;
+                }
+                if !responses.contains(&response) {
+                    responses.push(response);
+                }
+                if responses.len() == k {
+                    break;
+                }
+            }
+            let missing = k - responses.len();
+            responses.extend(stale.into_iter().take(missing));
+            match responses.first() {
+                Some(r) if !cached && fill_cache => self.cache().insert(
+                    chat_id,
+                    line,
+                    r.clone(),
+                    self.behavior.response_cache_size,
+                    unix_time(),
+                ),
+                Some(_) => {}
+                None => debug!("[generate] Giving up after {} attempts", max_attempts),
             }
         }
-
-        match pattern::matches_any(input, b.blacklisted_patterns()) {
-            Some(pattern) => {
-                debug!(
-                    "[should_learn] Input {:?} matches blacklisted pattern {:?}. Refusing to learn",
-                    input, pattern
-                );
-                return false;
+        if let Some(chat_id) = chat_id {
+            for response in &responses {
+                self.remember_recent(chat_id, response);
             }
-            None => {
-                debug!(
-                    "[should_learn] Input {:?} does not match any blacklisted pattern.",
-                    input
-                );
+        }
+        responses
+            .into_iter()
+            .map(|mut response| {
+                let max_punctuation = b.max_repeated_punctuation();
+                if max_punctuation > 0 {
+                    response = postprocess::normalize_punctuation(&response, max_punctuation);
+                }
+                let mention_handling = b.output_mention_handling();
+                if mention_handling != MentionHandling::Keep {
+                    response = postprocess::handle_mentions(&response, mention_handling);
+                }
+                if b.capitalize_responses() {
+                    response = postprocess::capitalize(&response);
+                }
+                if b.punctuate_responses() {
+                    response = postprocess::punctuate(&response);
+                }
+                if let Some(template) = b.reply_template() {
+                    response = postprocess::apply_template(template, &response);
+                }
+                response
+            })
+            .collect()
+    }
+
+    /// Makes up the answer once reply_reason has decided to reply to the
+    /// input. The pivot input is what the response is made from, which can
+    /// hold more context than the input that triggered the reply. Canned
+    /// responses are filled from the input itself, since that is what their
+    /// patterns matched. On platforms that support reactions, nick mentions
+    /// are answered with a reaction when reaction_mode is on.
+    pub fn reply_action_for(
+        &self,
+        reason: ReplyReason,
+        chat_id: &str,
+        input: &str,
+        pivot_input: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+        can_react: bool,
+    ) -> Option<ReplyAction> {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        if should_react(reason, b.reaction_mode(), can_react) {
+            debug!("[reply_action] Reacting with {:?}", b.reaction_emoji());
+            return Some(ReplyAction::React(b.reaction_emoji().clone()));
+        }
+        self.response_for(reason, chat_id, input, pivot_input, behavior)
+            .map(ReplyAction::Text)
+    }
+
+    fn response_for(
+        &self,
+        reason: ReplyReason,
+        chat_id: &str,
+        input: &str,
+        pivot_input: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Option<String> {
+        if let ReplyReason::Canned(i) = reason {
+            // The pattern that decided to reply is the first one matching the
+            // input, like in decide.
+            let b = BehaviorValueResolver::new(&self.behavior, behavior);
+            let canned = b.canned_responses().get(i)?;
+            return pattern::matches_any(input, &canned.patterns)?.expand(input, &canned.response);
+        }
+        let input = pivot_input;
+        if let ReplyReason::MagicGroup(i) = reason {
+            let b = BehaviorValueResolver::new(&self.behavior, behavior);
+            let group = b.magic_groups().get(i)?;
+            return match group.response {
+                Some(ref r) if r.contains(postprocess::RESPONSE_PLACEHOLDER) => self
+                    .respond_to_in_chat(input, chat_id, behavior)
+                    .map(|response| postprocess::apply_template(r, &response)),
+                Some(ref r) => Some(r.clone()),
+                None => self.respond_to_in_chat(input, chat_id, behavior),
+            };
+        }
+        match self.respond_to_in_chat(input, chat_id, behavior) {
+            Some(response) => Some(response),
+            None if reason == ReplyReason::Nick || reason == ReplyReason::Name => {
+                let b = BehaviorValueResolver::new(&self.behavior, behavior);
+                let fallback = b.fallback_responses().choose(&mut *self.rng()).cloned();
+                debug!("[reply] No response, falling back to {:?}", fallback);
+                fallback
             }
+            None => None,
         }
+    }
 
-        debug!("[should_learn] Should learn {:?}", input);
-        true
+    /// Returns the emoji to react with to a message that was just learned,
+    /// which is only done when learn_ack is on, learning is on and speaking
+    /// is off. The acknowledgement is a reaction, so platforms that cannot
+    /// react get none.
+    pub fn learn_ack(
+        &self,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+        can_react: bool,
+    ) -> Option<String> {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        if !can_react || !b.learn_ack() || !b.is_learning() || b.is_speaking() {
+            return None;
+        }
+        if self.kill_switch() != KillSwitch::Off {
+            debug!("[learn_ack] The kill switch is on");
+            return None;
+        }
+        let emoji = b.reaction_emoji().clone();
+        debug!("[learn_ack] Acknowledging with {:?}", emoji);
+        Some(emoji)
     }
 
-    pub fn should_reply_to(
-        &mut self,
-        user_id: &str,
+    /// Returns how to react to the input when react_to_sentiment is on and
+    /// the input is strongly positive or negative. It is only asked once
+    /// reply_reason decided to reply, so that reactions follow the reply rate,
+    /// the cooldown and the throttle like replies. Platforms that cannot react
+    /// get none.
+    pub fn sentiment_reaction(
+        &self,
         input: &str,
         behavior: &Option<BehaviorOverrideValueResolver>,
-    ) -> bool {
+        can_react: bool,
+    ) -> Option<SentimentReaction> {
         let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        if !can_react || !b.react_to_sentiment() {
+            return None;
+        }
+        let reactions = b.sentiment_reactions();
+        let sentiment = sentiment::classify(input, reactions.min_score);
+        let emoji = match sentiment {
+            Sentiment::Positive => reactions.positive.clone()?,
+            Sentiment::Negative => reactions.negative.clone()?,
+            Sentiment::Neutral => return None,
+        };
         debug!(
-            "[should_reply_to] Using {:?} for resolving behavior values.",
-            b
+            "[sentiment_reaction] Input {:?} is {:?}, reacting with {:?}",
+            input, sentiment, emoji
         );
+        Some(SentimentReaction {
+            emoji,
+            with_reply: reactions.with_reply,
+        })
+    }
 
-        if let Some(matched) = pattern::matches_any(user_id, b.ignored_users()) {
+    /// Returns the messages to send for the response: the response itself,
+    /// and at burst_chance a follow-up made up from the response. There is
+    /// never more than one follow-up, since follow-ups are not followed up.
+    pub fn with_follow_up(
+        &self,
+        response: String,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Vec<String> {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        let burst_chance = b.burst_chance();
+        if burst_chance <= 0.0 || !chance(burst_chance, &mut self.rng()) {
+            return vec![response];
+        }
+        match self.respond_to(&response, behavior) {
+            Some(follow_up) if follow_up != response => {
+                debug!(
+                    "[with_follow_up] Following {:?} up with {:?}",
+                    response, follow_up
+                );
+                vec![response, follow_up]
+            }
+            _ => {
+                debug!("[with_follow_up] No follow-up for {:?}", response);
+                vec![response]
+            }
+        }
+    }
+
+    /// Picks how long to wait before sending a reply, so that replies do not
+    /// arrive instantly.
+    pub fn reply_delay(&self, behavior: &Option<BehaviorOverrideValueResolver>) -> Duration {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        delay_between(
+            b.reply_delay_min_ms(),
+            b.reply_delay_max_ms(),
+            &mut self.rng(),
+        )
+    }
+
+    /// Locks the user throttle. The lock is never held across an await, so
+    /// a poisoned lock can only come from a panic while updating a bucket,
+    /// which leaves the buckets usable.
+    fn throttle(&self) -> MutexGuard<'_, UserThrottle> {
+        self.user_throttle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the learn velocity, which like the user throttle is never held
+    /// across an await.
+    fn velocity(&self) -> MutexGuard<'_, LearnVelocity> {
+        self.learn_velocity
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the random number generator. Like the user throttle, it is never
+    /// held across an await.
+    fn rng(&self) -> MutexGuard<'_, SmallRng> {
+        self.rng.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns a generator of its own seeded from the shared one, for work
+    /// that needs many random numbers, so that replies in different chats do
+    /// not wait on each other for the lock. With an rng_seed, the forks are
+    /// as reproducible as the shared generator.
+    fn fork_rng(&self) -> SmallRng {
+        SmallRng::from_rng(&mut *self.rng()).unwrap_or_else(|_| SmallRng::from_entropy())
+    }
+
+    /// Remembers that the response was sent to the chat, so that it is
+    /// neither learned nor replied to if it comes back within
+    /// echo_window_secs.
+    pub fn remember_sent(&self, chat_id: &str, response: &str) {
+        let window = self.behavior.echo_window_secs;
+        if window == 0 {
+            return;
+        }
+        self.sent().insert(
+            chat_id,
+            response,
+            MAX_SENT_RESPONSES_PER_CHAT,
+            window,
+            unix_time(),
+        );
+    }
+
+    /// Cleans up the text of a message as input_sanitization asks. The
+    /// handlers do this first, before the message is matched or learned.
+    pub fn sanitize(&self, text: &str) -> String {
+        sanitize(text, self.behavior.input_sanitization)
+    }
+
+    /// Returns true if the input is one of the responses recently sent to the
+    /// chat, which the handlers ignore so that the bot does not end up in a
+    /// loop with itself.
+    pub fn is_echo(&self, chat_id: &str, input: &str) -> bool {
+        let window = self.behavior.echo_window_secs;
+        if window == 0 {
+            return false;
+        }
+        let echo = self.sent().contains(chat_id, input, window, unix_time());
+        if echo {
             debug!(
-                "[should_reply_to] User is ignored, user ID {:?} matched pattern {:?}",
-                user_id, matched
+                "[is_echo] Input {:?} was recently sent to {:?}",
+                input, chat_id
             );
-            return true;
         }
+        echo
+    }
 
-        if !b.is_speaking() {
-            debug!("[should_reply_to] Speaking is off");
+    /// Remembers the input as the last message of the chat, and returns true
+    /// if the same user had just sent the same message there. The handlers
+    /// ignore repeats, so that spam is neither learned nor replied to again.
+    pub fn is_repeat(&self, chat_id: &str, user_id: &str, input: &str) -> bool {
+        if !self.behavior.skip_repeated_messages {
             return false;
         }
-
-        if let Some(matched) = pattern::matches_any(input, b.nick_patterns()) {
+        let message = (user_id.to_owned(), text_hash(input));
+        let previous = self
+            .last_messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(chat_id.to_owned(), message.clone());
+        let repeat = previous == Some(message);
+        if repeat {
             debug!(
-                "[should_reply_to] Input {:?} matched nick pattern {:?}",
-                input, matched
+                "[is_repeat] User {:?} repeated {:?} in {:?}",
+                user_id, input, chat_id
             );
-            let reply_nick = b.reply_nick();
+        }
+        repeat
+    }
+
+    /// Records activity in the chat at the given time, and returns how many
+    /// seconds the chat had been quiet before, or None if it was never seen.
+    pub fn touch_chat(&self, chat_id: &str, now: u64) -> Option<u64> {
+        self.prune_chats(now);
+        self.activity()
+            .insert(chat_id.to_owned(), now)
+            .map(|last| now.saturating_sub(last))
+    }
+
+    /// Forgets the last message and activity of the chats that have been quiet
+    /// for CHAT_MEMORY_TTL_SECS, so that they do not pile up. Such a chat is
+    /// treated like a new one when it wakes up. The chats are only looked
+    /// through once every CHAT_PRUNE_INTERVAL_SECS.
+    fn prune_chats(&self, now: u64) {
+        {
+            let mut pruned_at = self
+                .chats_pruned_at
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if now.saturating_sub(*pruned_at) < CHAT_PRUNE_INTERVAL_SECS {
+                return;
+            }
+            *pruned_at = now;
+        }
+        let mut forgotten = vec![];
+        self.activity().retain(|chat_id, last| {
+            let quiet = now.saturating_sub(*last) >= CHAT_MEMORY_TTL_SECS;
+            if quiet {
+                forgotten.push(chat_id.clone());
+            }
+            !quiet
+        });
+        let mut last_messages = self
+            .last_messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        for chat_id in &forgotten {
+            last_messages.remove(chat_id);
+        }
+        debug!("[prune_chats] Forgot {} quiet chats", forgotten.len());
+    }
+
+    /// Starts the cooldown of the chat after the response was sent there.
+    /// The cooldown grows with the length of the response, and a shorter one
+    /// never cuts a running cooldown short. The cooldowns that are over are
+    /// forgotten, so that they do not pile up.
+    pub fn start_cooldown(
+        &self,
+        chat_id: &str,
+        response: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        let cooldown = reply_cooldown(
+            b.reply_cooldown_ms(),
+            b.reply_cooldown_ms_per_word(),
+            response,
+        );
+        if cooldown == Duration::from_millis(0) {
+            return;
+        }
+        let now = unix_time_ms();
+        let ends_at = now.saturating_add(cooldown.as_millis() as u64);
+        debug!(
+            "[start_cooldown] Cooling down in {:?} for {:?}",
+            chat_id, cooldown
+        );
+        let mut cooldowns = self.cooldowns();
+        cooldowns.retain(|_, end| *end > now);
+        let end = cooldowns.entry(chat_id.to_owned()).or_insert(ends_at);
+        *end = (*end).max(ends_at);
+    }
+
+    /// Returns how much is left of the cooldown of the chat, or None if it is
+    /// not cooling down.
+    pub fn cooldown_left(&self, chat_id: &str) -> Option<Duration> {
+        let now = unix_time_ms();
+        let mut cooldowns = self.cooldowns();
+        let end = *cooldowns.get(chat_id)?;
+        if end > now {
+            Some(Duration::from_millis(end - now))
+        } else {
+            cooldowns.remove(chat_id);
+            None
+        }
+    }
+
+    fn cooldowns(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.reply_cooldowns
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn activity(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+        self.last_activity
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn remember_recent(&self, chat_id: &str, response: &str) {
+        let size = self.behavior.recent_responses_size;
+        if size == 0 {
+            return;
+        }
+        self.recent().insert(
+            chat_id,
+            response,
+            size,
+            self.behavior.recent_responses_ttl_secs,
+            unix_time(),
+        );
+    }
+
+    fn is_recent(&self, chat_id: &str, response: &str) -> bool {
+        if self.behavior.recent_responses_size == 0 {
+            return false;
+        }
+        self.recent().contains(
+            chat_id,
+            response,
+            self.behavior.recent_responses_ttl_secs,
+            unix_time(),
+        )
+    }
+
+    fn recent(&self) -> MutexGuard<'_, SentResponses> {
+        self.recent_responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the sent responses, which like the user throttle is never held
+    /// across an await.
+    fn sent(&self) -> MutexGuard<'_, SentResponses> {
+        self.sent_responses
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the decision log, which like the user throttle is never held
+    /// across an await.
+    fn decision_log(&self) -> MutexGuard<'_, VecDeque<DecisionRecord>> {
+        self.decisions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Locks the response cache, which like the user throttle is never held
+    /// across an await.
+    fn cache(&self) -> MutexGuard<'_, ResponseCache> {
+        self.response_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the cached response to the input, unless it has expired or
+    /// matches one of the blacklisted patterns. Responses are cached before
+    /// being post-processed, since that depends on the chat.
+    fn cached_response(
+        &self,
+        chat_id: Option<&str>,
+        line: &str,
+        b: &BehaviorValueResolver,
+    ) -> Option<String> {
+        if self.behavior.response_cache_size == 0 {
+            return None;
+        }
+        let response = self.cache().get(
+            chat_id,
+            line,
+            self.behavior.response_cache_ttl_secs,
+            unix_time(),
+        )?;
+        if pattern::matches_any(&response, b.blacklisted_patterns())
+            .or_else(|| pattern::matches_any(&response, b.output_blacklist()))
+            .is_some()
+        {
+            return None;
+        }
+        debug!("[cached_response] Reusing {:?} for {:?}", response, line);
+        Some(response)
+    }
+
+    /// Forgets the cached responses, which might not be what the bot would
+    /// say anymore once its dictionary has changed.
+    fn clear_response_cache(&mut self) {
+        self.response_cache
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.dictionary.stats()
+    }
+
+    /// Returns what to say on joining a chat: the greeting if there is one,
+    /// or else a line made up from random learned sentences. A sentence whose
+    /// words are all too rare to make anything up from is passed over.
+    pub fn greeting(
+        &self,
+        greeting: Option<&str>,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Option<String> {
+        if let Some(greeting) = greeting {
+            return Some(greeting.to_owned());
+        }
+        let sentences = self
+            .dictionary
+            .sample(self.behavior.max_generation_attempts, &mut *self.rng());
+        sentences
+            .into_iter()
+            .find_map(|sentence| self.respond_to(sentence, behavior))
+    }
+
+    /// Returns up to n random learned sentences, but never more than
+    /// MAX_SAMPLE_SIZE, so that admins can spot-check what was learned.
+    pub fn sample(&self, n: usize) -> Vec<&str> {
+        self.dictionary
+            .sample(n.min(MAX_SAMPLE_SIZE), &mut *self.rng())
+    }
+
+    /// Forgets every sentence containing the phrase, returning how many were
+    /// forgotten.
+    pub fn forget(&mut self, phrase: &str) -> usize {
+        self.clear_response_cache();
+        self.dictionary.forget(phrase)
+    }
+
+    /// Returns who taught the sentence, if it was learned with attribution.
+    pub fn source_of(&self, sentence: &str) -> Option<&Source> {
+        self.dictionary.source_of(sentence)
+    }
+
+    /// Returns the sentences forget would remove for the phrase.
+    pub fn forgettable(&self, phrase: &str) -> Vec<String> {
+        self.dictionary
+            .forgettable(phrase)
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Prunes words used in fewer than min_count sentences, returning how
+    /// many were pruned.
+    pub fn prune_rare_words(&mut self, min_count: usize) -> usize {
+        self.clear_response_cache();
+        self.dictionary.prune_rare_words(min_count)
+    }
+
+    /// Saves the dictionary, along with the chat state when
+    /// persist_chat_state is on. The chat state is saved on a best-effort
+    /// basis: failing to save it is logged, and does not fail the save.
+    pub fn save_dictionary(
+        &mut self,
+        path: &Path,
+        mode: SaveMode,
+    ) -> Result<(), dictionary::Error> {
+        self.dictionary.save(path, mode)?;
+        if self.behavior.persist_chat_state {
+            let state_path = chat_state_path(path);
+            if let Err(e) = self.save_chat_state(&state_path) {
+                error!("Could not save the chat state to {:?}: {}", state_path, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn save_chat_state(&self, path: &Path) -> Result<(), dictionary::Error> {
+        let state = ChatState {
+            sent_responses: self.sent().clone(),
+            recent_responses: self.recent().clone(),
+            last_messages: self
+                .last_messages
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+            last_activity: self.activity().clone(),
+            reply_cooldowns: self.cooldowns().clone(),
+        };
+        fs::write(path, serde_json::to_string(&state)?)?;
+        Ok(())
+    }
+
+    /// Reads back the chat state saved next to the dictionary at the given
+    /// path, when persist_chat_state is on. A missing state file is skipped,
+    /// and one that cannot be read is logged and ignored, so that the bot
+    /// still starts, only without remembering its chats.
+    pub fn load_chat_state(&self, path: &Path) {
+        if !self.behavior.persist_chat_state {
+            return;
+        }
+        let path = chat_state_path(path);
+        if !path.is_file() {
+            return;
+        }
+        let state: ChatState = match fs::read_to_string(&path)
+            .map_err(dictionary::Error::from)
+            .and_then(|data| serde_json::from_str(&data).map_err(dictionary::Error::from))
+        {
+            Ok(state) => state,
+            Err(e) => {
+                warn!("Ignoring the chat state in {:?}: {}", path, e);
+                return;
+            }
+        };
+        debug!("[load_chat_state] Loaded the chat state from {:?}", path);
+        *self.sent() = state.sent_responses;
+        *self.recent() = state.recent_responses;
+        *self
+            .last_messages
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = state.last_messages;
+        *self.activity() = state.last_activity;
+        *self.cooldowns() = state.reply_cooldowns;
+    }
+
+    pub fn learn(&mut self, line: &str, behavior: &Option<BehaviorOverrideValueResolver>) {
+        let job = self.prepare_learn(line, None, behavior);
+        self.learn_job(job);
+    }
+
+    /// Prepares a line for learning according to the behavior, without
+    /// learning it yet. The job can then be learned later with learn_job,
+    /// usually through the learn queue.
+    pub fn prepare_learn(
+        &self,
+        line: &str,
+        source: Option<Source>,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> LearnJob {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        let line = handle_urls(line, &b.url_handling());
+        let line = match b.blacklist_mode() {
+            BlacklistMode::Reject => line,
+            BlacklistMode::Scrub => {
+                let scrubbed = pattern::remove_all(&line, b.blacklisted_patterns());
+                debug!("[prepare_learn] Scrubbed {:?} into {:?}", line, scrubbed);
+                scrubbed
+            }
+        };
+        let line = match b.learn_filter() {
+            LearnFilter::All => line,
+            filter => filter_sentences(&line, filter),
+        };
+        let (min_words, max_words) = (b.min_sentence_words(), b.max_sentence_words());
+        let line = if min_words > 1 || max_words.is_some() {
+            filter_sentence_lengths(&line, min_words, max_words.unwrap_or(usize::MAX))
+        } else {
+            line
+        };
+        let min_ratio = b.min_unique_word_ratio();
+        let line = if min_ratio > 0.0 {
+            filter_repetitive_sentences(&line, min_ratio)
+        } else {
+            line
+        };
+        LearnJob { line, source }
+    }
+
+    pub fn learn_job(&mut self, job: LearnJob) {
+        let sentence_count = self.dictionary.stats().sentence_count;
+        let learned = match job.source {
+            Some(ref source) => self.dictionary.learn_attributed(&job.line, source),
+            None => self.dictionary.learn(&job.line),
+        };
+        if learned {
+            self.clear_response_cache();
+        }
+        if self.behavior.max_learned_sentences_per_minute.is_some() {
+            let added = self
+                .dictionary
+                .stats()
+                .sentence_count
+                .saturating_sub(sentence_count);
+            self.velocity().record(added, unix_time());
+        }
+    }
+
+    /// Learns the lines in a single batch, e.g. when importing a corpus from
+    /// the command line. This does not go through the learning behavior.
+    /// Returns how many new sentences were learned.
+    pub fn import<'a>(&mut self, lines: impl Iterator<Item = &'a str>) -> usize {
+        let added = self.dictionary.learn_batch(lines);
+        if added > 0 {
+            self.clear_response_cache();
+        }
+        added
+    }
+
+    /// Learns the lines in a single batch, attributing every new sentence to
+    /// the given user, e.g. when importing the logs of a chat. Like importing
+    /// from the command line, this does not go through the learning behavior.
+    /// Returns how many new sentences were learned.
+    pub fn import_with_source<'a>(
+        &mut self,
+        lines: impl Iterator<Item = &'a str>,
+        platform: &str,
+        chat_id: &str,
+        user_id: &str,
+    ) -> usize {
+        let source = Source::new(platform, chat_id, user_id);
+        let added = self
+            .dictionary
+            .learn_batch_attributed(lines.map(|line| (line, Some(&source))));
+        if added > 0 {
+            self.clear_response_cache();
+        }
+        added
+    }
+
+    /// Returns false when learn_only_from_replies is on and the input neither
+    /// addressed the bot, e.g. by replying to it, nor matched a nick pattern.
+    /// The handlers check it before should_learn, so that the messages it
+    /// rejects are not counted against the user's rate limit.
+    pub fn learns_from_input(
+        &self,
+        input: &str,
+        addressed: bool,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> bool {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        if !b.learn_only_from_replies() || addressed {
+            return true;
+        }
+        let nick_match = pattern::matches_any(input, b.nick_patterns());
+        if nick_match.is_none() {
             debug!(
-                "[should_reply_to] Reply to nickname chance: {:?}",
-                reply_nick
+                "[learns_from_input] Input {:?} does not address the bot. Refusing to learn",
+                input
             );
-            if chance(reply_nick, &mut self.rng) {
-                debug!("[should_reply_to] Reply nick decided to reply");
-                return true;
-            } else {
-                debug!("[should_reply_to] Reply nick decided not to reply")
+        }
+        nick_match.is_some()
+    }
+
+    /// Decides whether the input should be learned. Since this is the first
+    /// decision made for every message, it is also where the message is
+    /// counted against the user's rate limit.
+    pub fn should_learn(
+        &self,
+        user_id: &str,
+        chat_id: &str,
+        input: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> bool {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        debug!(
+            "[should_learn] Using {:?} for resolving behavior values.",
+            b
+        );
+
+        if self.kill_switch() == KillSwitch::RepliesAndLearning {
+            debug!("[should_learn] The kill switch is on");
+            return false;
+        }
+
+        if let Some(per_minute) = self.behavior.user_messages_per_minute {
+            if !self.throttle().take(user_id, per_minute, unix_time()) {
+                debug!(
+                    "[should_learn] User {:?} is sending messages too fast. Refusing to learn",
+                    user_id
+                );
+                return false;
             }
         }
 
-        if let Some(matched) = pattern::matches_any(input, b.magic_patterns()) {
+        if let Some(per_minute) = self.behavior.max_learned_sentences_per_minute {
+            if self.velocity().is_too_fast(per_minute, unix_time()) {
+                debug!("[should_learn] The dictionary is growing too fast. Refusing to learn");
+                return false;
+            }
+        }
+
+        if !b.is_learning() {
+            debug!("[should_learn] Learning is off");
+            return false;
+        }
+
+        if let Some(ref whitelist) = self.behavior.learn_chat_whitelist {
+            if pattern::matches_any(chat_id, whitelist).is_none() {
+                debug!(
+                    "[should_learn] Chat {:?} is not whitelisted. Refusing to learn",
+                    chat_id
+                );
+                return false;
+            }
+        }
+
+        match pattern::matches_any(user_id, b.ignored_users()) {
+            Some(pattern) => {
+                debug!(
+                    "[should_learn] User {:?} matches ignore pattern {:?}. Refusing to learn",
+                    user_id, pattern
+                );
+                return false;
+            }
+            None => {
+                debug!(
+                    "[should_learn] User ID {:?} does not match any blacklisted pattern.",
+                    user_id
+                );
+            }
+        }
+
+        if b.blacklist_mode() == BlacklistMode::Scrub {
+            debug!("[should_learn] Blacklisted fragments will be scrubbed from the input.");
+        } else if let Some(pattern) = pattern::matches_any(input, b.blacklisted_patterns()) {
             debug!(
-                "[should_reply_to] Input {:?} matched magic pattern {:?}",
-                input, matched
+                "[should_learn] Input {:?} matches blacklisted pattern {:?}. Refusing to learn",
+                input, pattern
             );
-            let reply_magic = b.reply_magic();
+            return false;
+        } else {
             debug!(
-                "[should_reply_to] Reply to magic patterns chance: {:?}",
-                reply_magic
+                "[should_learn] Input {:?} does not match any blacklisted pattern.",
+                input
             );
-            if chance(reply_magic, &mut self.rng) {
-                debug!("[should_reply_to] Reply magic decided to reply");
-                return true;
-            } else {
-                debug!("[should_reply_to] Reply magic decided not to reply");
+        }
+
+        if let Some(filter) = b.language_filter() {
+            if !passes_language_filter(input, filter) {
+                debug!(
+                    "[should_learn] Input {:?} is not in {:?}. Refusing to learn",
+                    input, filter.language
+                );
+                return false;
             }
         }
 
-        let reply_rate = b.reply_rate();
-        debug!("[should_reply_to] Reply rate: {:?}", reply_rate);
-        return if chance(reply_rate, &mut self.rng) {
-            debug!("[should_reply_to] Decided to reply to reply rate");
-            true
-        } else {
-            debug!("[should_reply_to] Decided not to reply to reply rate");
-            false
+        let learn_rate = b.learn_rate();
+        if !chance(learn_rate, &mut self.rng()) {
+            debug!(
+                "[should_learn] Learn rate {:?} decided not to learn",
+                learn_rate
+            );
+            return false;
+        }
+
+        debug!("[should_learn] Should learn {:?}", input);
+        true
+    }
+
+    /// Decides whether to reply to the input, and returns what triggered the
+    /// reply. An input that addresses the bot in some other way than by nick,
+    /// e.g. by replying to one of its messages, counts as a nick match. The
+    /// decision is kept in the decision log, and the input counts as activity
+    /// in the chat. The kind of chat picks between dm_reply_rate and
+    /// group_reply_rate.
+    pub fn reply_reason(
+        &self,
+        user_id: &str,
+        chat_id: &str,
+        chat_kind: ChatKind,
+        input: &str,
+        addressed: bool,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Option<ReplyReason> {
+        let now = unix_time();
+        let quiet_secs = self.touch_chat(chat_id, now);
+        // The cooldown only holds back replies, so that the decision log
+        // still tells why the bot would not have replied anyway.
+        let decision = match self.decide(user_id, chat_kind, input, addressed, quiet_secs, behavior)
+        {
+            Decision::Reply(reason) if self.cooldown_left(chat_id).is_some() => {
+                debug!(
+                    "[reply_reason] Not replying for {:?}, {:?} is cooling down",
+                    reason, chat_id
+                );
+                Decision::CoolingDown
+            }
+            decision => decision,
         };
+        self.record_decision(DecisionRecord {
+            time: now,
+            chat_id: chat_id.to_owned(),
+            user_id: user_id.to_owned(),
+            input: input.to_owned(),
+            decision,
+        });
+        match decision {
+            Decision::Reply(reason) => Some(reason),
+            _ => None,
+        }
     }
-}
 
-fn chance(chance: f32, rng: &mut SmallRng) -> bool {
-    let p = rng.next_u32() % 100;
-    p as f32 > chance || p == 100
+    pub fn kill_switch(&self) -> KillSwitch {
+        *self
+            .kill_switch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Turns the kill switch on or off. Like the other runtime state, it only
+    /// needs a shared reference, so that it works while replies are made up.
+    pub fn set_kill_switch(&self, kill_switch: KillSwitch) {
+        info!("Kill switch set to {:?}", kill_switch);
+        *self
+            .kill_switch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = kill_switch;
+    }
+
+    /// Returns the latest decisions about replying, from the oldest to the
+    /// newest.
+    pub fn decisions(&self) -> Vec<DecisionRecord> {
+        self.decision_log().iter().cloned().collect()
+    }
+
+    fn record_decision(&self, record: DecisionRecord) {
+        let capacity = self.behavior.decision_log_size;
+        if capacity == 0 {
+            return;
+        }
+        let mut decisions = self.decision_log();
+        while decisions.len() >= capacity {
+            decisions.pop_front();
+        }
+        decisions.push_back(record);
+    }
+
+    fn decide(
+        &self,
+        user_id: &str,
+        chat_kind: ChatKind,
+        input: &str,
+        addressed: bool,
+        quiet_secs: Option<u64>,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Decision {
+        let b = BehaviorValueResolver::new(&self.behavior, behavior);
+        debug!("[decide] Using {:?} for resolving behavior values.", b);
+
+        if self.kill_switch() != KillSwitch::Off {
+            debug!("[decide] The kill switch is on");
+            return Decision::Silenced;
+        }
+
+        if let Some(matched) = pattern::matches_any(user_id, b.ignored_users()) {
+            debug!(
+                "[decide] User is ignored, user ID {:?} matched pattern {:?}",
+                user_id, matched
+            );
+            return Decision::IgnoredUser;
+        }
+
+        if self.throttle().is_throttled(user_id) {
+            debug!("[decide] User {:?} is sending messages too fast", user_id);
+            return Decision::Throttled;
+        }
+
+        if !b.is_speaking() {
+            debug!("[decide] Speaking is off");
+            return Decision::NotSpeaking;
+        }
+
+        let sentence_count = self.dictionary.stats().sentence_count;
+        if sentence_count < b.min_dictionary_sentences() {
+            debug!(
+                "[decide] Only {:?} sentences known, which is below the minimum of {:?}",
+                sentence_count,
+                b.min_dictionary_sentences()
+            );
+            return Decision::SmallDictionary;
+        }
+
+        // A summon by name comes before the language filter, since the name
+        // alone is in no language.
+        if let Some(name) = b.bot_name().filter(|name| is_only_name(input, name)) {
+            debug!("[decide] Input {:?} is only the name {:?}", input, name);
+            let reply_name = b.reply_name();
+            debug!("[decide] Reply to name chance: {:?}", reply_name);
+            if chance(reply_name, &mut self.rng()) {
+                debug!("[decide] Reply name decided to reply");
+                return Decision::Reply(ReplyReason::Name);
+            } else {
+                debug!("[decide] Reply name decided not to reply")
+            }
+        }
+
+        if let Some(filter) = b.language_filter() {
+            if !passes_language_filter(input, filter) {
+                debug!("[decide] Input {:?} is not in {:?}", input, filter.language);
+                return Decision::WrongLanguage;
+            }
+        }
+
+        for (i, canned) in b.canned_responses().iter().enumerate() {
+            if let Some(matched) = pattern::matches_any(input, &canned.patterns) {
+                debug!(
+                    "[decide] Input {:?} matched pattern {:?} of canned response {:?}",
+                    input, matched, i
+                );
+                if chance(canned.chance, &mut self.rng()) {
+                    debug!("[decide] Canned response {:?} decided to reply", i);
+                    return Decision::Reply(ReplyReason::Canned(i));
+                } else {
+                    debug!("[decide] Canned response {:?} decided not to reply", i);
+                }
+            }
+        }
+
+        let nick_match = pattern::matches_any(input, b.nick_patterns());
+        if addressed || nick_match.is_some() {
+            debug!(
+                "[decide] Input {:?} addressed the bot, matched nick pattern {:?}",
+                input, nick_match
+            );
+            let reply_nick = b.reply_nick();
+            debug!("[decide] Reply to nickname chance: {:?}", reply_nick);
+            if chance(reply_nick, &mut self.rng()) {
+                debug!("[decide] Reply nick decided to reply");
+                return Decision::Reply(ReplyReason::Nick);
+            } else {
+                debug!("[decide] Reply nick decided not to reply")
+            }
+        }
+
+        if b.reply_only_when_addressed() {
+            debug!("[decide] Not addressed and only replying when addressed");
+            return Decision::NotAddressed;
+        }
+
+        for (i, group) in b.magic_groups().iter().enumerate() {
+            if let Some(matched) = pattern::matches_any(input, &group.patterns) {
+                debug!(
+                    "[decide] Input {:?} matched pattern {:?} of magic group {:?}",
+                    input, matched, group.name
+                );
+                if chance(group.reply_chance, &mut self.rng()) {
+                    debug!("[decide] Magic group {:?} decided to reply", group.name);
+                    return Decision::Reply(ReplyReason::MagicGroup(i));
+                } else {
+                    debug!("[decide] Magic group {:?} decided not to reply", group.name);
+                }
+            }
+        }
+
+        if let Some(matched) = pattern::matches_any(input, b.magic_patterns()) {
+            debug!(
+                "[decide] Input {:?} matched magic pattern {:?}",
+                input, matched
+            );
+            let reply_magic = b.reply_magic();
+            debug!("[decide] Reply to magic patterns chance: {:?}", reply_magic);
+            if chance(reply_magic, &mut self.rng()) {
+                debug!("[decide] Reply magic decided to reply");
+                return Decision::Reply(ReplyReason::Magic);
+            } else {
+                debug!("[decide] Reply magic decided not to reply");
+            }
+        }
+
+        let reply_rate = match chat_kind {
+            ChatKind::Private => b.dm_reply_rate(),
+            ChatKind::Group => b.group_reply_rate(),
+        };
+        let reply_rate = match b.reply_scoring() {
+            Some(scoring) => ReplyScorer::new(scoring, &self.dictionary).rate(
+                input,
+                reply_rate,
+                b.reply_rate_multiplier(),
+            ),
+            None => reply_rate,
+        };
+        let reply_rate = boosted_reply_rate(
+            reply_rate,
+            quiet_secs,
+            b.quiet_chat_secs(),
+            b.quiet_chat_boost(),
+        );
+        debug!("[decide] Reply rate: {:?}", reply_rate);
+        return if chance(reply_rate, &mut self.rng()) {
+            debug!("[decide] Decided to reply to reply rate");
+            Decision::Reply(ReplyReason::Rate)
+        } else {
+            debug!("[decide] Decided not to reply to reply rate");
+            Decision::NotChosen
+        };
+    }
+}
+
+/// Decides whether to answer with a reaction rather than text. Only nick
+/// mentions are reacted to, and only where the platform supports it.
+fn should_react(reason: ReplyReason, reaction_mode: bool, can_react: bool) -> bool {
+    reaction_mode && can_react && (reason == ReplyReason::Nick || reason == ReplyReason::Name)
+}
+
+/// Returns the reply rate for a chat that had been quiet for quiet_secs. The
+/// boost is added once the chat had been quiet for at least threshold_secs,
+/// and the rate never goes above 1. A chat that was never seen before is not
+/// considered quiet, since there is no telling how long it had been.
+fn boosted_reply_rate(
+    reply_rate: f32,
+    quiet_secs: Option<u64>,
+    threshold_secs: u64,
+    boost: f32,
+) -> f32 {
+    match quiet_secs {
+        Some(secs) if boost > 0.0 && secs >= threshold_secs => (reply_rate + boost).min(1.0),
+        _ => reply_rate,
+    }
+}
+
+/// Decides whether the input is only the name, ignoring case and the
+/// punctuation around it, so that "Borg?" and "@borg!" are the name "borg".
+fn is_only_name(input: &str, name: &str) -> bool {
+    let trim = |s: &str| {
+        s.trim_matches(|c: char| !c.is_alphanumeric())
+            .to_lowercase()
+    };
+    let name = trim(name);
+    !name.is_empty() && trim(input) == name
+}
+
+/// Keeps the sentences of the line that pass the filter, one per line so that
+/// they are split the same way when learned.
+fn filter_sentences(line: &str, filter: LearnFilter) -> String {
+    dictionary::split_sentences(line)
+        .into_iter()
+        .filter(|sentence| {
+            let question = sentence.trim_end().ends_with('?');
+            match filter {
+                LearnFilter::All => true,
+                LearnFilter::QuestionsOnly => question,
+                LearnFilter::StatementsOnly => !question,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Keeps only the sentences of the line that have between min_words and
+/// max_words words.
+fn filter_sentence_lengths(line: &str, min_words: usize, max_words: usize) -> String {
+    dictionary::split_sentences(line)
+        .into_iter()
+        .filter(|sentence| {
+            let words = dictionary::split_words(sentence).len();
+            let keep = words >= min_words && words <= max_words;
+            if !keep {
+                debug!(
+                    "[filter_sentence_lengths] Skipping {:?}, which has {:?} words",
+                    sentence, words
+                );
+            }
+            keep
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Keeps only the sentences of the line whose share of distinct words is at
+/// least min_ratio. Words are compared without regard to case.
+fn filter_repetitive_sentences(line: &str, min_ratio: f32) -> String {
+    dictionary::split_sentences(line)
+        .into_iter()
+        .filter(|sentence| {
+            let lowercase = sentence.to_lowercase();
+            let words = dictionary::split_words(&lowercase);
+            if words.is_empty() {
+                return true;
+            }
+            let unique = words.iter().collect::<HashSet<_>>().len();
+            let ratio = unique as f32 / words.len() as f32;
+            let keep = ratio >= min_ratio;
+            if !keep {
+                debug!(
+                    "[filter_repetitive_sentences] Skipping {:?}, whose unique word ratio is {:?}",
+                    sentence, ratio
+                );
+            }
+            keep
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Decides whether the input is in the language of the filter. When the
+/// language cannot be detected with enough confidence, the filter decides
+/// whether the input passes.
+fn passes_language_filter(input: &str, filter: &LanguageFilter) -> bool {
+    match whatlang::detect(input) {
+        Some(info) if info.confidence() >= filter.min_confidence => {
+            debug!(
+                "[passes_language_filter] Detected {:?} with confidence {:?}",
+                info.lang().code(),
+                info.confidence()
+            );
+            info.lang().code() == filter.language
+        }
+        _ => filter.uncertain == UncertainLanguage::Allow,
+    }
+}
+
+/// Removes the invisible and control characters from the text, as the
+/// sanitization asks.
+fn sanitize(text: &str, sanitization: InputSanitization) -> String {
+    if sanitization == InputSanitization::Off {
+        return text.to_owned();
+    }
+    let chars: Vec<char> = text.chars().collect();
+    let mut sanitized = String::with_capacity(text.len());
+    for (i, &c) in chars.iter().enumerate() {
+        match c {
+            '\n' => sanitized.push(c),
+            '\t' => sanitized.push(' '),
+            // A joiner between two emoji is part of an emoji sequence, but
+            // one next to a letter or digit only hides a word.
+            '\u{200d}' => {
+                let previous = i.checked_sub(1).map(|p| chars[p]);
+                let next = chars.get(i + 1).copied();
+                if ![previous, next]
+                    .iter()
+                    .any(|n| n.map_or(true, char::is_alphanumeric))
+                {
+                    sanitized.push(c);
+                }
+            }
+            c if c.is_control() || is_invisible_format(c) => {}
+            c => sanitized.push(c),
+        }
+    }
+    match sanitization {
+        InputSanitization::Normalize => sanitized.nfkc().collect(),
+        _ => sanitized,
+    }
+}
+
+/// Returns true for the invisible formatting characters, such as zero-width
+/// spaces and bidirectional overrides.
+fn is_invisible_format(c: char) -> bool {
+    match c {
+        '\u{ad}'
+        | '\u{61c}'
+        | '\u{180e}'
+        | '\u{200b}'..='\u{200f}'
+        | '\u{202a}'..='\u{202e}'
+        | '\u{2060}'..='\u{2064}'
+        | '\u{2066}'..='\u{206f}'
+        | '\u{feff}'
+        | '\u{fff9}'..='\u{fffb}' => true,
+        _ => false,
+    }
+}
+
+/// Strips or replaces the URLs in the line, as the URL handling asks.
+fn handle_urls(line: &str, handling: &UrlHandling) -> String {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"(?i)\b(?:https?://|www\.)\S+").unwrap();
+    }
+    match handling {
+        UrlHandling::Keep => line.to_owned(),
+        // Whitespace is collapsed line by line, since every line is its own
+        // sentence.
+        UrlHandling::Strip => RE
+            .replace_all(line, "")
+            .lines()
+            .map(|l| l.split_whitespace().collect::<Vec<_>>().join(" "))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        UrlHandling::Replace(text) => RE.replace_all(line, text.as_str()),
+    }
+}
+
+/// Returns how long the bot stays quiet in a chat after sending the
+/// response there: the base cooldown plus the increment for every word.
+fn reply_cooldown(base_ms: u64, per_word_ms: u64, response: &str) -> Duration {
+    let words = response.split_whitespace().count() as u64;
+    Duration::from_millis(base_ms + per_word_ms * words)
+}
+
+/// Picks a random delay between the given bounds, inclusive.
+fn delay_between(min_ms: u64, max_ms: u64, rng: &mut SmallRng) -> Duration {
+    if max_ms <= min_ms {
+        Duration::from_millis(min_ms)
+    } else {
+        Duration::from_millis(rng.gen_range(min_ms, max_ms + 1))
+    }
+}
+
+/// Returns a random number generator seeded with the seed, or with entropy
+/// when there is none.
+fn seeded_rng(seed: Option<u64>) -> SmallRng {
+    match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_entropy(),
+    }
+}
+
+/// Returns true with the given probability, which goes from 0.0 (never) to
+/// 1.0 (always).
+fn chance(chance: f32, rng: &mut SmallRng) -> bool {
+    rng.gen::<f32>() < chance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{BehaviorOverride, CannedResponse, MagicGroup, SentimentReactions};
+    use crate::pattern::Pattern;
+    use futures::future::join;
+    use std::sync::Arc;
+    use tokio::{
+        sync::RwLock,
+        time::{delay_for, timeout},
+    };
+
+    fn borg_with(behavior: MainBehavior) -> Borg {
+        Borg::new(Dictionary::new_empty(), behavior)
+    }
+
+    /// Decides whether to reply to a group message, like the platforms do,
+    /// and makes up the reply.
+    fn reply(
+        borg: &Borg,
+        chat_id: &str,
+        input: &str,
+        behavior: &Option<BehaviorOverrideValueResolver>,
+    ) -> Option<String> {
+        let reason = borg.reply_reason("1", chat_id, ChatKind::Group, input, false, behavior)?;
+        borg.response_for(reason, chat_id, input, input, behavior)
+    }
+
+    #[test]
+    fn test_user_rate_limit() {
+        let borg = borg_with(MainBehavior {
+            user_messages_per_minute: Some(3),
+            ..MainBehavior::default()
+        });
+
+        for _ in 0..3 {
+            assert!(borg.should_learn("spammer", "1", "buy my crabs", &None));
+        }
+        assert!(!borg.should_learn("spammer", "1", "buy my crabs", &None));
+        assert!(borg
+            .reply_reason(
+                "spammer",
+                "1",
+                ChatKind::Group,
+                "buy my crabs",
+                false,
+                &None
+            )
+            .is_none());
+
+        assert!(borg.should_learn("someone else", "1", "i like crabs", &None));
+    }
+
+    #[test]
+    fn test_user_throttle_refills() {
+        let mut throttle = UserThrottle::default();
+        assert!(throttle.take("1", 2, 0));
+        assert!(throttle.take("1", 2, 0));
+        assert!(!throttle.take("1", 2, 10));
+        assert!(throttle.is_throttled("1"));
+
+        // Two messages per minute refill one token every 30 seconds.
+        assert!(throttle.take("1", 2, 30));
+        assert!(!throttle.is_throttled("1"));
+        assert!(!throttle.take("1", 2, 31));
+        assert!(throttle.take("1", 2, 120));
+        assert!(throttle.take("1", 2, 120));
+        assert!(!throttle.take("1", 2, 120));
+    }
+
+    #[test]
+    fn test_user_throttle_prunes_quiet_users() {
+        let mut throttle = UserThrottle::default();
+        assert!(throttle.take("1", 2, 0));
+        assert!(throttle.take("2", 2, 30));
+        assert_eq!(2, throttle.buckets.len());
+
+        // A minute later, the bucket of user 1 is full and forgotten.
+        assert!(throttle.take("2", 2, 70));
+        assert_eq!(vec!["2"], throttle.buckets.keys().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_quiet_chats_are_forgotten() {
+        let borg = borg_with(MainBehavior {
+            skip_repeated_messages: true,
+            ..MainBehavior::default()
+        });
+        let now = unix_time();
+        let long_ago = now - CHAT_MEMORY_TTL_SECS;
+        assert!(!borg.is_repeat("quiet", "1", "hello"));
+        assert!(!borg.is_repeat("busy", "1", "hello"));
+        assert_eq!(None, borg.touch_chat("quiet", long_ago));
+        assert_eq!(None, borg.touch_chat("busy", now - 10));
+
+        // Pruning is only done once in a while.
+        *borg.chats_pruned_at.lock().unwrap() = 0;
+        assert_eq!(Some(10), borg.touch_chat("busy", now));
+        assert!(!borg.activity().contains_key("quiet"));
+        assert!(!borg.is_repeat("quiet", "1", "hello"));
+        assert!(borg.is_repeat("busy", "1", "hello"));
+    }
+
+    #[test]
+    fn test_learn_velocity() {
+        let mut borg = borg_with(MainBehavior {
+            max_learned_sentences_per_minute: Some(3),
+            ..MainBehavior::default()
+        });
+        assert!(borg.should_learn("1", "1", "crabs are red", &None));
+        borg.learn("crabs are red. lobsters are red.", &None);
+        assert!(borg.should_learn("1", "1", "crabs are red", &None));
+
+        // A burst pushes the dictionary past the cap and learning pauses for
+        // everyone.
+        borg.learn("buy my crabs. buy my lobsters. buy my shrimp.", &None);
+        assert!(!borg.should_learn("1", "1", "crabs are red", &None));
+        assert!(!borg.should_learn("2", "1", "i like crabs", &None));
+        assert_eq!(5, borg.stats().sentence_count);
+    }
+
+    #[test]
+    fn test_learn_velocity_window() {
+        let mut velocity = LearnVelocity::default();
+        velocity.record(2, 0);
+        assert!(!velocity.is_too_fast(3, 10));
+        velocity.record(2, 30);
+        assert!(velocity.is_too_fast(3, 30));
+        assert!(velocity.paused);
+
+        // Relearning known sentences adds nothing.
+        velocity.record(0, 40);
+        assert!(velocity.is_too_fast(3, 40));
+
+        // Learning resumes once the first burst leaves the window.
+        assert!(!velocity.is_too_fast(3, 60));
+        assert!(!velocity.paused);
+        assert!(velocity.is_too_fast(2, 89));
+        assert!(!velocity.is_too_fast(2, 90));
+    }
+
+    #[test]
+    fn test_response_cache() {
+        let mut cache = ResponseCache::default();
+        cache.insert(Some("#crabs"), "Hello ", "hello crabs".to_owned(), 2, 0);
+        assert_eq!(
+            Some("hello crabs".to_owned()),
+            cache.get(Some("#crabs"), "hello", 60, 30)
+        );
+        assert_eq!(None, cache.get(Some("#crabs"), "crabs", 60, 30));
+        assert_eq!(None, cache.get(Some("#lobsters"), "hello", 60, 30));
+        assert_eq!(None, cache.get(None, "hello", 60, 30));
+
+        // Expired entries are dropped.
+        assert_eq!(None, cache.get(Some("#crabs"), "hello", 60, 60));
+        assert_eq!(None, cache.get(Some("#crabs"), "hello", 60, 0));
+    }
+
+    #[test]
+    fn test_response_cache_evicts_least_recently_used() {
+        let mut cache = ResponseCache::default();
+        cache.insert(None, "a", "a".to_owned(), 2, 0);
+        cache.insert(None, "b", "b".to_owned(), 2, 0);
+        assert!(cache.get(None, "a", 60, 0).is_some());
+        cache.insert(None, "c", "c".to_owned(), 2, 0);
+        assert!(cache.get(None, "a", 60, 0).is_some());
+        assert!(cache.get(None, "b", 60, 0).is_none());
+        assert!(cache.get(None, "c", 60, 0).is_some());
+
+        cache.insert(None, "d", "d".to_owned(), 0, 0);
+        assert!(cache.get(None, "d", 60, 0).is_none());
+    }
+
+    #[test]
+    fn test_sent_responses_expire() {
+        let mut sent = SentResponses::default();
+        sent.insert("1", "crabs are red", MAX_SENT_RESPONSES_PER_CHAT, 60, 100);
+        assert!(sent.contains("1", "crabs are red", 60, 120));
+        assert!(sent.contains("1", "  Crabs are RED ", 60, 120));
+        assert!(!sent.contains("2", "crabs are red", 60, 120));
+        assert!(!sent.contains("1", "crabs are blue", 60, 120));
+        assert!(!sent.contains("1", "crabs are red", 60, 160));
+        assert!(sent.chats.is_empty());
+
+        for i in 0..MAX_SENT_RESPONSES_PER_CHAT + 1 {
+            sent.insert("1", &i.to_string(), MAX_SENT_RESPONSES_PER_CHAT, 60, 100);
+        }
+        assert!(!sent.contains("1", "0", 60, 100));
+        assert!(sent.contains("1", "1", 60, 100));
+    }
+
+    #[test]
+    fn test_echo_is_ignored() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_rate: 1.0,
+                echo_window_secs: 60,
+                ..MainBehavior::default()
+            },
+        );
+        let response = reply(&borg, "#crabs", "hello", &None).unwrap();
+        borg.remember_sent("#crabs", &response);
+
+        // The response comes back, e.g. through a bridge.
+        assert!(borg.is_echo("#crabs", &response));
+        assert!(!borg.is_echo("#lobsters", &response));
+        assert!(!borg.is_echo("#crabs", "hello"));
+
+        borg.behavior.echo_window_secs = 0;
+        assert!(!borg.is_echo("#crabs", &response));
+    }
+
+    #[test]
+    fn test_repeated_messages_are_skipped() {
+        let mut borg = borg_with(MainBehavior {
+            skip_repeated_messages: true,
+            ..MainBehavior::default()
+        });
+        let processed = ["hello crabs", "hello crabs", "Hello crabs "]
+            .iter()
+            .filter(|input| !borg.is_repeat("#crabs", "1", input))
+            .count();
+        assert_eq!(1, processed);
+
+        // Another chat, another user or another message is not a repeat.
+        assert!(!borg.is_repeat("#lobsters", "1", "hello crabs"));
+        assert!(!borg.is_repeat("#crabs", "2", "hello crabs"));
+        assert!(!borg.is_repeat("#crabs", "1", "hello crabs"));
+        assert!(!borg.is_repeat("#crabs", "1", "hello lobsters"));
+
+        borg.behavior.skip_repeated_messages = false;
+        assert!(!borg.is_repeat("#crabs", "1", "hello lobsters"));
+    }
+
+    #[test]
+    fn test_response_cache_cleared_on_learn() {
+        let mut borg = borg_with(MainBehavior {
+            response_cache_size: 10,
+            ..MainBehavior::default()
+        });
+        borg.learn("hello crabs. hello lobsters.", &None);
+        let response = borg.respond_to("hello", &None).unwrap();
+        for _ in 0..10 {
+            assert_eq!(Some(&response), borg.respond_to("Hello", &None).as_ref());
+        }
+
+        // Learning nothing new keeps the cache.
+        borg.learn("hello crabs.", &None);
+        assert!(borg.cache().get(None, "hello", 60, unix_time()).is_some());
+        borg.learn("hello squids.", &None);
+        assert!(borg.cache().get(None, "hello", 60, unix_time()).is_none());
+
+        // A preview does not fill the cache.
+        assert!(borg.preview("hello", &None).is_some());
+        assert!(borg.cache().get(None, "hello", 60, unix_time()).is_none());
+    }
+
+    #[test]
+    fn test_rng_seed() {
+        let borg = |seed| {
+            let mut borg = borg_with(MainBehavior {
+                reply_rate: 0.5,
+                rng_seed: Some(seed),
+                ..MainBehavior::default()
+            });
+            borg.learn("hello crabs. hello lobsters. hello squids.", &None);
+            borg
+        };
+        let decisions = |borg: &Borg| -> Vec<(bool, Option<String>)> {
+            (0..50)
+                .map(|_| {
+                    (
+                        borg.reply_reason("1", "1", ChatKind::Group, "hello", false, &None)
+                            .is_some(),
+                        borg.respond_to("hello", &None),
+                    )
+                })
+                .collect()
+        };
+
+        let first = decisions(&borg(7));
+        assert_eq!(first, decisions(&borg(7)));
+        assert_ne!(first, decisions(&borg(8)));
+    }
+
+    #[test]
+    fn test_reply_cooldown() {
+        let short = "hello crabs";
+        let long = "hello crabs and lobsters, the sea is full of friends today";
+        assert_eq!(Duration::from_millis(0), reply_cooldown(0, 0, long));
+        assert_eq!(Duration::from_millis(1000), reply_cooldown(1000, 0, long));
+        assert_eq!(
+            Duration::from_millis(1200),
+            reply_cooldown(1000, 100, short)
+        );
+        assert!(reply_cooldown(1000, 100, long) > reply_cooldown(1000, 100, short));
+
+        let borg = borg_with(MainBehavior {
+            reply_rate: 1.0,
+            reply_cooldown_ms: 60_000,
+            reply_cooldown_ms_per_word: 10_000,
+            ..MainBehavior::default()
+        });
+        borg.start_cooldown("a", short, &None);
+        borg.start_cooldown("b", long, &None);
+        let short_left = borg.cooldown_left("a").unwrap();
+        let long_left = borg.cooldown_left("b").unwrap();
+        assert!(short_left <= Duration::from_millis(80_000));
+        assert!(long_left > Duration::from_millis(80_000));
+        // A shorter cooldown does not cut a longer one short.
+        borg.start_cooldown("b", short, &None);
+        assert!(borg.cooldown_left("b").unwrap() > Duration::from_millis(80_000));
+        // Cooldowns that are over are forgotten.
+        borg.cooldowns().insert("over".to_owned(), 0);
+        borg.start_cooldown("a", short, &None);
+        assert!(!borg.cooldowns().contains_key("over"));
+
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "a", ChatKind::Group, "hello", false, &None)
+        );
+        assert_eq!(Decision::CoolingDown, borg.decisions()[0].decision);
+        assert_eq!(
+            Some(ReplyReason::Rate),
+            borg.reply_reason("1", "c", ChatKind::Group, "hello", false, &None)
+        );
+        assert_eq!(None, borg.cooldown_left("c"));
+    }
+
+    #[test]
+    fn test_chance() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..100 {
+            assert!(chance(1.0, &mut rng));
+            assert!(!chance(0.0, &mut rng));
+        }
+    }
+
+    #[test]
+    fn test_delay_between() {
+        let mut rng = SmallRng::seed_from_u64(0);
+        for _ in 0..100 {
+            let delay = delay_between(500, 1500, &mut rng);
+            assert!(delay >= Duration::from_millis(500));
+            assert!(delay <= Duration::from_millis(1500));
+        }
+        assert_eq!(Duration::from_millis(0), delay_between(0, 0, &mut rng));
+        assert_eq!(
+            Duration::from_millis(200),
+            delay_between(200, 100, &mut rng)
+        );
+    }
+
+    #[test]
+    fn test_reload_behavior() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let mut borg = Borg::new(dictionary, MainBehavior::default());
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "a", ChatKind::Group, "hi borg", false, &None)
+        );
+
+        // Deserialized patterns are not compiled yet.
+        let uncompiled = |original| -> Pattern {
+            serde_yaml::from_str(&format!("original: {:?}\nsubstring: true", original)).unwrap()
+        };
+        borg.reload_behavior(MainBehavior {
+            reply_nick: 1.0,
+            nick_patterns: vec![uncompiled("borg")],
+            ..MainBehavior::default()
+        })
+        .unwrap();
+        assert_eq!(
+            Some(ReplyReason::Nick),
+            borg.reply_reason("1", "a", ChatKind::Group, "hi borg", false, &None)
+        );
+
+        // An invalid pattern leaves the old behavior in place.
+        let invalid = MainBehavior {
+            nick_patterns: vec![uncompiled("(borg")],
+            ..MainBehavior::default()
+        };
+        assert!(borg.reload_behavior(invalid).is_err());
+        assert_eq!(1.0, borg.behavior.reply_nick);
+        // So does an invalid value.
+        let invalid = MainBehavior {
+            reply_rate: 2.0,
+            ..MainBehavior::default()
+        };
+        assert!(borg.reload_behavior(invalid).is_err());
+        assert_eq!(1.0, borg.behavior.reply_nick);
+        assert_eq!(
+            Some(ReplyReason::Nick),
+            borg.reply_reason("1", "a", ChatKind::Group, "hi borg", false, &None)
+        );
+    }
+
+    #[test]
+    fn test_min_dictionary_sentences() {
+        let mut borg = borg_with(MainBehavior {
+            reply_rate: 1.0,
+            min_dictionary_sentences: 3,
+            ..MainBehavior::default()
+        });
+        borg.learn("hello there. how are you?", &None);
+        assert!(borg
+            .reply_reason("1", "1", ChatKind::Group, "hello", false, &None)
+            .is_none());
+
+        borg.learn("i am fine.", &None);
+        assert!(borg
+            .reply_reason("1", "1", ChatKind::Group, "hello", false, &None)
+            .is_some());
+    }
+
+    #[test]
+    fn test_chat_kind_reply_rates() {
+        let mut borg = borg_with(MainBehavior {
+            reply_rate: 0.5,
+            dm_reply_rate: Some(1.0),
+            group_reply_rate: Some(0.0),
+            ..MainBehavior::default()
+        });
+        borg.learn("hello there. hello crabs.", &None);
+        for _ in 0..20 {
+            assert_eq!(
+                Some(ReplyReason::Rate),
+                borg.reply_reason("1", "1", ChatKind::Private, "hello", false, &None)
+            );
+            assert_eq!(
+                None,
+                borg.reply_reason("1", "2", ChatKind::Group, "hello", false, &None)
+            );
+        }
+
+        // The rates of overrides win over the main ones, and chats of a kind
+        // without a rate of their own fall back to the reply_rate of the same
+        // override before the rates of the main behavior.
+        let override_ = BehaviorOverride {
+            reply_rate: Some(1.0),
+            dm_reply_rate: Some(0.0),
+            ..BehaviorOverride::default()
+        };
+        let behavior = Some(BehaviorOverrideValueResolver::new(&override_, None));
+        let b = BehaviorValueResolver::new(&borg.behavior, &behavior);
+        assert_eq!(0.0, b.dm_reply_rate());
+        assert_eq!(1.0, b.group_reply_rate());
+        let borg = borg_with(MainBehavior::default());
+        let b = BehaviorValueResolver::new(&borg.behavior, &behavior);
+        assert_eq!(0.0, b.dm_reply_rate());
+        assert_eq!(1.0, b.group_reply_rate());
+
+        // The same goes for an override of an override.
+        let platform = BehaviorOverride {
+            group_reply_rate: Some(0.2),
+            ..BehaviorOverride::default()
+        };
+        let chat = BehaviorOverride {
+            reply_rate: Some(0.7),
+            ..BehaviorOverride::default()
+        };
+        let behavior = Some(BehaviorOverrideValueResolver::new(
+            &platform,
+            Some(Box::new(BehaviorOverrideValueResolver::new(&chat, None))),
+        ));
+        let b = BehaviorValueResolver::new(&borg.behavior, &behavior);
+        assert_eq!(0.7, b.dm_reply_rate());
+        assert_eq!(0.7, b.group_reply_rate());
+        let behavior = Some(BehaviorOverrideValueResolver::new(&platform, None));
+        let b = BehaviorValueResolver::new(&borg.behavior, &behavior);
+        assert_eq!(0.2, b.group_reply_rate());
+    }
+
+    #[test]
+    fn test_empty_dictionary() {
+        let mut borg = borg_with(MainBehavior {
+            reply_rate: 1.0,
+            ..MainBehavior::default()
+        });
+        assert!(borg
+            .empty_dictionary_warning()
+            .unwrap()
+            .contains("min_dictionary_sentences to 1"));
+        assert_eq!(None, borg.respond_to("hello", &None));
+
+        // With min_dictionary_sentences, the bot does not even try to reply
+        // until something is learned.
+        let mut guarded = borg_with(MainBehavior {
+            reply_rate: 1.0,
+            min_dictionary_sentences: 1,
+            ..MainBehavior::default()
+        });
+        assert!(guarded
+            .empty_dictionary_warning()
+            .unwrap()
+            .contains("stays quiet"));
+        assert!(guarded
+            .reply_reason("1", "1", ChatKind::Group, "hello", false, &None)
+            .is_none());
+        assert_eq!(
+            Decision::SmallDictionary,
+            guarded.decisions().last().unwrap().decision
+        );
+
+        for borg in vec![&mut borg, &mut guarded] {
+            borg.learn("hello there.", &None);
+            assert_eq!(None, borg.empty_dictionary_warning());
+        }
+        assert!(guarded
+            .reply_reason("1", "1", ChatKind::Group, "hello", false, &None)
+            .is_some());
+    }
+
+    #[test]
+    fn test_learn_rate() {
+        let mut borg = borg_with(MainBehavior {
+            learn_rate: 0.0,
+            ..MainBehavior::default()
+        });
+        for _ in 0..10 {
+            assert!(!borg.should_learn("1", "1", "hello crabs", &None));
+        }
+
+        borg.behavior.learn_rate = 1.0;
+        for _ in 0..10 {
+            assert!(borg.should_learn("1", "1", "hello crabs", &None));
+        }
+    }
+
+    #[test]
+    fn test_learn_chat_whitelist() {
+        let mut borg = borg_with(MainBehavior::default());
+        assert!(borg.should_learn("1", "-100", "hello crabs", &None));
+
+        borg.behavior.learn_chat_whitelist = Some(vec![Pattern::new("^-100$").unwrap()]);
+        assert!(borg.should_learn("1", "-100", "hello crabs", &None));
+        assert!(!borg.should_learn("1", "-1001", "hello crabs", &None));
+        assert!(!borg.should_learn("1", "#crabs", "hello crabs", &None));
+    }
+
+    #[test]
+    fn test_learn_filter() {
+        let input = "crabs are red. are lobsters red? they are! why?\nno idea";
+        let learned = |filter: LearnFilter| {
+            let mut borg = borg_with(MainBehavior {
+                learn_filter: filter,
+                ..MainBehavior::default()
+            });
+            borg.learn(input, &None);
+            let mut sentences: Vec<String> = [
+                "crabs are red.",
+                "are lobsters red?",
+                "they are!",
+                "why?",
+                "no idea",
+            ]
+            .iter()
+            .filter(|s| borg.dictionary.knows_sentence(s))
+            .map(|s| (*s).to_owned())
+            .collect();
+            sentences.sort();
+            sentences
+        };
+
+        assert_eq!(5, learned(LearnFilter::All).len());
+        assert_eq!(
+            vec!["are lobsters red?", "why?"],
+            learned(LearnFilter::QuestionsOnly)
+        );
+        assert_eq!(
+            vec!["crabs are red.", "no idea", "they are!"],
+            learned(LearnFilter::StatementsOnly)
+        );
+    }
+
+    #[test]
+    fn test_sentence_word_bounds() {
+        let mut borg = borg_with(MainBehavior {
+            min_sentence_words: 2,
+            max_sentence_words: Some(4),
+            ..MainBehavior::default()
+        });
+        borg.learn(
+            "ok. crabs are red. this sentence is far too long to learn. no idea",
+            &None,
+        );
+        assert!(!borg.dictionary.knows_sentence("ok."));
+        assert!(borg.dictionary.knows_sentence("crabs are red."));
+        assert!(!borg
+            .dictionary
+            .knows_sentence("this sentence is far too long to learn."));
+        assert!(borg.dictionary.knows_sentence("no idea"));
+
+        // Overrides can lift the lower bound.
+        let lenient = BehaviorOverride {
+            min_sentence_words: Some(1),
+            ..BehaviorOverride::default()
+        };
+        let behavior = Some(BehaviorOverrideValueResolver::new(&lenient, None));
+        borg.learn("ok.", &behavior);
+        assert!(borg.dictionary.knows_sentence("ok."));
+    }
+
+    #[test]
+    fn test_import_with_source() {
+        let mut borg = borg_with(MainBehavior::default());
+        borg.learn("crabs are red.", &None);
+        let lines = vec!["hello crabs. crabs are red.", "lobsters are red too."];
+        let added = borg.import_with_source(lines.into_iter(), "irc", "#crabs", "ferris");
+        assert_eq!(2, added);
+
+        let ferris = Source::new("irc", "#crabs", "ferris");
+        assert_eq!(Some(&ferris), borg.source_of("hello crabs."));
+        assert_eq!(Some(&ferris), borg.source_of("lobsters are red too."));
+        // Known sentences keep their source, or lack of one.
+        assert_eq!(None, borg.source_of("crabs are red."));
+    }
+
+    #[test]
+    fn test_min_unique_word_ratio() {
+        let mut borg = borg_with(MainBehavior {
+            min_unique_word_ratio: 0.5,
+            ..MainBehavior::default()
+        });
+        borg.learn("a a a a a. crabs are red. Spam spam SPAM spam eggs.", &None);
+        assert!(!borg.dictionary.knows_sentence("a a a a a."));
+        assert!(!borg.dictionary.knows_sentence("spam spam spam spam eggs."));
+        assert!(borg.dictionary.knows_sentence("crabs are red."));
+
+        // Nothing is filtered by default.
+        let mut borg = borg_with(MainBehavior::default());
+        borg.learn("a a a a a.", &None);
+        assert!(borg.dictionary.knows_sentence("a a a a a."));
+        assert_eq!("a a", filter_repetitive_sentences("a a", 0.5));
+        assert_eq!("", filter_repetitive_sentences("a a a", 0.5));
+    }
+
+    #[test]
+    fn test_sentiment_reaction() {
+        let borg = borg_with(MainBehavior {
+            react_to_sentiment: true,
+            sentiment_reactions: SentimentReactions {
+                negative: None,
+                ..SentimentReactions::default()
+            },
+            ..MainBehavior::default()
+        });
+        let reaction = |input| borg.sentiment_reaction(input, &None, true).map(|r| r.emoji);
+
+        assert_eq!(
+            Some("❤️".to_owned()),
+            reaction("i love crabs, they are great")
+        );
+        assert_eq!(None, reaction("i love crabs"));
+        assert_eq!(None, reaction("crabs are awful, the worst"));
+        // Platforms that cannot react get no reaction, not the emoji as text.
+        assert_eq!(
+            None,
+            borg.sentiment_reaction("i love crabs, they are great", &None, false)
+        );
+
+        let borg = borg_with(MainBehavior {
+            react_to_sentiment: true,
+            ..MainBehavior::default()
+        });
+        assert_eq!(
+            Some("😢".to_owned()),
+            borg.sentiment_reaction("crabs are awful, the worst", &None, true)
+                .map(|r| r.emoji)
+        );
+
+        // The flag is off by default.
+        let borg = borg_with(MainBehavior::default());
+        assert_eq!(
+            None,
+            borg.sentiment_reaction("i love crabs, they are great", &None, true)
+        );
+    }
+
+    #[test]
+    fn test_learn_ack() {
+        let borg = borg_with(MainBehavior {
+            speaking: false,
+            learn_ack: true,
+            reaction_emoji: "👀".to_owned(),
+            ..MainBehavior::default()
+        });
+        let ack = |speaking, learning, learn_ack, can_react| {
+            let behavior = BehaviorOverride {
+                speaking: Some(speaking),
+                learning: Some(learning),
+                learn_ack: Some(learn_ack),
+                ..BehaviorOverride::default()
+            };
+            borg.learn_ack(
+                &Some(BehaviorOverrideValueResolver::new(&behavior, None)),
+                can_react,
+            )
+        };
+
+        // Only learning without speaking is acknowledged, and only with a
+        // reaction.
+        assert_eq!(Some("👀".to_owned()), ack(false, true, true, true));
+        assert_eq!(None, ack(false, true, true, false));
+        assert_eq!(None, ack(false, true, false, true));
+        assert_eq!(None, ack(false, false, true, true));
+        assert_eq!(None, ack(true, true, true, true));
+
+        borg.set_kill_switch(KillSwitch::Replies);
+        assert_eq!(None, ack(false, true, true, true));
+    }
+
+    #[test]
+    fn test_output_mention_handling() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("@ferris likes crabs. @ferris likes crabs!");
+        let mut borg = Borg::new(dictionary, MainBehavior::default());
+
+        let response = borg.respond_to("crabs", &None).unwrap();
+        assert!(response.starts_with("@ferris likes crabs"), "{}", response);
+
+        borg.behavior.output_mention_handling = MentionHandling::Strip;
+        let response = borg.respond_to("crabs", &None).unwrap();
+        assert!(response.starts_with("likes crabs"), "{}", response);
+
+        borg.behavior.output_mention_handling = MentionHandling::Defang;
+        let response = borg.respond_to("crabs", &None).unwrap();
+        assert!(response.starts_with("@\u{200b}ferris"), "{}", response);
+    }
+
+    #[test]
+    fn test_url_handling() {
+        let input = "look at https://example.com/crabs?id=1 now.\nwww.example.com has crabs";
+        let borg_learning = |url_handling: UrlHandling| {
+            let mut borg = borg_with(MainBehavior {
+                url_handling,
+                ..MainBehavior::default()
+            });
+            borg.learn(input, &None);
+            borg
+        };
+
+        let borg = borg_learning(UrlHandling::Keep);
+        assert!(borg
+            .dictionary
+            .knows_sentence("look at https://example.com/crabs?id=1 now."));
+        assert!(borg.dictionary.knows_sentence("www.example.com has crabs"));
+
+        let borg = borg_learning(UrlHandling::Strip);
+        assert!(borg.dictionary.knows_sentence("look at now."));
+        assert!(borg.dictionary.knows_sentence("has crabs"));
+        assert_eq!(2, borg.stats().sentence_count);
+
+        let borg = borg_learning(UrlHandling::Replace("<link>".to_owned()));
+        assert!(borg.dictionary.knows_sentence("look at <link> now."));
+        assert!(borg.dictionary.knows_sentence("<link> has crabs"));
+        assert_eq!(2, borg.stats().sentence_count);
+    }
+
+    #[test]
+    fn test_language_filter() {
+        let english = "The crabs are walking along the beach while the sun goes down.";
+        let spanish = "Los cangrejos caminan por la playa mientras se pone el sol.";
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn(english);
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_rate: 1.0,
+                language_filter: Some(LanguageFilter {
+                    language: "eng".to_owned(),
+                    min_confidence: 0.5,
+                    uncertain: UncertainLanguage::Allow,
+                }),
+                ..MainBehavior::default()
+            },
+        );
+
+        assert!(borg.should_learn("1", "1", english, &None));
+        assert!(!borg.should_learn("1", "1", spanish, &None));
+        assert!(borg
+            .reply_reason("1", "1", ChatKind::Group, english, false, &None)
+            .is_some());
+        assert!(borg
+            .reply_reason("1", "1", ChatKind::Group, spanish, false, &None)
+            .is_none());
+        assert_eq!(
+            Some(Decision::WrongLanguage),
+            borg.decisions().last().map(|d| d.decision)
+        );
+
+        // Nothing can be detected from a lone emoji.
+        assert!(borg.should_learn("1", "1", "🦀", &None));
+        if let Some(ref mut filter) = borg.behavior.language_filter {
+            filter.uncertain = UncertainLanguage::Skip;
+        }
+        assert!(!borg.should_learn("1", "1", "🦀", &None));
+        assert!(borg.should_learn("1", "1", english, &None));
+    }
+
+    #[test]
+    fn test_reply_only_when_addressed() {
+        let mut borg = borg_with(MainBehavior {
+            reply_rate: 1.0,
+            reply_magic: 1.0,
+            reply_nick: 1.0,
+            nick_patterns: vec![Pattern::substring("borg")],
+            magic_patterns: vec![Pattern::substring("crab")],
+            reply_only_when_addressed: true,
+            ..MainBehavior::default()
+        });
+        for _ in 0..10 {
+            assert!(borg
+                .reply_reason("1", "1", ChatKind::Group, "hello crab", false, &None)
+                .is_none());
+        }
+        assert!(borg
+            .reply_reason("1", "1", ChatKind::Group, "hello borg", false, &None)
+            .is_some());
+
+        borg.behavior.reply_nick = 0.0;
+        assert!(borg
+            .reply_reason("1", "1", ChatKind::Group, "hello borg", false, &None)
+            .is_none());
+    }
+
+    #[test]
+    fn test_magic_groups() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                rng_seed: Some(7),
+                reply_magic: 1.0,
+                magic_patterns: vec![Pattern::substring("bot")],
+                magic_groups: vec![
+                    MagicGroup {
+                        name: "thanks".to_owned(),
+                        patterns: vec![Pattern::substring("good bot")],
+                        reply_chance: 0.25,
+                        response: Some("thanks!".to_owned()),
+                    },
+                    MagicGroup {
+                        name: "greeting".to_owned(),
+                        patterns: vec![Pattern::substring("hello")],
+                        reply_chance: 1.0,
+                        response: Some("🦀 {response}".to_owned()),
+                    },
+                ],
+                ..MainBehavior::default()
+            },
+        );
+
+        let mut replies = 0;
+        for _ in 0..1000 {
+            match borg.reply_reason("1", "1", ChatKind::Group, "good bot", false, &None) {
+                Some(ReplyReason::MagicGroup(0)) => {
+                    replies += 1;
+                    assert_eq!(
+                        Some("thanks!".to_owned()),
+                        borg.response_for(
+                            ReplyReason::MagicGroup(0),
+                            "1",
+                            "good bot",
+                            "good bot",
+                            &None
+                        )
+                    );
+                }
+                // The plain magic patterns still apply when the group does
+                // not reply.
+                reason => assert_eq!(Some(ReplyReason::Magic), reason),
+            }
+        }
+        assert!(replies > 200 && replies < 300, "{}", replies);
+
+        assert_eq!(
+            Some(ReplyReason::MagicGroup(1)),
+            borg.reply_reason("1", "1", ChatKind::Group, "hello", false, &None)
+        );
+        let response = borg
+            .response_for(ReplyReason::MagicGroup(1), "1", "hello", "hello", &None)
+            .unwrap();
+        assert!(response.starts_with("🦀 hello "), "{}", response);
+    }
+
+    #[test]
+    fn test_greeting() {
+        let mut borg = borg_with(MainBehavior::default());
+        assert_eq!(None, borg.greeting(None, &None));
+        assert_eq!(
+            Some("hello!".to_owned()),
+            borg.greeting(Some("hello!"), &None)
+        );
+
+        borg.learn(
+            "hello crabs. hello lobsters. crabs say hi. lobsters say hi.",
+            &None,
+        );
+        // The greeting is made up from the learned sentences.
+        let words = ["hello", "crabs", "lobsters", "say", "hi"];
+        for _ in 0..20 {
+            let greeting = borg.greeting(None, &None).unwrap();
+            assert!(
+                greeting.split(' ').all(|word| words.contains(&word)),
+                "{:?}",
+                greeting
+            );
+        }
+    }
+
+    #[test]
+    fn test_sample_is_bounded() {
+        let mut dictionary = Dictionary::new_empty();
+        for i in 0..MAX_SAMPLE_SIZE * 2 {
+            dictionary.learn(&format!("crab number {}", i));
+        }
+        let borg = Borg::new(dictionary, MainBehavior::default());
+        assert_eq!(5, borg.sample(5).len());
+        assert_eq!(MAX_SAMPLE_SIZE, borg.sample(MAX_SAMPLE_SIZE * 2).len());
+    }
+
+    #[test]
+    fn test_saved_seed() {
+        let path = std::env::temp_dir().join(format!("borg_test_seed_{}.json", std::process::id()));
+        let seed = saved_seed(&path);
+        assert_eq!(seed, saved_seed(&path));
+
+        let mut seed_path = path.into_os_string();
+        seed_path.push(".seed");
+        assert_eq!(seed.to_string(), fs::read_to_string(&seed_path).unwrap());
+        fs::remove_file(&seed_path).unwrap();
+    }
+
+    #[test]
+    fn test_text_hash_is_stable() {
+        // The hashes are saved, so they must not change.
+        assert_eq!(0xcbf2_9ce4_8422_2325, text_hash(""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, text_hash(" A "));
+    }
+
+    #[test]
+    fn test_chat_state_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("borg_test_chat_state_{}.json", std::process::id()));
+        let behavior = || MainBehavior {
+            echo_window_secs: 3600,
+            skip_repeated_messages: true,
+            recent_responses_size: 5,
+            persist_chat_state: true,
+            reply_cooldown_ms: 60_000,
+            ..MainBehavior::default()
+        };
+        let mut borg = Borg::new(Dictionary::new_empty(), behavior());
+        borg.start_cooldown("1", "hello crabs", &None);
+        borg.remember_sent("1", "hello crabs");
+        borg.remember_recent("1", "hello lobsters");
+        assert!(!borg.is_repeat("1", "2", "spam"));
+        borg.save_dictionary(&path, SaveMode::Full).unwrap();
+
+        let restarted = Borg::new(Dictionary::new_empty(), behavior());
+        restarted.load_chat_state(&path);
+        assert!(restarted.is_echo("1", "hello crabs"));
+        assert!(!restarted.is_echo("2", "hello crabs"));
+        assert!(restarted.is_recent("1", "hello lobsters"));
+        assert!(restarted.is_repeat("1", "2", "spam"));
+        assert!(restarted.cooldown_left("1").is_some());
+        assert_eq!(None, restarted.cooldown_left("2"));
+
+        // A corrupt state file is ignored.
+        fs::write(chat_state_path(&path), "{\"sent_responses\": 4").unwrap();
+        let restarted = Borg::new(Dictionary::new_empty(), behavior());
+        restarted.load_chat_state(&path);
+        assert!(!restarted.is_echo("1", "hello crabs"));
+
+        // Nothing is saved or loaded when persisting is off.
+        fs::remove_file(chat_state_path(&path)).unwrap();
+        borg.behavior.persist_chat_state = false;
+        borg.save_dictionary(&path, SaveMode::Full).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!chat_state_path(&path).exists());
+    }
+
+    #[test]
+    fn test_kill_switch() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_rate: 1.0,
+                ..MainBehavior::default()
+            },
+        );
+        let speaking = BehaviorOverride {
+            speaking: Some(true),
+            reply_rate: Some(1.0),
+            ..BehaviorOverride::default()
+        };
+        let chat_behavior = Some(BehaviorOverrideValueResolver::new(&speaking, None));
+
+        borg.set_kill_switch(KillSwitch::Replies);
+        assert_eq!(None, reply(&borg, "#crabs", "hello", &None));
+        assert_eq!(None, reply(&borg, "#lobsters", "hello", &chat_behavior));
+        assert_eq!(
+            Some(Decision::Silenced),
+            borg.decisions().last().map(|r| r.decision)
+        );
+        assert!(borg.should_learn("1", "#crabs", "hello", &None));
+
+        borg.set_kill_switch(KillSwitch::RepliesAndLearning);
+        assert_eq!(None, reply(&borg, "#crabs", "hello", &None));
+        assert!(!borg.should_learn("1", "#crabs", "hello", &None));
+
+        borg.set_kill_switch(KillSwitch::Off);
+        assert!(reply(&borg, "#crabs", "hello", &None).is_some());
+        assert!(borg.should_learn("1", "#crabs", "hello", &None));
+    }
+
+    #[test]
+    fn test_decision_log() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_nick: 1.0,
+                reply_magic: 1.0,
+                nick_patterns: vec![Pattern::substring("borg")],
+                magic_patterns: vec![Pattern::substring("crab")],
+                ignored_users: vec![Pattern::new("^spammer$").unwrap()],
+                decision_log_size: 4,
+                ..MainBehavior::default()
+            },
+        );
+        borg.reply_reason("1", "a", ChatKind::Group, "hi borg", false, &None);
+        borg.reply_reason("1", "a", ChatKind::Group, "crab", false, &None);
+        borg.reply_reason("spammer", "b", ChatKind::Group, "hi borg", false, &None);
+        borg.reply_reason("1", "a", ChatKind::Group, "hello", false, &None);
+        borg.behavior.reply_rate = 1.0;
+        borg.reply_reason("1", "c", ChatKind::Group, "hello", false, &None);
+        borg.behavior.speaking = false;
+        borg.reply_reason("1", "a", ChatKind::Group, "hi borg", false, &None);
+
+        let decisions = borg.decisions();
+        assert_eq!(
+            vec![
+                Decision::IgnoredUser,
+                Decision::NotChosen,
+                Decision::Reply(ReplyReason::Rate),
+                Decision::NotSpeaking,
+            ],
+            decisions.iter().map(|d| d.decision).collect::<Vec<_>>()
+        );
+        assert_eq!("spammer", decisions[0].user_id);
+        assert_eq!("c", decisions[2].chat_id);
+        assert_eq!("hi borg", decisions[3].input);
+
+        borg.behavior.speaking = true;
+        borg.reply_reason("1", "a", ChatKind::Group, "hi borg", false, &None);
+        borg.reply_reason("1", "a", ChatKind::Group, "crab", false, &None);
+        let decisions = borg.decisions();
+        assert_eq!(Decision::Reply(ReplyReason::Nick), decisions[2].decision);
+        assert_eq!(Decision::Reply(ReplyReason::Magic), decisions[3].decision);
+
+        borg.behavior.decision_log_size = 0;
+        borg.decisions.get_mut().unwrap().clear();
+        borg.reply_reason("1", "a", ChatKind::Group, "hi borg", false, &None);
+        assert!(borg.decisions().is_empty());
+    }
+
+    #[test]
+    fn test_fallback_responses() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_nick: 1.0,
+                reply_magic: 1.0,
+                nick_patterns: vec![Pattern::substring("borg")],
+                magic_patterns: vec![Pattern::substring("squid")],
+                fallback_responses: vec!["what?".to_owned()],
+                ..MainBehavior::default()
+            },
+        );
+
+        assert_eq!(
+            Some(ReplyReason::Nick),
+            borg.reply_reason("1", "1", ChatKind::Group, "borg", false, &None)
+        );
+        assert_eq!(Some("what?".to_owned()), reply(&borg, "1", "borg", &None));
+        // Known words make up a response instead.
+        assert_ne!(
+            Some("what?".to_owned()),
+            reply(&borg, "1", "borg hello", &None)
+        );
+        // Other triggers stay silent.
+        assert_eq!(
+            Some(ReplyReason::Magic),
+            borg.reply_reason("1", "1", ChatKind::Group, "squid", false, &None)
+        );
+        assert_eq!(None, reply(&borg, "1", "squid", &None));
+    }
+
+    #[test]
+    fn test_sanitize() {
+        let evasion = "b\u{200b}a\u{200d}d\u{202e} \u{feff}word\r\n\tnext\u{7}";
+        assert_eq!(evasion, sanitize(evasion, InputSanitization::Off));
+        assert_eq!(
+            "bad word\n next",
+            sanitize(evasion, InputSanitization::Strip)
+        );
+        // Emoji sequences keep their joiners.
+        let family = "\u{1f468}\u{200d}\u{1f469}\u{200d}\u{1f467}";
+        assert_eq!(family, sanitize(family, InputSanitization::Strip));
+        assert_eq!(
+            "bad ok",
+            sanitize("\u{ff42}\u{ff41}\u{ff44} ok", InputSanitization::Normalize)
+        );
+        assert_eq!(
+            "\u{ff42}ad",
+            sanitize("\u{ff42}a\u{200b}d", InputSanitization::Strip)
+        );
+    }
+
+    #[test]
+    fn test_canned_responses() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let canned = |pattern: &str, response: &str, chance: f32| CannedResponse {
+            patterns: vec![Pattern::substring(pattern)],
+            response: response.to_owned(),
+            chance,
+        };
+        let borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_rate: 1.0,
+                canned_responses: vec![
+                    canned("never", "unreachable", 0.0),
+                    canned("what are your rules", "https://example.com/rules", 1.0),
+                    canned(r"where is (\w+)", "$1 is in the sea, worth $$5", 1.0),
+                ],
+                ..MainBehavior::default()
+            },
+        );
+
+        assert_eq!(
+            Some(ReplyReason::Canned(1)),
+            borg.reply_reason(
+                "1",
+                "1",
+                ChatKind::Group,
+                "so what are your rules?",
+                false,
+                &None
+            )
+        );
+        assert_eq!(
+            Some("https://example.com/rules".to_owned()),
+            borg.response_for(
+                ReplyReason::Canned(1),
+                "1",
+                "what are your rules",
+                "what are your rules",
+                &None
+            )
+        );
+        assert_eq!(
+            Some(ReplyReason::Canned(2)),
+            borg.reply_reason("1", "1", ChatKind::Group, "where is crab", false, &None)
+        );
+        assert_eq!(
+            Some("crab is in the sea, worth $5".to_owned()),
+            borg.response_for(
+                ReplyReason::Canned(2),
+                "1",
+                "where is crab?",
+                "where is crab?",
+                &None
+            )
+        );
+        // A pattern that does not win its chance falls through to the other
+        // chances.
+        assert_eq!(
+            Some(ReplyReason::Rate),
+            borg.reply_reason("1", "1", ChatKind::Group, "never hello", false, &None)
+        );
+
+        // The replied-to message in the pivot input is not used to fill in
+        // canned responses, even when another pattern matches it.
+        let borg = Borg::new(
+            Dictionary::new_empty(),
+            MainBehavior {
+                canned_responses: vec![CannedResponse {
+                    patterns: vec![
+                        Pattern::substring(r"who is (\w+)"),
+                        Pattern::substring(r"where is (\w+)"),
+                    ],
+                    response: "$1!".to_owned(),
+                    chance: 1.0,
+                }],
+                ..MainBehavior::default()
+            },
+        );
+        let input = "where is crab";
+        let reason = borg.reply_reason("1", "1", ChatKind::Group, input, false, &None);
+        assert_eq!(Some(ReplyReason::Canned(0)), reason);
+        assert_eq!(
+            Some(ReplyAction::Text("crab!".to_owned())),
+            borg.reply_action_for(
+                reason.unwrap(),
+                "1",
+                input,
+                "where is crab\nwho is ferris",
+                &None,
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn test_reply_to_name() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                bot_name: Some("Borg".to_owned()),
+                reply_only_when_addressed: true,
+                fallback_responses: vec!["yes?".to_owned()],
+                ..MainBehavior::default()
+            },
+        );
+
+        for input in &["Borg?", "borg", "@BORG!!", "  borg...  "] {
+            for _ in 0..20 {
+                assert_eq!(
+                    Some(ReplyReason::Name),
+                    borg.reply_reason("1", "1", ChatKind::Group, input, false, &None)
+                );
+            }
+        }
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "1", ChatKind::Group, "hi borg", false, &None)
+        );
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "1", ChatKind::Group, "borgs", false, &None)
+        );
+        assert_eq!(
+            Some("yes?".to_owned()),
+            borg.response_for(ReplyReason::Name, "1", "borg?", "borg?", &None)
+        );
+
+        let quiet = BehaviorOverride {
+            reply_name: Some(0.0),
+            ..BehaviorOverride::default()
+        };
+        let behavior = Some(BehaviorOverrideValueResolver::new(&quiet, None));
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "1", ChatKind::Group, "borg?", false, &behavior)
+        );
+
+        borg.behavior.bot_name = None;
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "1", ChatKind::Group, "borg?", false, &None)
+        );
+    }
+
+    #[test]
+    fn test_quiet_chat_boost() {
+        let mut borg = borg_with(MainBehavior {
+            reply_rate: 0.0,
+            quiet_chat_secs: 600,
+            quiet_chat_boost: 1.0,
+            ..MainBehavior::default()
+        });
+        borg.learn("hello crabs. hello lobsters.", &None);
+
+        // The first message ever seen in a chat is not boosted.
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "quiet", ChatKind::Group, "hello", false, &None)
+        );
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "busy", ChatKind::Group, "hello", false, &None)
+        );
+
+        let long_ago = unix_time() - 3600;
+        borg.activity().insert("quiet".to_owned(), long_ago);
+        assert_eq!(
+            Some(ReplyReason::Rate),
+            borg.reply_reason("1", "quiet", ChatKind::Group, "hello", false, &None)
+        );
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "busy", ChatKind::Group, "hello", false, &None)
+        );
+        // Once the chat has woken up, the boost is gone.
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "quiet", ChatKind::Group, "hello", false, &None)
+        );
+    }
+
+    #[test]
+    fn test_reply_scorer() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs.");
+        let scorer = |scoring| ReplyScorer::new(scoring, &dictionary);
+        let scoring = ReplyScoring {
+            per_word: 0.1,
+            per_known_word: 0.0,
+            question: 0.5,
+            max_rate: 1.0,
+        };
+
+        let rate = |input| scorer(scoring).rate(input, 0.05, 1.0);
+        assert!((rate("hi") - 0.15).abs() < 1e-6);
+        assert!((rate("hi?") - 0.65).abs() < 1e-6);
+        assert!((rate("so how are you all doing") - 0.65).abs() < 1e-6);
+        assert_eq!(1.0, rate("so how are you all doing today?"));
+        // The weights are scaled like the reply rate.
+        assert!((scorer(scoring).rate("hi?", 0.05, 0.5) - 0.35).abs() < 1e-6);
+
+        let known = ReplyScoring {
+            per_word: 0.0,
+            per_known_word: 0.25,
+            question: 0.0,
+            max_rate: 0.4,
+        };
+        assert_eq!(0.0, scorer(known).rate("squids", 0.0, 1.0));
+        assert_eq!(0.25, scorer(known).rate("hello squids", 0.0, 1.0));
+        assert_eq!(0.4, scorer(known).rate("hello crabs", 0.0, 1.0));
+    }
+
+    #[test]
+    fn test_reply_scoring() {
+        let mut borg = borg_with(MainBehavior {
+            reply_rate: 0.0,
+            reply_scoring: Some(ReplyScoring {
+                per_word: 0.0,
+                per_known_word: 0.0,
+                question: 1.0,
+                max_rate: 1.0,
+            }),
+            ..MainBehavior::default()
+        });
+        borg.learn("hello crabs. hello lobsters.", &None);
+        for _ in 0..20 {
+            assert_eq!(
+                None,
+                borg.reply_reason("1", "1", ChatKind::Group, "hello", false, &None)
+            );
+            assert_eq!(
+                Some(ReplyReason::Rate),
+                borg.reply_reason("1", "1", ChatKind::Group, "hello?", false, &None)
+            );
+        }
+
+        // Without scoring, the flat rate applies to questions too.
+        borg.behavior.reply_scoring = None;
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "1", ChatKind::Group, "hello?", false, &None)
+        );
+    }
+
+    #[test]
+    fn test_boosted_reply_rate() {
+        assert_eq!(0.1, boosted_reply_rate(0.1, None, 600, 0.5));
+        assert_eq!(0.1, boosted_reply_rate(0.1, Some(10), 600, 0.5));
+        assert_eq!(0.6, boosted_reply_rate(0.1, Some(600), 600, 0.5));
+        assert_eq!(1.0, boosted_reply_rate(0.8, Some(3600), 600, 0.5));
+        assert_eq!(0.1, boosted_reply_rate(0.1, Some(3600), 600, 0.0));
+    }
+
+    #[test]
+    fn test_is_only_name() {
+        assert!(is_only_name("Borg?", "borg"));
+        assert!(is_only_name("@borg", "Borg"));
+        assert!(!is_only_name("hi borg", "borg"));
+        assert!(!is_only_name("?", "?"));
+    }
+
+    #[test]
+    fn test_should_react() {
+        assert!(should_react(ReplyReason::Nick, true, true));
+        assert!(!should_react(ReplyReason::Nick, false, true));
+        assert!(!should_react(ReplyReason::Nick, true, false));
+        assert!(!should_react(ReplyReason::Magic, true, true));
+        assert!(!should_react(ReplyReason::Rate, true, true));
+    }
+
+    #[test]
+    fn test_reply_action() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_nick: 1.0,
+                nick_patterns: vec![Pattern::substring("borg")],
+                reaction_mode: true,
+                reaction_emoji: "🦀".to_owned(),
+                ..MainBehavior::default()
+            },
+        );
+
+        assert_eq!(
+            Some(ReplyAction::React("🦀".to_owned())),
+            borg.reply_action_for(
+                ReplyReason::Nick,
+                "1",
+                "hello borg",
+                "hello borg",
+                &None,
+                true
+            )
+        );
+        match borg.reply_action_for(
+            ReplyReason::Nick,
+            "1",
+            "hello borg",
+            "hello borg",
+            &None,
+            false,
+        ) {
+            Some(ReplyAction::Text(_)) => {}
+            action => panic!("expected a text reply, got {:?}", action),
+        }
+
+        borg.behavior.reaction_mode = false;
+        match borg.reply_action_for(
+            ReplyReason::Nick,
+            "1",
+            "hello borg",
+            "hello borg",
+            &None,
+            true,
+        ) {
+            Some(ReplyAction::Text(_)) => {}
+            action => panic!("expected a text reply, got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_addressed_counts_as_nick() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_nick: 1.0,
+                reply_only_when_addressed: true,
+                ..MainBehavior::default()
+            },
+        );
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "1", ChatKind::Group, "hi", false, &None)
+        );
+        assert_eq!(
+            Some(ReplyReason::Nick),
+            borg.reply_reason("1", "1", ChatKind::Group, "hi", true, &None)
+        );
+        // The response is made from the pivot input, not the trigger.
+        match borg.reply_action_for(ReplyReason::Nick, "1", "hi hello", "hi hello", &None, false) {
+            Some(ReplyAction::Text(response)) => assert!(response.contains("hello")),
+            action => panic!("expected a text reply, got {:?}", action),
+        }
+
+        borg.behavior.reply_nick = 0.0;
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "1", ChatKind::Group, "hi", true, &None)
+        );
+    }
+
+    #[test]
+    fn test_with_follow_up() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. crabs are red. red lobsters say hello.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                rng_seed: Some(3),
+                ..MainBehavior::default()
+            },
+        );
+        for _ in 0..20 {
+            assert_eq!(
+                vec!["hello crabs"],
+                borg.with_follow_up("hello crabs".to_owned(), &None)
+            );
+        }
+
+        borg.behavior.burst_chance = 1.0;
+        let mut followed_up = 0;
+        for _ in 0..20 {
+            let messages = borg.with_follow_up("hello crabs".to_owned(), &None);
+            assert!(messages.len() <= 2);
+            assert_eq!("hello crabs", messages[0]);
+            if let Some(follow_up) = messages.get(1) {
+                assert_ne!("hello crabs", follow_up);
+                followed_up += 1;
+            }
+        }
+        assert!(followed_up > 0);
+
+        // Nothing can be made up from a response without known words.
+        assert_eq!(vec!["🦀"], borg.with_follow_up("🦀".to_owned(), &None));
+    }
+
+    #[test]
+    fn test_reply_template() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello crabs!");
+        let mut borg = Borg::new(dictionary, MainBehavior::default());
+        borg.behavior.capitalize_responses = true;
+        borg.behavior.reply_template = Some("🦀 _{response}_".to_owned());
+
+        let response = borg.respond_to("crabs", &None).unwrap();
+        assert!(response.starts_with("🦀 _Hello crabs"), "{}", response);
+        assert!(response.ends_with('_'), "{}", response);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_replies() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Arc::new(RwLock::new(Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_rate: 1.0,
+                ..MainBehavior::default()
+            },
+        )));
+
+        let reply = |borg: Arc<RwLock<Borg>>| async move {
+            let borg = borg.read().await;
+            assert!(borg
+                .reply_reason("1", "1", ChatKind::Group, "hello", false, &None)
+                .is_some());
+            // Hold on to the lock while the other reply is generated.
+            delay_for(Duration::from_millis(10)).await;
+            borg.respond_to("hello", &None)
+        };
+        let replies = timeout(
+            Duration::from_secs(5),
+            join(reply(borg.clone()), reply(borg.clone())),
+        )
+        .await
+        .expect("concurrent replies deadlocked");
+        assert!(replies.0.is_some());
+        assert!(replies.1.is_some());
+    }
+
+    #[test]
+    fn test_replies_from_threads() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters. hello shrimps.");
+        let borg = Arc::new(Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_rate: 1.0,
+                ..MainBehavior::default()
+            },
+        ));
+
+        // Replying only needs a shared reference, so no lock is needed at all.
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let borg = Arc::clone(&borg);
+                std::thread::spawn(move || {
+                    let chat_id = i.to_string();
+                    (0..50)
+                        .map(|_| {
+                            let reason = borg.reply_reason(
+                                "1",
+                                &chat_id,
+                                ChatKind::Group,
+                                "hello",
+                                false,
+                                &None,
+                            );
+                            assert_eq!(Some(ReplyReason::Rate), reason);
+                            borg.respond_to_in_chat("hello", &chat_id, &None)
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for thread in threads {
+            for response in thread.join().unwrap() {
+                assert!(response.unwrap().starts_with("hello"));
+            }
+        }
+    }
+
+    #[test]
+    fn test_blacklisted_response() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("evil crabs are here. evil crabs are there.");
+        let input = "evil crabs";
+
+        let mut borg = Borg::new(dictionary, MainBehavior::default());
+        assert!(borg.respond_to(input, &None).is_some());
+
+        borg.behavior.blacklisted_patterns = vec![Pattern::substring("evil")];
+        for _ in 0..10 {
+            assert_eq!(None, borg.respond_to(input, &None));
+        }
+    }
+
+    #[test]
+    fn test_output_blacklist() {
+        let mut borg = borg_with(MainBehavior {
+            output_blacklist: vec![Pattern::substring("bob")],
+            ..MainBehavior::default()
+        });
+        let input = "bob likes crabs";
+
+        // Output-blacklisted words are still learned, but never said.
+        assert!(borg.should_learn("1", "1", input, &None));
+        borg.learn("bob likes crabs. bob likes lobsters.", &None);
+        assert!(borg.dictionary.knows_sentence("bob likes crabs."));
+        for _ in 0..10 {
+            assert_eq!(None, borg.respond_to("crabs", &None));
+        }
+
+        borg.learn("everyone likes crabs.", &None);
+        for _ in 0..10 {
+            if let Some(response) = borg.respond_to("crabs", &None) {
+                assert!(!response.contains("bob"), "{}", response);
+            }
+        }
+    }
+
+    #[test]
+    fn test_min_confidence() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let mut borg = Borg::new(dictionary, MainBehavior::default());
+        assert!(borg
+            .respond_to("hello squids and octopuses", &None)
+            .is_some());
+
+        borg.behavior.min_confidence = 0.5;
+        assert!(borg.respond_to("hello squids", &None).is_some());
+        assert_eq!(None, borg.respond_to("hello squids and octopuses", &None));
+    }
+
+    #[test]
+    fn test_max_response_words() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("crabs are red and lobsters are red too. crabs are.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                max_response_words: 3,
+                ..MainBehavior::default()
+            },
+        );
+        for _ in 0..20 {
+            let response = borg.respond_to("crabs", &None).unwrap();
+            assert!(
+                response == "crabs are" || response == "crabs are red…",
+                "{}",
+                response
+            );
+        }
+
+        borg.behavior.long_responses = LongResponseMode::Reject;
+        for _ in 0..20 {
+            if let Some(response) = borg.respond_to("crabs", &None) {
+                assert_eq!("crabs are", response);
+            }
+        }
+        borg.behavior.max_response_words = 1;
+        assert_eq!(None, borg.respond_to("crabs", &None));
+    }
+
+    #[test]
+    fn test_min_response_words() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("ok. ok then. crabs. crabs are red.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                min_response_words: 2,
+                max_generation_attempts: 50,
+                ..MainBehavior::default()
+            },
+        );
+
+        // Short candidates are retried until a long enough one comes up.
+        for _ in 0..20 {
+            let response = borg.respond_to("crabs", &None).unwrap();
+            assert!(response.starts_with("crabs are red"), "{}", response);
+        }
+
+        // Every candidate is too short.
+        borg.behavior.min_response_words = 3;
+        assert_eq!(None, borg.respond_to("ok", &None));
+        borg.behavior.min_response_words = 2;
+
+        // Giving up stops at the first short candidate.
+        borg.behavior.short_responses = ShortResponseMode::GiveUp;
+        let responses: Vec<_> = (0..50).map(|_| borg.respond_to("crabs", &None)).collect();
+        assert!(responses.contains(&None));
+        for response in responses.into_iter().flatten() {
+            assert!(response.starts_with("crabs are red"), "{}", response);
+        }
+    }
+
+    #[test]
+    fn test_recent_responses_are_avoided() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let mut borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                rng_seed: Some(5),
+                recent_responses_size: 10,
+                ..MainBehavior::default()
+            },
+        );
+
+        let first = borg.respond_to_in_chat("hello", "#crabs", &None).unwrap();
+        let second = borg.respond_to_in_chat("hello", "#crabs", &None).unwrap();
+        assert_ne!(first, second);
+        // Once everything was said recently, the bot repeats itself rather
+        // than say nothing.
+        assert!(borg.respond_to_in_chat("hello", "#crabs", &None).is_some());
+        // Other chats are not affected.
+        assert!(!borg.is_recent("#lobsters", &first));
+
+        borg.behavior.recent_responses_size = 0;
+        assert!(!borg.is_recent("#crabs", &first));
+    }
+
+    #[test]
+    fn test_respond_to_many() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("red crabs are here. blue crabs are there. green crabs are everywhere.");
+        let borg = Borg::new(dictionary, MainBehavior::default());
+
+        let responses = borg.respond_to_many("crabs", 4, &None);
+        assert!(!responses.is_empty() && responses.len() <= 4);
+        for (i, response) in responses.iter().enumerate() {
+            assert!(response.contains("crabs are"), "{}", response);
+            assert!(!responses[i + 1..].contains(response));
+        }
+
+        // Only nine responses can be made from three sentences.
+        assert!(borg.respond_to_many("crabs", 20, &None).len() <= 9);
+        assert_eq!(1, borg.respond_to_many("crabs", 1, &None).len());
+        assert!(borg.respond_to_many("crabs", 0, &None).is_empty());
+        assert!(borg.respond_to_many("lobsters", 4, &None).is_empty());
+    }
+
+    #[test]
+    fn test_max_generation_attempts() {
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("evil crabs are here. evil crabs are there.");
+        let borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                blacklisted_patterns: vec![Pattern::substring("evil")],
+                max_generation_attempts: 1000,
+                ..MainBehavior::default()
+            },
+        );
+        assert_eq!(None, borg.respond_to("crabs", &None));
+    }
+
+    #[test]
+    fn test_blacklist_reject() {
+        let borg = borg_with(MainBehavior {
+            blacklisted_patterns: vec![Pattern::substring("darn")],
+            blacklist_mode: BlacklistMode::Reject,
+            ..MainBehavior::default()
+        });
+        let input = "that darn crab stole my lunch";
+
+        assert!(!borg.should_learn("1", "1", input, &None));
+        assert!(borg.should_learn("1", "1", "that crab stole my lunch", &None));
+    }
+
+    #[test]
+    fn test_blacklist_scrub() {
+        let mut borg = borg_with(MainBehavior {
+            blacklisted_patterns: vec![Pattern::new("darn").unwrap()],
+            blacklist_mode: BlacklistMode::Scrub,
+            ..MainBehavior::default()
+        });
+        let input = "that darn crab stole my lunch";
+
+        assert!(borg.should_learn("1", "1", input, &None));
+        borg.learn(input, &None);
+        assert!(borg.dictionary.knows_sentence("that crab stole my lunch"));
+        assert!(!borg.dictionary.knows_sentence(input));
+    }
 }
@@ -0,0 +1,349 @@
+use std::{
+    fs,
+    io::{self, BufRead},
+};
+
+use crate::{borg::Borg, config::Config, dictionary::Source};
+
+/// How many lines are read before they are learned, when importing.
+const IMPORT_BATCH_LINES: usize = 10_000;
+
+/////////////////////////////////////////////////////////////////////////////
+// Mode Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// Mode is what the program was asked to do on the command line.
+#[derive(Debug, PartialEq)]
+pub enum Mode {
+    /// Connect to the configured platforms and chat.
+    Run,
+    /// Print which configured patterns match the input, then exit.
+    TestPattern(String),
+    /// Prune and compact the dictionary, then exit.
+    Compact,
+    /// Merge the dictionary at the given path into the configured one, then
+    /// exit.
+    Merge(String),
+    /// Learn every line of the file at the given path, or of the standard
+    /// input when the path is "-", then save and exit.
+    Import(String),
+    /// Like Import, but every line starts with the platform, chat ID and user
+    /// ID it came from, separated by tabs, which are recorded as the source
+    /// of its sentences.
+    ImportAttributed(String),
+    /// Write every sentence of the dictionary to the file at the given path,
+    /// one per line, then exit.
+    Export(String),
+}
+
+/// Parses the command line arguments, not including the program name.
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Mode, String> {
+    let mut args = args.into_iter();
+    match args.next().as_deref() {
+        None => Ok(Mode::Run),
+        Some("--compact") => Ok(Mode::Compact),
+        Some("--merge") => args
+            .next()
+            .map(Mode::Merge)
+            .ok_or_else(|| "--merge needs the path of a dictionary to merge".to_owned()),
+        Some("--import") => args
+            .next()
+            .map(Mode::Import)
+            .ok_or_else(|| "--import needs the path of a file to import, or -".to_owned()),
+        Some("--import-attributed") => args.next().map(Mode::ImportAttributed).ok_or_else(|| {
+            "--import-attributed needs the path of a file to import, or -".to_owned()
+        }),
+        Some("--export") => args
+            .next()
+            .map(Mode::Export)
+            .ok_or_else(|| "--export needs the path of a file to export to".to_owned()),
+        Some("--test-pattern") => args
+            .next()
+            .map(Mode::TestPattern)
+            .ok_or_else(|| "--test-pattern needs an input to test".to_owned()),
+        Some(arg) => Err(format!("Unknown argument {:?}", arg)),
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Pattern Testing
+/////////////////////////////////////////////////////////////////////////////
+
+/// PatternResult tells whether one configured pattern matches the input.
+#[derive(Debug, PartialEq)]
+pub struct PatternResult<'a> {
+    /// Where the pattern was configured, e.g. "telegram chat -100".
+    pub scope: String,
+    pub field: &'static str,
+    pub pattern: &'a str,
+    pub matched: bool,
+}
+
+/// Tests the input against every pattern in the configuration. The patterns
+/// must have been compiled, which Config::load does.
+pub fn test_patterns<'a>(config: &'a Config, input: &str) -> Vec<PatternResult<'a>> {
+    let mut behaviors = vec![("behavior".to_owned(), config.behavior.patterns())];
+    for (scope, b) in config.behavior_overrides() {
+        behaviors.push((scope, b.patterns()));
+    }
+
+    let mut results = vec![];
+    for (scope, patterns) in behaviors {
+        for (field, ps) in patterns {
+            for p in ps {
+                results.push(PatternResult {
+                    scope: scope.clone(),
+                    field,
+                    pattern: &p.original,
+                    matched: p.matches(input),
+                });
+            }
+        }
+    }
+    results
+}
+
+pub fn print_pattern_results(input: &str, results: &[PatternResult]) {
+    if results.is_empty() {
+        println!("There are no patterns in the configuration.");
+        return;
+    }
+    println!("Testing {:?} against the configured patterns:", input);
+    for r in results {
+        println!(
+            "  {} {} {:?}: {}",
+            r.scope,
+            r.field,
+            r.pattern,
+            if r.matched {
+                "matches"
+            } else {
+                "does not match"
+            }
+        );
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Importing
+/////////////////////////////////////////////////////////////////////////////
+
+/// Learns every line of the file at the given path, or of the standard input
+/// when the path is "-", and returns how many new sentences were learned.
+/// When attributed, every line starts with the source of its sentences.
+pub fn import_from(borg: &mut Borg, path: &str, attributed: bool) -> io::Result<usize> {
+    if path == "-" {
+        let stdin = io::stdin();
+        let lock = stdin.lock();
+        import(borg, lock, attributed)
+    } else {
+        import(borg, io::BufReader::new(fs::File::open(path)?), attributed)
+    }
+}
+
+/// Learns every line of the reader and returns how many new sentences were
+/// learned. The lines are learned a batch at a time, so that streams of any
+/// size can be imported without reading them whole.
+pub fn import(borg: &mut Borg, reader: impl BufRead, attributed: bool) -> io::Result<usize> {
+    let mut added = 0;
+    let mut batch = Vec::with_capacity(IMPORT_BATCH_LINES);
+    for line in reader.lines() {
+        batch.push(line?);
+        if batch.len() == IMPORT_BATCH_LINES {
+            added += learn_batch(borg, &batch, attributed);
+            batch.clear();
+        }
+    }
+    added += learn_batch(borg, &batch, attributed);
+    debug!("[import] Learned {} new sentences", added);
+    Ok(added)
+}
+
+/// Learns the batch, a run of lines with the same source at a time when it is
+/// attributed.
+fn learn_batch(borg: &mut Borg, batch: &[String], attributed: bool) -> usize {
+    if !attributed {
+        return borg.import(batch.iter().map(String::as_str));
+    }
+    let lines: Vec<_> = batch
+        .iter()
+        .map(|line| parse_attributed_line(line))
+        .collect();
+    let mut added = 0;
+    let mut start = 0;
+    while start < lines.len() {
+        let source = &lines[start].0;
+        let end = lines[start..]
+            .iter()
+            .position(|(s, _)| s != source)
+            .map_or(lines.len(), |n| start + n);
+        let texts = lines[start..end].iter().map(|(_, text)| *text);
+        added += match source {
+            Some(s) => borg.import_with_source(texts, &s.platform, &s.chat_id, &s.user_id),
+            None => borg.import(texts),
+        };
+        start = end;
+    }
+    added
+}
+
+/// Splits a line of an attributed import into its source and its text. The
+/// source is the first three tab-separated columns: the platform, the chat ID
+/// and the user ID. A line without them is learned without a source.
+fn parse_attributed_line(line: &str) -> (Option<Source>, &str) {
+    let mut columns = line.splitn(4, '\t');
+    match (
+        columns.next(),
+        columns.next(),
+        columns.next(),
+        columns.next(),
+    ) {
+        (Some(platform), Some(chat_id), Some(user_id), Some(text)) => {
+            (Some(Source::new(platform, chat_id, user_id)), text)
+        }
+        _ => (None, line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::MainBehavior, dictionary::Dictionary};
+    use std::{fs, path::Path};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| (*a).to_owned()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(Ok(Mode::Run), parse_args(args(&[])));
+        assert_eq!(
+            Ok(Mode::TestPattern("hi borg".to_owned())),
+            parse_args(args(&["--test-pattern", "hi borg"]))
+        );
+        assert!(parse_args(args(&["--test-pattern"])).is_err());
+        assert_eq!(Ok(Mode::Compact), parse_args(args(&["--compact"])));
+        assert_eq!(
+            Ok(Mode::Merge("other.json".to_owned())),
+            parse_args(args(&["--merge", "other.json"]))
+        );
+        assert!(parse_args(args(&["--merge"])).is_err());
+        assert_eq!(
+            Ok(Mode::Import("-".to_owned())),
+            parse_args(args(&["--import", "-"]))
+        );
+        assert!(parse_args(args(&["--import"])).is_err());
+        assert_eq!(
+            Ok(Mode::ImportAttributed("log.tsv".to_owned())),
+            parse_args(args(&["--import-attributed", "log.tsv"]))
+        );
+        assert!(parse_args(args(&["--import-attributed"])).is_err());
+        assert_eq!(
+            Ok(Mode::Export("corpus.txt".to_owned())),
+            parse_args(args(&["--export", "corpus.txt"]))
+        );
+        assert!(parse_args(args(&["--export"])).is_err());
+        assert!(parse_args(args(&["--dance"])).is_err());
+    }
+
+    #[test]
+    fn test_test_patterns() {
+        let path = std::env::temp_dir().join("borg_test_test_patterns.yml");
+        fs::write(
+            &path,
+            r#"
+dictionary_path: dictionary.json
+auto_save_period: 60
+behavior:
+  speaking: true
+  learning: true
+  reply_rate: 0.0
+  reply_nick: 1.0
+  reply_magic: 0.0
+  nick_patterns:
+    - original: borg
+      substring: true
+  magic_patterns:
+    - original: crab
+      whole_word: true
+    - original: lobster
+      substring: true
+    - original: crab
+  blacklisted_patterns: []
+  ignored_users: []
+telegram:
+  token: ""
+  chat_behaviors:
+    - chat_id: "-100"
+      behavior:
+        magic_patterns:
+          - original: ^hi
+            substring: true
+"#,
+        )
+        .unwrap();
+        let config = Config::load(Path::new(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let matched: Vec<_> = test_patterns(&config, "hi borg, crab here")
+            .into_iter()
+            .map(|r| (r.scope, r.field, r.pattern, r.matched))
+            .collect();
+        assert_eq!(
+            vec![
+                ("behavior".to_owned(), "nick_patterns", "borg", true),
+                ("behavior".to_owned(), "magic_patterns", "crab", true),
+                ("behavior".to_owned(), "magic_patterns", "lobster", false),
+                ("behavior".to_owned(), "magic_patterns", "crab", false),
+                (
+                    "telegram chat -100".to_owned(),
+                    "magic_patterns",
+                    "^hi",
+                    true
+                ),
+            ],
+            matched
+        );
+    }
+
+    #[test]
+    fn test_import() {
+        let mut input = String::new();
+        for i in 0..IMPORT_BATCH_LINES + 500 {
+            input.push_str(&format!(
+                "crab number {} says hi. crabs say hi.\n",
+                i % 12000
+            ));
+        }
+        input.push_str("\nthe end");
+
+        let mut borg = Borg::new(Dictionary::new_empty(), MainBehavior::default());
+        let added = import(&mut borg, input.as_bytes(), false).unwrap();
+        assert_eq!(10502, added);
+        assert_eq!(10502, borg.stats().sentence_count);
+
+        // Invalid UTF-8 stops the import with an error.
+        assert!(import(&mut borg, &b"hello\n\xff\n"[..], false).is_err());
+    }
+
+    #[test]
+    fn test_import_attributed() {
+        let input = "irc\t#crabs\tferris\thello crabs. crabs are red.\n\
+                     telegram\t-100\t42\tlobsters are red too.\n\
+                     no source here.\n\
+                     irc\t#crabs\tferris\tbye crabs.\n";
+        let mut borg = Borg::new(Dictionary::new_empty(), MainBehavior::default());
+        assert_eq!(5, import(&mut borg, input.as_bytes(), true).unwrap());
+
+        let ferris = Source::new("irc", "#crabs", "ferris");
+        assert_eq!(Some(&ferris), borg.source_of("hello crabs."));
+        assert_eq!(Some(&ferris), borg.source_of("crabs are red."));
+        assert_eq!(
+            Some(&Source::new("telegram", "-100", "42")),
+            borg.source_of("lobsters are red too.")
+        );
+        assert_eq!(None, borg.source_of("no source here."));
+        assert_eq!(Some(&ferris), borg.source_of("bye crabs."));
+    }
+}
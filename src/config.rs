@@ -1,6 +1,10 @@
-use std::{error, fmt, fs, io, path::Path};
+use std::{collections::HashMap, error, fmt, fs, io, path::Path};
 
-use crate::pattern::{CompilationError, Pattern};
+use crate::{
+    dictionary::{CaseFolding, Elongation, Normalization, SaveMode},
+    pattern::{CompilationError, Pattern},
+    postprocess::RESPONSE_PLACEHOLDER,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -12,6 +16,8 @@ use serde::{Deserialize, Serialize};
 pub enum ConfigError {
     IOError(io::Error),
     YAMLError(serde_yaml::Error),
+    PatternError(CompilationError),
+    ValidationError(String),
 }
 
 impl fmt::Display for ConfigError {
@@ -19,6 +25,8 @@ impl fmt::Display for ConfigError {
         match *self {
             ConfigError::IOError(ref e) => e.fmt(f),
             ConfigError::YAMLError(ref e) => e.fmt(f),
+            ConfigError::PatternError(ref e) => e.fmt(f),
+            ConfigError::ValidationError(ref message) => write!(f, "{}", message),
         }
     }
 }
@@ -28,6 +36,8 @@ impl error::Error for ConfigError {
         match *self {
             ConfigError::IOError(ref e) => Some(e),
             ConfigError::YAMLError(ref e) => Some(e),
+            ConfigError::PatternError(ref e) => Some(e),
+            ConfigError::ValidationError(_) => None,
         }
     }
 }
@@ -44,13 +54,19 @@ impl From<serde_yaml::Error> for ConfigError {
     }
 }
 
+impl From<CompilationError> for ConfigError {
+    fn from(err: CompilationError) -> ConfigError {
+        ConfigError::PatternError(err)
+    }
+}
+
 /////////////////////////////////////////////////////////////////////////////
 // PatternOwner trait
 /////////////////////////////////////////////////////////////////////////////
 
 /// Any struct that has Patterns in it can optionally implement this trait
 /// to allow eager compilation of all patterns
-trait PatternOwner {
+pub(crate) trait PatternOwner {
     /// compile_patterns should compile all Pattern objects in the implementing
     /// struct.
     fn compile_patterns(&mut self) -> Result<(), CompilationError>;
@@ -62,11 +78,76 @@ trait PatternOwner {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Config {
+    /// Where the dictionary is kept. It is compressed with gzip when the path
+    /// ends in ".gz", e.g. "dictionary.json.gz".
     pub dictionary_path: String,
     pub auto_save_period: i64,
+    /// How many lines can wait to be learned before new ones are dropped.
+    #[serde(default = "default_learn_queue_size")]
+    pub learn_queue_size: usize,
+    /// Shortest time, in milliseconds, between two replies of the bot across
+    /// every platform and chat. Replies that come sooner wait their turn. 0
+    /// lets replies go out at once.
+    #[serde(default)]
+    pub reply_interval_ms: u64,
+    /// Longest random time, in milliseconds, added to reply_interval_ms for
+    /// every reply, so that replies do not go out at a steady pace.
+    #[serde(default)]
+    pub reply_interval_jitter_ms: u64,
+    /// How similar sentences have to be to be learned only once.
+    #[serde(default)]
+    pub sentence_normalization: Normalization,
+    /// How the case of words is folded, so that they are found whatever their
+    /// case.
+    #[serde(default)]
+    pub case_folding: CaseFolding,
+    /// How words stretched out by repeating a letter, like "soooo", are
+    /// indexed, so that they can be found by their usual spelling.
+    #[serde(default)]
+    pub elongation: Elongation,
+    /// Words longer than this many characters, like pasted hashes or URLs
+    /// without spaces, are not indexed. There is no limit by default.
+    #[serde(default)]
+    pub max_word_length: Option<usize>,
+    /// Sentences in which at least this share of the words are longer than
+    /// max_word_length are not learned at all. Between 0 and 1.
+    #[serde(default)]
+    pub long_word_share: Option<f32>,
+    /// Whether the sentence that follows each sentence in a message is
+    /// remembered, so that responses can continue the thought.
+    #[serde(default)]
+    pub track_successors: bool,
+    /// Lines that are always known, like catchphrases. They are learned when
+    /// the bot starts if they are not known yet, and are never forgotten or
+    /// pruned.
+    #[serde(default)]
+    pub pinned_sentences: Vec<String>,
+    /// How the dictionary is saved every auto_save_period seconds.
+    #[serde(default)]
+    pub save_mode: SaveMode,
+    /// In journal mode, every this many auto-saves the journal is compacted
+    /// into the dictionary file.
+    #[serde(default = "default_compact_every")]
+    pub compact_every: u32,
+    /// Words used in fewer sentences than this are pruned from the dictionary
+    /// whenever it is compacted, including by --compact.
+    #[serde(default)]
+    pub min_word_count: Option<usize>,
     pub behavior: MainBehavior,
     pub telegram: Option<TelegramPlatform>,
     pub discord: Option<DiscordPlatform>,
+    pub irc: Option<IrcPlatform>,
+    pub admin: Option<AdminInterface>,
+    #[serde(default)]
+    pub backups: Option<Backups>,
+}
+
+fn default_learn_queue_size() -> usize {
+    1000
+}
+
+fn default_compact_every() -> u32 {
+    60
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -81,9 +162,208 @@ pub struct Config {
 impl Config {
     pub fn load(path: &Path) -> Result<Self, ConfigError> {
         let data = fs::read_to_string(&path)?;
-        let config = serde_yaml::from_str(&data)?;
+        let mut config: Config = serde_yaml::from_str(&data)?;
+        config.compile_patterns()?;
+        config.validate()?;
         Ok(config)
     }
+
+    /// Checks the values that deserialization alone cannot check.
+    fn validate(&self) -> Result<(), ConfigError> {
+        self.behavior.validate()?;
+        if let Some(Backups { keep: 0, .. }) = self.backups {
+            return Err(ConfigError::ValidationError(
+                "backups.keep must be at least 1".to_owned(),
+            ));
+        }
+        if let Some(ref telegram) = self.telegram {
+            validate_telegram_chat_ids(telegram)?;
+        }
+        if let Some(ref admin) = self.admin {
+            if admin.token.trim().is_empty() {
+                return Err(ConfigError::ValidationError(
+                    "admin.token must not be empty".to_owned(),
+                ));
+            }
+        }
+        let multipliers = vec![
+            self.telegram
+                .as_ref()
+                .map(|p| ("telegram", p.reply_rate_multiplier)),
+            self.discord
+                .as_ref()
+                .map(|p| ("discord", p.reply_rate_multiplier)),
+            self.irc.as_ref().map(|p| ("irc", p.reply_rate_multiplier)),
+        ];
+        for (name, multiplier) in multipliers.into_iter().flatten() {
+            validate_reply_rate_multiplier(name, multiplier)?;
+        }
+        for (_, b) in self.behavior_overrides() {
+            validate_reply_rates(&[
+                ("reply_rate", b.reply_rate),
+                ("reply_nick", b.reply_nick),
+                ("reply_magic", b.reply_magic),
+                ("dm_reply_rate", b.dm_reply_rate),
+                ("group_reply_rate", b.group_reply_rate),
+            ])?;
+            validate_reply_template(b.reply_template.as_ref())?;
+            validate_language_filter(b.language_filter.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Returns every platform and chat behavior override, along with a name
+    /// describing where it was configured, e.g. "telegram chat -100".
+    pub(crate) fn behavior_overrides(&self) -> Vec<(String, &BehaviorOverride)> {
+        let platforms = vec![
+            self.telegram
+                .as_ref()
+                .map(|p| ("telegram", &p.behavior, &p.chat_behaviors)),
+            self.discord
+                .as_ref()
+                .map(|p| ("discord", &p.behavior, &p.chat_behaviors)),
+            self.irc
+                .as_ref()
+                .map(|p| ("irc", &p.behavior, &p.chat_behaviors)),
+        ];
+        let mut overrides = vec![];
+        for (name, behavior, chat_behaviors) in platforms.into_iter().flatten() {
+            if let Some(b) = behavior {
+                overrides.push((name.to_owned(), b));
+            }
+            for cb in chat_behaviors.iter().flatten() {
+                overrides.push((format!("{} chat {}", name, cb.chat_id), &cb.behavior));
+            }
+        }
+        overrides
+    }
+
+    /// Returns where the settings that only work with reactions are turned on
+    /// for the platform, e.g. ("telegram chat -100", "learn_ack"), so that
+    /// platforms that cannot react can warn about them.
+    pub(crate) fn reaction_settings(&self, platform: &str) -> Vec<(String, &'static str)> {
+        let main = [
+            ("learn_ack", self.behavior.learn_ack),
+            ("react_to_sentiment", self.behavior.react_to_sentiment),
+        ];
+        let mut settings: Vec<_> = main
+            .iter()
+            .filter(|(_, on)| *on)
+            .map(|(field, _)| ("behavior".to_owned(), *field))
+            .collect();
+        for (scope, b) in self.behavior_overrides() {
+            if scope.split(' ').next() != Some(platform) {
+                continue;
+            }
+            let overridden = [
+                ("learn_ack", b.learn_ack),
+                ("react_to_sentiment", b.react_to_sentiment),
+            ];
+            settings.extend(
+                overridden
+                    .iter()
+                    .filter(|(_, on)| *on == Some(true))
+                    .map(|(field, _)| (scope.clone(), *field)),
+            );
+        }
+        settings
+    }
+}
+
+/// Reply rates are chances, so they must be between 0 and 1. Rates that are
+/// not set are left alone.
+fn validate_reply_rates(rates: &[(&str, Option<f32>)]) -> Result<(), ConfigError> {
+    for (name, rate) in rates {
+        match rate {
+            Some(rate) if !(0.0..=1.0).contains(rate) => {
+                return Err(ConfigError::ValidationError(format!(
+                    "{} must be a number from 0 to 1, not {}",
+                    name, rate
+                )))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn validate_reply_template(template: Option<&String>) -> Result<(), ConfigError> {
+    match template {
+        Some(t) if !t.contains(RESPONSE_PLACEHOLDER) => Err(ConfigError::ValidationError(format!(
+            "The reply template {:?} does not contain the {} placeholder",
+            t, RESPONSE_PLACEHOLDER
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Telegram chat IDs are integers, negative for groups and channels. A chat
+/// ID in another form, e.g. a @username, would never match any chat.
+fn validate_telegram_chat_ids(telegram: &TelegramPlatform) -> Result<(), ConfigError> {
+    for cb in telegram.chat_behaviors.iter().flatten() {
+        if cb.chat_id.trim().parse::<i64>().is_err() {
+            return Err(ConfigError::ValidationError(format!(
+                "The telegram chat_id {:?} is not a chat ID. Chat IDs are integers, \
+                negative for groups, e.g. \"-1001234567890\"",
+                cb.chat_id
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn validate_reply_rate_multiplier(platform: &str, multiplier: f32) -> Result<(), ConfigError> {
+    if multiplier.is_finite() && multiplier >= 0.0 {
+        Ok(())
+    } else {
+        Err(ConfigError::ValidationError(format!(
+            "The {} reply_rate_multiplier must be a number of at least 0, not {}",
+            platform, multiplier
+        )))
+    }
+}
+
+fn validate_language_filter(filter: Option<&LanguageFilter>) -> Result<(), ConfigError> {
+    match filter {
+        Some(f) if whatlang::Lang::from_code(f.language.as_str()).is_none() => {
+            Err(ConfigError::ValidationError(format!(
+                "{:?} is not the ISO 639-3 code of a language that can be detected",
+                f.language
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl PatternOwner for Config {
+    fn compile_patterns(&mut self) -> Result<(), CompilationError> {
+        self.behavior.compile_patterns()?;
+        if let Some(ref mut telegram) = self.telegram {
+            compile_platform_patterns(&mut telegram.behavior, &mut telegram.chat_behaviors)?;
+        }
+        if let Some(ref mut discord) = self.discord {
+            compile_platform_patterns(&mut discord.behavior, &mut discord.chat_behaviors)?;
+        }
+        if let Some(ref mut irc) = self.irc {
+            compile_platform_patterns(&mut irc.behavior, &mut irc.chat_behaviors)?;
+        }
+        Ok(())
+    }
+}
+
+fn compile_platform_patterns(
+    behavior: &mut Option<BehaviorOverride>,
+    chat_behaviors: &mut Option<Vec<ChatBehaviorOverrides>>,
+) -> Result<(), CompilationError> {
+    if let Some(ref mut b) = behavior {
+        b.compile_patterns()?;
+    }
+    if let Some(ref mut cbs) = chat_behaviors {
+        for cb in cbs.iter_mut() {
+            cb.behavior.compile_patterns()?;
+        }
+    }
+    Ok(())
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -94,19 +374,373 @@ impl Config {
 pub struct MainBehavior {
     pub speaking: bool,
     pub learning: bool,
+    /// The chance of replying to any message, from 0.0 (never) to 1.0
+    /// (always).
     pub reply_rate: f32,
+    /// The chance of replying to a message that matches a nick pattern.
     pub reply_nick: f32,
+    /// The chance of replying to a message that matches a magic pattern.
     pub reply_magic: f32,
     pub nick_patterns: Vec<Pattern>,
     pub magic_patterns: Vec<Pattern>,
     pub blacklisted_patterns: Vec<Pattern>,
+    #[serde(default)]
+    pub blacklist_mode: BlacklistMode,
     pub ignored_users: Vec<Pattern>,
+    /// Maximum number of messages a single user can send per minute before
+    /// the bot stops learning from and replying to them.
+    #[serde(default)]
+    pub user_messages_per_minute: Option<u32>,
+    /// Maximum number of sentences the dictionary may grow by per minute.
+    /// Learning pauses while a flood of messages, e.g. a raid or copypasta,
+    /// makes it grow faster, and resumes when it calms down.
+    #[serde(default)]
+    pub max_learned_sentences_per_minute: Option<u32>,
+    /// Seeds the random decisions of the bot. A restart with the same seed
+    /// and dictionary makes the same decisions for the same messages. A random
+    /// seed is picked and logged when none is set.
+    #[serde(default)]
+    pub rng_seed: Option<u64>,
+    /// How many candidate responses are made up before giving up on replying,
+    /// e.g. when every candidate is blacklisted.
+    #[serde(default = "default_max_generation_attempts")]
+    pub max_generation_attempts: usize,
+    /// How many recent responses are remembered and reused when the same
+    /// input comes again. 0 turns the cache off.
+    #[serde(default)]
+    pub response_cache_size: usize,
+    /// How long, in seconds, a remembered response is reused before a new one
+    /// is made up.
+    #[serde(default = "default_response_cache_ttl_secs")]
+    pub response_cache_ttl_secs: u64,
+    /// How many of the latest decisions about replying are kept for the
+    /// admin interface. 0 turns the decision log off.
+    #[serde(default = "default_decision_log_size")]
+    pub decision_log_size: usize,
+    /// For how long, in seconds, the responses sent to a chat are remembered,
+    /// so that they are ignored if they are echoed back, e.g. by a bridge.
+    /// 0 turns the echo check off.
+    #[serde(default)]
+    pub echo_window_secs: u64,
+    /// Ignores a message that repeats the previous message of the same user
+    /// in the chat, so that spam is only learned and replied to once.
+    #[serde(default)]
+    pub skip_repeated_messages: bool,
+    /// What is done with invisible and control characters in messages before
+    /// they are matched against the patterns or learned.
+    #[serde(default)]
+    pub input_sanitization: InputSanitization,
+    /// How many of the latest responses in each chat are avoided when making
+    /// up a new one, as long as there is something else to say. 0 turns this
+    /// off.
+    #[serde(default)]
+    pub recent_responses_size: usize,
+    /// For how long, in seconds, a response counts as recent.
+    #[serde(default = "default_recent_responses_ttl_secs")]
+    pub recent_responses_ttl_secs: u64,
+    /// Saves what the bot remembers about every chat, like its recent
+    /// responses, next to the dictionary whenever the dictionary is saved, and
+    /// reads it back on startup, so that a restart does not make the bot
+    /// repeat itself.
+    #[serde(default)]
+    pub persist_chat_state: bool,
+    /// Uppercases the first letter of every response.
+    #[serde(default)]
+    pub capitalize_responses: bool,
+    /// Ends every response with punctuation, adding a period if needed.
+    #[serde(default)]
+    pub punctuate_responses: bool,
+    /// The bot stays quiet until its dictionary holds at least this many
+    /// sentences. Learning is not affected.
+    #[serde(default)]
+    pub min_dictionary_sentences: usize,
+    /// Makes responses favor longer sentences over short ones.
+    #[serde(default)]
+    pub prefer_longer: bool,
+    /// How strongly longer sentences are favored when prefer_longer is on.
+    /// A sentence's chance of being picked grows with its word count raised to
+    /// this power.
+    #[serde(default = "default_prefer_longer_strength")]
+    pub prefer_longer_strength: f32,
+    /// Shortest delay, in milliseconds, before a reply is sent.
+    #[serde(default)]
+    pub reply_delay_min_ms: u64,
+    /// Longest delay, in milliseconds, before a reply is sent. The actual delay
+    /// is picked at random between the minimum and this value.
+    #[serde(default)]
+    pub reply_delay_max_ms: u64,
+    /// Only replies when a nick pattern matches, ignoring reply_rate and
+    /// reply_magic.
+    #[serde(default)]
+    pub reply_only_when_addressed: bool,
+    /// Wraps every response, e.g. "_{response}_". It must contain the
+    /// {response} placeholder.
+    #[serde(default)]
+    pub reply_template: Option<String>,
+    /// Chance of learning a message that is otherwise eligible, from 0.0 to
+    /// 1.0.
+    #[serde(default = "default_learn_rate")]
+    pub learn_rate: f32,
+    /// Responses picked from when the bot is addressed by nick but does not
+    /// know any of the words in the message.
+    #[serde(default)]
+    pub fallback_responses: Vec<String>,
+    /// When set, the bot only learns in chats whose ID matches one of these
+    /// patterns, e.g. "^-100123$". It still replies everywhere. Unlike the
+    /// other behavior values, this list cannot be overridden per platform or
+    /// chat.
+    #[serde(default)]
+    pub learn_chat_whitelist: Option<Vec<Pattern>>,
+    /// Reacts to nick mentions with reaction_emoji instead of replying with
+    /// text, on platforms that support reactions.
+    #[serde(default)]
+    pub reaction_mode: bool,
+    /// The emoji used to react when reaction_mode is on.
+    #[serde(default = "default_reaction_emoji")]
+    pub reaction_emoji: String,
+    /// Only learns questions or only statements, going by how each sentence
+    /// ends.
+    #[serde(default)]
+    pub learn_filter: LearnFilter,
+    /// Responses matching any of these patterns are never sent, even though the
+    /// words in them may still be learned.
+    #[serde(default)]
+    pub output_blacklist: Vec<Pattern>,
+    /// The bot stays quiet when less than this share of the words in a message,
+    /// from 0.0 to 1.0, are known.
+    #[serde(default)]
+    pub min_confidence: f32,
+    /// Longest response, in words, that is sent. 0 means no limit.
+    #[serde(default)]
+    pub max_response_words: usize,
+    /// What happens to responses longer than max_response_words.
+    #[serde(default)]
+    pub long_responses: LongResponseMode,
+    /// Groups of magic patterns, each with its own reply chance and response.
+    /// They are checked before magic_patterns.
+    #[serde(default)]
+    pub magic_groups: Vec<MagicGroup>,
+    /// What is done with the URLs in messages before they are learned.
+    #[serde(default)]
+    pub url_handling: UrlHandling,
+    /// Only learns from and replies to messages in the given language.
+    #[serde(default)]
+    pub language_filter: Option<LanguageFilter>,
+    /// The chance, from 0.0 to 1.0, of sending a follow-up message made up
+    /// from the response right after replying.
+    #[serde(default)]
+    pub burst_chance: f32,
+    /// The name of the bot, e.g. "Borg". A message that is only the name,
+    /// like "borg?", is a summon that is replied to at reply_name, whatever
+    /// the nick patterns are.
+    #[serde(default)]
+    pub bot_name: Option<String>,
+    /// Chance of replying to a message that is only the bot_name, from 0.0 to
+    /// 1.0. Unlike reply_nick, it is not changed by reply_rate_multiplier.
+    #[serde(default = "default_reply_name")]
+    pub reply_name: f32,
+    /// Fixed responses to inputs that match their patterns, checked before the
+    /// nick, magic and reply rate chances.
+    #[serde(default)]
+    pub canned_responses: Vec<CannedResponse>,
+    /// Acknowledges every learned message with reaction_emoji while learning is
+    /// on and speaking is off, so that trainers can tell that the bot is working.
+    /// Platforms that cannot react do not acknowledge anything, and warn about
+    /// it at startup.
+    #[serde(default)]
+    pub learn_ack: bool,
+    /// How many seconds a chat has to be silent for the next message in it to
+    /// get quiet_chat_boost.
+    #[serde(default = "default_quiet_chat_secs")]
+    pub quiet_chat_secs: u64,
+    /// Added to the reply rate, from 0.0 to 1.0, for the first message in a chat
+    /// after it was silent for quiet_chat_secs. A reply is more welcome then than
+    /// in the middle of a busy conversation.
+    #[serde(default)]
+    pub quiet_chat_boost: f32,
+    /// Only learns messages that reply to the bot or match its nick patterns, for
+    /// a dictionary made of direct interactions with it. Platforms without replies
+    /// can still learn from mentions.
+    #[serde(default)]
+    pub learn_only_from_replies: bool,
+    /// Sentences with fewer words than this are not learned.
+    #[serde(default = "default_min_sentence_words")]
+    pub min_sentence_words: usize,
+    /// Sentences with more words than this, like pasted paragraphs, are not
+    /// learned. There is no limit by default.
+    #[serde(default)]
+    pub max_sentence_words: Option<usize>,
+    /// What is done with @mentions in responses, so that the bot does not ping
+    /// the people it learned them from.
+    #[serde(default)]
+    pub output_mention_handling: MentionHandling,
+    /// Makes the reply rate depend on the input, e.g. to reply more often to
+    /// questions. Without it, every input gets the flat reply_rate.
+    #[serde(default)]
+    pub reply_scoring: Option<ReplyScoring>,
+    /// Reacts to strongly positive or negative inputs with the emoji of
+    /// sentiment_reactions, when the bot decided to reply to them. Platforms
+    /// that cannot react reply as usual, and warn about it at startup.
+    #[serde(default)]
+    pub react_to_sentiment: bool,
+    /// The emoji for react_to_sentiment, and how strong a sentiment must be.
+    #[serde(default)]
+    pub sentiment_reactions: SentimentReactions,
+    /// The reply rate in one-on-one chats with the bot, where every message
+    /// is more or less addressed to it. Falls back to reply_rate.
+    #[serde(default)]
+    pub dm_reply_rate: Option<f32>,
+    /// The reply rate in groups and channels. Falls back to reply_rate.
+    #[serde(default)]
+    pub group_reply_rate: Option<f32>,
+    /// Sentences in which the share of distinct words is below this, like
+    /// copypasta or "a a a a a", are not learned. 0.0 learns everything.
+    #[serde(default)]
+    pub min_unique_word_ratio: f32,
+    /// Responses with fewer words than this, like "ok" or "lol", are not
+    /// sent. 0 allows any response.
+    #[serde(default)]
+    pub min_response_words: usize,
+    /// What happens to responses shorter than min_response_words.
+    #[serde(default)]
+    pub short_responses: ShortResponseMode,
+    /// How long, in milliseconds, the bot stays quiet in a chat after replying
+    /// there. The cooldown is off while both this and
+    /// reply_cooldown_ms_per_word are 0.
+    #[serde(default)]
+    pub reply_cooldown_ms: u64,
+    /// Added to reply_cooldown_ms for every word of the reply, so that a long
+    /// reply quiets the bot for longer than a one-liner.
+    #[serde(default)]
+    pub reply_cooldown_ms_per_word: u64,
+    /// Collapses runs of the same punctuation mark in responses to at most this
+    /// many, e.g. "!!!" to "!" with the default of 1, and trims punctuation left
+    /// at the start of a response. 0 leaves punctuation as it is.
+    #[serde(default = "default_max_repeated_punctuation")]
+    pub max_repeated_punctuation: usize,
 }
 
 /////////////////////////////////////////////////////////////////////////////
 // MainBehavior Implementations
 /////////////////////////////////////////////////////////////////////////////
 
+impl Default for MainBehavior {
+    fn default() -> Self {
+        MainBehavior {
+            speaking: true,
+            learning: true,
+            reply_rate: 0.0,
+            reply_nick: 0.0,
+            reply_magic: 0.0,
+            nick_patterns: vec![],
+            magic_patterns: vec![],
+            blacklisted_patterns: vec![],
+            blacklist_mode: BlacklistMode::default(),
+            ignored_users: vec![],
+            user_messages_per_minute: None,
+            max_learned_sentences_per_minute: None,
+            rng_seed: None,
+            max_generation_attempts: default_max_generation_attempts(),
+            response_cache_size: 0,
+            response_cache_ttl_secs: default_response_cache_ttl_secs(),
+            decision_log_size: default_decision_log_size(),
+            echo_window_secs: 0,
+            skip_repeated_messages: false,
+            input_sanitization: InputSanitization::default(),
+            recent_responses_size: 0,
+            recent_responses_ttl_secs: default_recent_responses_ttl_secs(),
+            persist_chat_state: false,
+            capitalize_responses: false,
+            punctuate_responses: false,
+            min_dictionary_sentences: 0,
+            prefer_longer: false,
+            prefer_longer_strength: 1.0,
+            reply_delay_min_ms: 0,
+            reply_delay_max_ms: 0,
+            reply_only_when_addressed: false,
+            reply_template: None,
+            learn_rate: 1.0,
+            fallback_responses: vec![],
+            learn_chat_whitelist: None,
+            reaction_mode: false,
+            reaction_emoji: default_reaction_emoji(),
+            learn_filter: LearnFilter::default(),
+            output_blacklist: vec![],
+            min_confidence: 0.0,
+            max_response_words: 0,
+            long_responses: LongResponseMode::default(),
+            magic_groups: vec![],
+            url_handling: UrlHandling::default(),
+            language_filter: None,
+            burst_chance: 0.0,
+            bot_name: None,
+            reply_name: default_reply_name(),
+            canned_responses: vec![],
+            learn_ack: false,
+            quiet_chat_secs: default_quiet_chat_secs(),
+            quiet_chat_boost: 0.0,
+            learn_only_from_replies: false,
+            min_sentence_words: default_min_sentence_words(),
+            max_sentence_words: None,
+            output_mention_handling: MentionHandling::default(),
+            reply_scoring: None,
+            react_to_sentiment: false,
+            sentiment_reactions: SentimentReactions::default(),
+            dm_reply_rate: None,
+            group_reply_rate: None,
+            min_unique_word_ratio: 0.0,
+            min_response_words: 0,
+            short_responses: ShortResponseMode::default(),
+            reply_cooldown_ms: 0,
+            reply_cooldown_ms_per_word: 0,
+            max_repeated_punctuation: default_max_repeated_punctuation(),
+        }
+    }
+}
+
+impl MainBehavior {
+    /// Checks the values of the main behavior that deserialization alone
+    /// cannot check, both when the configuration is loaded and when the
+    /// behavior is reloaded.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.max_generation_attempts == 0 {
+            return Err(ConfigError::ValidationError(
+                "max_generation_attempts must be at least 1".to_owned(),
+            ));
+        }
+        validate_reply_rates(&[
+            ("reply_rate", Some(self.reply_rate)),
+            ("reply_nick", Some(self.reply_nick)),
+            ("reply_magic", Some(self.reply_magic)),
+            ("dm_reply_rate", self.dm_reply_rate),
+            ("group_reply_rate", self.group_reply_rate),
+        ])?;
+        validate_reply_template(self.reply_template.as_ref())?;
+        validate_language_filter(self.language_filter.as_ref())
+    }
+
+    /// Returns every list of patterns, along with the name of its field.
+    pub(crate) fn patterns(&self) -> Vec<(&'static str, &Vec<Pattern>)> {
+        let mut patterns = vec![
+            ("nick_patterns", &self.nick_patterns),
+            ("magic_patterns", &self.magic_patterns),
+            ("blacklisted_patterns", &self.blacklisted_patterns),
+            ("ignored_users", &self.ignored_users),
+            ("output_blacklist", &self.output_blacklist),
+        ];
+        if let Some(ref ps) = self.learn_chat_whitelist {
+            patterns.push(("learn_chat_whitelist", ps));
+        }
+        for group in &self.magic_groups {
+            patterns.push(("magic_groups", &group.patterns));
+        }
+        for canned in &self.canned_responses {
+            patterns.push(("canned_responses", &canned.patterns));
+        }
+        patterns
+    }
+}
+
 impl PatternOwner for MainBehavior {
     fn compile_patterns(&mut self) -> Result<(), CompilationError> {
         for p in self
@@ -114,6 +748,19 @@ impl PatternOwner for MainBehavior {
             .iter_mut()
             .chain(self.blacklisted_patterns.iter_mut())
             .chain(self.nick_patterns.iter_mut())
+            .chain(self.ignored_users.iter_mut())
+            .chain(self.output_blacklist.iter_mut())
+            .chain(self.learn_chat_whitelist.iter_mut().flatten())
+            .chain(
+                self.magic_groups
+                    .iter_mut()
+                    .flat_map(|g| g.patterns.iter_mut()),
+            )
+            .chain(
+                self.canned_responses
+                    .iter_mut()
+                    .flat_map(|c| c.patterns.iter_mut()),
+            )
         {
             p.compile()?;
         }
@@ -121,12 +768,289 @@ impl PatternOwner for MainBehavior {
     }
 }
 
+fn default_prefer_longer_strength() -> f32 {
+    1.0
+}
+
+fn default_learn_rate() -> f32 {
+    1.0
+}
+
+fn default_max_repeated_punctuation() -> usize {
+    1
+}
+
+fn default_max_generation_attempts() -> usize {
+    5
+}
+
+fn default_response_cache_ttl_secs() -> u64 {
+    300
+}
+
+fn default_decision_log_size() -> usize {
+    100
+}
+
+fn default_recent_responses_ttl_secs() -> u64 {
+    3600
+}
+
+fn default_reply_name() -> f32 {
+    1.0
+}
+
+fn default_quiet_chat_secs() -> u64 {
+    600
+}
+
+fn default_min_sentence_words() -> usize {
+    1
+}
+
+fn default_reaction_emoji() -> String {
+    "👀".to_owned()
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// BlacklistMode Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// BlacklistMode decides what happens to an input that matches one of the
+/// blacklisted patterns.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum BlacklistMode {
+    /// The whole input is refused and nothing is learned from it.
+    Reject,
+    /// The matching fragments are removed and the remainder is learned.
+    /// Fragments are found anywhere in the input, even for patterns that
+    /// otherwise have to match the whole input.
+    Scrub,
+}
+
+impl Default for BlacklistMode {
+    fn default() -> Self {
+        BlacklistMode::Reject
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LongResponseMode Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// LongResponseMode decides what happens to a response that has more words
+/// than max_response_words.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LongResponseMode {
+    /// The response is cut at the limit and ends with an ellipsis.
+    Truncate,
+    /// The response is dropped and another candidate is made up.
+    Reject,
+}
+
+impl Default for LongResponseMode {
+    fn default() -> Self {
+        LongResponseMode::Truncate
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ShortResponseMode Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// ShortResponseMode decides what happens when a response has fewer words
+/// than min_response_words.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShortResponseMode {
+    /// The response is dropped and another candidate is made up.
+    Retry,
+    /// The bot gives up on replying without trying other candidates.
+    GiveUp,
+}
+
+impl Default for ShortResponseMode {
+    fn default() -> Self {
+        ShortResponseMode::Retry
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnFilter Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// LearnFilter decides which kinds of sentences are learned. A sentence is a
+/// question if it ends with a question mark, and a statement otherwise.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum LearnFilter {
+    All,
+    QuestionsOnly,
+    StatementsOnly,
+}
+
+impl Default for LearnFilter {
+    fn default() -> Self {
+        LearnFilter::All
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// UrlHandling Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// UrlHandling decides what is done with URLs before a message is learned,
+/// so that the bot does not repeat links.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum UrlHandling {
+    /// URLs are learned like any other word.
+    Keep,
+    /// URLs are removed.
+    Strip,
+    /// Every URL is replaced with the given text, e.g. "<link>".
+    Replace(String),
+}
+
+impl Default for UrlHandling {
+    fn default() -> Self {
+        UrlHandling::Keep
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// MergeMode Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// MergeMode is how a behavior override's list of patterns is combined with
+/// the list it overrides.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MergeMode {
+    /// Only the override's patterns are used.
+    Replace,
+    /// The override's patterns are used along with the overridden ones, e.g.
+    /// to give a chat an extra nick pattern without restating the others.
+    Append,
+}
+
+impl Default for MergeMode {
+    fn default() -> Self {
+        MergeMode::Replace
+    }
+}
+
+/// PatternMerge holds the MergeMode of each list of patterns of a behavior
+/// override.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PatternMerge {
+    #[serde(default)]
+    pub nick_patterns: MergeMode,
+    #[serde(default)]
+    pub magic_patterns: MergeMode,
+    #[serde(default)]
+    pub blacklisted_patterns: MergeMode,
+    #[serde(default)]
+    pub ignored_users: MergeMode,
+    #[serde(default)]
+    pub output_blacklist: MergeMode,
+}
+
+/// Picks a list of patterns out of a behavior override, along with how it is
+/// merged.
+type PatternField = fn(&BehaviorOverride) -> (&Option<Vec<Pattern>>, MergeMode);
+
+/////////////////////////////////////////////////////////////////////////////
+// MentionHandling Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// MentionHandling decides what is done with @mentions in responses.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum MentionHandling {
+    /// Mentions are sent as they were learned.
+    Keep,
+    /// Mentions are removed.
+    Strip,
+    /// A zero-width space is put after the @, which keeps the mention
+    /// readable without pinging anyone.
+    Defang,
+}
+
+impl Default for MentionHandling {
+    fn default() -> Self {
+        MentionHandling::Keep
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// InputSanitization Enum
+/////////////////////////////////////////////////////////////////////////////
+
+/// InputSanitization decides how messages are cleaned up before anything is
+/// done with them. Zero-width and control characters can be used to sneak
+/// words past the patterns, e.g. "b\u{200b}ad" for "bad", and they end up in
+/// the indices as words of their own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputSanitization {
+    /// Messages are used as they were sent.
+    Off,
+    /// Zero-width, bidirectional and control characters are removed. Line
+    /// breaks are kept, tabs become spaces, and joiners between emoji are
+    /// kept so that emoji sequences stay whole.
+    Strip,
+    /// Like Strip, and the text is brought to Unicode NFKC form, so that
+    /// look-alikes such as fullwidth letters become plain ones.
+    Normalize,
+}
+
+impl Default for InputSanitization {
+    fn default() -> Self {
+        InputSanitization::Off
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LanguageFilter Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// LanguageFilter keeps the bot to messages in one language, so that it does
+/// not mix languages in multilingual chats.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LanguageFilter {
+    /// The ISO 639-3 code of the language, e.g. "eng" or "spa".
+    pub language: String,
+    /// How confident the detection has to be, from 0.0 to 1.0, for its result
+    /// to be used.
+    #[serde(default = "default_language_min_confidence")]
+    pub min_confidence: f64,
+    /// What is done with messages whose language is not detected with enough
+    /// confidence, which is often the case for short ones.
+    #[serde(default)]
+    pub uncertain: UncertainLanguage,
+}
+
+fn default_language_min_confidence() -> f64 {
+    0.5
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum UncertainLanguage {
+    Allow,
+    Skip,
+}
+
+impl Default for UncertainLanguage {
+    fn default() -> Self {
+        UncertainLanguage::Allow
+    }
+}
+
 /////////////////////////////////////////////////////////////////////////////
 // OverrideBehavior Struct
 /////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct BehaviorOverride {
+    /// Whether each list of patterns replaces the overridden one or is added
+    /// to it.
+    #[serde(default)]
+    pub pattern_merge: PatternMerge,
     pub speaking: Option<bool>,
     pub learning: Option<bool>,
     pub reply_rate: Option<f32>,
@@ -135,13 +1059,79 @@ pub struct BehaviorOverride {
     pub nick_patterns: Option<Vec<Pattern>>,
     pub magic_patterns: Option<Vec<Pattern>>,
     pub blacklisted_patterns: Option<Vec<Pattern>>,
+    pub blacklist_mode: Option<BlacklistMode>,
     pub ignored_users: Option<Vec<Pattern>>,
+    pub capitalize_responses: Option<bool>,
+    pub punctuate_responses: Option<bool>,
+    pub min_dictionary_sentences: Option<usize>,
+    pub prefer_longer: Option<bool>,
+    pub prefer_longer_strength: Option<f32>,
+    pub reply_delay_min_ms: Option<u64>,
+    pub reply_delay_max_ms: Option<u64>,
+    pub reply_only_when_addressed: Option<bool>,
+    pub reply_template: Option<String>,
+    pub learn_rate: Option<f32>,
+    pub fallback_responses: Option<Vec<String>>,
+    pub reaction_mode: Option<bool>,
+    pub reaction_emoji: Option<String>,
+    pub learn_filter: Option<LearnFilter>,
+    pub output_blacklist: Option<Vec<Pattern>>,
+    pub min_confidence: Option<f32>,
+    pub max_response_words: Option<usize>,
+    pub long_responses: Option<LongResponseMode>,
+    pub magic_groups: Option<Vec<MagicGroup>>,
+    pub url_handling: Option<UrlHandling>,
+    pub language_filter: Option<LanguageFilter>,
+    pub burst_chance: Option<f32>,
+    pub bot_name: Option<String>,
+    pub reply_name: Option<f32>,
+    pub canned_responses: Option<Vec<CannedResponse>>,
+    pub learn_ack: Option<bool>,
+    pub quiet_chat_secs: Option<u64>,
+    pub quiet_chat_boost: Option<f32>,
+    pub learn_only_from_replies: Option<bool>,
+    pub min_sentence_words: Option<usize>,
+    pub max_sentence_words: Option<usize>,
+    pub output_mention_handling: Option<MentionHandling>,
+    pub reply_scoring: Option<ReplyScoring>,
+    pub react_to_sentiment: Option<bool>,
+    pub sentiment_reactions: Option<SentimentReactions>,
+    pub dm_reply_rate: Option<f32>,
+    pub group_reply_rate: Option<f32>,
+    pub min_unique_word_ratio: Option<f32>,
+    pub min_response_words: Option<usize>,
+    pub short_responses: Option<ShortResponseMode>,
+    pub reply_cooldown_ms: Option<u64>,
+    pub reply_cooldown_ms_per_word: Option<u64>,
+    pub max_repeated_punctuation: Option<usize>,
 }
 
 /////////////////////////////////////////////////////////////////////////////
 // OverrideBehavior Implementations
 /////////////////////////////////////////////////////////////////////////////
 
+impl BehaviorOverride {
+    /// Returns every list of patterns that is set, along with the name of its
+    /// field.
+    pub(crate) fn patterns(&self) -> Vec<(&'static str, &Vec<Pattern>)> {
+        let patterns = vec![
+            ("nick_patterns", &self.nick_patterns),
+            ("magic_patterns", &self.magic_patterns),
+            ("blacklisted_patterns", &self.blacklisted_patterns),
+            ("ignored_users", &self.ignored_users),
+            ("output_blacklist", &self.output_blacklist),
+        ];
+        let groups = self.magic_groups.iter().flatten();
+        let canned = self.canned_responses.iter().flatten();
+        patterns
+            .into_iter()
+            .filter_map(|(name, ps)| ps.as_ref().map(|ps| (name, ps)))
+            .chain(groups.map(|g| ("magic_groups", &g.patterns)))
+            .chain(canned.map(|c| ("canned_responses", &c.patterns)))
+            .collect()
+    }
+}
+
 impl PatternOwner for BehaviorOverride {
     fn compile_patterns(&mut self) -> Result<(), CompilationError> {
         if let Some(ref mut ps) = self.magic_patterns {
@@ -159,6 +1149,26 @@ impl PatternOwner for BehaviorOverride {
                 p.compile()?;
             }
         }
+        if let Some(ref mut ps) = self.ignored_users {
+            for p in ps.iter_mut() {
+                p.compile()?;
+            }
+        }
+        if let Some(ref mut ps) = self.output_blacklist {
+            for p in ps.iter_mut() {
+                p.compile()?;
+            }
+        }
+        for group in self.magic_groups.iter_mut().flatten() {
+            for p in group.patterns.iter_mut() {
+                p.compile()?;
+            }
+        }
+        for canned in self.canned_responses.iter_mut().flatten() {
+            for p in canned.patterns.iter_mut() {
+                p.compile()?;
+            }
+        }
         Ok(())
     }
 }
@@ -173,6 +1183,172 @@ pub struct ChatBehaviorOverrides {
     pub behavior: BehaviorOverride,
 }
 
+/////////////////////////////////////////////////////////////////////////////
+// MagicGroup Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// MagicGroup is a named group of magic patterns that replies at its own
+/// chance. The response is made from the dictionary unless one is given. A
+/// response with the {response} placeholder has it replaced with one from the
+/// dictionary; any other response is sent as it is, e.g. "thanks!" for a
+/// "good bot" pattern.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MagicGroup {
+    pub name: String,
+    pub patterns: Vec<Pattern>,
+    pub reply_chance: f32,
+    #[serde(default)]
+    pub response: Option<String>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// CannedResponse Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// CannedResponse is a fixed response to inputs that match any of its
+/// patterns, e.g. a link to the rules for "what are the rules". $1, $2 and so
+/// on in the response are replaced with the capture groups of the pattern
+/// that matched, and $$ with a dollar sign.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CannedResponse {
+    pub patterns: Vec<Pattern>,
+    pub response: String,
+    /// Chance of responding when a pattern matches, from 0.0 to 1.0.
+    #[serde(default = "default_canned_response_chance")]
+    pub chance: f32,
+}
+
+fn default_canned_response_chance() -> f32 {
+    1.0
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// ReplyScoring Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// ReplyScoring weighs the features of an input into its reply rate. Every
+/// weight is added to reply_rate once for each time its feature is found in
+/// the input, and the sum is kept between 0.0 and max_rate. Like reply_rate,
+/// the weights are changed by reply_rate_multiplier.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReplyScoring {
+    /// Added for every word of the input.
+    #[serde(default)]
+    pub per_word: f32,
+    /// Added for every word of the input that the bot knows.
+    #[serde(default)]
+    pub per_known_word: f32,
+    /// Added when the input ends with a question mark.
+    #[serde(default)]
+    pub question: f32,
+    #[serde(default = "default_max_scored_rate")]
+    pub max_rate: f32,
+}
+
+fn default_max_scored_rate() -> f32 {
+    1.0
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// SentimentReactions Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// SentimentReactions maps the sentiment of an input to the emoji the bot
+/// reacts with when react_to_sentiment is on. Inputs are scored by adding a
+/// point for every positive word and taking one away for every negative word.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SentimentReactions {
+    /// The emoji for inputs that score at least min_score. Without it,
+    /// positive inputs get no reaction.
+    #[serde(default = "default_positive_emoji")]
+    pub positive: Option<String>,
+    /// The emoji for inputs that score at most -min_score. Without it,
+    /// negative inputs get no reaction.
+    #[serde(default = "default_negative_emoji")]
+    pub negative: Option<String>,
+    /// How strongly positive or negative an input must be to get a reaction.
+    #[serde(default = "default_min_sentiment_score")]
+    pub min_score: u32,
+    /// Still replies with text as usual, instead of only reacting.
+    #[serde(default)]
+    pub with_reply: bool,
+}
+
+impl Default for SentimentReactions {
+    fn default() -> Self {
+        SentimentReactions {
+            positive: default_positive_emoji(),
+            negative: default_negative_emoji(),
+            min_score: default_min_sentiment_score(),
+            with_reply: false,
+        }
+    }
+}
+
+fn default_positive_emoji() -> Option<String> {
+    Some("❤️".to_owned())
+}
+
+fn default_negative_emoji() -> Option<String> {
+    Some("😢".to_owned())
+}
+
+fn default_min_sentiment_score() -> u32 {
+    2
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Command Settings
+/////////////////////////////////////////////////////////////////////////////
+
+/// CommandRole is who may use a command.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CommandRole {
+    /// Anyone in the chat.
+    Anyone,
+    /// The owner on Telegram, and members with the Manage Server permission
+    /// on Discord. Without an owner, such commands are off.
+    Admin,
+}
+
+impl Default for CommandRole {
+    fn default() -> Self {
+        CommandRole::Admin
+    }
+}
+
+/// CommandSettings turns a command off or changes who may use it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct CommandSettings {
+    #[serde(default = "default_command_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub role: CommandRole,
+}
+
+impl Default for CommandSettings {
+    fn default() -> Self {
+        CommandSettings {
+            enabled: default_command_enabled(),
+            role: CommandRole::default(),
+        }
+    }
+}
+
+fn default_command_enabled() -> bool {
+    true
+}
+
+/// Commands maps the names of commands, without the slash, to their
+/// settings, e.g. {"preview": {"enabled": false}}. Commands that are left out
+/// are enabled for admins only.
+pub type Commands = HashMap<String, CommandSettings>;
+
+/// Returns the settings of the command, or the defaults if it has none.
+pub fn command_settings(commands: &Commands, name: &str) -> CommandSettings {
+    commands.get(name).copied().unwrap_or_default()
+}
+
 /////////////////////////////////////////////////////////////////////////////
 // Telegram Struct
 /////////////////////////////////////////////////////////////////////////////
@@ -180,6 +1356,45 @@ pub struct ChatBehaviorOverrides {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TelegramPlatform {
     pub token: String,
+    /// Leaves bot commands and mentions out of what is learned.
+    #[serde(default)]
+    pub strip_entities: bool,
+    /// Treats replies to the bot's messages like nick mentions, and makes up
+    /// the response from both the reply and the message it replies to.
+    #[serde(default)]
+    pub reply_to_replies: bool,
+    /// Also learns the file names of documents. File names are often noisy,
+    /// so this is off by default.
+    #[serde(default)]
+    pub learn_attachments: bool,
+    /// Also learns forwarded messages, attributed to their original author.
+    /// Forwards are someone else's words, so this is off by default.
+    #[serde(default)]
+    pub learn_forwards: bool,
+    /// The user ID of the bot's owner, the only one who can use the /shutup,
+    /// /speak and /preview commands unless commands says otherwise.
+    #[serde(default)]
+    pub owner_id: Option<String>,
+    /// Turns commands off or changes who may use them.
+    #[serde(default)]
+    pub commands: Commands,
+    /// The bot's @username. Commands can be addressed to it, e.g.
+    /// "/speak@borg"; commands addressed to other bots are ignored, and so
+    /// are all addressed commands without it.
+    #[serde(default)]
+    pub username: Option<String>,
+    /// Says something when the bot is added to a chat, so that it does not
+    /// sit there silently until someone talks.
+    #[serde(default)]
+    pub greet_on_join: bool,
+    /// What is said on joining. Without it, a line is made up from what the
+    /// bot has learned.
+    #[serde(default)]
+    pub greeting: Option<String>,
+    /// Scales the reply chances of every chat on the platform, on top of
+    /// their behavior, e.g. 0.5 replies half as often.
+    #[serde(default = "default_reply_rate_multiplier")]
+    pub reply_rate_multiplier: f32,
     pub behavior: Option<BehaviorOverride>,
     pub chat_behaviors: Option<Vec<ChatBehaviorOverrides>>,
 }
@@ -191,27 +1406,117 @@ pub struct TelegramPlatform {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct DiscordPlatform {
     pub token: String,
+    /// Scales the reply chances of every guild on the platform.
+    #[serde(default = "default_reply_rate_multiplier")]
+    pub reply_rate_multiplier: f32,
     pub behavior: Option<BehaviorOverride>,
     pub chat_behaviors: Option<Vec<ChatBehaviorOverrides>>,
+    /// Where the overrides set through slash commands are saved, so that they
+    /// survive restarts. Without it they only last until the bot stops.
+    #[serde(default)]
+    pub overrides_path: Option<String>,
+    /// Prefixes the slash commands, e.g. "borg1" registers "borg1_stats", so
+    /// that several bots can share a server. Empty keeps the bare names.
+    #[serde(default)]
+    pub command_namespace: String,
+    /// Greets the guilds the bot is added to, in their system channel.
+    #[serde(default)]
+    pub greet_on_join: bool,
+    /// The greeting. Without it, a line is made up from what was learned.
+    #[serde(default)]
+    pub greeting: Option<String>,
+    /// Turns slash commands off or lets everyone use them. Disabled commands
+    /// are not registered.
+    #[serde(default)]
+    pub commands: Commands,
 }
 
 /////////////////////////////////////////////////////////////////////////////
-// BehaviorValues Struct
+// Admin Interface Struct
 /////////////////////////////////////////////////////////////////////////////
 
-#[derive(Debug)]
-pub struct BehaviorValueResolver<'a> {
-    behavior: &'a MainBehavior,
-    override_: &'a Option<BehaviorOverrideValueResolver<'a>>,
+/// AdminInterface configures the line-based JSON admin listener. It only
+/// listens on loopback addresses, and every request must carry the token.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdminInterface {
+    pub bind_address: String,
+    pub token: String,
 }
 
 /////////////////////////////////////////////////////////////////////////////
-// BehaviorValues Implementations
+// Backups Struct
 /////////////////////////////////////////////////////////////////////////////
 
-impl<'a> BehaviorValueResolver<'a> {
-    pub fn new(
-        behavior: &'a MainBehavior,
+/// Backups configures the timestamped copies of the dictionary that are made
+/// whenever the auto-save writes the whole dictionary file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backups {
+    pub directory: String,
+    /// How many backups are kept. Older ones are removed.
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+    /// Replaces a dictionary file that cannot be parsed with the newest
+    /// backup that can, instead of refusing to start.
+    #[serde(default)]
+    pub restore_on_corruption: bool,
+}
+
+fn default_backup_keep() -> usize {
+    10
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// IRC Struct
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IrcPlatform {
+    pub server: String,
+    #[serde(default = "default_irc_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: bool,
+    pub nick: String,
+    pub channels: Vec<String>,
+    /// Server password, if the server requires one.
+    pub password: Option<String>,
+    /// Scales the reply chances of every channel on the platform.
+    #[serde(default = "default_reply_rate_multiplier")]
+    pub reply_rate_multiplier: f32,
+    pub behavior: Option<BehaviorOverride>,
+    /// Per-channel behaviors. The chat_id of each entry is a channel name.
+    pub chat_behaviors: Option<Vec<ChatBehaviorOverrides>>,
+    /// Commands that anyone may use. IRC has no owner, so commands for admins
+    /// only are off.
+    #[serde(default)]
+    pub commands: Commands,
+}
+
+fn default_irc_port() -> u16 {
+    6667
+}
+
+fn default_reply_rate_multiplier() -> f32 {
+    1.0
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// BehaviorValues Struct
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub struct BehaviorValueResolver<'a> {
+    behavior: &'a MainBehavior,
+    override_: &'a Option<BehaviorOverrideValueResolver<'a>>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// BehaviorValues Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl<'a> BehaviorValueResolver<'a> {
+    pub fn new(
+        behavior: &'a MainBehavior,
         override_: &'a Option<BehaviorOverrideValueResolver<'a>>,
     ) -> BehaviorValueResolver<'a> {
         BehaviorValueResolver {
@@ -230,15 +1535,47 @@ impl<'a> BehaviorValueResolver<'a> {
     pub fn is_learning(&self) -> bool {
         self.override_
             .as_ref()
-            .and_then(|o| o.is_speaking())
+            .and_then(|o| o.is_learning())
             .unwrap_or(self.behavior.learning)
     }
 
+    /// The platform's reply rate multiplier, which the reply_rate, reply_magic
+    /// and reply_nick chances are already multiplied by.
+    pub fn reply_rate_multiplier(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .map_or(1.0, |o| o.reply_rate_multiplier())
+    }
+
     pub fn reply_rate(&self) -> f32 {
         self.override_
             .as_ref()
             .and_then(|o| o.reply_rate())
             .unwrap_or(self.behavior.reply_rate)
+            * self.reply_rate_multiplier()
+    }
+
+    /// The reply rate in one-on-one chats, or reply_rate without one. The
+    /// overrides are resolved one level at a time, so that the reply_rate of
+    /// an override outranks the dm_reply_rate of the main behavior.
+    pub fn dm_reply_rate(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.dm_reply_rate())
+            .or(self.behavior.dm_reply_rate)
+            .unwrap_or(self.behavior.reply_rate)
+            * self.reply_rate_multiplier()
+    }
+
+    /// The reply rate in groups, or reply_rate without one. Like
+    /// dm_reply_rate, it is resolved one level at a time.
+    pub fn group_reply_rate(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.group_reply_rate())
+            .or(self.behavior.group_reply_rate)
+            .unwrap_or(self.behavior.reply_rate)
+            * self.reply_rate_multiplier()
     }
 
     pub fn reply_magic(&self) -> f32 {
@@ -246,6 +1583,7 @@ impl<'a> BehaviorValueResolver<'a> {
             .as_ref()
             .and_then(|o| o.reply_magic())
             .unwrap_or(self.behavior.reply_magic)
+            * self.reply_rate_multiplier()
     }
 
     pub fn reply_nick(&self) -> f32 {
@@ -253,34 +1591,337 @@ impl<'a> BehaviorValueResolver<'a> {
             .as_ref()
             .and_then(|o| o.reply_nick())
             .unwrap_or(self.behavior.reply_nick)
+            * self.reply_rate_multiplier()
+    }
+
+    /// Resolves a list of patterns, starting from the main behavior's list and
+    /// merging in the list of each override.
+    fn patterns(&self, patterns: &'a [Pattern], field: PatternField) -> Vec<&Pattern> {
+        let patterns = patterns.iter().collect();
+        match self.override_ {
+            Some(ref o) => o.merge_patterns(patterns, field),
+            None => patterns,
+        }
+    }
+
+    pub fn nick_patterns(&self) -> Vec<&Pattern> {
+        self.patterns(&self.behavior.nick_patterns, |o| {
+            (&o.nick_patterns, o.pattern_merge.nick_patterns)
+        })
+    }
+
+    pub fn magic_patterns(&self) -> Vec<&Pattern> {
+        self.patterns(&self.behavior.magic_patterns, |o| {
+            (&o.magic_patterns, o.pattern_merge.magic_patterns)
+        })
+    }
+
+    pub fn blacklisted_patterns(&self) -> Vec<&Pattern> {
+        self.patterns(&self.behavior.blacklisted_patterns, |o| {
+            (
+                &o.blacklisted_patterns,
+                o.pattern_merge.blacklisted_patterns,
+            )
+        })
+    }
+
+    pub fn blacklist_mode(&self) -> BlacklistMode {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.blacklist_mode())
+            .unwrap_or(self.behavior.blacklist_mode)
+    }
+
+    pub fn ignored_users(&self) -> Vec<&Pattern> {
+        self.patterns(&self.behavior.ignored_users, |o| {
+            (&o.ignored_users, o.pattern_merge.ignored_users)
+        })
+    }
+
+    pub fn capitalize_responses(&self) -> bool {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.capitalize_responses())
+            .unwrap_or(self.behavior.capitalize_responses)
+    }
+
+    pub fn punctuate_responses(&self) -> bool {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.punctuate_responses())
+            .unwrap_or(self.behavior.punctuate_responses)
+    }
+
+    pub fn min_dictionary_sentences(&self) -> usize {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.min_dictionary_sentences())
+            .unwrap_or(self.behavior.min_dictionary_sentences)
+    }
+
+    pub fn prefer_longer(&self) -> bool {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.prefer_longer())
+            .unwrap_or(self.behavior.prefer_longer)
+    }
+
+    pub fn prefer_longer_strength(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.prefer_longer_strength())
+            .unwrap_or(self.behavior.prefer_longer_strength)
+    }
+
+    pub fn reply_delay_min_ms(&self) -> u64 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_delay_min_ms())
+            .unwrap_or(self.behavior.reply_delay_min_ms)
+    }
+
+    pub fn reply_delay_max_ms(&self) -> u64 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_delay_max_ms())
+            .unwrap_or(self.behavior.reply_delay_max_ms)
+    }
+
+    pub fn reply_only_when_addressed(&self) -> bool {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_only_when_addressed())
+            .unwrap_or(self.behavior.reply_only_when_addressed)
+    }
+
+    pub fn reply_template(&self) -> Option<&String> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_template())
+            .or_else(|| self.behavior.reply_template.as_ref())
+    }
+
+    pub fn language_filter(&self) -> Option<&LanguageFilter> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.language_filter())
+            .or_else(|| self.behavior.language_filter.as_ref())
+    }
+
+    pub fn learn_rate(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.learn_rate())
+            .unwrap_or(self.behavior.learn_rate)
+    }
+
+    pub fn fallback_responses(&self) -> &Vec<String> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.fallback_responses())
+            .unwrap_or(&self.behavior.fallback_responses)
+    }
+
+    pub fn reaction_mode(&self) -> bool {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reaction_mode())
+            .unwrap_or(self.behavior.reaction_mode)
     }
 
-    pub fn nick_patterns(&self) -> &Vec<Pattern> {
+    pub fn reaction_emoji(&self) -> &String {
         self.override_
             .as_ref()
-            .and_then(|o| o.nick_patterns())
-            .unwrap_or(&self.behavior.nick_patterns)
+            .and_then(|o| o.reaction_emoji())
+            .unwrap_or(&self.behavior.reaction_emoji)
     }
 
-    pub fn magic_patterns(&self) -> &Vec<Pattern> {
+    pub fn learn_filter(&self) -> LearnFilter {
         self.override_
             .as_ref()
-            .and_then(|o| o.magic_patterns())
-            .unwrap_or(&self.behavior.magic_patterns)
+            .and_then(|o| o.learn_filter())
+            .unwrap_or(self.behavior.learn_filter)
     }
 
-    pub fn blacklisted_patterns(&self) -> &Vec<Pattern> {
+    pub fn output_blacklist(&self) -> Vec<&Pattern> {
+        self.patterns(&self.behavior.output_blacklist, |o| {
+            (&o.output_blacklist, o.pattern_merge.output_blacklist)
+        })
+    }
+
+    pub fn min_confidence(&self) -> f32 {
         self.override_
             .as_ref()
-            .and_then(|o| o.blacklisted_patterns())
-            .unwrap_or(&self.behavior.blacklisted_patterns)
+            .and_then(|o| o.min_confidence())
+            .unwrap_or(self.behavior.min_confidence)
     }
 
-    pub fn ignored_users(&self) -> &Vec<Pattern> {
+    pub fn max_response_words(&self) -> usize {
         self.override_
             .as_ref()
-            .and_then(|o| o.ignored_users())
-            .unwrap_or(&self.behavior.ignored_users)
+            .and_then(|o| o.max_response_words())
+            .unwrap_or(self.behavior.max_response_words)
+    }
+
+    pub fn long_responses(&self) -> LongResponseMode {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.long_responses())
+            .unwrap_or(self.behavior.long_responses)
+    }
+
+    pub fn magic_groups(&self) -> &Vec<MagicGroup> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.magic_groups())
+            .unwrap_or(&self.behavior.magic_groups)
+    }
+
+    pub fn url_handling(&self) -> UrlHandling {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.url_handling())
+            .unwrap_or_else(|| self.behavior.url_handling.clone())
+    }
+
+    pub fn burst_chance(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.burst_chance())
+            .unwrap_or(self.behavior.burst_chance)
+    }
+
+    pub fn bot_name(&self) -> Option<&String> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.bot_name())
+            .or_else(|| self.behavior.bot_name.as_ref())
+    }
+
+    pub fn reply_name(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_name())
+            .unwrap_or(self.behavior.reply_name)
+    }
+
+    pub fn canned_responses(&self) -> &Vec<CannedResponse> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.canned_responses())
+            .unwrap_or(&self.behavior.canned_responses)
+    }
+
+    pub fn learn_ack(&self) -> bool {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.learn_ack())
+            .unwrap_or(self.behavior.learn_ack)
+    }
+
+    pub fn quiet_chat_secs(&self) -> u64 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.quiet_chat_secs())
+            .unwrap_or(self.behavior.quiet_chat_secs)
+    }
+
+    pub fn quiet_chat_boost(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.quiet_chat_boost())
+            .unwrap_or(self.behavior.quiet_chat_boost)
+    }
+
+    pub fn learn_only_from_replies(&self) -> bool {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.learn_only_from_replies())
+            .unwrap_or(self.behavior.learn_only_from_replies)
+    }
+
+    pub fn min_sentence_words(&self) -> usize {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.min_sentence_words())
+            .unwrap_or(self.behavior.min_sentence_words)
+    }
+
+    pub fn max_sentence_words(&self) -> Option<usize> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.max_sentence_words())
+            .or(self.behavior.max_sentence_words)
+    }
+
+    pub fn output_mention_handling(&self) -> MentionHandling {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.output_mention_handling())
+            .unwrap_or(self.behavior.output_mention_handling)
+    }
+
+    pub fn reply_scoring(&self) -> Option<ReplyScoring> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_scoring())
+            .or(self.behavior.reply_scoring)
+    }
+
+    pub fn react_to_sentiment(&self) -> bool {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.react_to_sentiment())
+            .unwrap_or(self.behavior.react_to_sentiment)
+    }
+
+    pub fn sentiment_reactions(&self) -> &SentimentReactions {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.sentiment_reactions())
+            .unwrap_or(&self.behavior.sentiment_reactions)
+    }
+
+    pub fn min_unique_word_ratio(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.min_unique_word_ratio())
+            .unwrap_or(self.behavior.min_unique_word_ratio)
+    }
+
+    pub fn min_response_words(&self) -> usize {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.min_response_words())
+            .unwrap_or(self.behavior.min_response_words)
+    }
+
+    pub fn short_responses(&self) -> ShortResponseMode {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.short_responses())
+            .unwrap_or(self.behavior.short_responses)
+    }
+
+    pub fn reply_cooldown_ms(&self) -> u64 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_cooldown_ms())
+            .unwrap_or(self.behavior.reply_cooldown_ms)
+    }
+
+    pub fn reply_cooldown_ms_per_word(&self) -> u64 {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_cooldown_ms_per_word())
+            .unwrap_or(self.behavior.reply_cooldown_ms_per_word)
+    }
+
+    pub fn max_repeated_punctuation(&self) -> usize {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.max_repeated_punctuation())
+            .unwrap_or(self.behavior.max_repeated_punctuation)
     }
 }
 
@@ -292,6 +1933,49 @@ impl<'a> BehaviorValueResolver<'a> {
 pub struct BehaviorOverrideValueResolver<'a> {
     behavior: &'a BehaviorOverride,
     override_: Option<Box<BehaviorOverrideValueResolver<'a>>>,
+    reply_rate_multiplier: f32,
+}
+
+lazy_static! {
+    static ref NO_BEHAVIOR_OVERRIDE: BehaviorOverride = BehaviorOverride::default();
+}
+
+/// Applies a platform's reply rate multiplier to the behavior resolved for one
+/// of its chats. A chat without any behavior overrides gets a resolver anyway,
+/// so that the multiplier still applies to it.
+pub fn with_reply_rate_multiplier(
+    behavior: Option<BehaviorOverrideValueResolver>,
+    multiplier: f32,
+) -> Option<BehaviorOverrideValueResolver> {
+    if multiplier == 1.0 {
+        return behavior;
+    }
+    let behavior =
+        behavior.unwrap_or_else(|| BehaviorOverrideValueResolver::new(&NO_BEHAVIOR_OVERRIDE, None));
+    Some(BehaviorOverrideValueResolver {
+        reply_rate_multiplier: behavior.reply_rate_multiplier * multiplier,
+        ..behavior
+    })
+}
+
+/// Chains the behavior overrides that apply to a chat, ordered from the
+/// lowest to the highest precedence, and applies the platform's reply rate
+/// multiplier on top. Missing overrides are skipped.
+pub fn resolve_behavior(
+    behaviors: Vec<Option<&BehaviorOverride>>,
+    reply_rate_multiplier: f32,
+) -> Option<BehaviorOverrideValueResolver> {
+    let behavior = behaviors
+        .into_iter()
+        .flatten()
+        .rev()
+        .fold(None, |override_, behavior| {
+            Some(BehaviorOverrideValueResolver::new(
+                behavior,
+                override_.map(Box::new),
+            ))
+        });
+    with_reply_rate_multiplier(behavior, reply_rate_multiplier)
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -306,69 +1990,644 @@ impl<'a> BehaviorOverrideValueResolver<'a> {
         BehaviorOverrideValueResolver {
             behavior,
             override_,
+            reply_rate_multiplier: 1.0,
+        }
+    }
+
+    pub fn reply_rate_multiplier(&self) -> f32 {
+        self.override_
+            .as_ref()
+            .map_or(1.0, |o| o.reply_rate_multiplier())
+            * self.reply_rate_multiplier
+    }
+
+    /// Merges this override's list of patterns into the patterns it
+    /// overrides, and then the list of the override that overrides it.
+    fn merge_patterns(&self, patterns: Vec<&'a Pattern>, field: PatternField) -> Vec<&'a Pattern> {
+        let patterns = match field(self.behavior) {
+            (Some(ps), MergeMode::Replace) => ps.iter().collect(),
+            (Some(ps), MergeMode::Append) => patterns.into_iter().chain(ps).collect(),
+            (None, _) => patterns,
+        };
+        match self.override_ {
+            Some(ref o) => o.merge_patterns(patterns, field),
+            None => patterns,
         }
     }
 
     pub fn is_speaking(&self) -> Option<bool> {
         self.override_
             .as_ref()
-            .map(|o| o.is_speaking())
-            .unwrap_or(self.behavior.speaking)
+            .and_then(|o| o.is_speaking())
+            .or(self.behavior.speaking)
     }
 
     pub fn is_learning(&self) -> Option<bool> {
         self.override_
             .as_ref()
-            .map(|o| o.is_speaking())
-            .unwrap_or(self.behavior.learning)
+            .and_then(|o| o.is_learning())
+            .or(self.behavior.learning)
     }
 
     pub fn reply_rate(&self) -> Option<f32> {
         self.override_
             .as_ref()
-            .map(|o| o.reply_rate())
-            .unwrap_or(self.behavior.reply_rate)
+            .and_then(|o| o.reply_rate())
+            .or(self.behavior.reply_rate)
+    }
+
+    /// The dm_reply_rate, or else the reply_rate, of the most specific
+    /// override that sets either.
+    pub fn dm_reply_rate(&self) -> Option<f32> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.dm_reply_rate())
+            .or(self.behavior.dm_reply_rate)
+            .or(self.behavior.reply_rate)
+    }
+
+    /// The group_reply_rate, or else the reply_rate, of the most specific
+    /// override that sets either.
+    pub fn group_reply_rate(&self) -> Option<f32> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.group_reply_rate())
+            .or(self.behavior.group_reply_rate)
+            .or(self.behavior.reply_rate)
     }
 
     pub fn reply_magic(&self) -> Option<f32> {
         self.override_
             .as_ref()
-            .map(|o| o.reply_magic())
-            .unwrap_or(self.behavior.reply_magic)
+            .and_then(|o| o.reply_magic())
+            .or(self.behavior.reply_magic)
     }
 
     pub fn reply_nick(&self) -> Option<f32> {
         self.override_
             .as_ref()
-            .map(|o| o.reply_nick())
-            .unwrap_or(self.behavior.reply_nick)
+            .and_then(|o| o.reply_nick())
+            .or(self.behavior.reply_nick)
+    }
+
+    pub fn blacklist_mode(&self) -> Option<BlacklistMode> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.blacklist_mode())
+            .or(self.behavior.blacklist_mode)
+    }
+
+    pub fn capitalize_responses(&self) -> Option<bool> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.capitalize_responses())
+            .or(self.behavior.capitalize_responses)
+    }
+
+    pub fn punctuate_responses(&self) -> Option<bool> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.punctuate_responses())
+            .or(self.behavior.punctuate_responses)
+    }
+
+    pub fn min_dictionary_sentences(&self) -> Option<usize> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.min_dictionary_sentences())
+            .or(self.behavior.min_dictionary_sentences)
+    }
+
+    pub fn prefer_longer(&self) -> Option<bool> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.prefer_longer())
+            .or(self.behavior.prefer_longer)
+    }
+
+    pub fn prefer_longer_strength(&self) -> Option<f32> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.prefer_longer_strength())
+            .or(self.behavior.prefer_longer_strength)
+    }
+
+    pub fn reply_delay_min_ms(&self) -> Option<u64> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_delay_min_ms())
+            .or(self.behavior.reply_delay_min_ms)
+    }
+
+    pub fn reply_delay_max_ms(&self) -> Option<u64> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_delay_max_ms())
+            .or(self.behavior.reply_delay_max_ms)
+    }
+
+    pub fn reply_only_when_addressed(&self) -> Option<bool> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_only_when_addressed())
+            .or(self.behavior.reply_only_when_addressed)
+    }
+
+    pub fn reply_template(&self) -> Option<&String> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_template())
+            .or_else(|| self.behavior.reply_template.as_ref())
+    }
+
+    pub fn language_filter(&self) -> Option<&LanguageFilter> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.language_filter())
+            .or_else(|| self.behavior.language_filter.as_ref())
+    }
+
+    pub fn learn_rate(&self) -> Option<f32> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.learn_rate())
+            .or(self.behavior.learn_rate)
+    }
+
+    pub fn fallback_responses(&self) -> Option<&Vec<String>> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.fallback_responses())
+            .or_else(|| self.behavior.fallback_responses.as_ref())
+    }
+
+    pub fn reaction_mode(&self) -> Option<bool> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reaction_mode())
+            .or(self.behavior.reaction_mode)
+    }
+
+    pub fn reaction_emoji(&self) -> Option<&String> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reaction_emoji())
+            .or_else(|| self.behavior.reaction_emoji.as_ref())
+    }
+
+    pub fn learn_filter(&self) -> Option<LearnFilter> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.learn_filter())
+            .or(self.behavior.learn_filter)
+    }
+
+    pub fn min_confidence(&self) -> Option<f32> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.min_confidence())
+            .or(self.behavior.min_confidence)
+    }
+
+    pub fn max_response_words(&self) -> Option<usize> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.max_response_words())
+            .or(self.behavior.max_response_words)
+    }
+
+    pub fn long_responses(&self) -> Option<LongResponseMode> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.long_responses())
+            .or(self.behavior.long_responses)
+    }
+
+    pub fn magic_groups(&self) -> Option<&Vec<MagicGroup>> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.magic_groups())
+            .or_else(|| self.behavior.magic_groups.as_ref())
+    }
+
+    pub fn url_handling(&self) -> Option<UrlHandling> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.url_handling())
+            .or_else(|| self.behavior.url_handling.clone())
+    }
+
+    pub fn burst_chance(&self) -> Option<f32> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.burst_chance())
+            .or(self.behavior.burst_chance)
+    }
+
+    pub fn bot_name(&self) -> Option<&String> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.bot_name())
+            .or_else(|| self.behavior.bot_name.as_ref())
+    }
+
+    pub fn reply_name(&self) -> Option<f32> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_name())
+            .or(self.behavior.reply_name)
+    }
+
+    pub fn canned_responses(&self) -> Option<&Vec<CannedResponse>> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.canned_responses())
+            .or_else(|| self.behavior.canned_responses.as_ref())
+    }
+
+    pub fn learn_ack(&self) -> Option<bool> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.learn_ack())
+            .or(self.behavior.learn_ack)
+    }
+
+    pub fn quiet_chat_secs(&self) -> Option<u64> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.quiet_chat_secs())
+            .or(self.behavior.quiet_chat_secs)
+    }
+
+    pub fn quiet_chat_boost(&self) -> Option<f32> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.quiet_chat_boost())
+            .or(self.behavior.quiet_chat_boost)
+    }
+
+    pub fn learn_only_from_replies(&self) -> Option<bool> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.learn_only_from_replies())
+            .or(self.behavior.learn_only_from_replies)
+    }
+
+    pub fn min_sentence_words(&self) -> Option<usize> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.min_sentence_words())
+            .or(self.behavior.min_sentence_words)
+    }
+
+    pub fn max_sentence_words(&self) -> Option<usize> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.max_sentence_words())
+            .or(self.behavior.max_sentence_words)
+    }
+
+    pub fn output_mention_handling(&self) -> Option<MentionHandling> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.output_mention_handling())
+            .or(self.behavior.output_mention_handling)
     }
 
-    pub fn nick_patterns(&self) -> Option<&Vec<Pattern>> {
+    pub fn reply_scoring(&self) -> Option<ReplyScoring> {
         self.override_
             .as_ref()
-            .map(|o| o.nick_patterns())
-            .unwrap_or(self.behavior.nick_patterns.as_ref())
+            .and_then(|o| o.reply_scoring())
+            .or(self.behavior.reply_scoring)
     }
 
-    pub fn magic_patterns(&self) -> Option<&Vec<Pattern>> {
+    pub fn react_to_sentiment(&self) -> Option<bool> {
         self.override_
             .as_ref()
-            .map(|o| o.magic_patterns())
-            .unwrap_or(self.behavior.magic_patterns.as_ref())
+            .and_then(|o| o.react_to_sentiment())
+            .or(self.behavior.react_to_sentiment)
     }
 
-    pub fn blacklisted_patterns(&self) -> Option<&Vec<Pattern>> {
+    pub fn sentiment_reactions(&self) -> Option<&SentimentReactions> {
         self.override_
             .as_ref()
-            .map(|o| o.blacklisted_patterns())
-            .unwrap_or(self.behavior.blacklisted_patterns.as_ref())
+            .and_then(|o| o.sentiment_reactions())
+            .or_else(|| self.behavior.sentiment_reactions.as_ref())
     }
 
-    pub fn ignored_users(&self) -> Option<&Vec<Pattern>> {
+    pub fn min_unique_word_ratio(&self) -> Option<f32> {
         self.override_
             .as_ref()
-            .map(|o| o.ignored_users())
-            .unwrap_or(self.behavior.ignored_users.as_ref())
+            .and_then(|o| o.min_unique_word_ratio())
+            .or(self.behavior.min_unique_word_ratio)
+    }
+
+    pub fn min_response_words(&self) -> Option<usize> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.min_response_words())
+            .or(self.behavior.min_response_words)
+    }
+
+    pub fn short_responses(&self) -> Option<ShortResponseMode> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.short_responses())
+            .or(self.behavior.short_responses)
+    }
+
+    pub fn reply_cooldown_ms(&self) -> Option<u64> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_cooldown_ms())
+            .or(self.behavior.reply_cooldown_ms)
+    }
+
+    pub fn reply_cooldown_ms_per_word(&self) -> Option<u64> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.reply_cooldown_ms_per_word())
+            .or(self.behavior.reply_cooldown_ms_per_word)
+    }
+
+    pub fn max_repeated_punctuation(&self) -> Option<usize> {
+        self.override_
+            .as_ref()
+            .and_then(|o| o.max_repeated_punctuation())
+            .or(self.behavior.max_repeated_punctuation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_reply_template() {
+        assert!(validate_reply_template(None).is_ok());
+        assert!(validate_reply_template(Some(&"_{response}_".to_owned())).is_ok());
+        assert!(validate_reply_template(Some(&"_response_".to_owned())).is_err());
+    }
+
+    fn telegram_with_chat_ids(chat_ids: &[&str]) -> TelegramPlatform {
+        TelegramPlatform {
+            token: String::new(),
+            strip_entities: false,
+            reply_to_replies: false,
+            learn_attachments: false,
+            learn_forwards: false,
+            owner_id: None,
+            commands: Commands::new(),
+            username: None,
+            greet_on_join: false,
+            greeting: None,
+            reply_rate_multiplier: 1.0,
+            behavior: None,
+            chat_behaviors: Some(
+                chat_ids
+                    .iter()
+                    .map(|chat_id| ChatBehaviorOverrides {
+                        chat_id: (*chat_id).to_owned(),
+                        behavior: BehaviorOverride::default(),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_malformed_telegram_chat_id_is_reported_at_load() {
+        let valid = telegram_with_chat_ids(&["-1001234567890", "42", " -100 "]);
+        assert!(validate_telegram_chat_ids(&valid).is_ok());
+        for chat_id in &["@crabchat", "-100.5", "", "chat -100"] {
+            assert!(
+                validate_telegram_chat_ids(&telegram_with_chat_ids(&["-100", chat_id])).is_err()
+            );
+        }
+
+        let path = std::env::temp_dir().join("borg_test_malformed_chat_id.yml");
+        fs::write(
+            &path,
+            r#"
+dictionary_path: dictionary.json
+auto_save_period: 60
+behavior:
+  speaking: true
+  learning: true
+  reply_rate: 0.0
+  reply_nick: 1.0
+  reply_magic: 0.0
+  nick_patterns: []
+  magic_patterns: []
+  blacklisted_patterns: []
+  ignored_users: []
+telegram:
+  token: ""
+  chat_behaviors:
+    - chat_id: "@crabchat"
+      behavior: {}
+"#,
+        )
+        .unwrap();
+        let result = Config::load(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(ConfigError::ValidationError(message)) => assert!(message.contains("@crabchat")),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reaction_settings() {
+        let config: Config = serde_yaml::from_str(
+            r#"
+dictionary_path: dictionary.json
+auto_save_period: 60
+behavior:
+  speaking: false
+  learning: true
+  reply_rate: 0.0
+  reply_nick: 1.0
+  reply_magic: 0.0
+  nick_patterns: []
+  magic_patterns: []
+  blacklisted_patterns: []
+  ignored_users: []
+telegram:
+  token: ""
+  behavior: {}
+  chat_behaviors:
+    - chat_id: "-100"
+      behavior:
+        learn_ack: true
+    - chat_id: "-200"
+      behavior:
+        learn_ack: false
+        react_to_sentiment: true
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                ("telegram chat -100".to_owned(), "learn_ack"),
+                ("telegram chat -200".to_owned(), "react_to_sentiment"),
+            ],
+            config.reaction_settings("telegram")
+        );
+        assert!(config.reaction_settings("irc").is_empty());
+
+        let config = Config {
+            behavior: MainBehavior {
+                learn_ack: true,
+                ..config.behavior
+            },
+            ..config
+        };
+        assert_eq!(
+            vec![
+                ("behavior".to_owned(), "learn_ack"),
+                ("telegram chat -100".to_owned(), "learn_ack"),
+                ("telegram chat -200".to_owned(), "react_to_sentiment"),
+            ],
+            config.reaction_settings("telegram")
+        );
+    }
+
+    #[test]
+    fn test_resolve_behavior() {
+        let platform = BehaviorOverride {
+            reply_rate: Some(0.1),
+            speaking: Some(true),
+            ..BehaviorOverride::default()
+        };
+        let chat = BehaviorOverride {
+            speaking: Some(false),
+            ..BehaviorOverride::default()
+        };
+
+        let behavior = resolve_behavior(vec![Some(&platform), Some(&chat)], 1.0).unwrap();
+        assert_eq!(Some(false), behavior.is_speaking());
+        assert_eq!(Some(0.1), behavior.reply_rate());
+
+        // Missing overrides are skipped, whatever their place.
+        let behavior = resolve_behavior(vec![None, Some(&chat), None], 1.0).unwrap();
+        assert_eq!(Some(false), behavior.is_speaking());
+        assert_eq!(None, behavior.reply_rate());
+
+        assert!(resolve_behavior(vec![None, None], 1.0).is_none());
+        let behavior = resolve_behavior(vec![None], 0.5).unwrap();
+        assert_eq!(0.5, behavior.reply_rate_multiplier());
+        let behavior = resolve_behavior(vec![Some(&platform)], 0.5).unwrap();
+        assert_eq!(0.5, behavior.reply_rate_multiplier());
+        assert_eq!(Some(true), behavior.is_speaking());
+    }
+
+    #[test]
+    fn test_reply_rate_multiplier() {
+        let main = MainBehavior {
+            reply_rate: 0.2,
+            reply_magic: 0.5,
+            reply_nick: 1.0,
+            ..MainBehavior::default()
+        };
+        let chat = BehaviorOverride {
+            reply_rate: Some(0.4),
+            ..BehaviorOverride::default()
+        };
+
+        let unscaled = Some(BehaviorOverrideValueResolver::new(&chat, None));
+        let b = BehaviorValueResolver::new(&main, &unscaled);
+        assert_eq!(0.4, b.reply_rate());
+
+        let scaled =
+            with_reply_rate_multiplier(Some(BehaviorOverrideValueResolver::new(&chat, None)), 0.5);
+        let b = BehaviorValueResolver::new(&main, &scaled);
+        assert_eq!(0.5, b.reply_rate_multiplier());
+        assert_eq!(0.4 * 0.5, b.reply_rate());
+        assert_eq!(0.5 * 0.5, b.reply_magic());
+        assert_eq!(1.0 * 0.5, b.reply_nick());
+
+        // A chat without overrides still gets the platform's multiplier.
+        let scaled = with_reply_rate_multiplier(None, 2.0);
+        let b = BehaviorValueResolver::new(&main, &scaled);
+        assert_eq!(0.2 * 2.0, b.reply_rate());
+        assert!(with_reply_rate_multiplier(None, 1.0).is_none());
+
+        assert!(validate_reply_rate_multiplier("irc", 0.0).is_ok());
+        assert!(validate_reply_rate_multiplier("irc", -1.0).is_err());
+        assert!(validate_reply_rate_multiplier("irc", std::f32::NAN).is_err());
+    }
+
+    #[test]
+    fn test_pattern_merge() {
+        let patterns = |originals: &[&str]| -> Vec<Pattern> {
+            originals.iter().map(|o| Pattern::new(o).unwrap()).collect()
+        };
+        let originals = |patterns: Vec<&Pattern>| -> Vec<String> {
+            patterns.iter().map(|p| p.original.clone()).collect()
+        };
+        let main = MainBehavior {
+            nick_patterns: patterns(&["borg"]),
+            ignored_users: patterns(&["spammer"]),
+            ..MainBehavior::default()
+        };
+        let platform = BehaviorOverride {
+            nick_patterns: Some(patterns(&["cyborg"])),
+            pattern_merge: PatternMerge {
+                nick_patterns: MergeMode::Append,
+                ..PatternMerge::default()
+            },
+            ..BehaviorOverride::default()
+        };
+        let chat = BehaviorOverride {
+            nick_patterns: Some(patterns(&["crabbot"])),
+            ignored_users: Some(patterns(&["lobster"])),
+            pattern_merge: PatternMerge {
+                nick_patterns: MergeMode::Append,
+                ..PatternMerge::default()
+            },
+            ..BehaviorOverride::default()
+        };
+
+        let appended = Some(BehaviorOverrideValueResolver::new(
+            &platform,
+            Some(Box::new(BehaviorOverrideValueResolver::new(&chat, None))),
+        ));
+        let b = BehaviorValueResolver::new(&main, &appended);
+        assert_eq!(
+            vec!["borg", "cyborg", "crabbot"],
+            originals(b.nick_patterns())
+        );
+        assert!(crate::pattern::matches_any("borg", b.nick_patterns()).is_some());
+        assert!(crate::pattern::matches_any("crabbot", b.nick_patterns()).is_some());
+        // Lists are replaced by default.
+        assert_eq!(vec!["lobster"], originals(b.ignored_users()));
+        assert_eq!(0, b.magic_patterns().len());
+
+        // A replacing override drops everything it overrides, even what was
+        // appended before it.
+        let chat = BehaviorOverride {
+            nick_patterns: Some(patterns(&["crabbot"])),
+            ..BehaviorOverride::default()
+        };
+        let replaced = Some(BehaviorOverrideValueResolver::new(
+            &platform,
+            Some(Box::new(BehaviorOverrideValueResolver::new(&chat, None))),
+        ));
+        let b = BehaviorValueResolver::new(&main, &replaced);
+        assert_eq!(vec!["crabbot"], originals(b.nick_patterns()));
+        assert_eq!(vec!["spammer"], originals(b.ignored_users()));
+    }
+
+    #[test]
+    fn test_validate_language_filter() {
+        let filter = |language: &str| LanguageFilter {
+            language: language.to_owned(),
+            min_confidence: default_language_min_confidence(),
+            uncertain: UncertainLanguage::default(),
+        };
+        assert!(validate_language_filter(None).is_ok());
+        assert!(validate_language_filter(Some(&filter("eng"))).is_ok());
+        assert!(validate_language_filter(Some(&filter("english"))).is_err());
     }
 }
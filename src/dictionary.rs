@@ -1,17 +1,21 @@
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use onig::Regex;
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::error;
 use std::fmt;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug)]
 pub enum Error {
     IOError(io::Error),
     JSONError(serde_json::Error),
+    CompressionError(io::Error),
+    UnsupportedVersion(u32),
 }
 
 impl fmt::Display for Error {
@@ -19,6 +23,12 @@ impl fmt::Display for Error {
         match *self {
             Error::IOError(ref e) => e.fmt(f),
             Error::JSONError(ref e) => e.fmt(f),
+            Error::CompressionError(ref e) => write!(f, "Invalid gzip data: {}", e),
+            Error::UnsupportedVersion(version) => write!(
+                f,
+                "Dictionary format version {} is newer than the supported version {}",
+                version, DICTIONARY_VERSION
+            ),
         }
     }
 }
@@ -28,6 +38,8 @@ impl error::Error for Error {
         match *self {
             Error::IOError(ref e) => Some(e),
             Error::JSONError(ref e) => Some(e),
+            Error::CompressionError(ref e) => Some(e),
+            Error::UnsupportedVersion(_) => None,
         }
     }
 }
@@ -46,15 +58,203 @@ impl From<serde_json::Error> for Error {
 
 type Indices = HashMap<String, Vec<usize>>;
 
+/// The first bytes of every gzip file.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// The version of the on-disk dictionary format. It must be bumped whenever
+/// the serialized shape of the dictionary or the way it is indexed changes,
+/// so that older versions of the bot refuse a file they would misread.
+///
+/// Version 4 adds the counts, version 5 adds max_word_length, and version 6
+/// adds the successors and case_folding.
+pub const DICTIONARY_VERSION: u32 = 6;
+
+/// Dictionaries written before the format was versioned have no version
+/// field and are treated as version 1.
+fn legacy_version() -> u32 {
+    1
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Dictionary {
+    #[serde(default = "legacy_version")]
+    version: u32,
     sentences: Vec<String>,
+    /// How many times each sentence was learned, in the same order as the
+    /// sentences. Dictionaries saved before counts were kept have none, and
+    /// every sentence in them counts as learned once.
+    #[serde(default)]
+    counts: Vec<u32>,
     indices: Indices,
+    /// Who taught each sentence, keyed by the sentence. Sentences learned
+    /// without attribution have no entry.
+    #[serde(default)]
+    sources: HashMap<String, Source>,
+    /// The sentence that followed each sentence the last time it was learned
+    /// as part of a longer message, keyed by the sentence. It is only kept
+    /// when track_successors is set.
+    #[serde(default)]
+    successors: HashMap<String, String>,
+    #[serde(skip)]
+    track_successors: bool,
+    /// Sentences that are never removed, whatever is forgotten or pruned.
+    /// They come from the configuration every time the bot starts.
+    #[serde(skip)]
+    pinned: HashSet<String>,
+    #[serde(skip)]
+    normalization: Normalization,
+    /// How words were folded when they were indexed. Like elongation, it is
+    /// saved so that the indices can be rebuilt when it changes.
+    #[serde(default)]
+    case_folding: CaseFolding,
+    /// How elongated words were indexed. It is saved along with the indices,
+    /// so that they can be rebuilt when the setting changes.
+    #[serde(default)]
+    elongation: Elongation,
+    /// Words longer than this many characters are left out of the indices.
+    /// Like elongation, it is saved so that the indices can be rebuilt when
+    /// it changes.
+    #[serde(default)]
+    max_word_length: Option<usize>,
+    /// Sentences in which at least this share of the words are longer than
+    /// max_word_length are not learned.
+    #[serde(skip)]
+    long_word_share: Option<f32>,
+    /// The position of every known sentence, keyed by its normalized form,
+    /// used to find duplicates and near-duplicates without going through
+    /// every sentence. Sentences that normalize the same way, which were
+    /// learned before normalization was turned on, are found at the first
+    /// one.
+    #[serde(skip)]
+    positions: HashMap<String, usize>,
+    /// Sentences learned since the dictionary was last saved.
+    #[serde(skip)]
+    unsaved: Vec<String>,
+    /// Set when sentences are removed, which the journal cannot record.
+    #[serde(skip)]
+    needs_full_save: bool,
+}
+
+/// SaveMode decides how the dictionary is saved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SaveMode {
+    /// The whole dictionary is written every time.
+    Full,
+    /// New sentences are appended to a journal next to the dictionary file,
+    /// which is merged into the dictionary when it is compacted.
+    Journal,
+}
+
+impl Default for SaveMode {
+    fn default() -> Self {
+        SaveMode::Full
+    }
+}
+
+/// JournalEntry is one line of the journal.
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalEntry {
+    sentence: String,
+    #[serde(default)]
+    source: Option<Source>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    successor: Option<String>,
+}
+
+/// Normalization decides how similar a sentence has to be to a known one to
+/// be considered a duplicate. Sentences are always stored as they were
+/// written; only the duplicate check uses the normalized form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Normalization {
+    /// Only exactly equal sentences are duplicates.
+    Exact,
+    /// Trailing punctuation is ignored, so "lol" and "lol!!" are duplicates.
+    Punctuation,
+    /// Like Punctuation, and runs of a repeated character are collapsed, so
+    /// "lol" and "loool" are duplicates too.
+    Aggressive,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization::Exact
+    }
+}
+
+/// CaseFolding decides how words are folded when they are indexed or looked
+/// up, so that they are found whatever their case. Sentences are always
+/// stored in lowercase, and are not changed by the folding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaseFolding {
+    /// Words are only lowercased.
+    Lowercase,
+    /// Unicode case folding, so that e.g. "STRASSE" and "straße" match.
+    Unicode,
+    /// Like Unicode, but with the Turkish dotted and dotless i, so that
+    /// "İstanbul" matches "istanbul" and "IRMAK" matches "ırmak". Sentences
+    /// are also lowercased with the Turkish rules.
+    Turkish,
+}
+
+impl Default for CaseFolding {
+    fn default() -> Self {
+        CaseFolding::Lowercase
+    }
+}
+
+/// Elongation decides how words stretched out by repeating a letter, like
+/// "soooo", are indexed. Sentences are always stored as they were written;
+/// only the words they are found by are shortened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Elongation {
+    /// Words are indexed as they were written.
+    Keep,
+    /// Runs of three or more of the same letter are shortened to two, so
+    /// that "gooood" is found by "good".
+    Double,
+    /// Runs of three or more of the same letter are shortened to one, so
+    /// that "soooo" is found by "so".
+    Single,
+}
+
+impl Default for Elongation {
+    fn default() -> Self {
+        Elongation::Keep
+    }
+}
+
+/// Source records where a sentence was learned from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Source {
+    pub platform: String,
+    pub chat_id: String,
+    pub user_id: String,
+}
+
+impl Source {
+    pub fn new(platform: &str, chat_id: &str, user_id: &str) -> Source {
+        Source {
+            platform: platform.to_owned(),
+            chat_id: chat_id.to_owned(),
+            user_id: user_id.to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub sentence_count: usize,
+    pub word_count: usize,
 }
 
 impl PartialEq for Dictionary {
     fn eq(&self, other: &Dictionary) -> bool {
-        self.sentences == other.sentences && self.indices == other.indices
+        self.version == other.version
+            && self.sentences == other.sentences
+            && (0..self.sentences.len()).all(|i| self.count(i) == other.count(i))
+            && self.indices == other.indices
+            && self.sources == other.sources
+            && self.successors == other.successors
     }
 }
 
@@ -63,34 +263,205 @@ impl Eq for Dictionary {}
 impl Dictionary {
     // load loads a dictionary from the specified path.
     // If there is no file at the specified path, it will create a blank
-    // dictionary at that location.
+    // dictionary at that location. The file is parsed as it is read, so that
+    // its contents are never in memory next to the parsed dictionary.
     pub fn load(path: &Path) -> Result<Self, Error> {
         if !path.is_file() {
             let d = Dictionary::new_empty();
             d.write_to_disk(&path)?;
             Ok(d)
         } else {
-            let data = fs::read_to_string(path)?;
-            let dict: Dictionary = serde_json::from_str(&data)?;
+            let mut dict = Dictionary::from_reader(io::BufReader::new(fs::File::open(path)?))?;
+            if dict.version > DICTIONARY_VERSION {
+                return Err(Error::UnsupportedVersion(dict.version));
+            }
+            if dict.version < DICTIONARY_VERSION {
+                warn!(
+                    "Dictionary format version {} is outdated. Migrating it to version {}.",
+                    dict.version, DICTIONARY_VERSION
+                );
+                dict.migrate();
+                if let Err(e) = dict.write_to_disk(path) {
+                    warn!("Could not save the migrated dictionary: {}", e);
+                }
+            }
+            dict.merge_journal(&journal_path(path))?;
             Ok(dict)
         }
     }
 
+    // from_reader parses a dictionary as it is read. A gzip-compressed
+    // dictionary is recognized by its first bytes. The dictionary is not
+    // migrated, since only load knows where to save the migrated one.
+    pub fn from_reader(mut reader: impl BufRead) -> Result<Self, Error> {
+        let mut dict: Dictionary = if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+            let decoder = io::BufReader::new(GzDecoder::new(reader));
+            serde_json::from_reader(decoder).map_err(|e| {
+                if e.is_io() {
+                    Error::CompressionError(e.into())
+                } else {
+                    Error::JSONError(e)
+                }
+            })?
+        } else {
+            serde_json::from_reader(reader)?
+        };
+        dict.fill_counts();
+        dict.reindex_positions();
+        Ok(dict)
+    }
+
+    // migrate upgrades a dictionary read from an older format version to the
+    // current one. The stored sentences are split and tokenized again under
+    // the current rules, and the indices are rebuilt from scratch.
+    fn migrate(&mut self) {
+        let sentences = std::mem::replace(&mut self.sentences, vec![]);
+        let counts = std::mem::replace(&mut self.counts, vec![]);
+        self.reset_indices();
+        for (sentence, count) in sentences.iter().zip(counts) {
+            self.learn_with_source(sentence, None, count);
+        }
+        self.rebuild_indices();
+        self.unsaved.clear();
+        self.version = DICTIONARY_VERSION;
+    }
+
+    // merge_journal learns the sentences in the journal at the given path, if
+    // there is one. A line that cannot be read, which is what a crash in the
+    // middle of a write leaves behind, is skipped.
+    fn merge_journal(&mut self, path: &Path) -> Result<(), Error> {
+        if !path.is_file() {
+            return Ok(());
+        }
+        let data = fs::read_to_string(path)?;
+        for line in data.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<JournalEntry>(line) {
+                Ok(entry) => {
+                    match entry.source {
+                        Some(ref source) => self.learn_attributed(&entry.sentence, source),
+                        None => self.learn(&entry.sentence),
+                    };
+                    if let Some(successor) = entry.successor {
+                        self.successors.insert(entry.sentence, successor);
+                    }
+                }
+                Err(e) => warn!("Skipping unreadable journal line {:?}: {}", line, e),
+            }
+        }
+        self.rebuild_indices();
+        // The merged sentences are already in the journal.
+        self.unsaved.clear();
+        Ok(())
+    }
+
+    // save saves the dictionary according to the save mode. In journal mode,
+    // only the sentences learned since the last save are appended to the
+    // journal, unless sentences were removed since then.
+    pub fn save(&mut self, path: &Path, mode: SaveMode) -> Result<(), Error> {
+        match mode {
+            SaveMode::Journal if !self.needs_full_save => self.append_to_journal(path),
+            _ => self.compact(path),
+        }
+    }
+
+    fn append_to_journal(&mut self, path: &Path) -> Result<(), Error> {
+        if self.unsaved.is_empty() {
+            return Ok(());
+        }
+        let mut data = String::new();
+        for sentence in &self.unsaved {
+            let entry = JournalEntry {
+                sentence: sentence.clone(),
+                source: self.sources.get(sentence).cloned(),
+                successor: self.successors.get(sentence).cloned(),
+            };
+            data.push_str(&serde_json::to_string(&entry)?);
+            data.push('\n');
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(journal_path(path))?
+            .write_all(data.as_bytes())?;
+        self.unsaved.clear();
+        Ok(())
+    }
+
+    // compact writes the whole dictionary and removes the journal, since
+    // everything in it is now part of the dictionary file.
+    pub fn compact(&mut self, path: &Path) -> Result<(), Error> {
+        self.write_to_disk(path)?;
+        let journal = journal_path(path);
+        if journal.is_file() {
+            fs::remove_file(journal)?;
+        }
+        self.unsaved.clear();
+        self.needs_full_save = false;
+        Ok(())
+    }
+
+    // write_to_disk writes the whole dictionary to the path, compressed with
+    // gzip if the path ends in ".gz".
     pub fn write_to_disk(&self, path: &Path) -> Result<(), Error> {
-        let json = serde_json::to_string(&self)?;
-        fs::write(path, json)?;
+        if is_compressed_path(path) {
+            let file = io::BufWriter::new(fs::File::create(path)?);
+            let mut encoder = GzEncoder::new(file, Compression::default());
+            serde_json::to_writer(&mut encoder, &self)?;
+            encoder
+                .finish()
+                .and_then(|mut file| file.flush())
+                .map_err(Error::CompressionError)?;
+        } else {
+            let json = serde_json::to_string(&self)?;
+            fs::write(path, json)?;
+        }
+        Ok(())
+    }
+
+    // export_text writes every sentence on a line of its own, in the order
+    // they are stored, so that they can be read by other tools or imported
+    // again. Sentences are stored in lowercase, so they are exported in
+    // lowercase rather than as they were written.
+    pub fn export_text(&self, path: &Path) -> Result<(), Error> {
+        let mut file = io::BufWriter::new(fs::File::create(path)?);
+        for sentence in &self.sentences {
+            writeln!(file, "{}", sentence)?;
+        }
+        file.flush()?;
         Ok(())
     }
 
     pub fn new_empty() -> Dictionary {
         Dictionary {
+            version: DICTIONARY_VERSION,
             sentences: vec![],
+            counts: vec![],
             indices: HashMap::new(),
+            sources: HashMap::new(),
+            successors: HashMap::new(),
+            track_successors: false,
+            pinned: HashSet::new(),
+            normalization: Normalization::default(),
+            case_folding: CaseFolding::default(),
+            elongation: Elongation::default(),
+            max_word_length: None,
+            long_word_share: None,
+            positions: HashMap::new(),
+            unsaved: vec![],
+            needs_full_save: false,
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            sentence_count: self.sentences.len(),
+            word_count: self.indices.len(),
         }
     }
 
     fn reset_indices(&mut self) {
         self.indices = HashMap::new();
+        self.positions = HashMap::new();
     }
 
     pub fn needs_to_build_indices(&self) -> bool {
@@ -99,67 +470,565 @@ impl Dictionary {
 
     pub fn rebuild_indices(&mut self) {
         self.reset_indices();
-        sort_sentences(&mut self.sentences);
+        self.fill_counts();
+        sort_sentences(&mut self.sentences, &mut self.counts);
 
         let mut indices: Indices = HashMap::new();
         self.sentences
             .iter()
             .enumerate()
-            .map(|(i, sentence)| (i, sentence.to_lowercase()))
+            .map(|(i, sentence)| (i, fold_case(sentence, self.case_folding)))
             .for_each(|(i, sentence)| {
                 println!("Indexing: {:?}", sentence);
                 let words = split_words(&sentence);
-                for word in words {
-                    insert_word_into_indices(&mut indices, word, i);
+                for word in words.into_iter().filter(|w| !self.is_too_long(w)) {
+                    insert_word_into_indices(&mut indices, &self.word_key(word), i);
                 }
             });
-        self.indices = indices
+        self.indices = indices;
+        self.reindex_positions();
+    }
+
+    // reindex_positions rebuilds the positions of the sentences, which change
+    // whenever the sentences are sorted.
+    fn reindex_positions(&mut self) {
+        let mut positions = HashMap::with_capacity(self.sentences.len());
+        for (i, sentence) in self.sentences.iter().enumerate() {
+            positions
+                .entry(normalize(sentence, self.normalization))
+                .or_insert(i);
+        }
+        self.positions = positions;
     }
 
-    fn knows_sentence(&self, sentence: &str) -> bool {
+    #[cfg(test)]
+    pub(crate) fn knows_sentence(&self, sentence: &str) -> bool {
         self.sentences.iter().any(|x| x == sentence)
     }
 
+    // count returns how many times the sentence at the given index was
+    // learned.
+    fn count(&self, i: usize) -> u32 {
+        self.counts.get(i).copied().unwrap_or(1)
+    }
+
+    // fill_counts gives every sentence that has no count yet a count of one,
+    // so that the counts line up with the sentences again.
+    fn fill_counts(&mut self) {
+        self.counts.resize(self.sentences.len(), 1);
+    }
+
+    // set_normalization changes how near-duplicate sentences are detected
+    // when learning. It does not change the sentences that are already known.
+    pub fn set_normalization(&mut self, normalization: Normalization) {
+        self.normalization = normalization;
+        self.reindex_positions();
+    }
+
+    // set_case_folding changes how words are folded in the indices. The
+    // indices are rebuilt if they were built with a different setting, and
+    // the known sentences are left as they are.
+    pub fn set_case_folding(&mut self, case_folding: CaseFolding) {
+        if case_folding == self.case_folding {
+            return;
+        }
+        self.case_folding = case_folding;
+        self.needs_full_save = true;
+        self.rebuild_indices();
+    }
+
+    // set_elongation changes how elongated words are indexed. The indices
+    // are rebuilt if they were built with a different setting.
+    pub fn set_elongation(&mut self, elongation: Elongation) {
+        if elongation == self.elongation {
+            return;
+        }
+        self.elongation = elongation;
+        self.needs_full_save = true;
+        self.rebuild_indices();
+    }
+
+    // set_max_word_length changes how long a word can be to be indexed. The
+    // indices are rebuilt if they were built with a different limit.
+    pub fn set_max_word_length(&mut self, max_word_length: Option<usize>) {
+        if max_word_length == self.max_word_length {
+            return;
+        }
+        self.max_word_length = max_word_length;
+        self.needs_full_save = true;
+        self.rebuild_indices();
+    }
+
+    // set_long_word_share changes which share of overlong words makes a
+    // sentence too noisy to be learned. It does not change the sentences that
+    // are already known.
+    pub fn set_long_word_share(&mut self, long_word_share: Option<f32>) {
+        self.long_word_share = long_word_share;
+    }
+
+    // is_too_long returns true if the word is too long to be indexed.
+    fn is_too_long(&self, word: &str) -> bool {
+        self.max_word_length
+            .map_or(false, |max| word.chars().count() > max)
+    }
+
+    // has_too_many_long_words returns true if the sentence is mostly made of
+    // words that are too long, like pasted hashes, and should not be learned.
+    fn has_too_many_long_words(&self, sentence: &str) -> bool {
+        let share = match self.long_word_share {
+            Some(share) => share,
+            None => return false,
+        };
+        let words = split_words(sentence);
+        let long = words.iter().filter(|w| self.is_too_long(w)).count();
+        long > 0 && long as f32 >= share * words.len() as f32
+    }
+
+    // set_track_successors decides whether the sentence that follows each
+    // sentence in a message is recorded, and appended to the responses built
+    // from it.
+    pub fn set_track_successors(&mut self, track_successors: bool) {
+        self.track_successors = track_successors;
+    }
+
+    // successor_of returns the sentence that followed the given one the last
+    // time it was learned, if any.
+    #[cfg(test)]
+    pub fn successor_of(&self, sentence: &str) -> Option<&str> {
+        self.successors
+            .get(&self.lowercase(sentence))
+            .map(String::as_str)
+    }
+
+    // record_successor links a sentence to the one that followed it, when
+    // successors are tracked.
+    fn record_successor(&mut self, previous: Option<String>, sentence: &str) {
+        if let (true, Some(previous)) = (self.track_successors, previous) {
+            self.successors.insert(previous, sentence.to_owned());
+        }
+    }
+
+    // lowercase returns the text the way sentences are stored.
+    fn lowercase(&self, s: &str) -> String {
+        lowercase(s, self.case_folding)
+    }
+
+    // fold returns the text the way words are indexed.
+    fn fold(&self, s: &str) -> String {
+        fold_case(s, self.case_folding)
+    }
+
+    // word_key returns the key a word is indexed by.
+    fn word_key(&self, word: &str) -> String {
+        shorten_elongation(word, self.elongation)
+    }
+
+    // position_of_similar_sentence returns the index of the known sentence
+    // that the given one is a duplicate of.
+    fn position_of_similar_sentence(&self, sentence: &str) -> Option<usize> {
+        self.positions
+            .get(&normalize(sentence, self.normalization))
+            .copied()
+    }
+
     fn knows_word(&self, word: &str) -> bool {
         self.indices.contains_key(word)
     }
 
+    // learn learns the sentences in the line and returns whether any of them
+    // were new. A sentence that is already known is counted again instead, so
+    // that sentences which are said often are picked more often.
     pub fn learn(&mut self, line: &str) -> bool {
+        self.learn_with_source(line, None, 1)
+    }
+
+    // learn_attributed works like learn, but also records the source of every
+    // new sentence so that it can be looked up later with source_of.
+    pub fn learn_attributed(&mut self, line: &str, source: &Source) -> bool {
+        self.learn_with_source(line, Some(source), 1)
+    }
+
+    fn learn_with_source(&mut self, line: &str, source: Option<&Source>, count: u32) -> bool {
+        self.fill_counts();
         let mut learned_something = false;
-        for sentence in split_sentences(&line.to_lowercase()) {
-            if self.knows_sentence(sentence) {
+        let mut previous = None;
+        for sentence in split_sentences(&self.lowercase(line)) {
+            if self.has_too_many_long_words(sentence) {
+                debug!(
+                    "[learn] Skipping {:?}, which has too many long words",
+                    sentence
+                );
+                previous = None;
+                continue;
+            }
+            if let Some(i) = self.position_of_similar_sentence(sentence) {
+                self.counts[i] = self.counts[i].saturating_add(count);
+                // The journal records the sentence again, so that the count
+                // is restored when it is merged.
+                let known = self.sentences[i].clone();
+                self.record_successor(previous, &known);
+                self.unsaved.push(known.clone());
+                previous = Some(known);
                 continue;
             }
             self.sentences.push(sentence.to_owned());
+            self.counts.push(count);
             let sentence_index = self.sentences.len() - 1;
+            self.positions
+                .insert(normalize(sentence, self.normalization), sentence_index);
 
             // Update the indices with the sentence's words
-            for word in split_words(&sentence) {
-                insert_word_into_indices(&mut self.indices, &word, sentence_index);
+            let folded = self.fold(sentence);
+            for word in split_words(&folded) {
+                if self.is_too_long(word) {
+                    continue;
+                }
+                let key = self.word_key(word);
+                insert_word_into_indices(&mut self.indices, &key, sentence_index);
             }
+            if let Some(source) = source {
+                self.sources.insert(sentence.to_owned(), source.clone());
+            }
+            self.record_successor(previous, sentence);
+            self.unsaved.push(sentence.to_owned());
+            previous = Some(sentence.to_owned());
             learned_something = true;
         }
         learned_something
     }
 
+    // learn_batch learns many lines at once and returns how many new sentences
+    // were added. Unlike calling learn for every line, known sentences are
+    // looked up in a map and the new sentences are indexed in a single pass,
+    // which makes it much faster for importing large corpora.
+    pub fn learn_batch<'a>(&mut self, lines: impl Iterator<Item = &'a str>) -> usize {
+        self.learn_batch_attributed(lines.map(|line| (line, None)))
+    }
+
+    // learn_batch_attributed works like learn_batch, but also records the
+    // source that comes with each line for its new sentences, like
+    // learn_attributed. Lines without a source are learned without one.
+    pub fn learn_batch_attributed<'a, 'b>(
+        &mut self,
+        lines: impl Iterator<Item = (&'a str, Option<&'b Source>)>,
+    ) -> usize {
+        self.fill_counts();
+        let normalization = self.normalization;
+        let first_index = self.sentences.len();
+        // The positions of the new sentences, before they are sorted.
+        let mut new_positions: HashMap<String, usize> = HashMap::new();
+        let mut new_sentences: Vec<String> = vec![];
+        let mut new_counts = vec![];
+        for (line, source) in lines {
+            let line = lowercase(line, self.case_folding);
+            let mut previous = None;
+            for sentence in split_sentences(&line) {
+                if self.has_too_many_long_words(sentence) {
+                    previous = None;
+                    continue;
+                }
+                let key = normalize(sentence, normalization);
+                let position = self
+                    .positions
+                    .get(&key)
+                    .or_else(|| new_positions.get(&key))
+                    .copied();
+                let stored = match position {
+                    Some(i) if i < first_index => {
+                        self.counts[i] = self.counts[i].saturating_add(1);
+                        self.sentences[i].clone()
+                    }
+                    Some(i) => {
+                        let count: &mut u32 = &mut new_counts[i - first_index];
+                        *count = count.saturating_add(1);
+                        new_sentences[i - first_index].clone()
+                    }
+                    None => {
+                        new_positions.insert(key, first_index + new_sentences.len());
+                        new_sentences.push(sentence.to_owned());
+                        new_counts.push(1);
+                        if let Some(source) = source {
+                            self.sources.insert(sentence.to_owned(), source.clone());
+                        }
+                        sentence.to_owned()
+                    }
+                };
+                self.record_successor(previous, &stored);
+                self.unsaved.push(stored.clone());
+                previous = Some(stored);
+            }
+        }
+        sort_sentences(&mut new_sentences, &mut new_counts);
+
+        for (i, sentence) in new_sentences.iter().enumerate() {
+            let sentence_index = first_index + i;
+            let folded = fold_case(sentence, self.case_folding);
+            for word in split_words(&folded) {
+                if self.is_too_long(word) {
+                    continue;
+                }
+                // Sentence indices only grow here, so checking the last entry
+                // is enough to avoid duplicates.
+                let key = shorten_elongation(word, self.elongation);
+                let entry = self.indices.entry(key).or_insert_with(Vec::new);
+                if entry.last() != Some(&sentence_index) {
+                    entry.push(sentence_index);
+                }
+            }
+        }
+
+        for (i, sentence) in new_sentences.iter().enumerate() {
+            self.positions
+                .insert(normalize(sentence, normalization), first_index + i);
+        }
+        let added = new_sentences.len();
+        self.sentences.extend(new_sentences);
+        self.counts.extend(new_counts);
+        added
+    }
+
+    // merge adds the sentences of another dictionary that this one does not
+    // know yet, along with their sources, and returns how many were added.
+    // The indices of the two dictionaries point at different positions, so
+    // they are rebuilt from scratch rather than combined.
+    pub fn merge(&mut self, mut other: Dictionary) -> usize {
+        self.fill_counts();
+        other.fill_counts();
+        let mut sources = other.sources;
+        let mut added = 0;
+        for (sentence, count) in other.sentences.into_iter().zip(other.counts) {
+            let key = normalize(&sentence, self.normalization);
+            if self.positions.contains_key(&key) {
+                continue;
+            }
+            self.positions.insert(key, self.sentences.len());
+            if let Some(source) = sources.remove(&sentence) {
+                self.sources.insert(sentence.clone(), source);
+            }
+            self.sentences.push(sentence);
+            self.counts.push(count);
+            added += 1;
+        }
+        for (sentence, successor) in other.successors {
+            self.successors.entry(sentence).or_insert(successor);
+        }
+        if added > 0 {
+            self.needs_full_save = true;
+            self.rebuild_indices();
+        }
+        added
+    }
+
+    // pin learns the sentences of every line, unless they are already known,
+    // and protects them from forget and prune_rare_words. Pinning a known
+    // sentence does not count it again, so that pinning the same lines every
+    // time the bot starts does not make them more likely to be picked.
+    pub fn pin(&mut self, lines: &[String]) {
+        for line in lines {
+            for sentence in split_sentences(&self.lowercase(line)) {
+                if self.position_of_similar_sentence(sentence).is_none() {
+                    self.learn_with_source(sentence, None, 1);
+                }
+                if let Some(i) = self.position_of_similar_sentence(sentence) {
+                    self.pinned.insert(self.sentences[i].clone());
+                }
+            }
+        }
+    }
+
+    // is_pinned returns true if the sentence is protected by pin.
+    #[cfg(test)]
+    pub fn is_pinned(&self, sentence: &str) -> bool {
+        self.pinned.contains(&self.lowercase(sentence))
+    }
+
+    // forget removes every sentence that contains the phrase and returns how
+    // many were removed. Pinned sentences are kept.
+    pub fn forget(&mut self, phrase: &str) -> usize {
+        let phrase = self.fold(phrase.trim());
+        if phrase.is_empty() {
+            return 0;
+        }
+        let case_folding = self.case_folding;
+        self.remove_sentences(|sentence| fold_case(sentence, case_folding).contains(&phrase))
+    }
+
+    // forgettable returns the sentences forget would remove for the phrase,
+    // without removing them.
+    pub fn forgettable(&self, phrase: &str) -> Vec<&str> {
+        let phrase = self.fold(phrase.trim());
+        if phrase.is_empty() {
+            return vec![];
+        }
+        self.sentences
+            .iter()
+            .filter(|sentence| {
+                self.fold(sentence).contains(&phrase) && !self.pinned.contains(*sentence)
+            })
+            .map(String::as_str)
+            .collect()
+    }
+
+    // prune_rare_words removes the index entries of words that appear in fewer
+    // than min_count sentences, and returns how many were removed. Sentences
+    // made only of such words could never be picked again, so they are
+    // removed as well. The words of pinned sentences are never rare.
+    pub fn prune_rare_words(&mut self, min_count: usize) -> usize {
+        let case_folding = self.case_folding;
+        let elongation = self.elongation;
+        let pinned_words: HashSet<String> = self
+            .pinned
+            .iter()
+            .flat_map(|sentence| {
+                split_words(&fold_case(sentence, case_folding))
+                    .into_iter()
+                    .map(|word| shorten_elongation(word, elongation))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let rare: HashSet<String> = self
+            .indices
+            .iter()
+            .filter(|(word, sentences)| {
+                sentences.len() < min_count && !pinned_words.contains(*word)
+            })
+            .map(|(word, _)| word.clone())
+            .collect();
+        if rare.is_empty() {
+            return 0;
+        }
+        self.remove_sentences(|sentence| {
+            split_words(&fold_case(sentence, case_folding))
+                .iter()
+                .all(|word| rare.contains(&shorten_elongation(word, elongation)))
+        });
+        // Rebuilding the indices after removing sentences brings back the
+        // entries of rare words from the sentences that were kept.
+        self.indices.retain(|word, _| !rare.contains(word));
+        rare.len()
+    }
+
+    // remove_sentences removes every sentence for which the predicate returns
+    // true, except for the pinned ones, keeping the indices and everything
+    // else about the sentences consistent. It returns how many sentences were
+    // removed.
+    fn remove_sentences(&mut self, predicate: impl Fn(&str) -> bool) -> usize {
+        let pinned = std::mem::replace(&mut self.pinned, HashSet::new());
+        let predicate = |sentence: &str| predicate(sentence) && !pinned.contains(sentence);
+        self.fill_counts();
+        let before = self.sentences.len();
+        let sources = &mut self.sources;
+        let mut counts = std::mem::replace(&mut self.counts, vec![]).into_iter();
+        let mut kept_counts = vec![];
+        self.sentences.retain(|sentence| {
+            let count = counts.next().unwrap_or(1);
+            let remove = predicate(sentence);
+            if remove {
+                sources.remove(sentence);
+            } else {
+                kept_counts.push(count);
+            }
+            !remove
+        });
+        self.counts = kept_counts;
+        let removed = before - self.sentences.len();
+        if removed > 0 {
+            self.successors
+                .retain(|sentence, successor| !predicate(sentence) && !predicate(successor));
+            self.unsaved.retain(|sentence| !predicate(sentence));
+            self.needs_full_save = true;
+            self.rebuild_indices();
+            self.set_normalization(self.normalization);
+        }
+        self.pinned = pinned;
+        removed
+    }
+
+    // source_of returns who taught the given sentence, if it was learned with
+    // attribution.
+    pub fn source_of(&self, sentence: &str) -> Option<&Source> {
+        self.sources.get(&self.lowercase(sentence))
+    }
+
+    // sample returns n distinct sentences picked at random, or every sentence
+    // in a random order when there are fewer than n.
+    pub fn sample(&self, n: usize, rng: &mut dyn RngCore) -> Vec<&str> {
+        let amount = n.min(self.sentences.len());
+        rand::seq::index::sample(rng, self.sentences.len(), amount)
+            .into_iter()
+            .map(|i| self.sentences[i].as_str())
+            .collect()
+    }
+
     pub fn respond_to(&self, line: &str, rng: &mut dyn RngCore) -> Option<String> {
-        let known_words = self.known_words(line);
-        if known_words.is_empty() {
+        self.respond_to_weighted(line, 0.0, rng)
+    }
+
+    // respond_to_weighted works like respond_to, but favors longer sentences
+    // when length_bias is above zero. Each sentence is weighted by how many
+    // times it was learned, times its word count raised to the power of
+    // length_bias. When the pivot and the word after it in the line appear
+    // next to each other in at least two sentences, only those sentences are
+    // used, so that the response is closer to the line.
+    pub fn respond_to_weighted(
+        &self,
+        line: &str,
+        length_bias: f32,
+        rng: &mut dyn RngCore,
+    ) -> Option<String> {
+        let words: Vec<String> = split_words(&self.fold(line))
+            .iter()
+            .map(|s| self.word_key(s))
+            .collect();
+        let known: Vec<usize> = (0..words.len())
+            .filter(|&i| self.knows_word(&words[i]))
+            .collect();
+        if known.is_empty() {
             None
         } else {
-            let pivot = &known_words[rng.next_u64() as usize % known_words.len()];
-            let sentences_with_word = self.sentences_with_word(pivot);
-            if sentences_with_word.len() < 2 {
+            let at = known[rng.next_u64() as usize % known.len()];
+            let pivot = &words[at];
+            let with_phrase: Vec<usize> = match words.get(at..at + 2) {
+                Some(phrase) => self
+                    .sentences_with_phrase(&phrase.join(" "))
+                    .into_iter()
+                    .filter_map(|sentence| self.position_of_similar_sentence(sentence))
+                    .collect(),
+                None => vec![],
+            };
+            let candidates = if with_phrase.len() >= 2 {
+                debug!("[respond_to_weighted] Pivoting on {:?}", &words[at..at + 2]);
+                &with_phrase[..]
+            } else {
+                self.indices.get(pivot).map_or(&[][..], Vec::as_slice)
+            };
+            if candidates.len() < 2 {
                 None
             } else {
-                let s1 = *pick_random(&sentences_with_word, rng);
-                let s2 = *pick_random(&sentences_with_word, rng);
-                let left = get_words_left_of_pivot(s1, pivot)
+                let repeated = candidates.iter().any(|&i| self.count(i) > 1);
+                let (i1, i2) = if length_bias > 0.0 || repeated {
+                    let weight = |&i: &usize| {
+                        let words = split_words(&self.sentences[i]).len() as f64;
+                        f64::from(self.count(i)) * words.powf(length_bias.into())
+                    };
+                    (
+                        *pick_weighted(candidates, weight, rng),
+                        *pick_weighted(candidates, weight, rng),
+                    )
+                } else {
+                    (*pick_random(candidates, rng), *pick_random(candidates, rng))
+                };
+                let (s1, s2) = (self.sentences[i1].as_str(), self.sentences[i2].as_str());
+                let left = get_words_left_of_pivot(s1, pivot, self.elongation)
                     .unwrap_or_else(|| vec![""])
                     .join(" ");
-                let right = get_words_right_of_pivot_inclusive(s2, pivot)
+                let mut right = get_words_right_of_pivot_inclusive(s2, pivot, self.elongation)
                     .unwrap()
                     .join(" ");
+                // The response ends like s2, so it can go on like s2 did.
+                if let (true, Some(successor)) = (self.track_successors, self.successors.get(s2)) {
+                    right = format!("{} {}", right, successor);
+                }
                 if left == "" {
                     Some(right)
                 } else {
@@ -169,38 +1038,229 @@ impl Dictionary {
         }
     }
 
-    fn known_words(&self, line: &str) -> Vec<String> {
-        split_words(&line.to_lowercase())
+    // confidence returns the share of the words in the line that are known,
+    // from 0.0 to 1.0. A line without words has a confidence of 0.0.
+    pub fn confidence(&self, line: &str) -> f32 {
+        let folded = self.fold(line);
+        let words = split_words(&folded);
+        if words.is_empty() {
+            return 0.0;
+        }
+        let known = words
             .iter()
+            .filter(|w| self.knows_word(&self.word_key(w)))
+            .count();
+        known as f32 / words.len() as f32
+    }
+
+    pub(crate) fn known_words(&self, line: &str) -> Vec<String> {
+        split_words(&self.fold(line))
+            .iter()
+            .map(|s| self.word_key(s))
             .filter(|s| self.knows_word(s))
-            .map(|s| s.to_string())
             .collect::<Vec<_>>()
     }
 
+    #[cfg(test)]
     fn sentences_with_word(&self, word: &str) -> Vec<&str> {
         self.indices
             .get(word)
             .map(|ys| ys.iter().map(|y| self.sentences[*y].as_str()).collect())
             .unwrap_or_else(Vec::new)
     }
+
+    // sentences_with_phrase returns the sentences in which the words of the
+    // phrase appear next to each other, in order. The candidates are the
+    // sentences indexed under every word of the phrase, starting from the
+    // rarest word, and only those are split to check that the words are
+    // adjacent. The sentence indices of every word are in ascending order,
+    // so they can be searched rather than scanned.
+    pub fn sentences_with_phrase(&self, phrase: &str) -> Vec<&str> {
+        let keys: Vec<String> = split_words(&self.fold(phrase))
+            .iter()
+            .map(|w| self.word_key(w))
+            .collect();
+        let mut entries = Vec::with_capacity(keys.len());
+        for key in &keys {
+            match self.indices.get(key) {
+                Some(ys) => entries.push(ys),
+                None => return vec![],
+            }
+        }
+        entries.sort_by_key(|ys| ys.len());
+        let candidates = match entries.split_first() {
+            Some((first, rest)) => {
+                let mut candidates = (*first).clone();
+                for ys in rest {
+                    candidates.retain(|y| ys.binary_search(y).is_ok());
+                }
+                candidates
+            }
+            None => return vec![],
+        };
+
+        candidates
+            .into_iter()
+            .map(|y| self.sentences[y].as_str())
+            .filter(|sentence| {
+                let words: Vec<String> = split_words(&self.fold(sentence))
+                    .iter()
+                    .map(|w| self.word_key(w))
+                    .collect();
+                words.windows(keys.len()).any(|w| w == keys.as_slice())
+            })
+            .collect()
+    }
 }
 
-fn split_sentences(s: &str) -> Vec<&str> {
+// split_sentences splits on whitespace that follows sentence-ending
+// punctuation, and on line breaks, since each line of a multi-line message is
+// usually its own sentence even without punctuation.
+pub(crate) fn split_sentences(s: &str) -> Vec<&str> {
     lazy_static! {
-        static ref RE: Regex = Regex::new(r"(?<=[.!?]+)\s+").unwrap();
+        static ref RE: Regex = Regex::new(r"(?<=[.!?]+)\s+|\s*\n\s*").unwrap();
     }
     RE.split(s).filter(|s| !s.is_empty()).collect()
 }
 
-fn split_words(s: &str) -> Vec<&str> {
+// split_words splits on whitespace and punctuation. Every emoji is a word of
+// its own, even when it is stuck to other words, so that emoji can be used as
+// pivots. An emoji is matched as a whole grapheme cluster, which keeps ZWJ
+// sequences, flags and keycaps together.
+pub(crate) fn split_words(s: &str) -> Vec<&str> {
     lazy_static! {
-        static ref RE: Regex = Regex::new(r"[,.!?:\s]+").unwrap();
+        static ref RE: Regex = Regex::new(&format!(
+            r"{emoji}|(?:(?!{emoji})[^,.!?:\s])+",
+            emoji =
+                r"(?=\p{Extended_Pictographic}|\p{Regional_Indicator}|[0-9#*]\x{FE0F}?\x{20E3})\X"
+        ))
+        .unwrap();
     }
-    RE.split(s).filter(|s| !s.is_empty()).collect()
+    RE.find_iter(s).map(|(start, end)| &s[start..end]).collect()
 }
 
-fn sort_sentences(sentences: &mut Vec<String>) {
-    sentences.sort_by(|a, b| a.to_lowercase().cmp(&b.to_lowercase()))
+/// Returns the path of the journal that belongs to the dictionary at the
+/// given path.
+pub fn journal_path(path: &Path) -> PathBuf {
+    let mut journal = path.as_os_str().to_owned();
+    journal.push(".journal");
+    PathBuf::from(journal)
+}
+
+/// Returns true if the dictionary at the path is written compressed.
+fn is_compressed_path(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension == "gz")
+}
+
+/// Returns the form of the sentence that is used to find near-duplicates.
+fn normalize(sentence: &str, normalization: Normalization) -> String {
+    if normalization == Normalization::Exact {
+        return sentence.to_owned();
+    }
+    let trimmed = sentence
+        .trim_end_matches(|c: char| c.is_ascii_punctuation() || c == '…' || c.is_whitespace());
+    if normalization == Normalization::Aggressive {
+        let mut collapsed = String::with_capacity(trimmed.len());
+        let mut previous = None;
+        for c in trimmed.chars() {
+            if previous != Some(c) {
+                collapsed.push(c);
+            }
+            previous = Some(c);
+        }
+        collapsed
+    } else {
+        trimmed.to_owned()
+    }
+}
+
+/// Lowercases the text the way sentences are stored, which only differs from
+/// to_lowercase for Turkish.
+fn lowercase(s: &str, case_folding: CaseFolding) -> String {
+    if case_folding != CaseFolding::Turkish {
+        return s.to_lowercase();
+    }
+    let mut lowercase = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            'İ' => lowercase.push('i'),
+            // An I followed by a combining dot above is another way to write
+            // İ.
+            'I' if chars.peek() == Some(&'\u{307}') => {
+                chars.next();
+                lowercase.push('i');
+            }
+            'I' => lowercase.push('ı'),
+            _ => lowercase.extend(c.to_lowercase()),
+        }
+    }
+    lowercase
+}
+
+/// Folds the case of the text, like lowercase but so that different ways of
+/// writing a word in a given language end up the same. It is only used for
+/// the indices, so it may lose what the text looked like.
+fn fold_case(s: &str, case_folding: CaseFolding) -> String {
+    let lowercase = lowercase(s, case_folding);
+    if case_folding == CaseFolding::Lowercase {
+        return lowercase;
+    }
+    let mut folded = String::with_capacity(lowercase.len());
+    for c in lowercase.chars() {
+        match (case_folding, c) {
+            // Sentences learned before Turkish was turned on store İ as an i
+            // with a combining dot above, which is just an i in Turkish.
+            (CaseFolding::Turkish, '\u{307}') if folded.ends_with('i') => {}
+            (_, 'ß') => folded.push_str("ss"),
+            (_, 'ς') => folded.push('σ'),
+            _ => folded.push(c),
+        }
+    }
+    folded
+}
+
+/// Shortens runs of three or more of the same letter in the word, as the
+/// elongation setting asks.
+fn shorten_elongation(word: &str, elongation: Elongation) -> String {
+    let keep = match elongation {
+        Elongation::Keep => return word.to_owned(),
+        Elongation::Double => 2,
+        Elongation::Single => 1,
+    };
+    let chars: Vec<char> = word.chars().collect();
+    let mut shortened = String::with_capacity(word.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let run = chars[i..].iter().take_while(|&&x| x == c).count();
+        let count = if run >= 3 && c.is_alphabetic() {
+            keep
+        } else {
+            run
+        };
+        shortened.extend(std::iter::repeat(c).take(count));
+        i += run;
+    }
+    shortened
+}
+
+// sort_sentences sorts the sentences and moves their counts along with them.
+fn sort_sentences(sentences: &mut Vec<String>, counts: &mut Vec<u32>) {
+    let mut counted: Vec<(String, u32)> = std::mem::replace(sentences, vec![])
+        .into_iter()
+        .zip(std::mem::replace(counts, vec![]))
+        .collect();
+    // Sentences that only differ in case are ordered by their exact text, so
+    // that rebuilding the indices always puts every sentence at the same
+    // position.
+    counted.sort_by_cached_key(|(sentence, count)| {
+        (sentence.to_lowercase(), sentence.clone(), *count)
+    });
+    let (sorted, sorted_counts) = counted.into_iter().unzip();
+    *sentences = sorted;
+    *counts = sorted_counts;
 }
 
 fn insert_word_into_indices(indices: &mut Indices, word: &str, sentence_index: usize) {
@@ -214,19 +1274,40 @@ fn pick_random<'a, T>(v: &'a [T], rng: &mut dyn RngCore) -> &'a T {
     &v[rng.next_u64() as usize % v.len()]
 }
 
-fn get_words_left_of_pivot<'a>(line: &'a str, pivot: &'a str) -> Option<Vec<&'a str>> {
+fn pick_weighted<'a, T>(v: &'a [T], weight: impl Fn(&T) -> f64, rng: &mut dyn RngCore) -> &'a T {
+    let weights: Vec<f64> = v.iter().map(|s| weight(s)).collect();
+    let total: f64 = weights.iter().sum();
+    let mut target = rng.next_u64() as f64 / u64::MAX as f64 * total;
+    for (s, w) in v.iter().zip(weights) {
+        if target < w {
+            return s;
+        }
+        target -= w;
+    }
+    &v[v.len() - 1]
+}
+
+fn get_words_left_of_pivot<'a>(
+    line: &'a str,
+    pivot: &str,
+    elongation: Elongation,
+) -> Option<Vec<&'a str>> {
     let words = split_words(line);
     words
         .iter()
-        .position(|word| word == &pivot)
+        .position(|word| shorten_elongation(word, elongation) == pivot)
         .map(|pivot_position| words[0..pivot_position].to_vec())
 }
 
-fn get_words_right_of_pivot_inclusive<'a>(line: &'a str, pivot: &'a str) -> Option<Vec<&'a str>> {
+fn get_words_right_of_pivot_inclusive<'a>(
+    line: &'a str,
+    pivot: &str,
+    elongation: Elongation,
+) -> Option<Vec<&'a str>> {
     let words = split_words(line);
     words
         .iter()
-        .position(|word| word == &pivot)
+        .position(|word| shorten_elongation(word, elongation) == pivot)
         .map(|pivot_position| words[pivot_position..words.len()].to_vec())
 }
 
@@ -254,6 +1335,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_split_sentences_on_newlines() {
+        assert_eq!(
+            vec![
+                "first line without punctuation",
+                "second line, see https://example.com/a.b",
+                "third line 3.14",
+                "Done.",
+                "Really"
+            ],
+            split_sentences(
+                "first line without punctuation\n\
+                second line, see https://example.com/a.b\r\n\
+                \n\
+                third line 3.14\n\
+                Done. Really"
+            ),
+        );
+    }
+
+    #[test]
+    fn test_learn_multiline() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("buy milk\nfeed the crabs\nwater the plants");
+        assert_eq!(3, dict.stats().sentence_count);
+        assert!(dict.knows_sentence("feed the crabs"));
+    }
+
     // This tests that the Dictionary::rebuild_indices function is building indices correctly.
     #[test]
     fn test_dictionary_rebuild_indices() {
@@ -264,6 +1373,7 @@ mod tests {
                 "hello world!".to_string(),
             ],
             indices: hashmap![],
+            ..Dictionary::new_empty()
         };
         d.rebuild_indices();
 
@@ -293,6 +1403,588 @@ mod tests {
         );
     }
 
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("borg_test_{}.json", name))
+    }
+
+    #[test]
+    fn test_load_legacy_dictionary() {
+        let path = temp_path("load_legacy");
+        fs::write(
+            &path,
+            r#"{"sentences": ["hello world", "hello crabs"], "indices": {}}"#,
+        )
+        .unwrap();
+
+        let d = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(DICTIONARY_VERSION, d.version);
+        assert_eq!(
+            vec!["hello crabs", "hello world"],
+            d.sentences_with_word("hello")
+        );
+        assert_eq!(vec![1, 1], d.counts);
+    }
+
+    #[test]
+    fn test_migrate_older_versions() {
+        for version in 2..DICTIONARY_VERSION {
+            let path = temp_path(&format!("migrate_version_{}", version));
+            fs::write(
+                &path,
+                format!(
+                    r#"{{"version": {}, "sentences": ["hello crabs", "hello world"],
+                    "indices": {{"hello": [0, 1], "crabs": [0], "world": [1]}}}}"#,
+                    version
+                ),
+            )
+            .unwrap();
+
+            let mut d = Dictionary::load(&path).unwrap();
+            assert_eq!(DICTIONARY_VERSION, d.version);
+            assert_eq!(vec![1, 1], d.counts);
+            assert!(!d.learn("hello crabs"));
+            assert_eq!(vec![2, 1], d.counts);
+
+            // The migrated dictionary was saved in the current format.
+            let saved: serde_json::Value =
+                serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+            fs::remove_file(&path).unwrap();
+            assert_eq!(serde_json::json!(DICTIONARY_VERSION), saved["version"]);
+            assert_eq!(serde_json::json!([1, 1]), saved["counts"]);
+            assert!(saved.as_object().unwrap().contains_key("max_word_length"));
+            assert_eq!(serde_json::json!({}), saved["successors"]);
+            assert_eq!(serde_json::json!("Lowercase"), saved["case_folding"]);
+        }
+    }
+
+    #[test]
+    fn test_export_text() {
+        let path = temp_path("export_text");
+        let mut d = Dictionary::new_empty();
+        d.learn("Hello crabs. Lobsters are RED!");
+        d.learn("hello world");
+        d.export_text(&path).unwrap();
+        let exported = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let expected: String = d.sentences.iter().map(|s| format!("{}\n", s)).collect();
+        assert_eq!(expected, exported);
+        assert!(exported.contains("lobsters are red"), "{:?}", exported);
+
+        // Importing the export gives back the same sentences, though a batch
+        // is stored in sorted order.
+        let mut imported = Dictionary::new_empty();
+        imported.learn_batch(exported.lines());
+        let mut sentences = d.sentences.clone();
+        sentences.sort();
+        assert_eq!(sentences, imported.sentences);
+    }
+
+    #[test]
+    fn test_load_migrates_v1_dictionary() {
+        let path = temp_path("load_migrates_v1");
+        fs::write(
+            &path,
+            r#"{
+                "sentences": ["hello there. general crabs", "hello world"],
+                "indices": {"hello": [0, 1], "there": [0], "world": [1]}
+            }"#,
+        )
+        .unwrap();
+
+        let loaded = Dictionary::load(&path).unwrap();
+        let reloaded = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut fresh = Dictionary::new_empty();
+        fresh.learn("hello there. general crabs");
+        fresh.learn("hello world");
+        fresh.rebuild_indices();
+
+        assert_eq!(fresh, loaded);
+        // The migrated dictionary was written back in the current format.
+        assert_eq!(fresh, reloaded);
+    }
+
+    #[test]
+    fn test_load_current_dictionary() {
+        let path = temp_path("load_current");
+        let mut d = Dictionary::new_empty();
+        d.learn("hello world. hello crabs.");
+        d.write_to_disk(&path).unwrap();
+
+        let loaded = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(d, loaded);
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!("lol!", normalize("lol!", Normalization::Exact));
+        assert_eq!("lol", normalize("lol!!!", Normalization::Punctuation));
+        assert_eq!("lol", normalize("lol...", Normalization::Punctuation));
+        assert_eq!("loool", normalize("loool?", Normalization::Punctuation));
+        assert_eq!("lol", normalize("loool?", Normalization::Aggressive));
+    }
+
+    #[test]
+    fn test_learn_near_duplicates() {
+        let mut d = Dictionary::new_empty();
+        d.set_normalization(Normalization::Aggressive);
+        assert!(d.learn("lol"));
+        assert!(!d.learn("lol!"));
+        assert!(!d.learn("loool"));
+        assert!(!d.learn("LOL..."));
+        assert_eq!(vec!["lol"], d.sentences_with_word("lol"));
+
+        let mut d = Dictionary::new_empty();
+        d.set_normalization(Normalization::Aggressive);
+        assert_eq!(1, d.learn_batch(vec!["lol!", "loool", "lol"].into_iter()));
+
+        let mut d = Dictionary::new_empty();
+        d.set_normalization(Normalization::Punctuation);
+        assert!(d.learn("lol!"));
+        assert!(!d.learn("lol"));
+        assert!(d.learn("loool"));
+        assert_eq!(2, d.stats().sentence_count);
+        assert!(d.knows_sentence("lol!"));
+    }
+
+    #[test]
+    fn test_forget() {
+        let source = Source::new("irc", "#crabs", "ferris");
+        let mut d = Dictionary::new_empty();
+        d.learn("hello crabs. hello lobsters. goodbye crabs.");
+        d.learn_attributed("evil crabs!", &source);
+
+        assert_eq!(0, d.forget("  "));
+        assert_eq!(3, d.forgettable("Crabs").len());
+        assert_eq!(3, d.forget("Crabs"));
+        assert_eq!(vec!["hello lobsters."], d.sentences_with_word("hello"));
+        assert!(d.sentences_with_word("crabs").is_empty());
+        assert_eq!(None, d.source_of("evil crabs!"));
+    }
+
+    #[test]
+    fn test_pinned_sentences() {
+        let mut d = Dictionary::new_empty();
+        d.learn("hello crabs. you are a crab.");
+        d.pin(&["Resistance is futile. hello crabs.".to_owned()]);
+        d.pin(&["resistance is futile.".to_owned()]);
+        assert!(d.is_pinned("resistance is futile."));
+        assert!(d.is_pinned("hello crabs."));
+        assert!(!d.is_pinned("you are a crab."));
+        // Pinning again does not count the sentences again.
+        let futile = d.position_of_similar_sentence("resistance is futile.");
+        assert_eq!(1, d.count(futile.unwrap()));
+
+        assert_eq!(0, d.forget("crabs"));
+        assert_eq!(1, d.forget("crab"));
+        assert!(d.knows_sentence("hello crabs."));
+        assert!(!d.knows_sentence("you are a crab."));
+
+        d.learn("squids are odd.");
+        assert_eq!(3, d.prune_rare_words(2));
+        assert!(!d.knows_sentence("squids are odd."));
+        assert!(d.knows_sentence("resistance is futile."));
+        assert_eq!(1, d.sentences_with_word("futile").len());
+    }
+
+    #[test]
+    fn test_prune_rare_words() {
+        let mut d = Dictionary::new_empty();
+        d.learn("hello crabs. hello lobsters. hello crabs and lobsters. asdfgh qwerty.");
+
+        assert_eq!(0, d.prune_rare_words(1));
+        // "and", "asdfgh" and "qwerty" appear only once.
+        assert_eq!(3, d.prune_rare_words(2));
+
+        assert_eq!(3, d.stats().sentence_count);
+        assert_eq!(3, d.stats().word_count);
+        assert!(d.sentences_with_word("asdfgh").is_empty());
+        assert!(d.sentences_with_word("and").is_empty());
+        assert_eq!(
+            vec!["hello crabs and lobsters.", "hello crabs."],
+            d.sentences_with_word("crabs")
+        );
+        for word in &["hello", "crabs", "lobsters"] {
+            for sentence in d.sentences_with_word(word) {
+                assert!(sentence.contains(word));
+            }
+        }
+    }
+
+    #[test]
+    fn test_learn_attributed() {
+        let source = Source::new("telegram", "-100", "42");
+        let mut d = Dictionary::new_empty();
+        d.learn("hello world.");
+        d.learn_attributed("Hello world. Hello crabs!", &source);
+
+        assert_eq!(None, d.source_of("hello world."));
+        assert_eq!(Some(&source), d.source_of("Hello crabs!"));
+        assert_eq!(None, d.source_of("goodbye crabs!"));
+    }
+
+    #[test]
+    fn test_sources_round_trip() {
+        let path = temp_path("sources_round_trip");
+        let source = Source::new("irc", "#crabs", "ferris");
+        let mut d = Dictionary::new_empty();
+        d.learn_attributed("hello crabs.", &source);
+        d.write_to_disk(&path).unwrap();
+
+        let loaded = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(d, loaded);
+        assert_eq!(Some(&source), loaded.source_of("hello crabs."));
+    }
+
+    #[test]
+    fn test_successors() {
+        let path = temp_path("successors");
+        let mut d = Dictionary::new_empty();
+        d.write_to_disk(&path).unwrap();
+        d.learn("hello crabs. they are red.");
+        assert_eq!(None, d.successor_of("hello crabs."));
+
+        d.set_track_successors(true);
+        d.learn("hello crabs. they are red.");
+        d.learn("hello lobsters.");
+        assert_eq!(Some("they are red."), d.successor_of("Hello crabs."));
+        assert_eq!(None, d.successor_of("they are red."));
+        assert_eq!(None, d.successor_of("hello lobsters."));
+
+        // Responses that end like a sentence with a successor go on with it.
+        let mut rng = rand::thread_rng();
+        let responses: Vec<String> = (0..50)
+            .map(|_| d.respond_to("hello", &mut rng).unwrap())
+            .collect();
+        assert!(responses.contains(&"hello crabs they are red.".to_owned()));
+        assert!(responses
+            .iter()
+            .all(|r| r == "hello crabs they are red." || r == "hello lobsters"));
+
+        // Successors are saved in the journal too.
+        d.save(&path, SaveMode::Journal).unwrap();
+        let loaded = Dictionary::load(&path).unwrap();
+        fs::remove_file(journal_path(&path)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Some("they are red."), loaded.successor_of("hello crabs."));
+
+        d.forget("red");
+        assert_eq!(None, d.successor_of("hello crabs."));
+    }
+
+    #[test]
+    fn test_compressed_round_trip() {
+        let path = temp_path("compressed_round_trip");
+        let compressed_path = path.with_extension("json.gz");
+        let mut d = Dictionary::new_empty();
+        for i in 0..100 {
+            d.learn(&format!("crab number {} says hello to the lobsters.", i));
+        }
+        d.write_to_disk(&path).unwrap();
+        d.write_to_disk(&compressed_path).unwrap();
+
+        let size = fs::metadata(&path).unwrap().len();
+        let compressed_size = fs::metadata(&compressed_path).unwrap().len();
+        let loaded = Dictionary::load(&compressed_path).unwrap();
+        // Compression is recognized by content, not only by name.
+        let renamed = temp_path("compressed_renamed");
+        fs::rename(&compressed_path, &renamed).unwrap();
+        let loaded_renamed = Dictionary::load(&renamed).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&renamed).unwrap();
+
+        assert!(compressed_size < size / 2, "{} {}", compressed_size, size);
+        assert_eq!(d, loaded);
+        assert_eq!(d, loaded_renamed);
+    }
+
+    #[test]
+    fn test_load_truncated_compressed_dictionary() {
+        let path = temp_path("truncated").with_extension("json.gz");
+        let mut d = Dictionary::new_empty();
+        d.learn("hello crabs. hello lobsters.");
+        d.write_to_disk(&path).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() / 2]).unwrap();
+
+        let result = Dictionary::load(&path);
+        fs::remove_file(&path).unwrap();
+        match result {
+            Err(Error::CompressionError(_)) => {}
+            other => panic!("expected a compression error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_journal_append() {
+        let path = temp_path("journal_append");
+        let journal = journal_path(&path);
+        let mut d = Dictionary::new_empty();
+        d.write_to_disk(&path).unwrap();
+
+        d.learn("hello world. hello crabs.");
+        d.save(&path, SaveMode::Journal).unwrap();
+        d.save(&path, SaveMode::Journal).unwrap();
+        d.learn_attributed("hello lobsters.", &Source::new("irc", "#crabs", "ferris"));
+        d.save(&path, SaveMode::Journal).unwrap();
+
+        let data = fs::read_to_string(&journal).unwrap();
+        let main = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&journal).unwrap();
+
+        assert_eq!(3, data.lines().count());
+        assert!(data.contains("ferris"));
+        assert!(!main.contains("crabs"));
+    }
+
+    #[test]
+    fn test_load_with_journal() {
+        let path = temp_path("load_with_journal");
+        let mut d = Dictionary::new_empty();
+        d.learn("hello world.");
+        d.write_to_disk(&path).unwrap();
+        d.learn_attributed("hello crabs.", &Source::new("irc", "#crabs", "ferris"));
+        d.save(&path, SaveMode::Journal).unwrap();
+        // A crash in the middle of an append leaves a partial line behind.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(journal_path(&path))
+            .unwrap()
+            .write_all(br#"{"sentence": "hello lob"#)
+            .unwrap();
+
+        let loaded = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(journal_path(&path)).unwrap();
+
+        assert_eq!(
+            vec!["hello crabs.", "hello world."],
+            loaded.sentences_with_word("hello")
+        );
+        assert_eq!("ferris", loaded.source_of("hello crabs.").unwrap().user_id);
+        assert!(loaded.unsaved.is_empty());
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = temp_path("compact");
+        let mut d = Dictionary::new_empty();
+        d.write_to_disk(&path).unwrap();
+        d.learn("hello world. hello crabs.");
+        d.save(&path, SaveMode::Journal).unwrap();
+        assert!(journal_path(&path).is_file());
+
+        d.compact(&path).unwrap();
+        assert!(!journal_path(&path).is_file());
+        let loaded = Dictionary::load(&path).unwrap();
+        assert_eq!(d, loaded);
+
+        // Removing sentences makes the next journal save a full one.
+        d.forget("crabs");
+        d.save(&path, SaveMode::Journal).unwrap();
+        assert!(!journal_path(&path).is_file());
+        let loaded = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(1, loaded.stats().sentence_count);
+    }
+
+    #[test]
+    fn test_load_newer_dictionary() {
+        let path = temp_path("load_newer");
+        fs::write(
+            &path,
+            format!(
+                r#"{{"version": {}, "sentences": [], "indices": {{}}}}"#,
+                DICTIONARY_VERSION + 1
+            ),
+        )
+        .unwrap();
+
+        let result = Dictionary::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        match result {
+            Err(Error::UnsupportedVersion(v)) => assert_eq!(DICTIONARY_VERSION + 1, v),
+            other => panic!("Unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut d1 = Dictionary::new_empty();
+        d1.learn("hello crabs. hello lobsters.");
+        let mut d2 = Dictionary::new_empty();
+        let source = Source::new("irc", "#crabs", "ferris");
+        d2.learn_attributed("hello lobsters. goodbye crabs.", &source);
+
+        assert_eq!(1, d1.merge(d2));
+        let mut sentences = d1.sentences.clone();
+        sentences.sort();
+        assert_eq!(
+            vec!["goodbye crabs.", "hello crabs.", "hello lobsters."],
+            sentences
+        );
+        assert_eq!(2, d1.sentences_with_word("crabs").len());
+        assert_eq!(2, d1.sentences_with_word("hello").len());
+        assert_eq!(vec!["goodbye crabs."], d1.sentences_with_word("goodbye"));
+        assert_eq!(Some(&source), d1.source_of("goodbye crabs."));
+        assert_eq!(None, d1.source_of("hello lobsters."));
+        assert!(!d1.needs_to_build_indices());
+    }
+
+    #[test]
+    fn test_rebuild_indices_is_deterministic() {
+        let sentences = ["hello crabs.", "Hello crabs.", "HELLO crabs.", "bye crabs."];
+        let expected = vec!["bye crabs.", "HELLO crabs.", "Hello crabs.", "hello crabs."];
+        let mut orders = vec![];
+        for shift in 0..sentences.len() {
+            let mut d = Dictionary::new_empty();
+            d.sentences = sentences
+                .iter()
+                .cycle()
+                .skip(shift)
+                .take(sentences.len())
+                .map(|s| (*s).to_owned())
+                .collect();
+            d.rebuild_indices();
+            assert_eq!(expected, d.sentences);
+            d.rebuild_indices();
+            assert_eq!(expected, d.sentences);
+            orders.push(d.indices.get("hello").cloned().unwrap());
+        }
+        assert!(orders.iter().all(|order| *order == vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_case_folding() {
+        let mut d = Dictionary::new_empty();
+        d.learn("Die STRASSE ist lang. Die Straße ist breit.");
+        assert_eq!(1, d.sentences_with_word("strasse").len());
+        assert_eq!(1, d.sentences_with_word("straße").len());
+
+        let mut d = Dictionary::new_empty();
+        d.set_case_folding(CaseFolding::Unicode);
+        d.learn("Die STRASSE ist lang. Die Straße ist breit.");
+        assert_eq!(2, d.sentences_with_word("strasse").len());
+        assert_eq!(1.0, d.confidence("straße"));
+        assert_eq!(1.0, d.confidence("STRASSE"));
+        assert!(d.knows_sentence("die straße ist breit."));
+        assert_eq!(2, d.forget("Straße"));
+
+        let mut d = Dictionary::new_empty();
+        d.set_case_folding(CaseFolding::Turkish);
+        d.learn("İstanbul çok büyük. istanbul güzel. IRMAK derin.");
+        assert_eq!(2, d.sentences_with_word("istanbul").len());
+        assert_eq!(1.0, d.confidence("İSTANBUL"));
+        assert_eq!(1.0, d.confidence("ırmak"));
+        assert_eq!(0.0, d.confidence("irmak"));
+        assert!(d.knows_sentence("ırmak derin."));
+        assert!(d.knows_sentence("istanbul çok büyük."));
+        assert_eq!(
+            "istanbul",
+            lowercase("I\u{307}stanbul", CaseFolding::Turkish)
+        );
+    }
+
+    #[test]
+    fn test_set_case_folding_reindexes() {
+        let mut d = Dictionary::new_empty();
+        let source = Source::new("irc", "#istanbul", "ferris");
+        d.learn_attributed("İstanbul güzel. istanbul büyük.", &source);
+        assert_eq!(1, d.sentences_with_word("istanbul").len());
+
+        d.set_case_folding(CaseFolding::Turkish);
+        assert_eq!(2, d.sentences_with_word("istanbul").len());
+        assert!(d.knows_sentence("i\u{307}stanbul güzel."));
+        assert_eq!(Some(&source), d.source_of("i\u{307}stanbul güzel."));
+        assert!(d.needs_full_save);
+    }
+
+    #[test]
+    fn test_shorten_elongation() {
+        assert_eq!("soooo", shorten_elongation("soooo", Elongation::Keep));
+        assert_eq!("soo", shorten_elongation("soooo", Elongation::Double));
+        assert_eq!("so", shorten_elongation("soooo", Elongation::Single));
+        assert_eq!("hello", shorten_elongation("heeeello", Elongation::Single));
+        assert_eq!("good", shorten_elongation("good", Elongation::Single));
+        assert_eq!("good", shorten_elongation("gooood", Elongation::Double));
+        assert_eq!("1000", shorten_elongation("1000", Elongation::Single));
+    }
+
+    #[test]
+    fn test_elongation() {
+        let mut d = Dictionary::new_empty();
+        d.set_elongation(Elongation::Single);
+        d.learn("soooo cool. so what.");
+        assert_eq!(2, d.sentences_with_word("so").len());
+        assert_eq!(1, d.sentences_with_word("cool").len());
+        assert!(d.knows_sentence("soooo cool."));
+        assert_eq!(1.0, d.confidence("so"));
+        assert_eq!(1.0, d.confidence("sooooooo"));
+
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        for _ in 0..10 {
+            let response = d.respond_to("so", &mut rng).unwrap();
+            assert!(
+                ["soooo cool", "soooo what", "so cool", "so what"].contains(&response.as_str()),
+                "{}",
+                response
+            );
+        }
+
+        let mut d = Dictionary::new_empty();
+        d.learn("soooo cool.");
+        assert!(d.sentences_with_word("so").is_empty());
+        d.set_elongation(Elongation::Single);
+        assert_eq!(1, d.sentences_with_word("so").len());
+        assert!(d.sentences_with_word("soooo").is_empty());
+        assert!(d.needs_full_save);
+    }
+
+    #[test]
+    fn test_max_word_length() {
+        let blob = "agvsbg8gy3jhynmgyw5kigxvynn0zxjz";
+        let mut d = Dictionary::new_empty();
+        d.set_max_word_length(Some(20));
+        d.learn(&format!("look at this {} crabs.", blob));
+        assert!(d.knows_sentence(&format!("look at this {} crabs.", blob)));
+        assert_eq!(1, d.sentences_with_word("look").len());
+        assert_eq!(1, d.sentences_with_word("crabs").len());
+        assert!(d.sentences_with_word(blob).is_empty());
+        assert_eq!(4, d.stats().word_count);
+
+        // Sentences mostly made of long words are not learned at all.
+        d.set_long_word_share(Some(0.5));
+        assert!(!d.learn(&format!("{} {} ok", blob, blob)));
+        assert!(d.learn(&format!("{} is a hash", blob)));
+        d.learn_batch(vec![blob, "lobsters too"].into_iter());
+        assert_eq!(3, d.stats().sentence_count);
+
+        // Lifting the limit indexes the long words again.
+        d.set_max_word_length(None);
+        assert_eq!(2, d.sentences_with_word(blob).len());
+    }
+
+    #[test]
+    fn test_confidence() {
+        let mut d = Dictionary::new_empty();
+        d.learn("hello crabs. hello lobsters.");
+        assert_eq!(1.0, d.confidence("Hello crabs!"));
+        assert_eq!(0.5, d.confidence("hello squids"));
+        assert_eq!(0.25, d.confidence("lobsters are not squids"));
+        assert_eq!(0.0, d.confidence("squids"));
+        assert_eq!(0.0, d.confidence("..."));
+    }
+
     #[test]
     fn test_split_words() {
         assert_eq!(
@@ -301,6 +1993,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_split_words_emoji() {
+        assert_eq!(
+            vec![
+                "hi",
+                "👨\u{200d}👩\u{200d}👧",
+                "family",
+                "1\u{fe0f}\u{20e3}",
+                "12"
+            ],
+            split_words("hi👨‍👩‍👧family 1️⃣ 12")
+        );
+        assert_eq!(
+            vec!["crabs", "🦀", "🦀", "🇫🇷", "#tag"],
+            split_words("crabs🦀🦀, 🇫🇷 #tag")
+        );
+    }
+
+    #[test]
+    fn test_emoji_pivot() {
+        let mut d = Dictionary::new_empty();
+        d.learn("crabs🦀 are red. i love 🦀");
+        assert_eq!(2, d.sentences_with_word("🦀").len());
+    }
+
     #[test]
     fn test_needs_to_build_indices() {
         // Indices should have to be rebuilt when the bot has sentences,
@@ -310,6 +2027,7 @@ mod tests {
         assert!(Dictionary {
             sentences: vec!["hello world".to_string()],
             indices: hashmap![],
+            ..Dictionary::new_empty()
         }
         .needs_to_build_indices());
 
@@ -319,12 +2037,14 @@ mod tests {
                 "hello".to_string() => vec![0],
                 "world".to_string() => vec![0]
             ],
+            ..Dictionary::new_empty()
         }
         .needs_to_build_indices());
 
         assert!(!Dictionary {
             sentences: vec![],
             indices: hashmap![],
+            ..Dictionary::new_empty()
         }
         .needs_to_build_indices());
     }
@@ -354,6 +2074,7 @@ mod tests {
                 "and".to_string() => vec![3],
                 "stout".to_string() => vec![3]
             ],
+            ..Dictionary::new_empty()
         };
         assert!(d.knows_sentence(&"my name is foo...".to_string()));
         assert!(d.knows_sentence(&"i am a little teapot.".to_string()));
@@ -383,6 +2104,7 @@ mod tests {
                 "is".to_string() => vec![1],
                 "josh".to_string() => vec![1]
             ],
+            ..Dictionary::new_empty()
         };
 
         assert!(d.knows_word("and"));
@@ -438,6 +2160,7 @@ mod tests {
         let mut dict = Dictionary {
             sentences: vec![],
             indices: hashmap![],
+            ..Dictionary::new_empty()
         };
         dict.learn("Hey there, everyone!");
         assert_eq!(
@@ -447,7 +2170,8 @@ mod tests {
                     "hey".to_string() => vec![0],
                     "there".to_string() => vec![0],
                     "everyone".to_string() => vec![0]
-                ]
+                ],
+                ..Dictionary::new_empty()
             },
             dict
         );
@@ -466,7 +2190,8 @@ mod tests {
                     "is".to_string() => vec![1],
                     "doing".to_string() => vec![1],
                     "today".to_string() => vec![1]
-                ]
+                ],
+                ..Dictionary::new_empty()
             },
             dict
         );
@@ -492,12 +2217,54 @@ mod tests {
                     "what".to_string() => vec![2],
                     "about".to_string() => vec![2],
                     "you".to_string() => vec![2]
-                ]
+                ],
+                ..Dictionary::new_empty()
             },
             dict
         );
     }
 
+    #[test]
+    fn test_learn_batch() {
+        let lines: Vec<String> = (0..500)
+            .map(|i| {
+                format!(
+                    "Crab number {} says hi. Crab number {} waves!",
+                    i % 50,
+                    i % 7
+                )
+            })
+            .chain(vec!["hello world".to_string(), "HELLO WORLD".to_string()])
+            .collect();
+
+        let mut sequential = Dictionary::new_empty();
+        sequential.learn("hello world");
+        let mut batch = Dictionary::new_empty();
+        batch.learn("hello world");
+
+        let mut learned = 0;
+        for line in &lines {
+            let before = sequential.stats().sentence_count;
+            sequential.learn(line);
+            learned += sequential.stats().sentence_count - before;
+        }
+        let added = batch.learn_batch(lines.iter().map(String::as_str));
+
+        assert_eq!(57, added);
+        assert_eq!(learned, added);
+        for word in &["crab", "number", "42", "6", "waves", "hello"] {
+            let mut expected = sequential.sentences_with_word(word);
+            let mut actual = batch.sentences_with_word(word);
+            expected.sort();
+            actual.sort();
+            assert_eq!(expected, actual);
+        }
+
+        sequential.rebuild_indices();
+        batch.rebuild_indices();
+        assert_eq!(sequential, batch);
+    }
+
     #[test]
     fn test_respond() {
         let dict = Dictionary {
@@ -520,6 +2287,7 @@ mod tests {
                 "great".to_string() => vec![3],
                 "many".to_string() => vec![3]
             ],
+            ..Dictionary::new_empty()
         };
         use rand::rngs::mock::StepRng;
         assert_eq!(
@@ -540,6 +2308,122 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sample() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("hello crabs. crabs are red. lobsters are red too. ferris is a crab.");
+        use rand::rngs::mock::StepRng;
+        let mut rng = StepRng::new(3, 7);
+
+        let sample = dict.sample(3, &mut rng);
+        assert_eq!(3, sample.len());
+        let mut distinct = sample.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(3, distinct.len());
+        assert!(sample.iter().all(|s| dict.knows_sentence(s)));
+
+        assert_eq!(4, dict.sample(10, &mut rng).len());
+        assert!(dict.sample(0, &mut rng).is_empty());
+        assert!(Dictionary::new_empty().sample(3, &mut rng).is_empty());
+    }
+
+    #[test]
+    fn test_respond_weighted() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn(
+            "crabs. crabs rule. crabs are cool. \
+            crabs are the best animals in the whole wide sea.",
+        );
+        use rand::rngs::mock::StepRng;
+        let rng = || StepRng::new(u64::MAX / 2 - 1, 0);
+
+        assert_eq!(
+            Some("crabs are cool".to_string()),
+            dict.respond_to("crabs", &mut rng())
+        );
+        assert_eq!(
+            Some("crabs are the best animals in the whole wide sea".to_string()),
+            dict.respond_to_weighted("crabs", 1.0, &mut rng())
+        );
+    }
+
+    #[test]
+    fn test_respond_to_phrase() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn(
+            "the red crabs dance. some red crabs sing. red lobsters sleep. \
+            red apples fall. red wine spills.",
+        );
+        use rand::rngs::mock::StepRng;
+        // Whichever word is the pivot, the response keeps "red crabs"
+        // together, since two sentences have them next to each other.
+        let rng = |step| StepRng::new(u64::MAX / 20 * step, u64::MAX / 3 + step);
+        for step in 0..20 {
+            let response = dict.respond_to("red crabs", &mut rng(step)).unwrap();
+            assert!(response.contains("red crabs"), "{:?}", response);
+        }
+        // Without such sentences, any sentence with the pivot will do.
+        assert!((0..20).any(|step| {
+            let response = dict.respond_to("red pears", &mut rng(step)).unwrap();
+            !response.contains("red crabs")
+        }));
+    }
+
+    #[test]
+    fn test_learn_counts_repeated_sentences() {
+        let path = temp_path("learn_counts");
+        let mut d = Dictionary::new_empty();
+        assert!(d.learn("Hello crabs. Hello lobsters."));
+        assert!(!d.learn("hello crabs."));
+        assert!(!d.learn("HELLO CRABS."));
+        assert_eq!(vec![3, 1], d.counts);
+
+        d.set_normalization(Normalization::Punctuation);
+        assert!(!d.learn("hello lobsters!!"));
+        assert_eq!(vec![3, 2], d.counts);
+
+        d.rebuild_indices();
+        assert_eq!(
+            2,
+            d.learn_batch(vec!["hello lobsters", "hi. hi. bye."].into_iter())
+        );
+        assert_eq!(
+            vec!["hello crabs.", "hello lobsters.", "bye.", "hi."],
+            d.sentences
+        );
+        assert_eq!(vec![3, 3, 1, 2], d.counts);
+
+        // The repetitions are saved in the journal too.
+        d.compact(&path).unwrap();
+        d.learn("hi.");
+        d.save(&path, SaveMode::Journal).unwrap();
+        let loaded = Dictionary::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(journal_path(&path)).unwrap();
+        assert_eq!(vec![1, 3, 3, 3], loaded.counts);
+    }
+
+    #[test]
+    fn test_respond_weighted_by_count() {
+        use rand::rngs::mock::StepRng;
+        let rng = || StepRng::new(u64::MAX / 2 - 1, 0);
+
+        let mut dict = Dictionary::new_empty();
+        dict.learn("crabs rule. crabs drool.");
+        assert_eq!(
+            Some("crabs rule".to_string()),
+            dict.respond_to("crabs", &mut rng())
+        );
+
+        dict.learn("crabs drool.");
+        dict.learn("crabs drool.");
+        assert_eq!(
+            Some("crabs drool".to_string()),
+            dict.respond_to("crabs", &mut rng())
+        );
+    }
+
     #[test]
     fn test_known_words() {
         let dict = Dictionary {
@@ -551,6 +2435,7 @@ mod tests {
                 "love".to_string() => vec![1],
                 "pizza".to_string() => vec![1]
             ],
+            ..Dictionary::new_empty()
         };
 
         let empty: Vec<&str> = vec![];
@@ -580,6 +2465,7 @@ mod tests {
                 "like".to_string() => vec![2],
                 "cool".to_string() => vec![2]
             ],
+            ..Dictionary::new_empty()
         };
 
         let empty: Vec<&str> = vec![];
@@ -594,24 +2480,65 @@ mod tests {
         assert_eq!(empty, dict.sentences_with_word(""));
     }
 
+    #[test]
+    fn test_sentences_with_phrase() {
+        let mut dict = Dictionary::new_empty();
+        dict.learn("i love red crabs. red is nice, crabs too. crabs are red. i love red crabs!");
+        let empty: Vec<&str> = vec![];
+
+        assert_eq!(
+            vec!["i love red crabs.", "i love red crabs!"],
+            dict.sentences_with_phrase("Red Crabs")
+        );
+        assert_eq!(
+            vec!["crabs are red."],
+            dict.sentences_with_phrase("crabs are")
+        );
+        assert_eq!(
+            vec!["i love red crabs.", "i love red crabs!"],
+            dict.sentences_with_phrase("love red crabs")
+        );
+        assert_eq!(
+            vec!["red is nice, crabs too."],
+            dict.sentences_with_phrase("nice crabs")
+        );
+
+        // Every word is known, but never next to each other in this order.
+        assert_eq!(empty, dict.sentences_with_phrase("crabs red"));
+        assert_eq!(empty, dict.sentences_with_phrase("love crabs"));
+        assert_eq!(empty, dict.sentences_with_phrase("red lobsters"));
+        assert_eq!(empty, dict.sentences_with_phrase(""));
+    }
+
     #[test]
     fn test_get_words_left_of_pivot() {
         assert_eq!(
             Some(vec!["this", "is", "a"]),
-            get_words_left_of_pivot("this is a test yeah this is a test", "test")
+            get_words_left_of_pivot(
+                "this is a test yeah this is a test",
+                "test",
+                Elongation::Keep
+            )
         );
         assert_eq!(
             Some(Vec::<&str>::new()),
-            get_words_left_of_pivot("this", "this")
+            get_words_left_of_pivot("this", "this", Elongation::Keep)
         );
         assert_eq!(
             Some(Vec::<&str>::new()),
-            get_words_left_of_pivot("this this", "this")
+            get_words_left_of_pivot("this this", "this", Elongation::Keep)
+        );
+        assert_eq!(
+            None,
+            get_words_left_of_pivot("i am a little teapot", "fox", Elongation::Keep)
         );
-        assert_eq!(None, get_words_left_of_pivot("i am a little teapot", "fox"));
         assert_eq!(
             None,
-            get_words_left_of_pivot("abc def ghi jkl", "abc def" /* not a word */)
+            get_words_left_of_pivot(
+                "abc def ghi jkl",
+                "abc def", /* not a word */
+                Elongation::Keep
+            )
         );
     }
 
@@ -619,20 +2546,31 @@ mod tests {
     fn test_get_words_right_of_pivot_inclusive() {
         assert_eq!(
             Some(vec!["test", "yeah", "this", "is", "a", "test"]),
-            get_words_right_of_pivot_inclusive("this is a test yeah this is a test", "test")
+            get_words_right_of_pivot_inclusive(
+                "this is a test yeah this is a test",
+                "test",
+                Elongation::Keep
+            )
         );
         assert_eq!(
             Some(vec!["this"]),
-            get_words_right_of_pivot_inclusive("this", "this")
+            get_words_right_of_pivot_inclusive("this", "this", Elongation::Keep)
         );
         assert_eq!(
             Some(vec!["this", "this"]),
-            get_words_right_of_pivot_inclusive("this this", "this")
+            get_words_right_of_pivot_inclusive("this this", "this", Elongation::Keep)
+        );
+        assert_eq!(
+            None,
+            get_words_left_of_pivot("i am a little teapot", "fox", Elongation::Keep)
         );
-        assert_eq!(None, get_words_left_of_pivot("i am a little teapot", "fox"));
         assert_eq!(
             None,
-            get_words_right_of_pivot_inclusive("abc def ghi jkl", "abc def" /* not a word */)
+            get_words_right_of_pivot_inclusive(
+                "abc def ghi jkl",
+                "abc def", /* not a word */
+                Elongation::Keep
+            )
         );
     }
 }
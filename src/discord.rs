@@ -1,11 +1,643 @@
-use crate::borg::Borg;
+use std::{collections::HashMap, error, fmt, fs, io, path::Path, sync::Arc};
 
-struct Discord<'a> {
-    borg: &'a Borg,
+use futures::lock::Mutex;
+use tokio::sync::RwLock;
+
+use crate::{
+    borg::Borg,
+    config,
+    config::{
+        BehaviorOverride, BehaviorOverrideValueResolver, CommandRole, ConfigError, PatternOwner,
+    },
+};
+
+/////////////////////////////////////////////////////////////////////////////
+// Constants
+/////////////////////////////////////////////////////////////////////////////
+
+/// The "Manage Server" bit of a Discord permission set.
+const MANAGE_GUILD: u64 = 0x20;
+
+/// Separates the namespace from the command name. Slash command names cannot
+/// contain a colon, but they can contain an underscore.
+const NAMESPACE_SEPARATOR: char = '_';
+
+/// How many sentences the sample command shows when no count is given.
+const DEFAULT_SAMPLE_SIZE: usize = 5;
+
+/////////////////////////////////////////////////////////////////////////////
+// API Error
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub struct ApiError {
+    message: String,
+}
+
+impl ApiError {
+    pub fn new(message: &str) -> ApiError {
+        ApiError {
+            message: message.to_owned(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl error::Error for ApiError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        None
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Slash Commands
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlashCommand {
+    Stats,
+    Speaking,
+    Learning,
+    Sample,
 }
 
-impl Discord<'_> {
-    fn new(borg: &Borg) -> Discord {
-        Discord { borg }
+impl SlashCommand {
+    pub const ALL: [SlashCommand; 4] = [
+        SlashCommand::Stats,
+        SlashCommand::Speaking,
+        SlashCommand::Learning,
+        SlashCommand::Sample,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SlashCommand::Stats => "stats",
+            SlashCommand::Speaking => "speaking",
+            SlashCommand::Learning => "learning",
+            SlashCommand::Sample => "sample",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            SlashCommand::Stats => "Show what the bot knows",
+            SlashCommand::Speaking => "Turn speaking on or off in this server",
+            SlashCommand::Learning => "Turn learning on or off in this server",
+            SlashCommand::Sample => "Show random sentences the bot has learned",
+        }
+    }
+
+    /// Returns the name of the command in the namespace, which is the bare
+    /// name when the namespace is empty.
+    pub fn namespaced_name(self, namespace: &str) -> String {
+        if namespace.is_empty() {
+            self.name().to_owned()
+        } else {
+            format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, self.name())
+        }
+    }
+
+    /// Finds the command with the name in the namespace. Commands of other
+    /// namespaces, or bare commands when there is a namespace, are not found.
+    pub fn from_namespaced_name(name: &str, namespace: &str) -> Option<SlashCommand> {
+        SlashCommand::ALL
+            .iter()
+            .copied()
+            .find(|c| c.namespaced_name(namespace) == name)
+    }
+}
+
+/// CommandDefinition is a slash command as it is registered with Discord.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandDefinition {
+    pub name: String,
+    pub description: &'static str,
+}
+
+/// Interaction is an invocation of one of the slash commands.
+#[derive(Debug, Clone)]
+pub struct Interaction {
+    pub id: String,
+    pub guild_id: String,
+    /// Permission set of the member that used the command.
+    pub member_permissions: u64,
+    pub command: String,
+    /// The on/off option passed to toggling commands.
+    pub enabled: Option<bool>,
+    /// The number of sentences asked for with the sample command.
+    pub count: Option<usize>,
+}
+
+/// InteractionApi is the part of Discord's API that the bot needs to answer
+/// commands and greet guilds. It is a trait so that the command handling does not depend on
+/// any particular gateway client.
+#[async_trait]
+pub trait InteractionApi {
+    async fn register_commands(&self, commands: &[CommandDefinition]) -> Result<(), ApiError>;
+    async fn respond(&self, interaction: &Interaction, content: String) -> Result<(), ApiError>;
+    async fn send_message(&self, channel_id: &str, content: String) -> Result<(), ApiError>;
+}
+
+/// GuildJoin is the bot being added to a guild. The gateway also announces
+/// every guild the bot is already in on startup, and those should not be
+/// passed on as joins.
+#[derive(Debug, Clone)]
+pub struct GuildJoin {
+    pub guild_id: String,
+    /// The channel that the guild's system messages go to, if it has one.
+    pub system_channel_id: Option<String>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Context Struct
+/////////////////////////////////////////////////////////////////////////////
+
+pub struct Context<A> {
+    borg: Arc<RwLock<Borg>>,
+    platform_config: config::DiscordPlatform,
+    api: A,
+    /// Behavior overrides set at runtime through commands, keyed by guild ID.
+    /// They are loaded from and saved to the overrides_path, if there is one.
+    runtime_overrides: HashMap<String, BehaviorOverride>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Context Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl<A: InteractionApi> Context<A> {
+    pub fn new(
+        platform_config: config::DiscordPlatform,
+        borg: Arc<RwLock<Borg>>,
+        api: A,
+    ) -> Context<A> {
+        let runtime_overrides = match platform_config.overrides_path {
+            Some(ref path) => load_overrides(Path::new(path)).unwrap_or_else(|e| {
+                error!("Could not load the overrides from {}. Error: {}", path, e);
+                HashMap::new()
+            }),
+            None => HashMap::new(),
+        };
+        Context {
+            borg,
+            platform_config,
+            api,
+            runtime_overrides,
+        }
+    }
+
+    pub fn behavior_for_chat(&self, guild_id: &str) -> Option<BehaviorOverrideValueResolver> {
+        // Ordered from the lowest to the highest precedence.
+        let behaviors = vec![
+            self.platform_config.behavior.as_ref(),
+            self.override_for_chat(guild_id),
+            self.runtime_overrides.get(guild_id),
+        ];
+        config::resolve_behavior(behaviors, self.platform_config.reply_rate_multiplier)
+    }
+
+    fn override_for_chat(&self, guild_id: &str) -> Option<&BehaviorOverride> {
+        self.platform_config
+            .chat_behaviors
+            .as_ref()
+            .and_then(|bs| bs.iter().find(|cb| cb.chat_id == guild_id))
+            .map(|cb| &cb.behavior)
+    }
+
+    async fn run_command(&mut self, interaction: &Interaction) -> Option<String> {
+        let command = SlashCommand::from_namespaced_name(
+            &interaction.command,
+            &self.platform_config.command_namespace,
+        )?;
+        let settings = config::command_settings(&self.platform_config.commands, command.name());
+        if !settings.enabled {
+            return None;
+        }
+        if settings.role == CommandRole::Admin && !can_manage_guild(interaction.member_permissions)
+        {
+            return Some("You need the Manage Server permission to use this command.".to_owned());
+        }
+        match command {
+            SlashCommand::Stats => {
+                let stats = self.borg.read().await.stats();
+                Some(format!(
+                    "I know {} sentences and {} words.",
+                    stats.sentence_count, stats.word_count
+                ))
+            }
+            SlashCommand::Sample => {
+                let borg = self.borg.read().await;
+                let sentences = borg.sample(interaction.count.unwrap_or(DEFAULT_SAMPLE_SIZE));
+                if sentences.is_empty() {
+                    Some("I have not learned anything yet.".to_owned())
+                } else {
+                    Some(sentences.join("\n"))
+                }
+            }
+            SlashCommand::Speaking | SlashCommand::Learning => {
+                let enabled = interaction.enabled?;
+                set_toggle(
+                    &mut self.runtime_overrides,
+                    &interaction.guild_id,
+                    command,
+                    enabled,
+                );
+                self.save_overrides();
+                Some(format!(
+                    "{} is now {}.",
+                    command.name(),
+                    if enabled { "on" } else { "off" }
+                ))
+            }
+        }
+    }
+
+    /// Returns the channel to greet the guild in and the greeting, if the
+    /// guild should be greeted.
+    async fn greeting_for(&self, join: &GuildJoin) -> Option<(String, String)> {
+        if !self.platform_config.greet_on_join {
+            return None;
+        }
+        let channel_id = match join.system_channel_id {
+            Some(ref channel_id) => channel_id.clone(),
+            None => {
+                debug!(
+                    "[greeting_for] Guild {} has no system channel to greet",
+                    join.guild_id
+                );
+                return None;
+            }
+        };
+        let behavior = self.behavior_for_chat(&join.guild_id);
+        let greeting = self
+            .borg
+            .read()
+            .await
+            .greeting(self.platform_config.greeting.as_deref(), &behavior)?;
+        Some((channel_id, greeting))
+    }
+
+    /// Writes the runtime overrides to the overrides_path. A failure is only
+    /// logged, since the overrides still apply until the bot stops.
+    fn save_overrides(&self) {
+        if let Some(ref path) = self.platform_config.overrides_path {
+            if let Err(e) = save_overrides(Path::new(path), &self.runtime_overrides) {
+                error!("Could not save the overrides to {}. Error: {}", path, e);
+            }
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Utility Functions
+/////////////////////////////////////////////////////////////////////////////
+
+fn can_manage_guild(permissions: u64) -> bool {
+    permissions & MANAGE_GUILD != 0
+}
+
+/// Reads the overrides saved by save_overrides. A missing file means that no
+/// overrides were set yet.
+fn load_overrides(path: &Path) -> Result<HashMap<String, BehaviorOverride>, ConfigError> {
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut overrides: HashMap<String, BehaviorOverride> = serde_yaml::from_str(&data)?;
+    for behavior in overrides.values_mut() {
+        behavior.compile_patterns()?;
+    }
+    Ok(overrides)
+}
+
+fn save_overrides(
+    path: &Path,
+    overrides: &HashMap<String, BehaviorOverride>,
+) -> Result<(), ConfigError> {
+    fs::write(path, serde_yaml::to_string(overrides)?)?;
+    Ok(())
+}
+
+/// Sets the toggle in the guild's override, keeping whatever else was already
+/// overridden for the guild.
+fn set_toggle(
+    overrides: &mut HashMap<String, BehaviorOverride>,
+    guild_id: &str,
+    command: SlashCommand,
+    enabled: bool,
+) {
+    let behavior = overrides
+        .entry(guild_id.to_owned())
+        .or_insert_with(BehaviorOverride::default);
+    match command {
+        SlashCommand::Speaking => behavior.speaking = Some(enabled),
+        SlashCommand::Learning => behavior.learning = Some(enabled),
+        SlashCommand::Stats | SlashCommand::Sample => {}
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Interaction Handlers
+/////////////////////////////////////////////////////////////////////////////
+
+// There is no Discord gateway client yet, so nothing calls these handlers and
+// main does not start a Discord platform. A client only needs to implement
+// InteractionApi, call register_commands on startup, and pass interactions
+// and guild joins on to the handlers below.
+
+/// Registers the slash commands. This should be called once on startup.
+pub async fn register_commands<A: InteractionApi>(
+    context: &Arc<Mutex<Context<A>>>,
+) -> Result<(), ApiError> {
+    let context = context.lock().await;
+    let namespace = &context.platform_config.command_namespace;
+    let commands: Vec<CommandDefinition> = SlashCommand::ALL
+        .iter()
+        .filter(|c| config::command_settings(&context.platform_config.commands, c.name()).enabled)
+        .map(|c| CommandDefinition {
+            name: c.namespaced_name(namespace),
+            description: c.description(),
+        })
+        .collect();
+    context.api.register_commands(&commands).await
+}
+
+pub async fn handle_interaction<A: InteractionApi>(
+    context: &Arc<Mutex<Context<A>>>,
+    interaction: Interaction,
+) {
+    let mut context = context.lock().await;
+    if let Some(response) = context.run_command(&interaction).await {
+        if let Err(e) = context.api.respond(&interaction, response).await {
+            error!("Could not respond to interaction: {}", e);
+        }
+    }
+}
+
+pub async fn handle_guild_join<A: InteractionApi>(
+    context: &Arc<Mutex<Context<A>>>,
+    join: GuildJoin,
+) {
+    let context = context.lock().await;
+    if let Some((channel_id, greeting)) = context.greeting_for(&join).await {
+        if let Err(e) = context.api.send_message(&channel_id, greeting).await {
+            error!("Could not greet guild {}: {}", join.guild_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::MainBehavior, dictionary::Dictionary};
+
+    struct NoopApi;
+
+    #[async_trait]
+    impl InteractionApi for NoopApi {
+        async fn register_commands(&self, _: &[CommandDefinition]) -> Result<(), ApiError> {
+            Ok(())
+        }
+
+        async fn respond(&self, _: &Interaction, _: String) -> Result<(), ApiError> {
+            Ok(())
+        }
+
+        async fn send_message(&self, _: &str, _: String) -> Result<(), ApiError> {
+            Ok(())
+        }
+    }
+
+    fn context() -> Context<NoopApi> {
+        context_with_overrides(None)
+    }
+
+    fn context_with_overrides(overrides_path: Option<String>) -> Context<NoopApi> {
+        context_with(overrides_path, "")
+    }
+
+    fn context_with(overrides_path: Option<String>, command_namespace: &str) -> Context<NoopApi> {
+        let borg = Borg::new(Dictionary::new_empty(), MainBehavior::default());
+        let platform_config = config::DiscordPlatform {
+            token: String::new(),
+            reply_rate_multiplier: 1.0,
+            greet_on_join: false,
+            greeting: None,
+            behavior: Some(BehaviorOverride {
+                reply_rate: Some(0.5),
+                ..BehaviorOverride::default()
+            }),
+            chat_behaviors: None,
+            overrides_path,
+            command_namespace: command_namespace.to_owned(),
+            commands: HashMap::new(),
+        };
+        Context::new(platform_config, Arc::new(RwLock::new(borg)), NoopApi)
+    }
+
+    #[test]
+    fn test_set_toggle() {
+        let mut overrides = HashMap::new();
+        set_toggle(&mut overrides, "1", SlashCommand::Speaking, false);
+        set_toggle(&mut overrides, "1", SlashCommand::Learning, true);
+
+        assert_eq!(Some(false), overrides["1"].speaking);
+        assert_eq!(Some(true), overrides["1"].learning);
+        assert!(!overrides.contains_key("2"));
+    }
+
+    #[test]
+    fn test_runtime_override_is_resolved() {
+        let mut context = context();
+        set_toggle(
+            &mut context.runtime_overrides,
+            "1",
+            SlashCommand::Speaking,
+            false,
+        );
+
+        let behavior = context.behavior_for_chat("1").unwrap();
+        assert_eq!(Some(false), behavior.is_speaking());
+        assert_eq!(Some(0.5), behavior.reply_rate());
+
+        let behavior = context.behavior_for_chat("2").unwrap();
+        assert_eq!(None, behavior.is_speaking());
+    }
+
+    #[tokio::test]
+    async fn test_runtime_overrides_persist() {
+        let path = std::env::temp_dir().join("borg_test_discord_overrides.yaml");
+        let _ = fs::remove_file(&path);
+        let interaction = |command: &str, enabled: bool| Interaction {
+            id: "1".to_owned(),
+            guild_id: "1".to_owned(),
+            member_permissions: MANAGE_GUILD,
+            command: command.to_owned(),
+            enabled: Some(enabled),
+            count: None,
+        };
+
+        let mut context = context_with_overrides(Some(path.to_string_lossy().into_owned()));
+        context.run_command(&interaction("speaking", false)).await;
+        context.run_command(&interaction("learning", false)).await;
+        context.run_command(&interaction("learning", true)).await;
+
+        let context = context_with_overrides(Some(path.to_string_lossy().into_owned()));
+        let behavior = context.behavior_for_chat("1").unwrap();
+        assert_eq!(Some(false), behavior.is_speaking());
+        assert_eq!(Some(true), behavior.is_learning());
+        assert_eq!(Some(0.5), behavior.reply_rate());
+        assert_eq!(None, context.behavior_for_chat("2").unwrap().is_speaking());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_command_namespace() {
+        let stats = |command: &str| Interaction {
+            id: "1".to_owned(),
+            guild_id: "1".to_owned(),
+            member_permissions: MANAGE_GUILD,
+            command: command.to_owned(),
+            enabled: None,
+            count: None,
+        };
+
+        let mut namespaced = context_with(None, "borg1");
+        assert!(namespaced
+            .run_command(&stats("borg1_stats"))
+            .await
+            .is_some());
+        assert_eq!(None, namespaced.run_command(&stats("borg2_stats")).await);
+        assert_eq!(None, namespaced.run_command(&stats("stats")).await);
+
+        let mut bare = context();
+        assert!(bare.run_command(&stats("stats")).await.is_some());
+        assert_eq!(None, bare.run_command(&stats("borg1_stats")).await);
+
+        assert_eq!("stats", SlashCommand::Stats.namespaced_name(""));
+        assert_eq!(
+            "borg1_speaking",
+            SlashCommand::Speaking.namespaced_name("borg1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sample_command() {
+        let sample = |count: Option<usize>| Interaction {
+            id: "1".to_owned(),
+            guild_id: "1".to_owned(),
+            member_permissions: MANAGE_GUILD,
+            command: "sample".to_owned(),
+            enabled: None,
+            count,
+        };
+
+        let mut context = context();
+        assert_eq!(
+            Some("I have not learned anything yet.".to_owned()),
+            context.run_command(&sample(Some(3))).await
+        );
+
+        context
+            .borg
+            .write()
+            .await
+            .learn("hello crabs. crabs are red. lobsters are red too.", &None);
+        let response = context.run_command(&sample(Some(2))).await.unwrap();
+        assert_eq!(2, response.lines().count());
+        let response = context.run_command(&sample(None)).await.unwrap();
+        assert_eq!(3, response.lines().count());
+    }
+
+    #[tokio::test]
+    async fn test_greeting_on_guild_join() {
+        let join = |system_channel_id: Option<&str>| GuildJoin {
+            guild_id: "1".to_owned(),
+            system_channel_id: system_channel_id.map(str::to_owned),
+        };
+
+        let mut context = context();
+        assert_eq!(None, context.greeting_for(&join(Some("10"))).await);
+
+        context.platform_config.greet_on_join = true;
+        context.platform_config.greeting = Some("hello crabs!".to_owned());
+        assert_eq!(
+            Some(("10".to_owned(), "hello crabs!".to_owned())),
+            context.greeting_for(&join(Some("10"))).await
+        );
+        assert_eq!(None, context.greeting_for(&join(None)).await);
+
+        context.platform_config.greeting = None;
+        assert_eq!(None, context.greeting_for(&join(Some("10"))).await);
+        context.borg.write().await.learn(
+            "hello crabs. hello lobsters. crabs say hi. lobsters say hi.",
+            &None,
+        );
+        let (channel_id, greeting) = context.greeting_for(&join(Some("10"))).await.unwrap();
+        assert_eq!("10", channel_id);
+        let words = ["hello", "crabs", "lobsters", "say", "hi"];
+        assert!(
+            greeting.split(' ').all(|word| words.contains(&word)),
+            "{:?}",
+            greeting
+        );
+    }
+
+    #[tokio::test]
+    async fn test_command_settings() {
+        let interaction = |command: &str, member_permissions| Interaction {
+            id: "1".to_owned(),
+            guild_id: "1".to_owned(),
+            member_permissions,
+            command: command.to_owned(),
+            enabled: None,
+            count: None,
+        };
+
+        let mut context = context();
+        context.platform_config.commands.insert(
+            "stats".to_owned(),
+            config::CommandSettings {
+                enabled: false,
+                ..config::CommandSettings::default()
+            },
+        );
+        context.platform_config.commands.insert(
+            "sample".to_owned(),
+            config::CommandSettings {
+                role: CommandRole::Anyone,
+                ..config::CommandSettings::default()
+            },
+        );
+
+        assert_eq!(
+            None,
+            context
+                .run_command(&interaction("stats", MANAGE_GUILD))
+                .await
+        );
+        assert_eq!(
+            Some("I have not learned anything yet.".to_owned()),
+            context.run_command(&interaction("sample", 0)).await
+        );
+        assert_eq!(
+            Some("You need the Manage Server permission to use this command.".to_owned()),
+            context.run_command(&interaction("speaking", 0)).await
+        );
+    }
+
+    #[test]
+    fn test_commands_require_manage_guild() {
+        assert!(can_manage_guild(MANAGE_GUILD));
+        assert!(can_manage_guild(MANAGE_GUILD | 0x8));
+        assert!(!can_manage_guild(0x8));
+        assert!(!can_manage_guild(0));
     }
 }
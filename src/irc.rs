@@ -0,0 +1,314 @@
+use std::{error, fmt, sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use irc_client::client::prelude::{Client, Command, Config as ClientConfig, Message, Sender};
+use tokio::{sync::RwLock, time::delay_for};
+
+use crate::{
+    borg::{Borg, ChatKind},
+    config,
+    config::{BehaviorOverride, Commands},
+    learn_queue::LearnQueue,
+    platform::{IncomingMessage, Platform},
+    reply_scheduler::ReplyScheduler,
+};
+
+/////////////////////////////////////////////////////////////////////////////
+// RunError
+/////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+pub enum RunError {
+    ClientError(irc_client::error::Error),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RunError::ClientError(ref e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for RunError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            RunError::ClientError(ref e) => Some(e),
+        }
+    }
+}
+
+impl From<irc_client::error::Error> for RunError {
+    fn from(err: irc_client::error::Error) -> RunError {
+        RunError::ClientError(err)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Context Struct
+/////////////////////////////////////////////////////////////////////////////
+
+pub struct Context {
+    borg: Arc<RwLock<Borg>>,
+    platform_config: config::IrcPlatform,
+    learn_queue: LearnQueue,
+    reply_scheduler: ReplyScheduler,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Context Implementations
+/////////////////////////////////////////////////////////////////////////////
+
+impl Context {
+    pub fn new(
+        platform_config: config::IrcPlatform,
+        borg: Arc<RwLock<Borg>>,
+        learn_queue: LearnQueue,
+        reply_scheduler: ReplyScheduler,
+    ) -> Context {
+        Context {
+            borg,
+            platform_config,
+            learn_queue,
+            reply_scheduler,
+        }
+    }
+
+    fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            nickname: Some(self.platform_config.nick.clone()),
+            server: Some(self.platform_config.server.clone()),
+            port: Some(self.platform_config.port),
+            use_tls: Some(self.platform_config.tls),
+            password: self.platform_config.password.clone(),
+            channels: self.platform_config.channels.clone(),
+            ..ClientConfig::default()
+        }
+    }
+
+    fn override_for_chat(&self, channel: &str) -> Option<&BehaviorOverride> {
+        // Channel names are case insensitive.
+        self.platform_config
+            .chat_behaviors
+            .as_ref()
+            .and_then(|bs| {
+                bs.iter()
+                    .find(|cb| cb.chat_id.eq_ignore_ascii_case(channel))
+            })
+            .map(|cb| &cb.behavior)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Platform Implementation
+/////////////////////////////////////////////////////////////////////////////
+
+#[async_trait(?Send)]
+impl Platform for Context {
+    type Error = RunError;
+
+    const NAME: &'static str = "irc";
+
+    const CAN_REACT: bool = false;
+
+    fn learn_queue(&self) -> &LearnQueue {
+        &self.learn_queue
+    }
+
+    fn behaviors_for_chat(&self, channel: &str) -> Vec<Option<&BehaviorOverride>> {
+        vec![
+            self.platform_config.behavior.as_ref(),
+            self.override_for_chat(channel),
+        ]
+    }
+
+    fn reply_rate_multiplier(&self) -> f32 {
+        self.platform_config.reply_rate_multiplier
+    }
+
+    fn commands(&self) -> Option<&Commands> {
+        Some(&self.platform_config.commands)
+    }
+
+    /// Commands are addressed to the bot by its nick, e.g. "/speak@borg".
+    fn username(&self) -> Option<&str> {
+        Some(&self.platform_config.nick)
+    }
+
+    async fn run(self: Arc<Self>) -> Result<(), RunError> {
+        let mut client = Client::from_config(self.client_config()).await?;
+        client.identify()?;
+
+        let sender = client.sender();
+        let mut stream = client.stream()?;
+        while let Some(message) = stream.next().await.transpose()? {
+            handle(&self, &sender, &message).await;
+        }
+        Ok(())
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Message Handler
+/////////////////////////////////////////////////////////////////////////////
+
+/// Messages sent to a channel are group messages. Any other target is the
+/// bot's own nick, which is where private messages go.
+fn chat_kind(target: &str) -> ChatKind {
+    if target.starts_with(&['#', '&', '+', '!'][..]) {
+        ChatKind::Group
+    } else {
+        ChatKind::Private
+    }
+}
+
+/// Channels are chats of their own. Private messages are all sent to the
+/// bot's nick, so each sender is a chat of their own instead.
+fn chat_id<'a>(channel: &'a str, user_id: &'a str) -> &'a str {
+    match chat_kind(channel) {
+        ChatKind::Group => channel,
+        ChatKind::Private => user_id,
+    }
+}
+
+async fn handle(context: &Context, sender: &Sender, message: &Message) {
+    if let Command::PRIVMSG(ref channel, ref input) = message.command {
+        let (user_id, target) = match (message.source_nickname(), message.response_target()) {
+            (Some(user_id), Some(target)) => (user_id, target),
+            _ => return,
+        };
+        let incoming = IncomingMessage {
+            chat_id: chat_id(channel, user_id),
+            user_id,
+            line: input,
+            learn: true,
+            input: Some(input),
+            pivot_input: None,
+            addressed: false,
+            chat_kind: chat_kind(channel),
+            author_id: None,
+        };
+        let borg = context.borg.read().await;
+        let reply = context.process_message(&borg, &incoming);
+        drop(borg);
+
+        for (response, delay) in reply {
+            if delay > Duration::from_millis(0) {
+                delay_for(delay).await;
+            }
+            context.reply_scheduler.wait_turn().await;
+            if let Err(e) = sender.send_privmsg(target, response) {
+                error!("Could not send message to {}: {}", target, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{BehaviorValueResolver, ChatBehaviorOverrides, MainBehavior},
+        dictionary::Dictionary,
+    };
+
+    fn context() -> Context {
+        let borg = Borg::new(Dictionary::new_empty(), MainBehavior::default());
+        let platform_config = config::IrcPlatform {
+            server: "irc.example.com".to_owned(),
+            port: 6697,
+            tls: true,
+            nick: "borg".to_owned(),
+            channels: vec!["#crabs".to_owned(), "#lobsters".to_owned()],
+            password: None,
+            reply_rate_multiplier: 1.0,
+            behavior: Some(BehaviorOverride {
+                reply_rate: Some(0.1),
+                ..BehaviorOverride::default()
+            }),
+            chat_behaviors: Some(vec![ChatBehaviorOverrides {
+                chat_id: "#crabs".to_owned(),
+                behavior: BehaviorOverride {
+                    speaking: Some(false),
+                    ..BehaviorOverride::default()
+                },
+            }]),
+            commands: Commands::new(),
+        };
+        let (learn_queue, _) = LearnQueue::new(1);
+        let reply_scheduler =
+            ReplyScheduler::new(Duration::from_millis(0), Duration::from_millis(0));
+        Context::new(
+            platform_config,
+            Arc::new(RwLock::new(borg)),
+            learn_queue,
+            reply_scheduler,
+        )
+    }
+
+    #[test]
+    fn test_behavior_for_channel() {
+        let context = context();
+
+        let behavior = context.behavior_for_chat("#crabs").unwrap();
+        assert_eq!(Some(false), behavior.is_speaking());
+        assert_eq!(Some(0.1), behavior.reply_rate());
+
+        let behavior = context.behavior_for_chat("#CRABS").unwrap();
+        assert_eq!(Some(false), behavior.is_speaking());
+
+        let behavior = context.behavior_for_chat("#lobsters").unwrap();
+        assert_eq!(None, behavior.is_speaking());
+        assert_eq!(Some(0.1), behavior.reply_rate());
+    }
+
+    #[test]
+    fn test_reply_rate_multiplier() {
+        let mut context = context();
+        context.platform_config.reply_rate_multiplier = 0.5;
+
+        let main = MainBehavior {
+            reply_rate: 0.2,
+            ..MainBehavior::default()
+        };
+        let behavior = context.behavior_for_chat("#lobsters");
+        let b = BehaviorValueResolver::new(&main, &behavior);
+        assert_eq!(0.1 * 0.5, b.reply_rate());
+
+        context.platform_config.behavior = None;
+        let behavior = context.behavior_for_chat("#lobsters");
+        let b = BehaviorValueResolver::new(&main, &behavior);
+        assert_eq!(0.2 * 0.5, b.reply_rate());
+    }
+
+    #[test]
+    fn test_client_config() {
+        let config = context().client_config();
+        assert_eq!(Some("borg".to_owned()), config.nickname);
+        assert_eq!(Some(6697), config.port);
+        assert_eq!(Some(true), config.use_tls);
+        assert_eq!(
+            vec!["#crabs".to_owned(), "#lobsters".to_owned()],
+            config.channels
+        );
+    }
+
+    #[test]
+    fn test_username() {
+        assert_eq!(Some("borg"), context().username());
+    }
+
+    #[test]
+    fn test_chat_kind() {
+        assert_eq!(ChatKind::Group, chat_kind("#crabs"));
+        assert_eq!(ChatKind::Group, chat_kind("&lobsters"));
+        assert_eq!(ChatKind::Private, chat_kind("borg"));
+    }
+
+    #[test]
+    fn test_chat_id() {
+        assert_eq!("#crabs", chat_id("#crabs", "ferris"));
+        assert_eq!("ferris", chat_id("borg", "ferris"));
+        assert_eq!("corro", chat_id("borg", "corro"));
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use tokio::sync::{mpsc, RwLock};
+
+use crate::{borg::Borg, dictionary::Source};
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnJob Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// LearnJob is a line waiting to be learned. The line has already been
+/// prepared with the behavior of the chat it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LearnJob {
+    pub line: String,
+    pub source: Option<Source>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// LearnQueue Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// LearnQueue hands lines over to the learn worker, so that the platforms
+/// don't have to wait for the dictionary to be updated before replying. The
+/// queue is bounded: when it is full, new lines are dropped and counted
+/// instead of blocking the caller.
+#[derive(Clone)]
+pub struct LearnQueue {
+    sender: Arc<std::sync::Mutex<mpsc::Sender<LearnJob>>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl LearnQueue {
+    pub fn new(capacity: usize) -> (LearnQueue, mpsc::Receiver<LearnJob>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let queue = LearnQueue {
+            sender: Arc::new(std::sync::Mutex::new(sender)),
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (queue, receiver)
+    }
+
+    /// Queues the job without waiting. Returns false if the job was dropped.
+    pub fn push(&self, job: LearnJob) -> bool {
+        let result = match self.sender.lock() {
+            Ok(mut sender) => sender.try_send(job).is_ok(),
+            Err(_) => false,
+        };
+        if !result {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "The learn queue is full, dropped a line ({} dropped so far)",
+                dropped
+            );
+        }
+        result
+    }
+
+    /// Returns how many jobs were dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Worker
+/////////////////////////////////////////////////////////////////////////////
+
+/// Learns every queued line. Returns once all the queues have been dropped.
+pub async fn run_worker(borg: Arc<RwLock<Borg>>, mut receiver: mpsc::Receiver<LearnJob>) {
+    while let Some(job) = receiver.recv().await {
+        borg.write().await.learn_job(job);
+    }
+    debug!("[run_worker] Learn queue closed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::MainBehavior, dictionary::Dictionary};
+
+    fn job(line: &str) -> LearnJob {
+        LearnJob {
+            line: line.to_owned(),
+            source: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_worker_drains_queue() {
+        let borg = Arc::new(RwLock::new(Borg::new(
+            Dictionary::new_empty(),
+            MainBehavior::default(),
+        )));
+        let (queue, receiver) = LearnQueue::new(10);
+        assert!(queue.push(job("hello world.")));
+        assert!(queue.push(job("hello crabs.")));
+        drop(queue);
+
+        run_worker(borg.clone(), receiver).await;
+        assert_eq!(2, borg.read().await.stats().sentence_count);
+    }
+
+    #[test]
+    fn test_full_queue_drops() {
+        let (queue, _receiver) = LearnQueue::new(1);
+        assert!(queue.push(job("hello world.")));
+        assert!(!queue.push(job("hello crabs.")));
+        assert!(!queue.push(job("hello lobsters.")));
+        assert_eq!(2, queue.dropped());
+    }
+}
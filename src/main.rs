@@ -1,3 +1,10 @@
+// Without the telegram or irc features, only the command-line modes and the
+// admin interface are built, and the reply code has no caller.
+#![cfg_attr(
+    not(any(feature = "telegram", feature = "irc")),
+    allow(dead_code, unused_imports)
+)]
+
 mod pattern;
 
 #[macro_use]
@@ -9,6 +16,7 @@ extern crate serde;
 extern crate serde_json;
 extern crate serde_yaml;
 extern crate tokio;
+extern crate whatlang;
 #[macro_use]
 extern crate async_trait;
 #[macro_use]
@@ -17,22 +25,47 @@ extern crate env_logger;
 
 #[macro_use]
 mod util;
+mod admin;
+mod autosave;
+mod backup;
 mod borg;
+mod cli;
 mod config;
 mod dictionary;
+// There is no Discord gateway client yet, so nothing calls the Discord
+// handlers.
+#[cfg(feature = "discord")]
+#[allow(dead_code)]
 mod discord;
+#[cfg(feature = "irc")]
+mod irc;
+mod learn_queue;
+#[cfg(any(feature = "telegram", feature = "irc"))]
+mod platform;
+mod postprocess;
+#[cfg(any(feature = "telegram", feature = "irc"))]
+mod reply_scheduler;
+mod sentiment;
+#[cfg(feature = "telegram")]
 mod telegram;
 
 use borg::Borg;
-use config::{Config, ConfigError};
-use dictionary::Dictionary;
-use futures::lock::Mutex;
+use cli::Mode;
+use config::{Config, ConfigError, MainBehavior};
+use dictionary::{Dictionary, SaveMode};
 use futures::Future;
+use learn_queue::LearnQueue;
+#[cfg(any(feature = "telegram", feature = "irc"))]
+use platform::Platform;
+#[cfg(any(feature = "telegram", feature = "irc"))]
+use reply_scheduler::ReplyScheduler;
 use std::error;
 use std::fmt;
 use std::path::Path;
 use std::pin::Pin;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
 
 /////////////////////////////////////////////////////////////////////////////
 // Platform Error
@@ -40,13 +73,21 @@ use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum PlatformError {
+    AdminError(std::io::Error),
+    #[cfg(feature = "telegram")]
     TelegramError(telegram::RunError),
+    #[cfg(feature = "irc")]
+    IrcError(irc::RunError),
 }
 
 impl fmt::Display for PlatformError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            PlatformError::AdminError(ref e) => e.fmt(f),
+            #[cfg(feature = "telegram")]
             PlatformError::TelegramError(ref e) => e.fmt(f),
+            #[cfg(feature = "irc")]
+            PlatformError::IrcError(ref e) => e.fmt(f),
         }
     }
 }
@@ -54,17 +95,29 @@ impl fmt::Display for PlatformError {
 impl error::Error for PlatformError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
+            PlatformError::AdminError(ref e) => Some(e),
+            #[cfg(feature = "telegram")]
             PlatformError::TelegramError(ref e) => Some(e),
+            #[cfg(feature = "irc")]
+            PlatformError::IrcError(ref e) => Some(e),
         }
     }
 }
 
+#[cfg(feature = "telegram")]
 impl From<telegram::RunError> for PlatformError {
     fn from(err: telegram::RunError) -> PlatformError {
         PlatformError::TelegramError(err)
     }
 }
 
+#[cfg(feature = "irc")]
+impl From<irc::RunError> for PlatformError {
+    fn from(err: irc::RunError) -> PlatformError {
+        PlatformError::IrcError(err)
+    }
+}
+
 /////////////////////////////////////////////////////////////////////////////
 // Constants
 /////////////////////////////////////////////////////////////////////////////
@@ -75,7 +128,11 @@ const CONFIG_PATH: &str = "config.yml";
 // Types
 /////////////////////////////////////////////////////////////////////////////
 
-type PlatformTasks = Vec<Pin<Box<dyn Future<Output = Result<(), PlatformError>>>>>;
+type PlatformTask = Pin<Box<dyn Future<Output = Result<(), PlatformError>>>>;
+type PlatformTasks = Vec<PlatformTask>;
+/// The name of a platform along with its task, or the error that kept it from
+/// starting.
+type PlatformInit = (&'static str, Result<PlatformTask, Box<dyn error::Error>>);
 
 /////////////////////////////////////////////////////////////////////////////
 // Main Function
@@ -87,6 +144,14 @@ async fn main() {
 
     env_logger::init();
 
+    let mode = match cli::parse_args(std::env::args().skip(1)) {
+        Ok(m) => m,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+
     let config = match Config::load(Path::new(CONFIG_PATH)) {
         Ok(c) => c,
         Err(e) => match e {
@@ -110,12 +175,33 @@ async fn main() {
                 );
                 return;
             }
+            ConfigError::PatternError(e) => {
+                error!(
+                    "One of the patterns in the configuration file is not a \
+                    valid regular expression. Please check your patterns and \
+                    try again. Details: {}",
+                    e
+                );
+                return;
+            }
+            ConfigError::ValidationError(message) => {
+                error!(
+                    "The configuration file contains an invalid value. {}.",
+                    message
+                );
+                return;
+            }
         },
     };
 
     debug!("Config {:?} loaded.", CONFIG_PATH);
 
-    let mut dict = match Dictionary::load(Path::new(&config.dictionary_path)) {
+    if let Mode::TestPattern(ref input) = mode {
+        cli::print_pattern_results(input, &cli::test_patterns(&config, input));
+        return;
+    }
+
+    let mut dict = match load_dictionary(&config) {
         Ok(d) => d,
         Err(e) => match e {
             dictionary::Error::IOError(e) => {
@@ -137,11 +223,36 @@ async fn main() {
                 );
                 return;
             }
+            dictionary::Error::CompressionError(e) => {
+                error!(
+                    "The dictionary file {:?} ends in .gz, but is not valid gzip \
+                data. Please check the file, or rename it if it is not compressed. \
+                Details: {:?}",
+                    config.dictionary_path, e
+                );
+                return;
+            }
+            dictionary::Error::UnsupportedVersion(version) => {
+                error!(
+                    "The dictionary file uses format version {}, which was written \
+                by a newer version of this program and cannot be read. Please \
+                upgrade the program.",
+                    version
+                );
+                return;
+            }
         },
     };
 
     debug!("Dictionary {:?} loaded.", &config.dictionary_path);
 
+    dict.set_normalization(config.sentence_normalization);
+    dict.set_case_folding(config.case_folding);
+    dict.set_elongation(config.elongation);
+    dict.set_max_word_length(config.max_word_length);
+    dict.set_long_word_share(config.long_word_share);
+    dict.set_track_successors(config.track_successors);
+
     if dict.needs_to_build_indices() {
         warn!("Indices need to be built. Building indices.");
         dict.rebuild_indices();
@@ -152,31 +263,194 @@ async fn main() {
         }
     }
 
-    let borg = Arc::new(Mutex::new(Borg::new(dict, config.behavior)));
+    if let Mode::Export(ref path) = mode {
+        match dict.export_text(Path::new(path)) {
+            Ok(()) => info!(
+                "Exported {} sentences to {:?}.",
+                dict.stats().sentence_count,
+                path
+            ),
+            Err(e) => error!("Could not export the dictionary to {:?}: {}", path, e),
+        }
+        return;
+    }
+
+    dict.pin(&config.pinned_sentences);
+
+    if mode == Mode::Compact {
+        if let Some(min_count) = config.min_word_count {
+            let pruned = dict.prune_rare_words(min_count);
+            info!(
+                "Pruned {} words used in fewer than {} sentences.",
+                pruned, min_count
+            );
+        }
+        match dict.compact(Path::new(&config.dictionary_path)) {
+            Ok(()) => info!("Dictionary {:?} compacted.", &config.dictionary_path),
+            Err(e) => error!("Could not compact the dictionary: {}", e),
+        }
+        return;
+    }
+
+    if let Mode::Merge(ref other_path) = mode {
+        let other = match Dictionary::load(Path::new(other_path)) {
+            Ok(d) => d,
+            Err(e) => {
+                error!(
+                    "Could not load the dictionary {:?} to merge: {}",
+                    other_path, e
+                );
+                return;
+            }
+        };
+        let added = dict.merge(other);
+        info!("Merged {} new sentences from {:?}.", added, other_path);
+        match dict.compact(Path::new(&config.dictionary_path)) {
+            Ok(()) => info!("Dictionary {:?} saved.", &config.dictionary_path),
+            Err(e) => error!("Could not save the merged dictionary: {}", e),
+        }
+        return;
+    }
+
+    if let Mode::Import(ref source) | Mode::ImportAttributed(ref source) = mode {
+        let attributed = matches!(mode, Mode::ImportAttributed(_));
+        // Importing does not go through the learning behavior, so the
+        // default one will do.
+        let mut borg = Borg::new(dict, MainBehavior::default());
+        match cli::import_from(&mut borg, source, attributed) {
+            Ok(added) => info!("Imported {} new sentences from {:?}.", added, source),
+            Err(e) => {
+                error!("Could not import {:?}: {}", source, e);
+                return;
+            }
+        }
+        match borg.save_dictionary(Path::new(&config.dictionary_path), SaveMode::Full) {
+            Ok(()) => info!("Dictionary {:?} saved.", &config.dictionary_path),
+            Err(e) => error!("Could not save the dictionary: {}", e),
+        }
+        return;
+    }
+
+    warn_about_disabled_platforms(&config);
+    warn_about_reactions(&config);
+
+    let mut behavior = config.behavior;
+    let seed = match behavior.rng_seed {
+        Some(seed) => seed,
+        None => {
+            let seed = borg::saved_seed(Path::new(&config.dictionary_path));
+            info!(
+                "Using random seed {}. It is saved next to the dictionary and \
+                used again after a restart, unless rng_seed is set in the \
+                behavior configuration.",
+                seed
+            );
+            seed
+        }
+    };
+    behavior.rng_seed = Some(seed);
+
+    let borg = Borg::new(dict, behavior);
+    borg.load_chat_state(Path::new(&config.dictionary_path));
+    let borg = Arc::new(RwLock::new(borg));
+    let (learn_queue, learn_receiver) = LearnQueue::new(config.learn_queue_size);
+    // Replies are spaced out across all the platforms, so that they share
+    // one scheduler.
+    #[cfg(any(feature = "telegram", feature = "irc"))]
+    let reply_scheduler = ReplyScheduler::new(
+        Duration::from_millis(config.reply_interval_ms),
+        Duration::from_millis(config.reply_interval_jitter_ms),
+    );
     let mut tasks: PlatformTasks = vec![];
 
-    let telegram_context = match config.telegram {
-        Some(telegram_config) => Some(Arc::new(Mutex::new(
-            match telegram::Context::new(telegram_config, borg.clone()) {
-                Ok(o) => o,
-                Err(e) => {
-                    error!("Could not start Telegram. Error: {}", e);
-                    return;
+    let learn_borg = borg.clone();
+    tasks.push(Box::pin(async move {
+        learn_queue::run_worker(learn_borg, learn_receiver).await;
+        Ok(())
+    }));
+
+    // Auto-saving runs in the background, so that it does not keep the
+    // program alive once all the platforms have stopped.
+    if config.auto_save_period > 0 {
+        tokio::spawn(autosave::run(
+            borg.clone(),
+            Path::new(&config.dictionary_path).to_path_buf(),
+            Duration::from_secs(config.auto_save_period as u64),
+            config.save_mode,
+            config.compact_every,
+            config.min_word_count,
+            config.backups.clone(),
+        ));
+    }
+
+    let mut platforms: Vec<PlatformInit> = vec![];
+
+    #[cfg(feature = "telegram")]
+    if let Some(telegram_config) = config.telegram {
+        let init: Result<PlatformTask, Box<dyn error::Error>> = telegram::Context::new(
+            telegram_config,
+            borg.clone(),
+            learn_queue.clone(),
+            reply_scheduler.clone(),
+        )
+        .map(|telegram_context| {
+            let telegram_context = Arc::new(telegram_context);
+            Box::pin(async move {
+                match telegram_context.run().await {
+                    Err(e) => Err(PlatformError::TelegramError(e)),
+                    Ok(_) => Ok(()),
                 }
-            },
-        ))),
-        None => None,
-    };
+            }) as PlatformTask
+        })
+        .map_err(|e| e.into());
+        platforms.push(("Telegram", init));
+    }
 
-    if let Some(telegram_context) = telegram_context {
-        tasks.push(Box::pin(async move {
-            match telegram::run(telegram_context.clone()).await {
-                Err(e) => Err(PlatformError::TelegramError(e)),
-                Ok(_) => Ok(()),
-            }
-        }));
+    #[cfg(feature = "irc")]
+    if let Some(irc_config) = config.irc {
+        let irc_context = Arc::new(irc::Context::new(
+            irc_config,
+            borg.clone(),
+            learn_queue.clone(),
+            reply_scheduler.clone(),
+        ));
+        platforms.push((
+            "IRC",
+            Ok(Box::pin(async move {
+                match irc_context.run().await {
+                    Err(e) => Err(PlatformError::IrcError(e)),
+                    Ok(_) => Ok(()),
+                }
+            })),
+        ));
+    }
+
+    if let Some(admin_config) = config.admin {
+        let admin_context = Arc::new(admin::Context::new(
+            borg.clone(),
+            learn_queue.clone(),
+            Path::new(&config.dictionary_path).to_path_buf(),
+            Path::new(CONFIG_PATH).to_path_buf(),
+            admin_config.token.clone(),
+        ));
+        platforms.push((
+            "the admin interface",
+            Ok(Box::pin(async move {
+                match admin::run(admin_context, &admin_config.bind_address).await {
+                    Err(e) => Err(PlatformError::AdminError(e)),
+                    Ok(_) => Ok(()),
+                }
+            })),
+        ));
     }
 
+    tasks.extend(build_tasks(platforms));
+
+    // The platforms and the admin interface hold their own handles to the
+    // queue. Dropping this one lets the learn worker stop once all of them
+    // are gone.
+    drop(learn_queue);
+
     for result in futures::future::join_all(tasks).await {
         if let Err(e) = result {
             error!("Task exited with an error: {}", e);
@@ -184,6 +458,98 @@ async fn main() {
     }
 }
 
+/// Keeps the tasks of the platforms that could be started. A platform that
+/// could not be started is logged and skipped, so that the others still run.
+fn build_tasks(platforms: Vec<PlatformInit>) -> PlatformTasks {
+    let mut tasks: PlatformTasks = vec![];
+    for (name, init) in platforms {
+        match init {
+            Ok(task) => tasks.push(task),
+            Err(e) => error!("Could not start {}. Error: {}", name, e),
+        }
+    }
+    tasks
+}
+
+/// Warns about configured platforms that were left out of this build.
+fn warn_about_disabled_platforms(config: &Config) {
+    let platforms = [
+        (
+            "telegram",
+            config.telegram.is_some(),
+            cfg!(feature = "telegram"),
+        ),
+        (
+            "discord",
+            config.discord.is_some(),
+            cfg!(feature = "discord"),
+        ),
+        ("irc", config.irc.is_some(), cfg!(feature = "irc")),
+    ];
+    for (name, configured, enabled) in platforms.iter() {
+        if *configured && !*enabled {
+            warn!(
+                "The configuration has a {:?} section, but this program was built \
+                without the {:?} feature. The platform will not be started.",
+                name, name
+            );
+        }
+    }
+}
+
+/// Warns about the settings that only work with reactions, like learn_ack and
+/// react_to_sentiment, on the configured platforms that cannot react, where they do nothing.
+fn warn_about_reactions(config: &Config) {
+    let platforms: &[(&str, bool, bool)] = &[
+        #[cfg(feature = "telegram")]
+        (
+            telegram::Context::NAME,
+            config.telegram.is_some(),
+            telegram::Context::CAN_REACT,
+        ),
+        #[cfg(feature = "irc")]
+        (
+            irc::Context::NAME,
+            config.irc.is_some(),
+            irc::Context::CAN_REACT,
+        ),
+    ];
+    for &(name, configured, can_react) in platforms {
+        if !configured || can_react {
+            continue;
+        }
+        for (scope, field) in config.reaction_settings(name) {
+            warn!(
+                "{} is on in the {} configuration, but {} cannot react, so it \
+                does nothing there.",
+                field, scope, name
+            );
+        }
+    }
+}
+
+/// Loads the dictionary. When it cannot be parsed and restore_on_corruption
+/// is set, it is replaced with the newest backup that can.
+fn load_dictionary(config: &Config) -> Result<Dictionary, dictionary::Error> {
+    let path = Path::new(&config.dictionary_path);
+    match (Dictionary::load(path), &config.backups) {
+        (Err(dictionary::Error::JSONError(e)), Some(backups)) if backups.restore_on_corruption => {
+            error!("The dictionary file cannot be parsed: {}", e);
+            match backup::restore_newest(path, Path::new(&backups.directory))? {
+                Some(backup) => {
+                    warn!("Restored the dictionary from the backup {:?}.", backup);
+                    Dictionary::load(path)
+                }
+                None => {
+                    error!("None of the backups could be loaded.");
+                    Err(dictionary::Error::JSONError(e))
+                }
+            }
+        }
+        (result, _) => result,
+    }
+}
+
 fn save_dictionary(config: &Config, dict: &Dictionary) -> Result<(), dictionary::Error> {
     match dict.write_to_disk(Path::new(&config.dictionary_path)) {
         Ok(_) => Ok(()),
@@ -196,3 +562,21 @@ fn save_dictionary(config: &Config, dict: &Dictionary) -> Result<(), dictionary:
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_build_tasks_skips_failed_platforms() {
+        let platforms: Vec<PlatformInit> = vec![
+            ("broken", Err("bad token".into())),
+            ("working", Ok(Box::pin(async { Ok(()) }))),
+        ];
+        let tasks = build_tasks(platforms);
+        assert_eq!(1, tasks.len());
+        for result in futures::future::join_all(tasks).await {
+            assert!(result.is_ok());
+        }
+    }
+}
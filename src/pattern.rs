@@ -42,19 +42,64 @@ impl fmt::Display for NotCompiledError {
 
 impl error::Error for NotCompiledError {}
 
+/// Pattern is a regular expression from the configuration. By default a
+/// pattern has to match the whole input, so "borg" matches "borg" but not
+/// "hi borg"; set substring or whole_word to match anywhere in the input.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Pattern {
     #[serde(skip)]
     compiled: Option<Regex>,
+    /// The unanchored regex that finds fragments of the input, e.g. for
+    /// scrubbing. Only set when it differs from compiled.
+    #[serde(skip)]
+    fragments: Option<Regex>,
     pub original: String,
+    /// Only matches whole words, so that e.g. "bot" does not match "robot".
+    /// The word can be anywhere in the input.
+    #[serde(default)]
+    pub whole_word: bool,
+    /// Matches anywhere in the input instead of only the whole input.
+    #[serde(default)]
+    pub substring: bool,
 }
 
 impl Pattern {
+    /// Creates a pattern from the given regex source and compiles it.
+    pub fn new(original: &str) -> Result<Pattern, CompilationError> {
+        let mut pattern = Pattern {
+            compiled: None,
+            fragments: None,
+            original: original.to_owned(),
+            whole_word: false,
+            substring: false,
+        };
+        pattern.compile()?;
+        Ok(pattern)
+    }
+
+    /// Creates a pattern that matches anywhere in the input and compiles it.
+    #[cfg(test)]
+    pub(crate) fn substring(original: &str) -> Pattern {
+        let mut pattern = Pattern::new(original).unwrap();
+        pattern.compiled = None;
+        pattern.fragments = None;
+        pattern.substring = true;
+        pattern.compile().unwrap();
+        pattern
+    }
+
     pub fn compile(&mut self) -> Result<&Regex, CompilationError> {
         match self.compiled {
             Some(ref p) => Ok(p),
             None => {
-                self.compiled = Some(Regex::new(&self.original)?);
+                if self.whole_word {
+                    self.compiled = Some(Regex::new(&format!(r"\b(?:{})\b", self.original))?);
+                } else if self.substring {
+                    self.compiled = Some(Regex::new(&self.original)?);
+                } else {
+                    self.fragments = Some(Regex::new(&self.original)?);
+                    self.compiled = Some(Regex::new(&format!(r"\A(?:{})\z", self.original))?);
+                }
                 // Since self.compiled was assigned a value in the previous
                 // statement, it is safe to unwrap.
                 Ok(self.compiled.as_ref().unwrap())
@@ -68,18 +113,164 @@ impl Pattern {
             None => Err(NotCompiledError),
         }
     }
+
+    /// Returns true if the pattern matches the input, or anywhere in it for
+    /// substring and whole_word patterns.
+    pub(crate) fn matches(&self, input: &str) -> bool {
+        match self.get_regex() {
+            // The compiled regex is anchored unless the pattern may match
+            // anywhere, so searching finds exactly the matches we want.
+            Ok(regex) => regex.find(input).is_some(),
+            Err(_e) => panic!("Pattern {:?} is not compiled", self),
+        }
+    }
+
+    /// Fills the template with the capture groups of the first match in the
+    /// input: $0 is the whole match, $1 the first group and so on, and $$ is a
+    /// dollar sign. Groups that did not take part in the match are left
+    /// empty. Returns None if the pattern does not match.
+    pub(crate) fn expand(&self, input: &str, template: &str) -> Option<String> {
+        let captures = match self.get_regex() {
+            Ok(regex) => regex.captures(input)?,
+            Err(_e) => panic!("Pattern {:?} is not compiled", self),
+        };
+        let mut expanded = String::with_capacity(template.len());
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                expanded.push(c);
+                continue;
+            }
+            let mut group = String::new();
+            while let Some(&d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+                group.push(d);
+                chars.next();
+            }
+            match group.parse::<usize>() {
+                Ok(i) => expanded.push_str(captures.at(i).unwrap_or("")),
+                Err(_) if chars.peek() == Some(&'$') => {
+                    expanded.push('$');
+                    chars.next();
+                }
+                Err(_) => expanded.push('$'),
+            }
+        }
+        Some(expanded)
+    }
+}
+
+pub(crate) fn matches_any<'a>(
+    input: &str,
+    patterns: impl IntoIterator<Item = &'a Pattern>,
+) -> Option<&'a Pattern> {
+    patterns.into_iter().find(|p| p.matches(input))
 }
 
-pub(crate) fn matches_any<'a>(input: &str, patterns: &'a Vec<Pattern>) -> Option<&'a Pattern> {
+/// Removes every fragment of the input that matches any of the patterns and
+/// collapses the whitespace left behind. Fragments are found anywhere in the
+/// input, even for patterns that otherwise have to match the whole input.
+pub(crate) fn remove_all<'a>(
+    input: &str,
+    patterns: impl IntoIterator<Item = &'a Pattern>,
+) -> String {
+    let mut output = input.to_owned();
     for p in patterns {
         match p.get_regex() {
             Ok(regex) => {
-                if regex.is_match(input) {
-                    return Some(p);
-                }
+                let regex = p.fragments.as_ref().unwrap_or(regex);
+                output = regex.replace_all(&output, "");
             }
             Err(_e) => panic!("Pattern {:?} is not compiled", p),
         }
     }
-    None
+    output.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn whole_word(original: &str) -> Pattern {
+        let mut pattern = Pattern {
+            compiled: None,
+            fragments: None,
+            original: original.to_owned(),
+            whole_word: true,
+            substring: false,
+        };
+        pattern.compile().unwrap();
+        pattern
+    }
+
+    #[test]
+    fn test_expand() {
+        let p = Pattern::substring(r"(\w+) is (\w+)(!)?");
+        assert_eq!(
+            Some("crab was cool, $1 $".to_owned()),
+            p.expand("the crab is cool", "$1 was $2, $$1 $")
+        );
+        assert_eq!(
+            Some("[crab is cool] []".to_owned()),
+            p.expand("crab is cool", "[$0] [$3]")
+        );
+        assert_eq!(None, p.expand("crabs", "$1"));
+    }
+
+    #[test]
+    fn test_matches() {
+        let pattern = Pattern::new("-100123").unwrap();
+        assert!(pattern.matches("-100123"));
+        assert!(!pattern.matches("-1001234567890"));
+
+        let pattern = Pattern::new("a|ab").unwrap();
+        assert!(pattern.matches("ab"));
+        assert!(!pattern.matches("abc"));
+
+        let pattern = Pattern::substring("42");
+        assert!(pattern.matches("42"));
+        assert!(pattern.matches("4242"));
+    }
+
+    #[test]
+    fn test_remove_all() {
+        let patterns = vec![Pattern::new("darn").unwrap(), whole_word("heck")];
+        assert_eq!(
+            "oh it broke, heckler",
+            remove_all("oh darn it broke, heck heckler", &patterns)
+        );
+    }
+
+    #[test]
+    fn test_whole_word() {
+        let pattern = Pattern::substring("bot");
+        assert!(pattern.matches("hey bot"));
+        assert!(pattern.matches("robot"));
+
+        let pattern = whole_word("bot");
+        assert!(pattern.matches("hey bot"));
+        assert!(pattern.matches("bot, hi"));
+        assert!(!pattern.matches("reboot"));
+        assert!(!pattern.matches("robot"));
+        assert!(!pattern.matches("bots"));
+
+        let pattern = whole_word("bot|borg");
+        assert!(pattern.matches("hi borg"));
+        assert!(!pattern.matches("borgs"));
+    }
+
+    #[test]
+    fn test_whole_word_from_yaml() {
+        let mut patterns: Vec<Pattern> =
+            serde_yaml::from_str(
+                "- original: bot\n  whole_word: true\n- original: bot\n  substring: true\n- original: bot\n",
+            )
+            .unwrap();
+        for p in patterns.iter_mut() {
+            p.compile().unwrap();
+        }
+        assert!(!patterns[0].matches("robot"));
+        assert!(patterns[1].matches("robot"));
+        assert!(!patterns[2].matches("robot"));
+        assert!(patterns[2].matches("bot"));
+    }
 }
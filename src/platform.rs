@@ -0,0 +1,920 @@
+use std::{error, sync::Arc, time::Duration};
+
+use crate::{
+    borg::{Borg, ChatKind, KillSwitch, ReplyAction},
+    config,
+    config::{BehaviorOverride, BehaviorOverrideValueResolver, CommandRole, Commands},
+    dictionary::Source,
+    learn_queue::LearnQueue,
+};
+
+/////////////////////////////////////////////////////////////////////////////
+// Kill Switch Commands
+/////////////////////////////////////////////////////////////////////////////
+
+/// Returns the command without the username it is addressed to, e.g.
+/// "/speak" for "/speak@borg". A command addressed to another username is
+/// meant for another bot, and None is returned.
+fn command_for_bot<'a>(command: &'a str, username: Option<&str>) -> Option<&'a str> {
+    let mut parts = command.splitn(2, '@');
+    let name = parts.next()?;
+    match (parts.next(), username) {
+        (None, _) => Some(name),
+        (Some(target), Some(username)) if target.eq_ignore_ascii_case(username) => Some(name),
+        (Some(_), _) => None,
+    }
+}
+
+/// Parses the owner's kill switch commands: "/shutup" stops replying,
+/// "/shutup learning" also stops learning, and "/speak" turns the kill switch
+/// off. The command can be followed by the bot's username, e.g.
+/// "/speak@borg".
+fn parse_kill_switch_command(input: &str, username: Option<&str>) -> Option<KillSwitch> {
+    let mut words = input.split_whitespace();
+    let command = command_for_bot(words.next()?, username)?;
+    match (command, words.next(), words.next()) {
+        ("/shutup", None, _) => Some(KillSwitch::Replies),
+        ("/shutup", Some("learning"), None) => Some(KillSwitch::RepliesAndLearning),
+        ("/speak", None, _) => Some(KillSwitch::Off),
+        _ => None,
+    }
+}
+
+/// Returns the name of the command that sets the kill switch, as it is
+/// configured in commands.
+fn kill_switch_command_name(kill_switch: KillSwitch) -> &'static str {
+    match kill_switch {
+        KillSwitch::Off => "speak",
+        KillSwitch::Replies | KillSwitch::RepliesAndLearning => "shutup",
+    }
+}
+
+fn kill_switch_answer(kill_switch: KillSwitch) -> &'static str {
+    match kill_switch {
+        KillSwitch::Off => "Speaking again.",
+        KillSwitch::Replies => "Not saying anything until /speak.",
+        KillSwitch::RepliesAndLearning => "Not saying or learning anything until /speak.",
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Preview Command
+/////////////////////////////////////////////////////////////////////////////
+
+/// Parses the owner's "/preview <text>" command, which shows what the bot
+/// would reply to the text, and returns the text. Like the kill switch
+/// commands, it can be followed by the bot's username.
+fn parse_preview_command<'a>(input: &'a str, username: Option<&str>) -> Option<&'a str> {
+    let input = input.trim_start();
+    let command = input.split_whitespace().next()?;
+    if command_for_bot(command, username) != Some("/preview") {
+        return None;
+    }
+    let text = input[command.len()..].trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Command Access
+/////////////////////////////////////////////////////////////////////////////
+
+/// CommandAccess is whether a user may use a command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandAccess {
+    /// The command is off, and the message is handled like any other.
+    Disabled,
+    /// The command is on, but not for the user.
+    Denied,
+    Allowed,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// IncomingMessage Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// IncomingMessage is what the shared message processing needs to know about
+/// a message, once the platform has taken it apart.
+#[derive(Debug)]
+pub struct IncomingMessage<'a> {
+    pub chat_id: &'a str,
+    pub user_id: &'a str,
+    /// What is learned from the message. It is empty when there is nothing
+    /// to learn.
+    pub line: &'a str,
+    /// Whether the message may be learned at all, e.g. not when it is a
+    /// forward that the platform does not learn. Such messages are still
+    /// replied to.
+    pub learn: bool,
+    /// The text that can be replied to. A message without one, e.g. a lone
+    /// attachment, is only learned.
+    pub input: Option<&'a str>,
+    /// What the response is made from, when it is more than the input.
+    pub pivot_input: Option<&'a str>,
+    /// Set when the message addressed the bot in a way that the nick patterns
+    /// cannot see, e.g. by replying to one of its messages.
+    pub addressed: bool,
+    /// Whether the message was sent in a one-on-one chat or a group.
+    pub chat_kind: ChatKind,
+    /// Who wrote the message, when it is not the user who sent it, e.g. a
+    /// forwarded message. Learned sentences are attributed to them.
+    pub author_id: Option<&'a str>,
+}
+
+/////////////////////////////////////////////////////////////////////////////
+// Platform Trait
+/////////////////////////////////////////////////////////////////////////////
+
+/// Platform is what every chat platform has in common. A platform only needs
+/// to say which behavior overrides apply to a chat, to turn its messages into
+/// IncomingMessages, and to send the replies; learning and replying are the
+/// same everywhere.
+#[async_trait(?Send)]
+pub trait Platform {
+    type Error: error::Error;
+
+    /// The name that learned sentences are attributed to, e.g. "telegram".
+    const NAME: &'static str;
+
+    /// Whether the platform can answer with a reaction instead of a message.
+    const CAN_REACT: bool;
+
+    fn learn_queue(&self) -> &LearnQueue;
+
+    /// Returns the behavior overrides of the chat, ordered from the lowest to
+    /// the highest precedence.
+    fn behaviors_for_chat(&self, chat_id: &str) -> Vec<Option<&BehaviorOverride>>;
+
+    fn reply_rate_multiplier(&self) -> f32;
+
+    /// The user ID of the owner, who can use the kill switch commands. The
+    /// commands are off without one.
+    fn owner_id(&self) -> Option<&str> {
+        None
+    }
+
+    /// The settings of the commands, which can turn them off or let anyone
+    /// use them.
+    fn commands(&self) -> Option<&Commands> {
+        None
+    }
+
+    /// The bot's own username, without the @. Commands addressed to another
+    /// username, e.g. "/shutup@otherbot", are left alone.
+    fn username(&self) -> Option<&str> {
+        None
+    }
+
+    /// Decides whether the user may use the command with the given name.
+    fn command_access(&self, name: &str, user_id: &str) -> CommandAccess {
+        let settings = self
+            .commands()
+            .map(|commands| config::command_settings(commands, name))
+            .unwrap_or_default();
+        if !settings.enabled {
+            return CommandAccess::Disabled;
+        }
+        match (settings.role, self.owner_id()) {
+            (CommandRole::Anyone, _) => CommandAccess::Allowed,
+            (CommandRole::Admin, None) => CommandAccess::Disabled,
+            (CommandRole::Admin, Some(owner_id)) if owner_id == user_id => CommandAccess::Allowed,
+            (CommandRole::Admin, Some(_)) => CommandAccess::Denied,
+        }
+    }
+
+    /// Reacts to the message with the emoji. It is only called when CAN_REACT
+    /// is set, and platforms that set it must implement it.
+    fn react(&self, _message: &IncomingMessage, _emoji: &str) {}
+
+    fn behavior_for_chat(&self, chat_id: &str) -> Option<BehaviorOverrideValueResolver> {
+        config::resolve_behavior(
+            self.behaviors_for_chat(chat_id),
+            self.reply_rate_multiplier(),
+        )
+    }
+
+    /// Learns from the message and makes up the replies to it, along with how
+    /// long to wait before sending each of them. The borg is only borrowed
+    /// here, so that the platform can let go of it before waiting.
+    fn process_message(&self, borg: &Borg, message: &IncomingMessage) -> Vec<(String, Duration)> {
+        let IncomingMessage {
+            chat_id, user_id, ..
+        } = *message;
+        let line = borg.sanitize(message.line);
+        let input = message.input.map(|i| borg.sanitize(i));
+        let pivot_input = message.pivot_input.map(|i| borg.sanitize(i));
+        let command = input
+            .as_deref()
+            .and_then(|input| parse_kill_switch_command(input, self.username()));
+        if let Some(kill_switch) = command {
+            match self.command_access(kill_switch_command_name(kill_switch), user_id) {
+                CommandAccess::Allowed => {
+                    borg.set_kill_switch(kill_switch);
+                    let answer = kill_switch_answer(kill_switch).to_owned();
+                    return vec![(answer, Duration::from_millis(0))];
+                }
+                // Answering would let anyone make the bot talk, so commands
+                // that the user may not use are ignored.
+                CommandAccess::Denied => return vec![],
+                CommandAccess::Disabled => {}
+            }
+        }
+
+        let behavior = self.behavior_for_chat(chat_id);
+        // A preview is neither learned nor counted as a message of the chat,
+        // so that it can be used to tune the bot without changing it. It is
+        // only answered in private, so that the chat does not see what the
+        // bot is about to say, and a denied one is ignored like the other
+        // commands.
+        let preview = input
+            .as_deref()
+            .and_then(|input| parse_preview_command(input, self.username()));
+        if let Some(text) = preview {
+            match self.command_access("preview", user_id) {
+                CommandAccess::Allowed if message.chat_kind == ChatKind::Private => {
+                    let answer = borg
+                        .preview(text, &behavior)
+                        .unwrap_or_else(|| "No response.".to_owned());
+                    return vec![(answer, Duration::from_millis(0))];
+                }
+                CommandAccess::Allowed | CommandAccess::Denied => return vec![],
+                CommandAccess::Disabled => {}
+            }
+        }
+        let text = input.as_deref().unwrap_or(&line);
+
+        if borg.is_echo(chat_id, text) || borg.is_repeat(chat_id, user_id, &line) {
+            return vec![];
+        }
+
+        // should_learn counts the message against the user's rate limit, so
+        // it goes first, even for messages that are not learned anyway.
+        if borg.should_learn(user_id, chat_id, text, &behavior)
+            && message.learn
+            && borg.learns_from_input(text, message.addressed, &behavior)
+            && !line.is_empty()
+        {
+            let author_id = message.author_id.unwrap_or(user_id);
+            let source = Source::new(Self::NAME, chat_id, author_id);
+            let job = borg.prepare_learn(&line, Some(source), &behavior);
+            self.learn_queue().push(job);
+            // The bot does not reply while it acknowledges learning, since
+            // speaking is off.
+            if let Some(emoji) = borg.learn_ack(&behavior, Self::CAN_REACT) {
+                self.react(message, &emoji);
+                return vec![];
+            }
+        }
+
+        let input = match input.as_deref() {
+            Some(input) => input,
+            None => return vec![],
+        };
+        let pivot_input = pivot_input.as_deref().unwrap_or(input);
+        let reason = match borg.reply_reason(
+            user_id,
+            chat_id,
+            message.chat_kind,
+            input,
+            message.addressed,
+            &behavior,
+        ) {
+            Some(reason) => reason,
+            None => return vec![],
+        };
+        // A sentiment reaction is only made once the bot decided to reply,
+        // and it replaces the reply unless with_reply is on.
+        if let Some(reaction) = borg.sentiment_reaction(input, &behavior, Self::CAN_REACT) {
+            self.react(message, &reaction.emoji);
+            if !reaction.with_reply {
+                return vec![];
+            }
+        }
+        let responses = match borg.reply_action_for(
+            reason,
+            chat_id,
+            input,
+            pivot_input,
+            &behavior,
+            Self::CAN_REACT,
+        ) {
+            Some(ReplyAction::Text(response)) => borg.with_follow_up(response, &behavior),
+            Some(ReplyAction::React(emoji)) => {
+                self.react(message, &emoji);
+                vec![]
+            }
+            None => vec![],
+        };
+        responses
+            .into_iter()
+            .map(|response| {
+                borg.remember_sent(chat_id, &response);
+                borg.start_cooldown(chat_id, &response, &behavior);
+                (response, borg.reply_delay(&behavior))
+            })
+            .collect()
+    }
+
+    /// Connects to the platform and handles its messages until it fails. The
+    /// platforms run together on the main task, so this need not be Send.
+    async fn run(self: Arc<Self>) -> Result<(), Self::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{CommandSettings, InputSanitization, MainBehavior, SentimentReactions},
+        dictionary::Dictionary,
+        learn_queue::LearnJob,
+        pattern::Pattern,
+    };
+    use std::{cell::RefCell, fmt};
+
+    #[derive(Debug)]
+    struct NoError;
+
+    impl fmt::Display for NoError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "no error")
+        }
+    }
+
+    impl error::Error for NoError {}
+
+    struct TestPlatform {
+        learn_queue: LearnQueue,
+        behavior: BehaviorOverride,
+        owner_id: Option<String>,
+        commands: Commands,
+    }
+
+    #[async_trait(?Send)]
+    impl Platform for TestPlatform {
+        type Error = NoError;
+        const NAME: &'static str = "test";
+        const CAN_REACT: bool = false;
+
+        fn learn_queue(&self) -> &LearnQueue {
+            &self.learn_queue
+        }
+
+        fn behaviors_for_chat(&self, _: &str) -> Vec<Option<&BehaviorOverride>> {
+            vec![Some(&self.behavior)]
+        }
+
+        fn reply_rate_multiplier(&self) -> f32 {
+            1.0
+        }
+
+        fn owner_id(&self) -> Option<&str> {
+            self.owner_id.as_deref()
+        }
+
+        fn commands(&self) -> Option<&Commands> {
+            Some(&self.commands)
+        }
+
+        fn username(&self) -> Option<&str> {
+            Some("borg")
+        }
+
+        async fn run(self: Arc<Self>) -> Result<(), NoError> {
+            Ok(())
+        }
+    }
+
+    /// ReactingPlatform is a TestPlatform that can react, and keeps the
+    /// emoji it reacted with.
+    struct ReactingPlatform {
+        platform: TestPlatform,
+        reactions: RefCell<Vec<String>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Platform for ReactingPlatform {
+        type Error = NoError;
+        const NAME: &'static str = "reacting";
+        const CAN_REACT: bool = true;
+
+        fn learn_queue(&self) -> &LearnQueue {
+            self.platform.learn_queue()
+        }
+
+        fn behaviors_for_chat(&self, chat_id: &str) -> Vec<Option<&BehaviorOverride>> {
+            self.platform.behaviors_for_chat(chat_id)
+        }
+
+        fn reply_rate_multiplier(&self) -> f32 {
+            1.0
+        }
+
+        fn react(&self, _message: &IncomingMessage, emoji: &str) {
+            self.reactions.borrow_mut().push(emoji.to_owned());
+        }
+
+        async fn run(self: Arc<Self>) -> Result<(), NoError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_process_message() {
+        let (learn_queue, mut jobs) = LearnQueue::new(10);
+        let platform = TestPlatform {
+            learn_queue,
+            behavior: BehaviorOverride {
+                reply_rate: Some(1.0),
+                ..BehaviorOverride::default()
+            },
+            owner_id: None,
+            commands: Commands::new(),
+        };
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                skip_repeated_messages: true,
+                ..MainBehavior::default()
+            },
+        );
+        let message = |line, input| IncomingMessage {
+            chat_id: "1",
+            user_id: "2",
+            line,
+            learn: true,
+            input,
+            pivot_input: None,
+            addressed: false,
+            chat_kind: ChatKind::Group,
+            author_id: None,
+        };
+
+        let replies = platform.process_message(&borg, &message("hello", Some("hello")));
+        assert_eq!(1, replies.len());
+        assert!(replies[0].0.starts_with("hello"), "{:?}", replies);
+        assert_eq!(
+            LearnJob {
+                line: "hello".to_owned(),
+                source: Some(Source::new("test", "1", "2")),
+            },
+            jobs.try_recv().unwrap()
+        );
+
+        // A forwarded message is attributed to its author.
+        let forwarded = IncomingMessage {
+            author_id: Some("3"),
+            ..message("crabs are red", Some("crabs are red"))
+        };
+        platform.process_message(&borg, &forwarded);
+        assert_eq!(
+            Some(Source::new("test", "1", "3")),
+            jobs.try_recv().unwrap().source
+        );
+
+        // Messages that may not be learned are still replied to, and are not
+        // taken for repeats of each other.
+        for line in &["hello", "hello hello"] {
+            let unlearned = IncomingMessage {
+                learn: false,
+                ..message(line, Some(line))
+            };
+            let replies = platform.process_message(&borg, &unlearned);
+            assert_eq!(1, replies.len(), "{:?}", replies);
+        }
+        assert!(jobs.try_recv().is_err());
+
+        // A message without input is learned, but not replied to.
+        let replies = platform.process_message(&borg, &message("crabs.pdf", None));
+        assert!(replies.is_empty());
+        assert_eq!("crabs.pdf", jobs.try_recv().unwrap().line);
+
+        // Nor is anything learned from a message without a line.
+        assert!(platform
+            .process_message(&borg, &message("", None))
+            .is_empty());
+        assert!(jobs.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_learn_only_from_replies() {
+        let (learn_queue, mut jobs) = LearnQueue::new(10);
+        let platform = TestPlatform {
+            learn_queue,
+            behavior: BehaviorOverride {
+                learn_only_from_replies: Some(true),
+                ..BehaviorOverride::default()
+            },
+            owner_id: None,
+            commands: Commands::new(),
+        };
+        let borg = Borg::new(
+            Dictionary::new_empty(),
+            MainBehavior {
+                nick_patterns: vec![Pattern::substring("borg")],
+                ..MainBehavior::default()
+            },
+        );
+        let message = |line, addressed| IncomingMessage {
+            chat_id: "1",
+            user_id: "2",
+            line,
+            learn: true,
+            input: Some(line),
+            pivot_input: None,
+            addressed,
+            chat_kind: ChatKind::Group,
+            author_id: None,
+        };
+
+        // Neither a reply to the bot nor a mention.
+        platform.process_message(&borg, &message("hello crabs", false));
+        assert!(jobs.try_recv().is_err());
+
+        platform.process_message(&borg, &message("hello lobsters", true));
+        assert_eq!("hello lobsters", jobs.try_recv().unwrap().line);
+        platform.process_message(&borg, &message("hello borg", false));
+        assert_eq!("hello borg", jobs.try_recv().unwrap().line);
+    }
+
+    #[test]
+    fn test_learn_only_from_replies_throttles_flooding() {
+        let (learn_queue, mut jobs) = LearnQueue::new(10);
+        let platform = TestPlatform {
+            learn_queue,
+            behavior: BehaviorOverride {
+                learn_only_from_replies: Some(true),
+                ..BehaviorOverride::default()
+            },
+            owner_id: None,
+            commands: Commands::new(),
+        };
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                reply_rate: 1.0,
+                nick_patterns: vec![Pattern::substring("borg")],
+                user_messages_per_minute: Some(2),
+                ..MainBehavior::default()
+            },
+        );
+        let message = |line, addressed| IncomingMessage {
+            chat_id: "1",
+            user_id: "2",
+            line,
+            learn: true,
+            input: Some(line),
+            pivot_input: None,
+            addressed,
+            chat_kind: ChatKind::Group,
+            author_id: None,
+        };
+
+        // The messages that are not learned still use up the rate limit.
+        platform.process_message(&borg, &message("crabs", false));
+        platform.process_message(&borg, &message("lobsters", false));
+        let replies = platform.process_message(&borg, &message("hello", true));
+        assert!(replies.is_empty());
+        assert!(jobs.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_sanitized_input_is_blacklisted() {
+        let (learn_queue, mut jobs) = LearnQueue::new(10);
+        let platform = TestPlatform {
+            learn_queue,
+            behavior: BehaviorOverride::default(),
+            owner_id: None,
+            commands: Commands::new(),
+        };
+        let borg = |input_sanitization| {
+            Borg::new(
+                Dictionary::new_empty(),
+                MainBehavior {
+                    blacklisted_patterns: vec![Pattern::substring("bad")],
+                    input_sanitization,
+                    ..MainBehavior::default()
+                },
+            )
+        };
+        let message = |line| IncomingMessage {
+            chat_id: "1",
+            user_id: "2",
+            line,
+            learn: true,
+            input: Some(line),
+            pivot_input: None,
+            addressed: false,
+            chat_kind: ChatKind::Group,
+            author_id: None,
+        };
+
+        // Without sanitization, the zero-width characters hide the word.
+        let unsanitized = borg(InputSanitization::Off);
+        platform.process_message(&unsanitized, &message("b\u{200b}ad crabs"));
+        assert_eq!("b\u{200b}ad crabs", jobs.try_recv().unwrap().line);
+
+        let sanitized = borg(InputSanitization::Strip);
+        platform.process_message(&sanitized, &message("b\u{200b}ad crabs"));
+        platform.process_message(&sanitized, &message("b\u{200d}a\u{202e}d lobsters"));
+        assert!(jobs.try_recv().is_err());
+
+        platform.process_message(&sanitized, &message("good\u{200b} crabs"));
+        assert_eq!("good crabs", jobs.try_recv().unwrap().line);
+    }
+
+    #[test]
+    fn test_parse_kill_switch_command() {
+        let parse = |input| parse_kill_switch_command(input, Some("borg"));
+        assert_eq!(Some(KillSwitch::Replies), parse("/shutup"));
+        assert_eq!(Some(KillSwitch::Replies), parse("/shutup@borg"));
+        assert_eq!(Some(KillSwitch::Replies), parse("/shutup@Borg"));
+        assert_eq!(None, parse("/shutup@otherbot"));
+        assert_eq!(None, parse_kill_switch_command("/shutup@borg", None));
+        assert_eq!(
+            Some(KillSwitch::RepliesAndLearning),
+            parse("/shutup learning")
+        );
+        assert_eq!(Some(KillSwitch::Off), parse(" /speak "));
+        assert_eq!(None, parse("/shutup everyone now"));
+        assert_eq!(None, parse("please /shutup"));
+        assert_eq!(None, parse("/speaking"));
+    }
+
+    #[test]
+    fn test_only_the_owner_can_use_the_kill_switch() {
+        let (learn_queue, _jobs) = LearnQueue::new(10);
+        let mut platform = TestPlatform {
+            learn_queue,
+            behavior: BehaviorOverride {
+                reply_rate: Some(1.0),
+                ..BehaviorOverride::default()
+            },
+            owner_id: Some("1".to_owned()),
+            commands: Commands::new(),
+        };
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Borg::new(dictionary, MainBehavior::default());
+        let message = |chat_id, user_id, input| IncomingMessage {
+            chat_id,
+            user_id,
+            line: input,
+            learn: true,
+            input: Some(input),
+            pivot_input: None,
+            addressed: false,
+            chat_kind: ChatKind::Group,
+            author_id: None,
+        };
+        let answer = |replies: Vec<(String, Duration)>| {
+            assert_eq!(1, replies.len());
+            replies[0].0.clone()
+        };
+
+        // Other users are ignored, without an answer.
+        assert!(platform
+            .process_message(&borg, &message("#crabs", "2", "/shutup"))
+            .is_empty());
+        assert_eq!(KillSwitch::Off, borg.kill_switch());
+        // So are commands for another bot.
+        platform.process_message(&borg, &message("#crabs", "1", "/shutup@otherbot"));
+        assert_eq!(KillSwitch::Off, borg.kill_switch());
+
+        assert_eq!(
+            "Not saying anything until /speak.",
+            answer(platform.process_message(&borg, &message("#crabs", "1", "/shutup")))
+        );
+        assert_eq!(KillSwitch::Replies, borg.kill_switch());
+        // The kill switch applies to every chat.
+        assert!(platform
+            .process_message(&borg, &message("#lobsters", "2", "hello"))
+            .is_empty());
+
+        assert!(platform
+            .process_message(&borg, &message("#lobsters", "2", "/speak"))
+            .is_empty());
+        assert_eq!(KillSwitch::Replies, borg.kill_switch());
+        answer(platform.process_message(&borg, &message("#lobsters", "1", "/speak")));
+        assert_eq!(KillSwitch::Off, borg.kill_switch());
+        assert!(!platform
+            .process_message(&borg, &message("#lobsters", "2", "hello"))
+            .is_empty());
+
+        // Without an owner, the commands are ordinary messages.
+        platform.owner_id = None;
+        platform.process_message(&borg, &message("#crabs", "1", "/shutup"));
+        assert_eq!(KillSwitch::Off, borg.kill_switch());
+    }
+
+    #[test]
+    fn test_parse_preview_command() {
+        let parse = |input| parse_preview_command(input, Some("borg"));
+        assert_eq!(Some("hello crabs"), parse("/preview hello crabs"));
+        assert_eq!(Some("hi"), parse(" /preview@Borg  hi "));
+        assert_eq!(None, parse("/preview@other_bot hi"));
+        assert_eq!(None, parse("/preview"));
+        assert_eq!(None, parse("/previews hi"));
+        assert_eq!(None, parse("please /preview hi"));
+    }
+
+    #[test]
+    fn test_preview() {
+        let (learn_queue, mut jobs) = LearnQueue::new(10);
+        let platform = TestPlatform {
+            learn_queue,
+            behavior: BehaviorOverride::default(),
+            owner_id: Some("1".to_owned()),
+            commands: Commands::new(),
+        };
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Borg::new(
+            dictionary,
+            MainBehavior {
+                user_messages_per_minute: Some(1),
+                echo_window_secs: 3600,
+                ..MainBehavior::default()
+            },
+        );
+        let message = |chat_kind, user_id, input| IncomingMessage {
+            chat_id: "#crabs",
+            user_id,
+            line: input,
+            learn: true,
+            input: Some(input),
+            pivot_input: None,
+            addressed: false,
+            chat_kind,
+            author_id: None,
+        };
+        let private = |user_id, input| message(ChatKind::Private, user_id, input);
+
+        let replies = platform.process_message(&borg, &private("1", "/preview hello"));
+        assert_eq!(1, replies.len());
+        assert!(replies[0].0.starts_with("hello"), "{:?}", replies);
+        let replies = platform.process_message(&borg, &private("1", "/preview squid"));
+        assert_eq!("No response.", replies[0].0);
+        // Denied previews and previews outside of private chats are ignored.
+        assert!(platform
+            .process_message(&borg, &private("2", "/preview hello"))
+            .is_empty());
+        assert!(platform
+            .process_message(&borg, &message(ChatKind::Group, "1", "/preview hello"))
+            .is_empty());
+
+        // Nothing was learned, decided or sent, and the rate limit is intact.
+        assert!(jobs.try_recv().is_err());
+        assert!(borg.decisions().is_empty());
+        assert!(!borg.is_echo("#crabs", "hello crabs"));
+        assert!(!borg.is_echo("#crabs", "hello lobsters"));
+        platform.process_message(&borg, &private("1", "hello"));
+        assert_eq!("hello", jobs.try_recv().unwrap().line);
+    }
+
+    #[test]
+    fn test_sentiment_reactions() {
+        let (learn_queue, _jobs) = LearnQueue::new(10);
+        let test_platform = |with_reply| TestPlatform {
+            learn_queue: learn_queue.clone(),
+            behavior: BehaviorOverride {
+                react_to_sentiment: Some(true),
+                sentiment_reactions: Some(SentimentReactions {
+                    with_reply,
+                    ..SentimentReactions::default()
+                }),
+                ..BehaviorOverride::default()
+            },
+            owner_id: None,
+            commands: Commands::new(),
+        };
+        let platform = |with_reply| ReactingPlatform {
+            platform: test_platform(with_reply),
+            reactions: RefCell::new(vec![]),
+        };
+        let borg = |reply_rate| {
+            let mut dictionary = Dictionary::new_empty();
+            dictionary.learn("i love crabs. i love crabs too.");
+            Borg::new(
+                dictionary,
+                MainBehavior {
+                    reply_rate,
+                    ..MainBehavior::default()
+                },
+            )
+        };
+        let message = |input| IncomingMessage {
+            chat_id: "#crabs",
+            user_id: "1",
+            line: input,
+            learn: true,
+            input: Some(input),
+            pivot_input: None,
+            addressed: false,
+            chat_kind: ChatKind::Group,
+            author_id: None,
+        };
+
+        // The reaction replaces the reply unless with_reply is on.
+        let reacting = platform(false);
+        let replies = reacting.process_message(&borg(1.0), &message("i love crabs, great crabs"));
+        assert!(replies.is_empty(), "{:?}", replies);
+        assert_eq!(vec!["❤️"], *reacting.reactions.borrow());
+        let reacting = platform(true);
+        let replies = reacting.process_message(&borg(1.0), &message("i love awesome crabs"));
+        assert_eq!(1, replies.len(), "{:?}", replies);
+        assert_eq!(vec!["❤️"], *reacting.reactions.borrow());
+
+        let reacting = platform(false);
+        let replies = reacting.process_message(&borg(1.0), &message("crabs are red"));
+        assert_eq!(1, replies.len(), "{:?}", replies);
+        assert!(reacting.reactions.borrow().is_empty());
+
+        // Like replies, reactions follow the reply rate.
+        let reacting = platform(false);
+        let replies = reacting.process_message(&borg(0.0), &message("i love crabs, great crabs"));
+        assert!(replies.is_empty(), "{:?}", replies);
+        assert!(reacting.reactions.borrow().is_empty());
+
+        // Platforms that cannot react reply as usual.
+        let replies =
+            test_platform(false).process_message(&borg(1.0), &message("i love crabs, great crabs"));
+        assert_eq!(1, replies.len(), "{:?}", replies);
+        assert_ne!("❤️", replies[0].0);
+    }
+
+    #[test]
+    fn test_command_settings() {
+        let (learn_queue, _jobs) = LearnQueue::new(10);
+        let mut commands = Commands::new();
+        commands.insert(
+            "shutup".to_owned(),
+            CommandSettings {
+                enabled: false,
+                ..CommandSettings::default()
+            },
+        );
+        commands.insert(
+            "preview".to_owned(),
+            CommandSettings {
+                role: CommandRole::Anyone,
+                ..CommandSettings::default()
+            },
+        );
+        let platform = TestPlatform {
+            learn_queue,
+            behavior: BehaviorOverride::default(),
+            owner_id: Some("1".to_owned()),
+            commands,
+        };
+        let mut dictionary = Dictionary::new_empty();
+        dictionary.learn("hello crabs. hello lobsters.");
+        let borg = Borg::new(dictionary, MainBehavior::default());
+        let message = |user_id, input| IncomingMessage {
+            chat_id: "#crabs",
+            user_id,
+            line: input,
+            learn: true,
+            input: Some(input),
+            pivot_input: None,
+            addressed: false,
+            chat_kind: ChatKind::Group,
+            author_id: None,
+        };
+
+        // A disabled command is not handled, even for the owner.
+        let replies = platform.process_message(&borg, &message("1", "/shutup"));
+        assert!(replies
+            .iter()
+            .all(|(reply, _)| !reply.starts_with("Not saying")));
+        assert_eq!(KillSwitch::Off, borg.kill_switch());
+
+        // An enabled command open to anyone is handled for everyone.
+        let replies = platform.process_message(
+            &borg,
+            &IncomingMessage {
+                chat_kind: ChatKind::Private,
+                ..message("2", "/preview hello")
+            },
+        );
+        assert_eq!(1, replies.len());
+        assert!(replies[0].0.starts_with("hello"), "{:?}", replies);
+
+        // Commands left out of the settings still need the owner.
+        assert!(platform
+            .process_message(&borg, &message("2", "/speak"))
+            .is_empty());
+        let replies = platform.process_message(&borg, &message("1", "/speak"));
+        assert_eq!("Speaking again.", replies[0].0);
+
+        // Commands for another bot are not handled.
+        platform.process_message(&borg, &message("1", "/shutup@other_bot"));
+        assert_eq!(KillSwitch::Off, borg.kill_switch());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::config::MentionHandling;
+use onig::Regex;
+
+/////////////////////////////////////////////////////////////////////////////
+// Response Post-processing
+/////////////////////////////////////////////////////////////////////////////
+
+/// Characters that are accepted as the end of a sentence.
+const TERMINAL_PUNCTUATION: &[char] = &['.', '!', '?', '…'];
+
+/// The part of a reply template that is replaced with the response.
+pub(crate) const RESPONSE_PLACEHOLDER: &str = "{response}";
+
+/// Uppercases the first character of the response. Uppercasing works on
+/// whole characters, so letters outside of ASCII are handled correctly.
+pub(crate) fn capitalize(response: &str) -> String {
+    let mut chars = response.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+/// Appends a period to the response unless it already ends in punctuation.
+pub(crate) fn punctuate(response: &str) -> String {
+    if response.is_empty() || response.ends_with(TERMINAL_PUNCTUATION) {
+        response.to_owned()
+    } else {
+        format!("{}.", response)
+    }
+}
+
+/// Collapses runs of the same punctuation mark to at most max_repeats and
+/// trims the punctuation that a response can start with when it was cut from
+/// the middle of a sentence. A response of nothing but punctuation is only
+/// collapsed. Runs of full stops are left alone so ellipses survive.
+pub(crate) fn normalize_punctuation(response: &str, max_repeats: usize) -> String {
+    let trimmed = response
+        .trim_start_matches(TERMINAL_PUNCTUATION)
+        .trim_start();
+    let text = if trimmed.is_empty() {
+        response
+    } else {
+        trimmed
+    };
+    let mut normalized = String::with_capacity(text.len());
+    let mut previous = None;
+    let mut repeats = 0;
+    for c in text.chars() {
+        repeats = if previous == Some(c) { repeats + 1 } else { 1 };
+        previous = Some(c);
+        if c == '.' || !TERMINAL_PUNCTUATION.contains(&c) || repeats <= max_repeats {
+            normalized.push(c);
+        }
+    }
+    normalized
+}
+
+/// Cuts the response after max_words words and ends it with an ellipsis.
+/// Shorter responses are left as they are.
+pub(crate) fn truncate_words(response: &str, max_words: usize) -> String {
+    let words: Vec<&str> = response.split_whitespace().collect();
+    if words.len() <= max_words {
+        return response.to_owned();
+    }
+    format!("{}…", words[..max_words].join(" "))
+}
+
+/// Strips or defangs the @mentions in the response, as the mention handling
+/// asks. An @ inside a word, like in an email address, is not a mention. A
+/// stripped mention takes the whitespace before it along, so that no space is
+/// left in front of the punctuation that followed it.
+pub(crate) fn handle_mentions(response: &str, handling: MentionHandling) -> String {
+    lazy_static! {
+        static ref MENTION_AT: Regex = Regex::new(r"(?<![\w@])@(?=\w)").unwrap();
+        static ref MENTION: Regex = Regex::new(r"\s*(?<![\w@])@\w+").unwrap();
+    }
+    match handling {
+        MentionHandling::Keep => response.to_owned(),
+        MentionHandling::Strip => MENTION
+            .replace_all(response, "")
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" "),
+        MentionHandling::Defang => MENTION_AT.replace_all(response, "@\u{200b}"),
+    }
+}
+
+/// Puts the response into the template, in place of the placeholder.
+pub(crate) fn apply_template(template: &str, response: &str) -> String {
+    template.replace(RESPONSE_PLACEHOLDER, response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::MainBehavior;
+
+    #[test]
+    fn test_capitalize() {
+        assert_eq!("Hello world", capitalize("hello world"));
+        assert_eq!("Hello world", capitalize("Hello world"));
+        assert_eq!("Élan vital", capitalize("élan vital"));
+        assert_eq!("", capitalize(""));
+    }
+
+    #[test]
+    fn test_punctuate() {
+        assert_eq!("hello world.", punctuate("hello world"));
+        assert_eq!("hello world!", punctuate("hello world!"));
+        assert_eq!("hello world?", punctuate("hello world?"));
+        assert_eq!("hello world…", punctuate("hello world…"));
+        assert_eq!("", punctuate(""));
+    }
+
+    #[test]
+    fn test_capitalize_and_punctuate() {
+        assert_eq!("Hello world.", punctuate(&capitalize("hello world")));
+    }
+
+    #[test]
+    fn test_handle_mentions() {
+        let response = "@ferris says hi to @crab_42, mail me@example.com";
+        assert_eq!(response, handle_mentions(response, MentionHandling::Keep));
+        assert_eq!(
+            "says hi to, mail me@example.com",
+            handle_mentions(response, MentionHandling::Strip)
+        );
+        assert_eq!(
+            "@\u{200b}ferris says hi to @\u{200b}crab_42, mail me@example.com",
+            handle_mentions(response, MentionHandling::Defang)
+        );
+        assert_eq!(
+            "hi and bye!",
+            handle_mentions("hi @ferris and @crab bye @ferris!", MentionHandling::Strip)
+        );
+        assert_eq!(
+            "@ alone",
+            handle_mentions("@ alone", MentionHandling::Strip)
+        );
+    }
+
+    #[test]
+    fn test_normalize_punctuation() {
+        let default = MainBehavior::default().max_repeated_punctuation;
+        assert_eq!(
+            "hello world?",
+            normalize_punctuation("!!!hello world???", default)
+        );
+        assert_eq!("hello world!!", normalize_punctuation("hello world!!!", 2));
+        assert_eq!("hello world?!", normalize_punctuation("hello world?!", 1));
+        assert_eq!("hello world.", normalize_punctuation("hello world.", 1));
+        assert_eq!("hello… world", normalize_punctuation(". hello…… world", 1));
+        assert_eq!("wait... what", normalize_punctuation("wait... what", 1));
+        assert_eq!("!", normalize_punctuation("!!!", 1));
+        assert_eq!("", normalize_punctuation("", 1));
+    }
+
+    #[test]
+    fn test_truncate_words() {
+        assert_eq!(
+            "crabs are red…",
+            truncate_words("crabs are red and lobsters are too", 3)
+        );
+        assert_eq!("crabs are red", truncate_words("crabs are red", 3));
+        assert_eq!("crabs are red", truncate_words("crabs are red", 5));
+        assert_eq!("crabs…", punctuate(&truncate_words("crabs are red", 1)));
+    }
+
+    #[test]
+    fn test_apply_template() {
+        assert_eq!(
+            "🦀 hello world",
+            apply_template("🦀 {response}", "hello world")
+        );
+        assert_eq!(
+            "_hello world_",
+            apply_template("_{response}_", "hello world")
+        );
+        assert_eq!("hello world", apply_template("{response}", "hello world"));
+    }
+}
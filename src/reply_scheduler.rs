@@ -0,0 +1,108 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tokio::{sync::Mutex, time::delay_until};
+
+/////////////////////////////////////////////////////////////////////////////
+// ReplyScheduler Struct
+/////////////////////////////////////////////////////////////////////////////
+
+/// ReplyScheduler spaces out the replies of every platform and chat, so that
+/// a burst of messages does not get replies everywhere at once. Replies take
+/// turns in the order they asked for one, and each turn comes at least the
+/// minimum interval after the previous one, plus up to the jitter at random.
+/// Clones share their turns.
+#[derive(Clone)]
+pub struct ReplyScheduler {
+    /// When the last turn was given. The lock is held while waiting for a
+    /// turn, which is what queues the replies.
+    last_turn: Arc<Mutex<Option<Instant>>>,
+    min_interval: Duration,
+    jitter: Duration,
+}
+
+impl ReplyScheduler {
+    pub fn new(min_interval: Duration, jitter: Duration) -> ReplyScheduler {
+        ReplyScheduler {
+            last_turn: Arc::new(Mutex::new(None)),
+            min_interval,
+            jitter,
+        }
+    }
+
+    /// Waits until it is the turn of the caller to send a reply. Returns
+    /// right away when there is neither an interval nor jitter.
+    pub async fn wait_turn(&self) {
+        if self.min_interval == Duration::from_millis(0) && self.jitter == Duration::from_millis(0)
+        {
+            return;
+        }
+        let mut last_turn = self.last_turn.lock().await;
+        if let Some(last) = *last_turn {
+            let turn = last + self.min_interval + self.random_jitter();
+            let now = Instant::now();
+            if turn > now {
+                debug!("[wait_turn] Waiting {:?} to reply", turn - now);
+                delay_until(turn.into()).await;
+            }
+        }
+        *last_turn = Some(Instant::now());
+    }
+
+    fn random_jitter(&self) -> Duration {
+        let jitter_ms = self.jitter.as_millis() as u64;
+        if jitter_ms == 0 {
+            Duration::from_millis(0)
+        } else {
+            Duration::from_millis(rand::thread_rng().gen_range(0, jitter_ms + 1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+
+    #[tokio::test]
+    async fn test_wait_turn() {
+        let interval = Duration::from_millis(30);
+        let scheduler = ReplyScheduler::new(interval, Duration::from_millis(10));
+        let start = Instant::now();
+
+        let sent: Vec<Instant> = join_all((0..4).map(|_| {
+            let scheduler = scheduler.clone();
+            async move {
+                scheduler.wait_turn().await;
+                Instant::now()
+            }
+        }))
+        .await;
+
+        // The first reply goes right away, and the others wait their turn.
+        assert!(sent[0] - start < interval);
+        for pair in sent.windows(2) {
+            assert!(pair[1] >= pair[0] + interval, "{:?}", sent);
+        }
+
+        // Once the turns have passed, the next reply goes right away again.
+        delay_until((Instant::now() + Duration::from_millis(50)).into()).await;
+        let before = Instant::now();
+        scheduler.wait_turn().await;
+        assert!(Instant::now() - before < interval);
+    }
+
+    #[tokio::test]
+    async fn test_no_interval() {
+        let scheduler = ReplyScheduler::new(Duration::from_millis(0), Duration::from_millis(0));
+        let start = Instant::now();
+        for _ in 0..100 {
+            scheduler.wait_turn().await;
+        }
+        assert!(Instant::now() - start < Duration::from_millis(100));
+        assert_eq!(None, *scheduler.last_turn.lock().await);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::dictionary::split_words;
+
+/////////////////////////////////////////////////////////////////////////////
+// Sentiment Scoring
+/////////////////////////////////////////////////////////////////////////////
+
+/// Words that count one point towards a positive message.
+const POSITIVE_WORDS: &[&str] = &[
+    "amazing",
+    "awesome",
+    "beautiful",
+    "best",
+    "brilliant",
+    "cool",
+    "cute",
+    "excellent",
+    "fantastic",
+    "fun",
+    "glad",
+    "good",
+    "great",
+    "happy",
+    "like",
+    "love",
+    "lovely",
+    "nice",
+    "perfect",
+    "thanks",
+    "wonderful",
+    "yay",
+    "❤️",
+    "😀",
+    "😊",
+    "😍",
+];
+
+/// Words that count one point towards a negative message.
+const NEGATIVE_WORDS: &[&str] = &[
+    "angry",
+    "annoying",
+    "awful",
+    "bad",
+    "boring",
+    "broken",
+    "disgusting",
+    "hate",
+    "horrible",
+    "hurt",
+    "sad",
+    "stupid",
+    "sucks",
+    "terrible",
+    "ugly",
+    "upset",
+    "worst",
+    "wrong",
+    "😞",
+    "😠",
+    "😢",
+];
+
+/// Words that flip the polarity of the word that follows them, so that
+/// "not good" counts as negative.
+const NEGATIONS: &[&str] = &["not", "no", "never", "don't", "isn't", "wasn't"];
+
+/// Sentiment is how a message feels, going by its words.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sentiment {
+    Positive,
+    Neutral,
+    Negative,
+}
+
+/// Scores the message by adding a point for every positive word and taking
+/// one away for every negative word.
+pub(crate) fn score(input: &str) -> i32 {
+    let input = input.to_lowercase();
+    let mut score = 0;
+    let mut negated = false;
+    for word in split_words(&input) {
+        let polarity = if POSITIVE_WORDS.contains(&word) {
+            1
+        } else if NEGATIVE_WORDS.contains(&word) {
+            -1
+        } else {
+            0
+        };
+        score += if negated { -polarity } else { polarity };
+        negated = NEGATIONS.contains(&word);
+    }
+    score
+}
+
+/// Buckets the message by its score. Only messages that score at least
+/// min_score either way count as positive or negative.
+pub(crate) fn classify(input: &str, min_score: u32) -> Sentiment {
+    let score = score(input);
+    let min_score = min_score.max(1) as i32;
+    if score >= min_score {
+        Sentiment::Positive
+    } else if score <= -min_score {
+        Sentiment::Negative
+    } else {
+        Sentiment::Neutral
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        assert_eq!(2, score("I love crabs, they are great!"));
+        assert_eq!(-2, score("this is awful. worst bot ever"));
+        assert_eq!(0, score("crabs are red"));
+        assert_eq!(0, score("good crabs, bad lobsters"));
+        assert_eq!(-1, score("not good"));
+        assert_eq!(1, score("GREAT"));
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(
+            Sentiment::Positive,
+            classify("I love crabs, they are great!", 2)
+        );
+        assert_eq!(
+            Sentiment::Negative,
+            classify("this is awful. worst bot ever", 2)
+        );
+        assert_eq!(Sentiment::Neutral, classify("crabs are nice", 2));
+        assert_eq!(Sentiment::Positive, classify("crabs are nice", 1));
+        assert_eq!(Sentiment::Positive, classify("crabs are nice", 0));
+        assert_eq!(Sentiment::Negative, classify("i don't like crabs", 1));
+    }
+}
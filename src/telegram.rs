@@ -1,19 +1,22 @@
-use std::{error, fmt, sync::Arc};
+use std::{error, fmt, path::Path, sync::Arc, time::Duration};
 
-use carapax::types::Message;
+use carapax::types::{Forward, ForwardFrom, Message, MessageData, MessageKind, Text, TextEntity};
 use carapax::{
     longpoll::LongPoll, Api, ApiError, Dispatcher, ErrorPolicy, HandlerResult, LoggingErrorHandler,
 };
-use futures::lock::Mutex;
 
 use crate::{
-    borg::Borg,
+    borg::{Borg, ChatKind},
     config,
-    config::{BehaviorOverride, BehaviorOverrideValueResolver},
+    config::{BehaviorOverride, ChatBehaviorOverrides, Commands},
+    learn_queue::LearnQueue,
+    platform::{IncomingMessage, Platform},
+    reply_scheduler::ReplyScheduler,
 };
 use carapax::handler;
 use carapax::methods::SendMessage;
 use futures::TryFutureExt;
+use tokio::{sync::RwLock, time::delay_for};
 
 /////////////////////////////////////////////////////////////////////////////
 // RunError
@@ -102,9 +105,16 @@ impl error::Error for LongPollError {
 /////////////////////////////////////////////////////////////////////////////
 
 pub struct Context {
-    borg: Arc<Mutex<Borg>>,
+    borg: Arc<RwLock<Borg>>,
     platform_config: config::TelegramPlatform,
     api: Api,
+    learn_queue: LearnQueue,
+    reply_scheduler: ReplyScheduler,
+    /// The bot's own user ID, used to tell when a message replies to it.
+    bot_id: Option<i64>,
+    /// When the context was made, in seconds since the Unix epoch. Messages
+    /// sent well before then were queued while the bot was down.
+    started_at: i64,
 }
 
 /////////////////////////////////////////////////////////////////////////////
@@ -114,38 +124,84 @@ pub struct Context {
 impl Context {
     pub fn new(
         platform_config: config::TelegramPlatform,
-        borg: Arc<Mutex<Borg>>,
+        borg: Arc<RwLock<Borg>>,
+        learn_queue: LearnQueue,
+        reply_scheduler: ReplyScheduler,
     ) -> Result<Context, ApiError> {
         let token = platform_config.token.clone();
+        let bot_id = bot_id_from_token(&token);
         Api::new(token).map(|api| Context {
             borg,
             platform_config,
             api,
+            bot_id,
+            learn_queue,
+            reply_scheduler,
+            started_at: crate::util::unix_time() as i64,
         })
     }
+}
 
-    fn behavior_for_chat(&self, chat_id: &i64) -> Option<BehaviorOverrideValueResolver> {
-        self.platform_config
-            .behavior
-            .as_ref()
-            .map(|b| {
-                (
-                    b,
-                    self.override_for_chat(&chat_id)
-                        .map(|o| Box::new(BehaviorOverrideValueResolver::new(o, None))),
-                )
-            })
-            .map(|(b, o)| BehaviorOverrideValueResolver::new(b, o))
-    }
-
-    fn override_for_chat(&self, chat_id: &i64) -> Option<&BehaviorOverride> {
-        let chat_id: i64 = (*chat_id).into();
-        let chat_id = chat_id.to_string();
+/////////////////////////////////////////////////////////////////////////////
+// Platform Implementation
+/////////////////////////////////////////////////////////////////////////////
+
+#[async_trait(?Send)]
+impl Platform for Context {
+    type Error = RunError;
+
+    const NAME: &'static str = "telegram";
+
+    /// The Bot API version supported by tgbot has no method for setting
+    /// message reactions, so reaction_mode falls back to text replies.
+    const CAN_REACT: bool = false;
+
+    fn learn_queue(&self) -> &LearnQueue {
+        &self.learn_queue
+    }
+
+    fn behaviors_for_chat(&self, chat_id: &str) -> Vec<Option<&BehaviorOverride>> {
+        // The chat behaviors only apply on top of a platform behavior.
+        match self.platform_config.behavior {
+            Some(ref behavior) => vec![
+                Some(behavior),
+                chat_id
+                    .parse()
+                    .ok()
+                    .and_then(|id| find_chat_behavior(&self.platform_config.chat_behaviors, id)),
+            ],
+            None => vec![],
+        }
+    }
+
+    fn reply_rate_multiplier(&self) -> f32 {
+        self.platform_config.reply_rate_multiplier
+    }
+
+    fn owner_id(&self) -> Option<&str> {
+        self.platform_config.owner_id.as_deref()
+    }
+
+    fn commands(&self) -> Option<&Commands> {
+        Some(&self.platform_config.commands)
+    }
+
+    fn username(&self) -> Option<&str> {
         self.platform_config
-            .chat_behaviors
-            .as_ref()
-            .and_then(|bs| bs.iter().find(|cb| cb.chat_id == chat_id))
-            .map(|cb| &cb.behavior)
+            .username
+            .as_deref()
+            .map(|username| username.trim_start_matches('@'))
+    }
+
+    async fn run(self: Arc<Self>) -> Result<(), RunError> {
+        let mut dispatcher = Dispatcher::new(self.clone());
+        dispatcher.set_error_handler(LoggingErrorHandler::new(ErrorPolicy::Continue));
+        dispatcher.add_handler(handle);
+
+        let api = self.api.clone();
+
+        LongPoll::new(api, dispatcher).run().await;
+        Ok(())
     }
 }
 
@@ -154,31 +210,61 @@ impl Context {
 /////////////////////////////////////////////////////////////////////////////
 
 #[handler]
-async fn handle(context: &Arc<Mutex<Context>>, message: Message) -> HandlerResult {
-    let context = context.lock().await;
-    if !message_is_older_than_now(&message) {
-        if let (Some(text), Some(user)) = (message.get_text(), message.get_user()) {
-            let behavior = context.behavior_for_chat(&message.get_chat_id());
-            let input = text.data.as_str();
+async fn handle(context: &Arc<Context>, message: Message) -> HandlerResult {
+    if !sent_before_startup(&message, context.started_at) {
+        if context.platform_config.greet_on_join && bot_was_added(&message, context.bot_id) {
+            greet(context, message.get_chat_id()).await;
+            return HandlerResult::Continue;
+        }
+        let line = learn_input(
+            &message,
+            context.platform_config.strip_entities,
+            context.platform_config.learn_attachments,
+        );
+        if let (Some(line), Some(user)) = (line, message.get_user()) {
+            let (learn, author_id) =
+                learn_forward(&message, context.platform_config.learn_forwards);
+            let input = message_input(&message);
             let user_id = &user.id.to_string();
             let chat_id = message.get_chat_id();
-            let mut borg = context.borg.lock().await;
-
-            if borg.should_learn(user_id, input, &behavior) {
-                borg.learn(input);
-            }
-
-            if borg.should_reply_to(user_id, input, &behavior) {
-                if let Some(response) = borg.respond_to(input) {
-                    match context
-                        .api
-                        .execute(SendMessage::new(chat_id, response))
-                        .await
-                    {
-                        Ok(..) => {}
-                        Err(e) => {
-                            error!("ExecuteError: {}", e);
-                        }
+            let chat_id_str = &chat_id.to_string();
+            let replied = if context.platform_config.reply_to_replies {
+                replied_to_bot(&message, context.bot_id)
+            } else {
+                None
+            };
+            let pivot_input = input.map(|input| pivot_input(input, replied));
+            // Messages with only an attachment have no input, so they are
+            // learned, but not replied to.
+            let incoming = IncomingMessage {
+                chat_id: chat_id_str,
+                user_id,
+                line: &line,
+                learn,
+                input,
+                pivot_input: pivot_input.as_deref(),
+                addressed: replied.is_some(),
+                chat_kind: chat_kind(&message),
+                author_id: author_id.as_deref(),
+            };
+            let borg = context.borg.read().await;
+            let reply = context.process_message(&borg, &incoming);
+
+            // Don't hold on to the lock while waiting to reply.
+            drop(borg);
+            for (response, delay) in reply {
+                if delay > Duration::from_millis(0) {
+                    delay_for(delay).await;
+                }
+                context.reply_scheduler.wait_turn().await;
+                match context
+                    .api
+                    .execute(SendMessage::new(chat_id, response))
+                    .await
+                {
+                    Ok(..) => {}
+                    Err(e) => {
+                        error!("ExecuteError: {}", e);
                     }
                 }
             }
@@ -187,25 +273,505 @@ async fn handle(context: &Arc<Mutex<Context>>, message: Message) -> HandlerResul
     HandlerResult::Continue
 }
 
+/// Sends the greeting to a chat that the bot was just added to.
+async fn greet(context: &Context, chat_id: i64) {
+    let behavior = context.behavior_for_chat(&chat_id.to_string());
+    let greeting = context
+        .borg
+        .read()
+        .await
+        .greeting(context.platform_config.greeting.as_deref(), &behavior);
+    let greeting = match greeting {
+        Some(greeting) => greeting,
+        None => {
+            debug!("[greet] Nothing to greet chat {} with", chat_id);
+            return;
+        }
+    };
+    context.reply_scheduler.wait_turn().await;
+    if let Err(e) = context
+        .api
+        .execute(SendMessage::new(chat_id, greeting))
+        .await
+    {
+        error!("ExecuteError: {}", e);
+    }
+}
+
 /////////////////////////////////////////////////////////////////////////////
 // Utility Functions
 /////////////////////////////////////////////////////////////////////////////
 
-fn message_is_older_than_now(message: &Message) -> bool {
-    message.date < crate::util::unix_time() as i64
+/// Finds the behavior override of the chat. Chat IDs are compared as numbers,
+/// so that e.g. surrounding whitespace in the configuration does not matter.
+fn find_chat_behavior(
+    chat_behaviors: &Option<Vec<ChatBehaviorOverrides>>,
+    chat_id: i64,
+) -> Option<&BehaviorOverride> {
+    chat_behaviors
+        .iter()
+        .flatten()
+        .find(|cb| cb.chat_id.trim().parse::<i64>() == Ok(chat_id))
+        .map(|cb| &cb.behavior)
 }
 
-/////////////////////////////////////////////////////////////////////////////
-// Run Method
-/////////////////////////////////////////////////////////////////////////////
+/// How many seconds before startup a message can have been sent and still be
+/// handled, since the clocks of Telegram and the bot need not agree.
+const STARTUP_GRACE_SECS: i64 = 10;
 
-pub async fn run(context: Arc<Mutex<Context>>) -> Result<(), RunError> {
-    let mut dispatcher = Dispatcher::new(context.clone());
-    dispatcher.set_error_handler(LoggingErrorHandler::new(ErrorPolicy::Continue));
-    dispatcher.add_handler(handle);
+/// Returns true if the message was sent before the bot started, i.e. it was
+/// queued while the bot was down, and should be ignored rather than replied
+/// to long after the fact.
+fn sent_before_startup(message: &Message, started_at: i64) -> bool {
+    message.date < started_at - STARTUP_GRACE_SECS
+}
 
-    let context = context.lock().await.api.clone();
+/// Private chats are one-on-one chats with the bot. Groups, supergroups and
+/// channels are all groups.
+fn chat_kind(message: &Message) -> ChatKind {
+    match message.kind {
+        MessageKind::Private { .. } => ChatKind::Private,
+        _ => ChatKind::Group,
+    }
+}
+
+/// Returns the text of a message that should go through the learn/reply
+/// pipeline. Media messages (photos, videos, documents, audio and voice notes)
+/// carry their text in the caption, which `get_text` falls back to when the
+/// message has no text body of its own.
+fn message_input(message: &Message) -> Option<&str> {
+    message.get_text().map(|text| text.data.as_str())
+}
 
-    LongPoll::new(context, dispatcher).run().await;
-    Ok(())
+/// Returns the text of a message that should be learned. When stripping is
+/// on, bot commands and mentions are left out, so that the bot does not learn
+/// to repeat them. They are still part of message_input, so they can trigger
+/// replies. When learning attachments, the file name of a document is added
+/// as a sentence of its own.
+fn learn_input(message: &Message, strip_entities: bool, learn_attachments: bool) -> Option<String> {
+    let text = message.get_text().map(|text| {
+        if strip_entities {
+            strip_commands_and_mentions(text)
+        } else {
+            text.data.clone()
+        }
+    });
+    let attachment = if learn_attachments {
+        attachment_text(message)
+    } else {
+        None
+    };
+    match (text, attachment) {
+        (Some(text), Some(attachment)) => Some(format!("{}\n{}", text, attachment)),
+        (text, attachment) => text.or(attachment),
+    }
+}
+
+/// Returns whether the message is learned and who it is attributed to.
+/// Forwarded messages are still replied to, but are only learned when
+/// learn_forwards is on, and then attributed to their original author.
+fn learn_forward(message: &Message, learn_forwards: bool) -> (bool, Option<String>) {
+    match message.forward {
+        Some(ref forward) if learn_forwards => (true, forward_author_id(forward)),
+        Some(..) => (false, None),
+        None => (true, None),
+    }
+}
+
+/// Returns the ID of the user or channel that a forwarded message came from.
+/// Users who hide their account in forwards have no ID, so their messages
+/// are attributed to the user who forwarded them.
+fn forward_author_id(forward: &Forward) -> Option<String> {
+    match forward.from {
+        ForwardFrom::User(ref user) => Some(user.id.to_string()),
+        ForwardFrom::Channel { ref chat, .. } => Some(chat.id.to_string()),
+        ForwardFrom::HiddenUser(..) => None,
+    }
+}
+
+/// Returns the words in the file name of a document, without the extension.
+fn attachment_text(message: &Message) -> Option<String> {
+    let file_name = match message.data {
+        MessageData::Document { ref data, .. } => data.file_name.as_ref()?,
+        _ => return None,
+    };
+    let stem = Path::new(file_name).file_stem()?.to_string_lossy();
+    let words = stem
+        .split(|c: char| c == '_' || c == '-' || c == '.' || c.is_whitespace())
+        .filter(|w| !w.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ");
+    Some(words).filter(|w| !w.is_empty())
+}
+
+/// Returns the bot's user ID, which is the part of the token before the colon.
+fn bot_id_from_token(token: &str) -> Option<i64> {
+    token.split(':').next().and_then(|id| id.parse().ok())
+}
+
+/// Returns true if the message announces that the bot was added to the chat.
+fn bot_was_added(message: &Message, bot_id: Option<i64>) -> bool {
+    match (&message.data, bot_id) {
+        (MessageData::NewChatMembers(users), Some(bot_id)) => {
+            users.iter().any(|user| user.id == bot_id)
+        }
+        _ => false,
+    }
+}
+
+/// Returns the text of the bot's message that the message replies to, if any.
+fn replied_to_bot(message: &Message, bot_id: Option<i64>) -> Option<&str> {
+    let replied = message.reply_to.as_ref()?;
+    match (replied.get_user(), bot_id) {
+        (Some(user), Some(bot_id)) if user.id == bot_id => message_input(replied),
+        _ => None,
+    }
+}
+
+/// Returns the input that the response is made from. When the message
+/// replies to the bot, the bot's message is added so that the conversation
+/// stays connected.
+fn pivot_input(input: &str, replied: Option<&str>) -> String {
+    match replied {
+        Some(replied) => format!("{}\n{}", input, replied),
+        None => input.to_owned(),
+    }
+}
+
+fn strip_commands_and_mentions(text: &Text) -> String {
+    // Entity offsets and lengths are counted in UTF-16 code units.
+    let spans: Vec<(usize, usize)> = text
+        .entities
+        .iter()
+        .flatten()
+        .filter_map(|entity| match entity {
+            TextEntity::BotCommand(command) => Some(&command.data),
+            TextEntity::Mention(data) => Some(data),
+            TextEntity::TextMention(mention) => Some(&mention.data),
+            _ => None,
+        })
+        .map(|data| (data.offset, data.offset + data.length))
+        .collect();
+    if spans.is_empty() {
+        return text.data.clone();
+    }
+    let units: Vec<u16> = text
+        .data
+        .encode_utf16()
+        .enumerate()
+        .filter(|(i, _)| !spans.iter().any(|(start, end)| start <= i && i < end))
+        .map(|(_, unit)| unit)
+        .collect();
+    String::from_utf16_lossy(&units)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::borg::{ReplyAction, ReplyReason};
+    use serde_json::json;
+
+    #[test]
+    fn test_find_chat_behavior() {
+        let chat_behavior = |chat_id: &str, reply_rate: f32| ChatBehaviorOverrides {
+            chat_id: chat_id.to_owned(),
+            behavior: BehaviorOverride {
+                reply_rate: Some(reply_rate),
+                ..BehaviorOverride::default()
+            },
+        };
+        let chat_behaviors = Some(vec![
+            chat_behavior("-1001234567890", 0.5),
+            chat_behavior(" 42 ", 0.25),
+        ]);
+
+        let found =
+            |chat_id| find_chat_behavior(&chat_behaviors, chat_id).and_then(|b| b.reply_rate);
+        assert_eq!(Some(0.5), found(-1001234567890));
+        assert_eq!(Some(0.25), found(42));
+        assert_eq!(None, found(1001234567890));
+        assert_eq!(None, find_chat_behavior(&None, 42).map(|b| b.reply_rate));
+    }
+
+    #[test]
+    fn test_sent_before_startup() {
+        let message = |date: i64| -> Message {
+            serde_json::from_value(json!({
+                "message_id": 1,
+                "date": date,
+                "from": {"id": 1, "first_name": "user", "is_bot": false},
+                "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+                "text": "hello crabs"
+            }))
+            .unwrap()
+        };
+        let started_at = 1_600_000_000;
+
+        assert!(sent_before_startup(&message(started_at - 3600), started_at));
+        assert!(!sent_before_startup(&message(started_at - 5), started_at));
+        assert!(!sent_before_startup(&message(started_at), started_at));
+        assert!(!sent_before_startup(&message(started_at + 60), started_at));
+    }
+
+    #[test]
+    fn test_chat_kind() {
+        let message = |chat: serde_json::Value| -> Message {
+            serde_json::from_value(json!({
+                "message_id": 1,
+                "date": 0,
+                "from": {"id": 1, "first_name": "user", "is_bot": false},
+                "chat": chat,
+                "text": "hello crabs"
+            }))
+            .unwrap()
+        };
+
+        assert_eq!(
+            ChatKind::Private,
+            chat_kind(&message(
+                json!({"id": 1, "type": "private", "first_name": "user"})
+            ))
+        );
+        assert_eq!(
+            ChatKind::Group,
+            chat_kind(&message(
+                json!({"id": -1, "type": "group", "title": "chat"})
+            ))
+        );
+        assert_eq!(
+            ChatKind::Group,
+            chat_kind(&message(
+                json!({"id": -100, "type": "supergroup", "title": "chat"})
+            ))
+        );
+    }
+
+    #[test]
+    fn test_message_input_uses_caption() {
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "from": {"id": 1, "first_name": "user", "is_bot": false},
+            "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+            "photo": [{"file_id": "f", "file_unique_id": "u", "width": 1, "height": 1}],
+            "caption": "look at this crab"
+        }))
+        .unwrap();
+
+        assert_eq!(Some("look at this crab"), message_input(&message));
+        assert_eq!(-100, message.get_chat_id());
+    }
+
+    #[test]
+    fn test_learn_input_strips_commands_and_mentions() {
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "from": {"id": 1, "first_name": "user", "is_bot": false},
+            "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+            "text": "/start@borgbot 🦀 hello @ferris, crabs are great",
+            "entities": [
+                {"type": "bot_command", "offset": 0, "length": 14},
+                {"type": "mention", "offset": 24, "length": 7}
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            Some("🦀 hello , crabs are great".to_owned()),
+            learn_input(&message, true, false)
+        );
+        assert_eq!(
+            Some("/start@borgbot 🦀 hello @ferris, crabs are great".to_owned()),
+            learn_input(&message, false, false)
+        );
+        assert_eq!(
+            Some("/start@borgbot 🦀 hello @ferris, crabs are great"),
+            message_input(&message)
+        );
+    }
+
+    #[test]
+    fn test_reply_to_bot() {
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 2,
+            "date": 0,
+            "from": {"id": 1, "first_name": "user", "is_bot": false},
+            "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+            "text": "why crabs",
+            "reply_to_message": {
+                "message_id": 1,
+                "date": 0,
+                "from": {"id": 42, "first_name": "borg", "is_bot": true},
+                "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+                "text": "lobsters are red"
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(Some(42), bot_id_from_token("42:secret"));
+        assert_eq!(None, bot_id_from_token("secret"));
+        let replied = replied_to_bot(&message, Some(42));
+        assert_eq!(Some("lobsters are red"), replied);
+        assert_eq!(None, replied_to_bot(&message, Some(43)));
+        assert_eq!(None, replied_to_bot(&message, None));
+
+        let mut dictionary = crate::dictionary::Dictionary::new_empty();
+        dictionary.learn("lobsters are tasty. lobsters are red.");
+        let borg = Borg::new(
+            dictionary,
+            config::MainBehavior {
+                reply_nick: 1.0,
+                reply_only_when_addressed: true,
+                ..config::MainBehavior::default()
+            },
+        );
+        let input = message_input(&message).unwrap();
+        assert_eq!(
+            None,
+            borg.reply_reason("1", "1", ChatKind::Group, input, false, &None)
+        );
+        let reason = borg.reply_reason("1", "1", ChatKind::Group, input, replied.is_some(), &None);
+        assert_eq!(Some(ReplyReason::Nick), reason);
+
+        // Only the replied-to message has known words to make a response from.
+        let pivot = pivot_input(input, replied);
+        assert_eq!("why crabs\nlobsters are red", pivot);
+        match borg.reply_action_for(reason.unwrap(), "1", input, &pivot, &None, false) {
+            Some(ReplyAction::Text(response)) => assert!(response.contains("lobsters")),
+            action => panic!("expected a text reply, got {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_learn_input_attachments() {
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "from": {"id": 1, "first_name": "user", "is_bot": false},
+            "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+            "document": {
+                "file_id": "f",
+                "file_unique_id": "u",
+                "file_name": "giant_crab-sightings.2020.pdf"
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(
+            Some("giant crab sightings 2020".to_owned()),
+            learn_input(&message, false, true)
+        );
+        assert_eq!(None, learn_input(&message, false, false));
+        assert_eq!(None, message_input(&message));
+
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "from": {"id": 1, "first_name": "user", "is_bot": false},
+            "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+            "document": {"file_id": "f", "file_unique_id": "u", "file_name": "crabs.txt"},
+            "caption": "read this"
+        }))
+        .unwrap();
+
+        assert_eq!(
+            Some("read this\ncrabs".to_owned()),
+            learn_input(&message, false, true)
+        );
+        assert_eq!(
+            Some("read this".to_owned()),
+            learn_input(&message, false, false)
+        );
+    }
+
+    #[test]
+    fn test_learn_forward() {
+        let forward = |fields: serde_json::Value| -> Message {
+            let mut message = json!({
+                "message_id": 1,
+                "date": 0,
+                "from": {"id": 1, "first_name": "user", "is_bot": false},
+                "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+                "text": "crabs are great",
+                "forward_date": 0
+            });
+            for (key, value) in fields.as_object().unwrap() {
+                message[key] = value.clone();
+            }
+            serde_json::from_value(message).unwrap()
+        };
+
+        let message = forward(json!({
+            "forward_from": {"id": 2, "first_name": "author", "is_bot": false}
+        }));
+        assert_eq!((false, None), learn_forward(&message, false));
+        assert_eq!((true, Some("2".to_owned())), learn_forward(&message, true));
+        // A forward is still replied to.
+        assert_eq!(Some("crabs are great"), message_input(&message));
+
+        let message = forward(json!({
+            "forward_from_chat": {"id": -200, "type": "channel", "title": "crab news"},
+            "forward_from_message_id": 5
+        }));
+        assert_eq!(
+            (true, Some("-200".to_owned())),
+            learn_forward(&message, true)
+        );
+
+        let message = forward(json!({"forward_sender_name": "hidden"}));
+        assert_eq!((true, None), learn_forward(&message, true));
+
+        let message = forward(json!({"forward_date": null}));
+        assert_eq!((true, None), learn_forward(&message, false));
+    }
+
+    #[test]
+    fn test_bot_was_added() {
+        let added = |ids: &[i64]| -> Message {
+            let users: Vec<_> = ids
+                .iter()
+                .map(|id| json!({"id": id, "first_name": "member", "is_bot": false}))
+                .collect();
+            serde_json::from_value(json!({
+                "message_id": 1,
+                "date": 0,
+                "from": {"id": 1, "first_name": "user", "is_bot": false},
+                "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+                "new_chat_members": users
+            }))
+            .unwrap()
+        };
+
+        assert!(bot_was_added(&added(&[2, 42]), Some(42)));
+        assert!(!bot_was_added(&added(&[2, 3]), Some(42)));
+        assert!(!bot_was_added(&added(&[42]), None));
+
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "from": {"id": 42, "first_name": "borg", "is_bot": true},
+            "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+            "text": "hello"
+        }))
+        .unwrap();
+        assert!(!bot_was_added(&message, Some(42)));
+    }
+
+    #[test]
+    fn test_message_input_without_text() {
+        let message: Message = serde_json::from_value(json!({
+            "message_id": 1,
+            "date": 0,
+            "from": {"id": 1, "first_name": "user", "is_bot": false},
+            "chat": {"id": -100, "type": "supergroup", "title": "chat"},
+            "photo": [{"file_id": "f", "file_unique_id": "u", "width": 1, "height": 1}]
+        }))
+        .unwrap();
+
+        assert_eq!(None, message_input(&message));
+    }
 }
@@ -24,3 +24,12 @@ pub fn unix_time() -> u64 {
         .expect("Time went backwards")
         .as_secs()
 }
+
+/// Like unix_time, but in milliseconds.
+pub fn unix_time_ms() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
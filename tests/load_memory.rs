@@ -0,0 +1,79 @@
+//! Checks that loading a dictionary parses the file while reading it, instead
+//! of reading it whole first. Peak memory is measured by counting
+//! allocations with a global allocator, so this test has a binary of its own
+//! and runs without the test harness, on a single thread.
+
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+
+#[allow(dead_code, unused_imports)]
+#[path = "../src/dictionary.rs"]
+mod dictionary;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dictionary::Dictionary;
+
+/// Counts the bytes currently allocated, and the most that were allocated at
+/// once since PEAK was last reset.
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let now = ALLOCATED.fetch_add(layout.size(), Ordering::SeqCst) + layout.size();
+        PEAK.fetch_max(now, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::SeqCst);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Returns how many more bytes than before were allocated at the peak of
+/// running f.
+fn peak_allocation<T>(f: impl FnOnce() -> T) -> usize {
+    let before = ALLOCATED.load(Ordering::SeqCst);
+    PEAK.store(before, Ordering::SeqCst);
+    drop(f());
+    PEAK.load(Ordering::SeqCst) - before
+}
+
+fn main() {
+    let path =
+        std::env::temp_dir().join(format!("borg_test_load_memory_{}.json", std::process::id()));
+    let mut d = Dictionary::new_empty();
+    let lines: Vec<String> = (0..20_000)
+        .map(|i| format!("crab number {} walks sideways along beach {}.", i, i % 97))
+        .collect();
+    d.learn_batch(lines.iter().map(String::as_str));
+    d.write_to_disk(&path).unwrap();
+    let file_size = fs::metadata(&path).unwrap().len() as usize;
+
+    let read_then_parse = peak_allocation(|| {
+        let data = fs::read(&path).unwrap();
+        Dictionary::from_reader(&data[..]).unwrap()
+    });
+    let streamed = peak_allocation(|| Dictionary::load(&path).unwrap());
+    fs::remove_file(&path).unwrap();
+
+    // Reading the whole file first needs room for both the file and the
+    // parsed dictionary at once.
+    assert!(
+        streamed + file_size / 2 < read_then_parse,
+        "loading peaked at {} bytes, reading then parsing at {} bytes",
+        streamed,
+        read_then_parse
+    );
+}